@@ -17,7 +17,7 @@
             (err "ns-export takes a symbol or sequence.")))))
 
 (defmacro ns-import (namespace)
-    `(core::for sym (eval (to-symbol (str ,namespace "::*ns-exports*"))) (def (to-symbol (str "ns::" sym)) (eval (to-symbol (str ,namespace "::" sym))))))
+    `(for sym (eval (to-symbol (str ,namespace "::*ns-exports*"))) (def (to-symbol (str "ns::" sym)) (eval (to-symbol (str ,namespace "::" sym))))))
 
 (defmacro setq (sym bind)
 	`(set (quote ,sym) ,bind))
@@ -34,12 +34,10 @@
 (defmacro loop (params bindings body)
 		`((fn ,params ,body) ,@bindings))
 
-(defmacro dotimes (times body)
-	(core::let ((idx-name (gensym)))
-	`(if (> ,times 0)
-		(core::loop (idx-name) (,times) (progn
-			(,@body)
-			(if (> idx-name 1) (recur (- idx-name 1))))))))
+;; dotimes and for are native special forms, these keep core::dotimes and
+;; core::for working.
+(def 'dotimes dotimes)
+(def 'for for)
 
 (defmacro dotimesi (idx-bind times body)
 	(core::let ((stop-name (gensym)))
@@ -48,14 +46,6 @@
 			(,@body)
 			(if (< ,idx-bind stop-name) (recur (+ ,idx-bind 1) stop-name)))))))
 
-(defmacro for (bind in_list body)
-	`(core::let ((,bind))
-		(if (> (length ,in_list) 0)
-			(core::loop (plist) (,in_list) (progn
-				(core::setq ,bind (core::first plist))
-				(,@body)
-				(if (> (length plist) 1) (recur (core::rest plist))))))))
-
 (defmacro fori (idx_bind bind in_list body)
 	`((fn () (progn
 		(core::defq ,bind nil)(core::defq ,idx_bind nil)
@@ -59,7 +59,6 @@
 (def 'append nil)
 (def 'append! nil)
 (def 'fn-append! nil)
-(def 'map nil)
 (let ((tseq))
     (defn copy-els (to l) (progn
         (def 'tcell nil)
@@ -136,32 +135,7 @@
     (setmacro append! (ret &rest others)
         `(if (and (symbol? (quote ,ret)) (null ,ret))
             (set (quote ,ret) (core::fn-append! ,ret ,@others))
-            (core::fn-append! ,ret ,@others)))
-
-    (defn map-into (fun items new-items) (progn
-        (def 'tcell nil)
-        (for i items
-            (progn
-                (if (null new-items)
-                    (progn (set 'tseq (set 'new-items (join (fun i) nil))))
-                    (progn (set 'tcell (join (fun i) nil)) (xdr! tseq tcell) (set 'tseq tcell)))))
-        new-items))
-
-    (setfn map (fun items)
-        (if (vec? items)
-            (progn
-                (defq new-items (make-vec (length items)))
-                (for i items (vec-push! new-items (fun i)))
-                new-items)
-            (if (list? items)
-                (progn
-                    (defq new-items nil)
-                    (set 'new-items (map-into(fun items new-items)))
-                    (set 'tseq nil)
-                    new-items)
-                (if (null items)
-                    nil
-                    (err "Not a list or vector"))))))
+            (core::fn-append! ,ret ,@others))))
 
 (defn map! (fun items) (progn
     (fori i it items
@@ -196,5 +170,5 @@
     (irev items 0 (- (length items) 1))
     items))
 
-(ns-export '(seq? first rest last butlast setnth! nth append append! map map! reverse reverse!))
+(ns-export '(seq? first rest last butlast setnth! nth append append! map! reverse reverse!))
 
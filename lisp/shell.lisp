@@ -45,16 +45,38 @@
 		(dyn '*stdout* (open ,file :create :truncate) (dyn '*stderr* *stdout* ,body))))
 
 ;; Redirect stdout to null (/dev/null equivelent).
-(defmacro out>null (body)
-	`(dyn '*stdout* (open "/dev/null" :write) ,body))
+(defmacro out>null (&rest body)
+	`(dyn '*stdout* (open "/dev/null" :write) (progn ,@body)))
 
 ;; Redirect stderr to null (/dev/null equivelent).
-(defmacro err>null (body)
-	`(dyn '*stderr* (open "/dev/null" :write) ,body))
+(defmacro err>null (&rest body)
+	`(dyn '*stderr* (open "/dev/null" :write) (progn ,@body)))
 
 ;; Redirect both stdout and stderr to null (/dev/null equivelent).
-(defmacro out-err>null (body)
-	`(dyn '*stdout* (open "/dev/null" :write) (dyn '*stderr* *stdout* ,body)))
+(defmacro out-err>null (&rest body)
+	`(dyn '*stdout* (open "/dev/null" :write) (dyn '*stderr* *stdout* (progn ,@body))))
+
+;; Discard stdout, same as out>null.
+(defmacro out-null (&rest body)
+	`(out>null ,@body))
+
+;; Discard stderr, same as err>null.
+(defmacro err-null (&rest body)
+	`(err>null ,@body))
+
+;; Discard stdout and stderr, same as out-err>null.
+(defmacro io-null (&rest body)
+	`(out-err>null ,@body))
+
+;; Discard all output of body and return the exit status of the last command.
+(defmacro quiet (&rest body)
+	`(progn (out-err>null ,@body) *last-status*))
+
+;; Read stdin from file, for commands and lisp reads (read-line, *stdin*).
+(defmacro stdin-from (file &rest body)
+	`(if (file? ,file)
+		(dyn '*stdin* ,file (progn ,@body))
+		(dyn '*stdin* (open ,file :read) (progn ,@body))))
 
 ;; Shorthand for pipe builtin.
 (defmacro | (&rest body)
@@ -102,14 +124,14 @@
 				(progn
 					(vec-insert-nth! idx (nth 0 el) params)
 					(vec-insert-nth! idx nil bindings)
-					(vec-insert-nth! idx (eval (to-symbol (str "\$" (nth 0 el)))) olds))
+					(vec-insert-nth! idx (to-symbol (str "\$" (nth 0 el))) olds))
 				(if (= 2 (length el))
 					(progn
 						(def 'binding (nth 1 el))
-						(if (or (list? binding)(vec? binding)) (set 'binding (eval binding)))
+						(if (not (or (list? binding)(vec? binding))) (set 'binding `(quote ,binding)))
 						(vec-insert-nth! idx (nth 0 el) params)
 						(vec-insert-nth! idx binding bindings)
-						(vec-insert-nth! idx (eval (to-symbol (str "\$" (nth 0 el)))) olds))
+						(vec-insert-nth! idx (to-symbol (str "\$" (nth 0 el))) olds))
 					(err "ERROR: invalid bindings on let-env"))))
 		`((fn (params bindings olds)
 			(unwind-protect
@@ -123,7 +145,9 @@
 					(if (null (nth i olds))
 						(unexport p)
 						(export p (nth i olds))))))
-		(quote ,params) (quote ,bindings) (quote ,olds))))
+		; The values and old values are read when the expansion runs (it is
+		; cached, see recompile).
+		(quote ,params) (vec ,@bindings) (vec ,@olds))))
 	(make-vec (length vals)) (make-vec (length vals)) (make-vec (length vals))))
 
 
@@ -314,4 +338,4 @@
 ;; Turn off syntax highlighting at the repl.
 (defmacro syntax-off () '(undef '__line_handler))
 
-(ns-export '(alias out>> out> err>> err> out-err>> out-err> out>null err>null out-err>null | pushd popd dirs get-dirs clear-dirs set-dirs-max let-env sys-command? syntax-on syntax-off set-tok-colors fg-color-rgb bg-color-rgb))
+(ns-export '(alias out>> out> err>> err> out-err>> out-err> out>null err>null out-err>null out-null err-null io-null quiet stdin-from | pushd popd dirs get-dirs clear-dirs set-dirs-max let-env sys-command? syntax-on syntax-off set-tok-colors fg-color-rgb bg-color-rgb))
@@ -3,23 +3,35 @@ use nix::{
         signal::{self, Signal},
         termios,
     },
-    unistd::{self, Pid},
+    unistd::{self, ForkResult, Pid},
 };
+use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::{hash_map, HashMap};
+use std::collections::{hash_map, HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::hash::BuildHasher;
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::path::Path;
+use std::process::{Command, Stdio};
 use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::builtins_options::{get_option, set_option};
+use crate::builtins_seq::{eval_seq, sequence, Sequence};
 use crate::builtins_util::*;
 use crate::config::VERSION_STRING;
 use crate::environment::*;
 use crate::eval::*;
+use crate::fmt::*;
+use crate::gc::*;
+use crate::pretty::*;
 use crate::process::*;
 use crate::reader::*;
+use crate::resolve::*;
+use crate::shell::default_read_hook;
+use crate::startup::{read_embedded, LoadProfile};
 use crate::types::*;
 
 fn builtin_eval(
@@ -27,20 +39,128 @@ fn builtin_eval(
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
     if let Some(arg) = args.next() {
+        let namespace = if let Some(ns) = args.next() {
+            let key = match eval(environment, ns)? {
+                Expression::Atom(Atom::Symbol(sym)) => sym,
+                Expression::Atom(Atom::String(s)) => s.into(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "eval: namespace must be a symbol or string",
+                    ))
+                }
+            };
+            match get_namespace(environment, &key) {
+                Some(scope) => Some(scope),
+                None => {
+                    let msg = format!("eval: namespace {} does not exist!", key);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            }
+        } else {
+            None
+        };
         if args.next().is_none() {
             let arg = eval(environment, &arg)?;
-            return match arg {
+            let pushed = if let Some(scope) = namespace {
+                environment.current_scope.push(scope);
+                true
+            } else {
+                false
+            };
+            let res = match arg {
                 Expression::Atom(Atom::String(s)) => match read(&s, false) {
                     Ok(ast) => eval(environment, &ast),
                     Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
                 },
                 _ => eval(environment, &arg),
             };
+            if pushed {
+                environment.current_scope.pop();
+            }
+            return res;
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "eval takes a form and optional namespace",
+    ))
+}
+
+fn builtin_read_string(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        let text = eval(environment, arg)?.as_string(environment)?;
+        let with_rest = match args.next() {
+            Some(Expression::Atom(Atom::Symbol(s))) if &**s == ":rest" => true,
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "read-string: only option is :rest",
+                ))
+            }
+            None => false,
+        };
+        if args.next().is_none() {
+            return match read_form(&text) {
+                Ok(Some((form, end))) => {
+                    if with_rest {
+                        let rest = Expression::Atom(Atom::String(text[end..].to_string().into()));
+                        Ok(Expression::with_list(vec![form, rest]))
+                    } else {
+                        Ok(form)
+                    }
+                }
+                Ok(None) => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "read-string: no form to read",
+                )),
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "read-string takes a string and optional :rest",
+    ))
+}
+
+fn builtin_read_all(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let text = eval(environment, arg)?.as_string(environment)?;
+            return match read(&text, true) {
+                Ok(forms) => Ok(Expression::with_list(forms.iter().cloned().collect())),
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "read-all takes one form",
+    ))
+}
+
+fn builtin_default_read_hook(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let line = eval(environment, arg)?.as_string(environment)?;
+            return Ok(Expression::Atom(Atom::String(
+                default_read_hook(&line).into(),
+            )));
         }
     }
     Err(io::Error::new(
         io::ErrorKind::Other,
-        "eval can only have one form",
+        "default-read-hook takes one form (a line)",
     ))
 }
 
@@ -106,6 +226,199 @@ fn builtin_apply(
     )
 }
 
+// Eval all the args, the first num_fns must be callable (what is the form
+// for the error).
+fn eval_fn_args(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    num_fns: usize,
+    what: &str,
+) -> io::Result<Vec<Expression>> {
+    let mut evaled = Vec::new();
+    for arg in args {
+        evaled.push(eval(environment, arg)?);
+    }
+    for f in evaled.iter().take(num_fns) {
+        match f {
+            Expression::Atom(Atom::Lambda(_)) | Expression::Func(_) => {}
+            Expression::Function(c) if !c.is_special_form => {}
+            _ => {
+                let msg = format!("{}: {} is not a function", what, f);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok(evaled)
+}
+
+// A lambda taking any arguments that calls body (the native part of a
+// combinator) with data bound where body can get it, no fn form to eval.
+fn native_lambda(
+    environment: &Environment,
+    body: Expression,
+    data: Vec<(&str, Expression)>,
+) -> Expression {
+    let capture = build_new_scope(Some(environment.current_scope.last().unwrap().clone()));
+    for (name, value) in data {
+        capture
+            .borrow_mut()
+            .data
+            .insert(name.to_string(), Rc::new(value));
+    }
+    Expression::Atom(Atom::Lambda(Lambda {
+        params: Rc::new(Expression::cons_from_vec(&mut vec![
+            Expression::Atom(Atom::Symbol("&rest".into())),
+            Expression::Atom(Atom::Symbol("%rest".into())),
+        ])),
+        body: Rc::new(Expression::cons_from_vec(&mut vec![body])),
+        capture,
+        slots: None,
+    }))
+}
+
+// Value bound by native_lambda (or the args it was called with for %rest).
+fn native_data(environment: &Environment, name: &str) -> Vec<Expression> {
+    match get_expression(environment, name).map(|e| (*e).clone()) {
+        Some(Expression::Vector(list)) => list.borrow().clone(),
+        Some(Expression::Atom(Atom::Nil)) | None => Vec::new(),
+        Some(exp) => exp.iter().cloned().collect(),
+    }
+}
+
+fn call_partial(
+    environment: &mut Environment,
+    _args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut args = native_data(environment, "%partial-args");
+    let f = args.remove(0);
+    args.append(&mut native_data(environment, "%rest"));
+    call_with(environment, &f, args)
+}
+
+fn builtin_partial(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_fn_args(environment, args, 1, "partial")?;
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "partial takes a function and the arguments to give it first",
+        ));
+    }
+    Ok(native_lambda(
+        environment,
+        Expression::make_function(call_partial, "Call of a partial."),
+        vec![("%partial-args", Expression::with_list(args))],
+    ))
+}
+
+fn call_compose(
+    environment: &mut Environment,
+    _args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let fns = native_data(environment, "%compose-fns");
+    let mut args = native_data(environment, "%rest");
+    let mut fns = fns.iter().rev();
+    let mut result = match fns.next() {
+        Some(f) => call_with(environment, f, args)?,
+        // (compose) is identity.
+        None if args.len() == 1 => args.remove(0),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "compose with no functions takes one argument",
+            ))
+        }
+    };
+    for f in fns {
+        result = call_with(environment, f, vec![result])?;
+    }
+    Ok(result)
+}
+
+fn builtin_compose(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let fns = eval_fn_args(environment, args, usize::max_value(), "compose")?;
+    Ok(native_lambda(
+        environment,
+        Expression::make_function(call_compose, "Call of a compose."),
+        vec![("%compose-fns", Expression::with_list(fns))],
+    ))
+}
+
+fn call_complement(
+    environment: &mut Environment,
+    _args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let f = native_data(environment, "%complement-fn").remove(0);
+    let args = native_data(environment, "%rest");
+    Ok(match call_with(environment, &f, args)? {
+        Expression::Atom(Atom::Nil) => Expression::Atom(Atom::True),
+        _ => Expression::Atom(Atom::Nil),
+    })
+}
+
+fn builtin_complement(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_fn_args(environment, args, 1, "complement")?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "complement takes one function",
+        ));
+    }
+    Ok(native_lambda(
+        environment,
+        Expression::make_function(call_complement, "Call of a complement."),
+        vec![("%complement-fn", Expression::with_list(args))],
+    ))
+}
+
+fn call_constantly(
+    environment: &mut Environment,
+    _args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    Ok(native_data(environment, "%constantly-value").remove(0))
+}
+
+fn builtin_constantly(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_fn_args(environment, args, 0, "constantly")?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "constantly takes one value",
+        ));
+    }
+    Ok(native_lambda(
+        environment,
+        Expression::make_function(call_constantly, "Call of a constantly."),
+        vec![("%constantly-value", Expression::with_list(args))],
+    ))
+}
+
+fn builtin_identity(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            return eval(environment, arg);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "identity takes one form",
+    ))
+}
+
 fn builtin_unwind_protect(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
@@ -113,7 +426,7 @@ fn builtin_unwind_protect(
     if let Some(protected) = args.next() {
         let result = eval(environment, protected);
         for a in args {
-            if let Err(err) = eval(environment, a) {
+            if let Err(err) = run_cleanup(environment, |environment| eval(environment, a)) {
                 eprintln!(
                     "ERROR in unwind-protect cleanup form {}, {} will continue cleanup",
                     a, err
@@ -126,25 +439,188 @@ fn builtin_unwind_protect(
     }
 }
 
-fn builtin_err(
+// Run a cleanup even while unwinding, anything that is unwinding eval (exit,
+// SIGINT, a timeout or a throw) is put aside for the cleanup then restored.
+pub fn run_cleanup<F>(environment: &mut Environment, cleanup: F) -> io::Result<Expression>
+where
+    F: FnOnce(&mut Environment) -> io::Result<Expression>,
+{
+    let exit_code = environment.exit_code.take();
+    let interrupted = environment
+        .sig_int
+        .swap(false, std::sync::atomic::Ordering::Relaxed);
+    let deadline = environment.state.deadline.take();
+    let thrown = environment.thrown.take();
+    let result = cleanup(environment);
+    if exit_code.is_some() {
+        environment.exit_code = exit_code;
+    }
+    if interrupted {
+        environment
+            .sig_int
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    if deadline.is_some() {
+        environment.state.deadline = deadline;
+    }
+    if thrown.is_some() {
+        environment.thrown = thrown;
+    }
+    result
+}
+
+fn builtin_dynamic_wind(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    if let Some(arg) = args.next() {
-        if args.next().is_none() {
-            let arg = eval(environment, arg)?;
+    let (before, during, after) = match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(before), Some(during), Some(after), None) => (
+            eval(environment, before)?,
+            eval(environment, during)?,
+            eval(environment, after)?,
+        ),
+        _ => {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
-                arg.as_string(environment)?,
-            ));
+                "dynamic-wind takes three functions (before, during and after)",
+            ))
+        }
+    };
+    let no_args: Vec<Expression> = Vec::new();
+    fn_call(environment, &before, Box::new(no_args.iter()))?;
+    let result = fn_call(environment, &during, Box::new(no_args.iter()));
+    let after_result = run_cleanup(environment, |environment| {
+        fn_call(environment, &after, Box::new(no_args.iter()))
+    });
+    match (result, after_result) {
+        (Ok(_), Err(err)) => Err(err),
+        (Err(err), Err(after_err)) => {
+            eprintln!("ERROR in dynamic-wind after function: {}", after_err);
+            Err(err)
+        }
+        (result, Ok(_)) => result,
+    }
+}
+
+fn builtin_defer(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut forms: Vec<Expression> = args.cloned().collect();
+    let form = match forms.len() {
+        0 => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "defer takes forms to run when the body exits",
+            ))
+        }
+        1 => forms.remove(0),
+        _ => {
+            forms.insert(0, Expression::Atom(Atom::Symbol("progn".into())));
+            Expression::cons_from_vec(&mut forms)
+        }
+    };
+    match environment.defers.last_mut() {
+        Some(defers) => {
+            defers.push(form);
+            Ok(Expression::Atom(Atom::Nil))
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "defer must be in a function, let or loaded file",
+        )),
+    }
+}
+
+fn builtin_catch(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let tag = match args.next() {
+        Some(tag) => eval(environment, tag)?.make_string(environment)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "catch takes a tag and forms",
+            ))
+        }
+    };
+    environment.catch_tags.push(tag.clone());
+    let mut ret = Ok(Expression::Atom(Atom::Nil));
+    for arg in args {
+        ret = eval(environment, arg);
+        if ret.is_err() {
+            break;
+        }
+    }
+    environment.catch_tags.pop();
+    match ret {
+        Err(ref err) if throw_tag(err) == Some(&tag[..]) => Ok(environment
+            .thrown
+            .take()
+            .unwrap_or(Expression::Atom(Atom::Nil))),
+        ret => ret,
+    }
+}
+
+fn builtin_throw(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(tag) = args.next() {
+        let tag = eval(environment, tag)?.make_string(environment)?;
+        let value = match args.next() {
+            Some(value) => eval(environment, value)?,
+            None => Expression::Atom(Atom::Nil),
+        };
+        if args.next().is_none() {
+            if !environment.catch_tags.contains(&tag) {
+                let msg = format!("throw: no catch for tag {}", tag);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+            environment.thrown = Some(value);
+            return Err(io::Error::new(io::ErrorKind::Other, ThrowError { tag }));
         }
     }
     Err(io::Error::new(
         io::ErrorKind::Other,
-        "err can only have one form",
+        "throw takes a tag and an optional value",
     ))
 }
 
+fn builtin_err(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut args: Vec<Expression> = args
+        .map(|a| eval(environment, a))
+        .collect::<io::Result<_>>()?;
+    let kind = match args.first() {
+        Some(Expression::Atom(Atom::Symbol(s))) if s.starts_with(':') && args.len() > 1 => {
+            Some(s.to_string())
+        }
+        _ => None,
+    };
+    if kind.is_some() {
+        args.remove(0);
+    }
+    if args.is_empty() || args.len() > 2 || (kind.is_none() && args.len() > 1) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "err takes a message or a type keyword, message and optional data",
+        ));
+    }
+    let msg = args[0].as_string(environment)?;
+    environment.error_data = args.get(1).cloned();
+    match kind {
+        Some(kind) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            TypedError { kind, msg },
+        )),
+        None => Err(io::Error::new(io::ErrorKind::Other, msg)),
+    }
+}
+
 pub fn load(environment: &mut Environment, file_name: &str) -> io::Result<Expression> {
     let core_lisp = include_bytes!("../lisp/core.lisp");
     let seq_lisp = include_bytes!("../lisp/seq.lisp");
@@ -167,8 +643,8 @@ pub fn load(environment: &mut Environment, file_name: &str) -> io::Result<Expres
         let mut path_out = file_name.clone();
         for l in p_itr {
             let path_name = match l {
-                Expression::Atom(Atom::Symbol(sym)) => Some(sym),
-                Expression::Atom(Atom::String(s)) => Some(s),
+                Expression::Atom(Atom::Symbol(sym)) => Some(&sym[..]),
+                Expression::Atom(Atom::String(s)) => Some(&s[..]),
                 _ => None,
             };
             if let Some(path_name) = path_name {
@@ -189,21 +665,26 @@ pub fn load(environment: &mut Environment, file_name: &str) -> io::Result<Expres
         file_name
     };
     let path = Path::new(&file_path);
+    let start = Instant::now();
+    let mut cached = false;
     let ast = if path.exists() {
-        let contents = fs::read_to_string(file_path)?;
+        let contents = fs::read_to_string(&file_path)?;
         read(&contents, false)
     } else {
-        match &file_path[..] {
-            "core.lisp" => read(&String::from_utf8_lossy(core_lisp), false),
-            "seq.lisp" => read(&String::from_utf8_lossy(seq_lisp), false),
-            "shell.lisp" => read(&String::from_utf8_lossy(shell_lisp), false),
-            "slsh-std.lisp" => read(&String::from_utf8_lossy(slsh_std_lisp), false),
-            "slshrc" => read(&String::from_utf8_lossy(slshrc), false),
+        let source: &[u8] = match &file_path[..] {
+            "core.lisp" => core_lisp,
+            "seq.lisp" => seq_lisp,
+            "shell.lisp" => shell_lisp,
+            "slsh-std.lisp" => slsh_std_lisp,
+            "slshrc" => slshrc,
             _ => {
                 let msg = format!("{} not found", file_path);
                 return Err(io::Error::new(io::ErrorKind::Other, msg));
             }
-        }
+        };
+        let (ast, from_cache) = read_embedded(&file_path, source);
+        cached = from_cache;
+        ast
     };
     match ast {
         Ok(ast) => {
@@ -214,7 +695,7 @@ pub fn load(environment: &mut Environment, file_name: &str) -> io::Result<Expres
                         match first {
                             Expression::Vector(_) => {
                                 let mut v = Vec::with_capacity(list.len() + 1);
-                                v.push(Expression::Atom(Atom::Symbol("progn".to_string())));
+                                v.push(Expression::Atom(Atom::Symbol("progn".into())));
                                 for l in list.drain(..) {
                                     v.push(l);
                                 }
@@ -222,7 +703,7 @@ pub fn load(environment: &mut Environment, file_name: &str) -> io::Result<Expres
                             }
                             Expression::Pair(_, _) => {
                                 let mut v = Vec::with_capacity(list.len() + 1);
-                                v.push(Expression::Atom(Atom::Symbol("progn".to_string())));
+                                v.push(Expression::Atom(Atom::Symbol("progn".into())));
                                 for l in list.drain(..) {
                                     v.push(l);
                                 }
@@ -240,9 +721,36 @@ pub fn load(environment: &mut Environment, file_name: &str) -> io::Result<Expres
                 }
                 _ => ast,
             };
-            eval(environment, &ast)
+            let profile_idx = if let Some(profile) = &mut environment.startup_profile {
+                profile.loads.push(LoadProfile {
+                    name: file_path.clone(),
+                    depth: profile.depth,
+                    cached,
+                    read: start.elapsed(),
+                    total: start.elapsed(),
+                });
+                profile.depth += 1;
+                Some(profile.loads.len() - 1)
+            } else {
+                None
+            };
+            let old_file = environment.state.load_file.replace(file_path.clone());
+            environment.defers.push(Vec::new());
+            let res = eval(environment, &ast);
+            let res = run_defers(environment, res);
+            environment.defers.pop();
+            environment.state.load_file = old_file;
+            if let (Some(idx), Some(profile)) = (profile_idx, &mut environment.startup_profile) {
+                profile.depth -= 1;
+                profile.loads[idx].total = start.elapsed();
+            }
+            res
+        }
+        // InvalidData so a script can report it as a parse error of file_path.
+        Err(err) => {
+            let msg = format!("{}: {}", file_path, err.reason);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
         }
-        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
     }
 }
 
@@ -308,6 +816,11 @@ fn builtin_length(
                 Expression::HashMap(map) => {
                     Ok(Expression::Atom(Atom::Int(map.borrow().len() as i64)))
                 }
+                Expression::PVec(pvec) => Ok(Expression::Atom(Atom::Int(pvec.len() as i64))),
+                Expression::Array(arr) => {
+                    Ok(Expression::Atom(Atom::Int(arr.borrow().len() as i64)))
+                }
+                Expression::PMap(pmap) => Ok(Expression::Atom(Atom::Int(pmap.len() as i64))),
                 _ => Ok(Expression::Atom(Atom::Int(0))),
             };
         }
@@ -324,15 +837,13 @@ fn builtin_if(
 ) -> io::Result<Expression> {
     if let Some(if_form) = args.next() {
         if let Some(then_form) = args.next() {
-            return match eval(environment, if_form)? {
-                Expression::Atom(Atom::Nil) => {
-                    if let Some(else_form) = args.next() {
-                        eval(environment, else_form)
-                    } else {
-                        Ok(Expression::Atom(Atom::Nil))
-                    }
-                }
-                _ => eval(environment, then_form),
+            let test = eval(environment, if_form)?;
+            return if is_truthy(environment, &test) {
+                eval(environment, then_form)
+            } else if let Some(else_form) = args.next() {
+                eval(environment, else_form)
+            } else {
+                Ok(Expression::Atom(Atom::Nil))
             };
         }
     }
@@ -342,30 +853,525 @@ fn builtin_if(
     ))
 }
 
-fn args_out(
-    environment: &mut Environment,
-    args: &mut dyn Iterator<Item = &Expression>,
-    add_newline: bool,
-    pretty: bool,
-    writer: &mut dyn Write,
-) -> io::Result<()> {
-    for a in args {
-        let aa = eval(environment, a)?;
-        // If we have a standalone string do not quote it...
-        let pretty = if let Expression::Atom(Atom::String(_)) = aa {
-            false
-        } else {
-            pretty
-        };
-        if pretty {
-            aa.pretty_printf(environment, writer)?;
-        } else {
-            aa.writef(environment, writer)?;
-        }
+// Evals body forms, the last value (nil if none).
+fn eval_body(environment: &mut Environment, body: &[Expression]) -> io::Result<Expression> {
+    let mut ret = Expression::Atom(Atom::Nil);
+    for form in body {
+        ret = eval(environment, form)?;
     }
-    if add_newline {
+    Ok(ret)
+}
+
+fn clause_items(clause: &Expression, form: &str) -> io::Result<Vec<Expression>> {
+    match clause {
+        Expression::Pair(_, _) => Ok(clause.iter().cloned().collect()),
+        Expression::Vector(list) => Ok(list.borrow().clone()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} clauses must be lists", form),
+        )),
+    }
+}
+
+fn is_else(exp: &Expression) -> bool {
+    if let Expression::Atom(Atom::Symbol(s)) = exp {
+        &**s == "else"
+    } else {
+        false
+    }
+}
+
+fn builtin_cond(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    for clause in args {
+        let items = clause_items(clause, "cond")?;
+        let (test, body) = match items.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "cond clauses need a test",
+                ))
+            }
+        };
+        let value = if is_else(test) {
+            Expression::Atom(Atom::True)
+        } else {
+            eval(environment, test)?
+        };
+        if !is_truthy(environment, &value) {
+            continue;
+        }
+        return if body.is_empty() {
+            Ok(value)
+        } else {
+            eval_body(environment, body)
+        };
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+// Constant a case clause can match.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum CaseKey {
+    Int(i64),
+    Str(String),
+    Sym(String),
+    Char(char),
+}
+
+impl CaseKey {
+    fn from_exp(exp: &Expression) -> Option<CaseKey> {
+        match exp {
+            Expression::Atom(Atom::Int(i)) => Some(CaseKey::Int(*i)),
+            Expression::Atom(Atom::String(s)) => Some(CaseKey::Str(s.to_string())),
+            Expression::Atom(Atom::Symbol(s)) => Some(CaseKey::Sym(s.to_string())),
+            Expression::Atom(Atom::Char(c)) => Some(CaseKey::Char(*c)),
+            _ => None,
+        }
+    }
+}
+
+// Jump table for a case form, built the first time it runs.
+#[derive(Debug)]
+pub struct CaseTable {
+    // First clause of the form, to make sure a cached table is for this form.
+    first: Expression,
+    keys: HashMap<CaseKey, usize>,
+    bodies: Vec<Vec<Expression>>,
+    default: Option<usize>,
+}
+
+// Case tables kept before the cache is cleared.
+const MAX_CASE_TABLES: usize = 1000;
+
+// True if a and b are the same list or vector (not just equal).
+pub fn same_form(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (Expression::Pair(a1, a2), Expression::Pair(b1, b2)) => {
+            Rc::ptr_eq(a1, b1) && Rc::ptr_eq(a2, b2)
+        }
+        (Expression::Vector(a), Expression::Vector(b)) => Rc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+fn build_case_table<'a>(
+    first: &'a Expression,
+    clauses: &mut dyn Iterator<Item = &'a Expression>,
+) -> io::Result<CaseTable> {
+    let bad_key = || {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "case values must be ints, strings, symbols or chars",
+        )
+    };
+    let mut table = CaseTable {
+        first: first.clone(),
+        keys: HashMap::new(),
+        bodies: Vec::new(),
+        default: None,
+    };
+    for clause in std::iter::once(first).chain(clauses) {
+        if table.default.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "case else must be the last clause",
+            ));
+        }
+        let mut items = clause_items(clause, "case")?;
+        if items.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "case clauses need a value",
+            ));
+        }
+        let values = items.remove(0);
+        let idx = table.bodies.len();
+        table.bodies.push(items);
+        if is_else(&values) {
+            table.default = Some(idx);
+            continue;
+        }
+        let values = match values {
+            Expression::Pair(_, _) | Expression::Vector(_) => clause_items(&values, "case")?,
+            value => vec![value],
+        };
+        for value in &values {
+            // The first clause with a value wins.
+            let key = CaseKey::from_exp(value).ok_or_else(bad_key)?;
+            table.keys.entry(key).or_insert(idx);
+        }
+    }
+    Ok(table)
+}
+
+fn builtin_case(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let key_form = match args.next() {
+        Some(key_form) => key_form,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "case needs a key form",
+            ))
+        }
+    };
+    let first = match args.next() {
+        Some(first) => first,
+        None => {
+            eval(environment, key_form)?;
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+    };
+    // Tables are found by where the first clause lives, checked since a
+    // freed form's address can be reused.
+    let id = first as *const Expression as usize;
+    let table = match environment.case_tables.get(&id) {
+        Some(table) if same_form(&table.first, first) => table.clone(),
+        _ => {
+            let table = Rc::new(build_case_table(first, args)?);
+            if environment.case_tables.len() >= MAX_CASE_TABLES {
+                environment.case_tables.clear();
+            }
+            environment.case_tables.insert(id, table.clone());
+            table
+        }
+    };
+    let key = eval(environment, key_form)?;
+    let idx = match CaseKey::from_exp(&key) {
+        Some(key) => table.keys.get(&key).copied().or(table.default),
+        None => table.default,
+    };
+    match idx {
+        Some(idx) => eval_body(environment, &table.bodies[idx]),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+fn builtin_when(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(test) = args.next() {
+        let test = eval(environment, test)?;
+        return if is_truthy(environment, &test) {
+            builtin_progn(environment, args)
+        } else {
+            Ok(Expression::Atom(Atom::Nil))
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "when needs a test form",
+    ))
+}
+
+fn builtin_unless(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(test) = args.next() {
+        let test = eval(environment, test)?;
+        return if is_truthy(environment, &test) {
+            Ok(Expression::Atom(Atom::Nil))
+        } else {
+            builtin_progn(environment, args)
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "unless needs a test form",
+    ))
+}
+
+fn builtin_while(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args: Vec<&Expression> = args.collect();
+    let (test, body) = match args.split_first() {
+        Some(split) => split,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "while needs a test form",
+            ))
+        }
+    };
+    loop {
+        check_interrupted(environment)?;
+        let value = eval(environment, test)?;
+        if !is_truthy(environment, &value) {
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+        for form in body {
+            eval(environment, form)?;
+        }
+    }
+}
+
+fn builtin_dotimes(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let times = match args.next() {
+        Some(times) => eval(environment, times)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "dotimes needs a count",
+            ))
+        }
+    };
+    let times = match times {
+        Expression::Atom(Atom::Int(times)) => times,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "dotimes count must be an int",
+            ))
+        }
+    };
+    let body: Vec<&Expression> = args.collect();
+    for _ in 0..times {
+        check_interrupted(environment)?;
+        for form in &body {
+            eval(environment, form)?;
+        }
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+// Run body for each item of seq with name bound to it, in scope.
+fn for_items(
+    environment: &mut Environment,
+    scope: &Rc<RefCell<Scope>>,
+    name: &str,
+    seq: &mut dyn Sequence,
+    body: &[&Expression],
+) -> io::Result<()> {
+    while let Some(item) = seq.next_item()? {
+        check_interrupted(environment)?;
+        scope
+            .borrow_mut()
+            .data
+            .insert(name.to_string(), Rc::new(item));
+        for form in body {
+            eval(environment, form)?;
+        }
+    }
+    Ok(())
+}
+
+fn builtin_for(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let name = match args.next() {
+        Some(Expression::Atom(Atom::Symbol(name))) => name.clone(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "for needs a symbol to bind",
+            ))
+        }
+    };
+    let items = match args.next() {
+        Some(items) => eval_seq(environment, items)?,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "for needs a sequence")),
+    };
+    let mut seq = sequence(environment, &items, "for")?;
+    let body: Vec<&Expression> = args.collect();
+    let scope = build_new_scope(environment.current_scope.last().cloned());
+    scope
+        .borrow_mut()
+        .data
+        .insert(name.to_string(), Rc::new(Expression::Atom(Atom::Nil)));
+    // The scope must be popped off before returning so no ? here.
+    environment.current_scope.push(scope.clone());
+    let result = for_items(environment, &scope, &name, &mut *seq, &body);
+    environment.current_scope.pop();
+    result.map(|_| Expression::Atom(Atom::Nil))
+}
+
+// Put form into step as the first (or last) argument, a symbol step is a call
+// with just form.
+fn thread_step(form: Expression, step: &Expression, last: bool) -> io::Result<Expression> {
+    let mut items = match step {
+        Expression::Pair(_, _) => step.iter().cloned().collect(),
+        Expression::Atom(Atom::Symbol(_)) | Expression::Atom(Atom::Lambda(_)) => {
+            vec![step.clone()]
+        }
+        _ => {
+            let msg = format!(
+                "threading steps must be lists or symbols, got {}",
+                step.display_type()
+            );
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    if last || items.is_empty() {
+        items.push(form);
+    } else {
+        items.insert(1, form);
+    }
+    Ok(Expression::cons_from_vec(&mut items))
+}
+
+// Rewrite (-> x a b) to (b (a x)) and eval it, done on the forms so macros
+// in the steps see what they would if written out.
+fn thread_forms(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    last: bool,
+) -> io::Result<Expression> {
+    let mut form = match args.next() {
+        Some(form) => form.clone(),
+        None => {
+            let msg = format!("{} needs a form", if last { "->>" } else { "->" });
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    for step in args {
+        form = thread_step(form, step, last)?;
+    }
+    eval(environment, &form)
+}
+
+fn builtin_thread_first(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    thread_forms(environment, args, false)
+}
+
+fn builtin_thread_last(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    thread_forms(environment, args, true)
+}
+
+// Eval each form with name (in scope) bound to the last value.
+fn as_thread_forms(
+    environment: &mut Environment,
+    scope: &Rc<RefCell<Scope>>,
+    name: &str,
+    mut value: Expression,
+    forms: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    for form in forms {
+        scope
+            .borrow_mut()
+            .data
+            .insert(name.to_string(), Rc::new(value));
+        value = eval(environment, form)?;
+    }
+    Ok(value)
+}
+
+fn builtin_as_thread(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let value = match args.next() {
+        Some(form) => eval(environment, form)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "as-> needs a form, a symbol and steps",
+            ))
+        }
+    };
+    let name = match args.next() {
+        Some(Expression::Atom(Atom::Symbol(name))) => name.clone(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "as-> needs a symbol to bind",
+            ))
+        }
+    };
+    let scope = build_new_scope(environment.current_scope.last().cloned());
+    // The scope must be popped off before returning so no ? here.
+    environment.current_scope.push(scope.clone());
+    let result = as_thread_forms(environment, &scope, &name, value, args);
+    environment.current_scope.pop();
+    result
+}
+
+// How print and friends show values.
+#[derive(Clone, Copy, PartialEq)]
+enum PrintStyle {
+    // Atoms as plain text (strings without quotes, chars without #\\).
+    Display,
+    // Pretty printed but a standalone string is not quoted (print).
+    Pretty,
+    // Pretty printed so it reads back, strings quoted and escaped (write).
+    Readable,
+}
+
+fn args_out(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    add_newline: bool,
+    style: PrintStyle,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    let mut args = args.peekable();
+    let mut add_newline = add_newline;
+    while let Some(a) = args.next() {
+        let aa = eval(environment, a)?;
+        let pretty = match (style, &aa) {
+            (PrintStyle::Display, _) => false,
+            // If we have a standalone string do not quote it...
+            (PrintStyle::Pretty, Expression::Atom(Atom::String(_))) => false,
+            _ => true,
+        };
+        match &aa {
+            // The common (println "text") is one write.
+            Expression::Atom(Atom::String(s))
+                if !pretty && add_newline && args.peek().is_none() =>
+            {
+                write_all_vectored(writer, &[s.as_bytes(), b"\n"])?;
+                add_newline = false;
+            }
+            _ if pretty => aa.pretty_printf(environment, writer)?,
+            _ => aa.writef(environment, writer)?,
+        }
+    }
+    if add_newline {
         writer.write_all("\n".as_bytes())?;
     }
+    // Once per print, not per item.
+    writer.flush()
+}
+
+// Write all of bufs with as few writes as the writer will do.
+fn write_all_vectored(writer: &mut dyn Write, bufs: &[&[u8]]) -> io::Result<()> {
+    let mut bufs: Vec<&[u8]> = bufs.iter().copied().filter(|b| !b.is_empty()).collect();
+    while !bufs.is_empty() {
+        let slices: Vec<io::IoSlice> = bufs.iter().map(|b| io::IoSlice::new(b)).collect();
+        let mut n = match writer.write_vectored(&slices) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        while n > 0 {
+            if n >= bufs[0].len() {
+                n -= bufs.remove(0).len();
+            } else {
+                bufs[0] = &bufs[0][n..];
+                n = 0;
+            }
+        }
+    }
     Ok(())
 }
 
@@ -373,7 +1379,7 @@ fn print_to_oe(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
     add_newline: bool,
-    pretty: bool,
+    style: PrintStyle,
     default_error: bool,
     key: &str,
 ) -> io::Result<()> {
@@ -385,15 +1391,15 @@ fn print_to_oe(
                     FileState::Stdout => {
                         let stdout = io::stdout();
                         let mut out = stdout.lock();
-                        args_out(environment, args, add_newline, pretty, &mut out)?;
+                        args_out(environment, args, add_newline, style, &mut out)?;
                     }
                     FileState::Stderr => {
                         let stdout = io::stderr();
                         let mut out = stdout.lock();
-                        args_out(environment, args, add_newline, pretty, &mut out)?;
+                        args_out(environment, args, add_newline, style, &mut out)?;
                     }
                     FileState::Write(f) => {
-                        args_out(environment, args, add_newline, pretty, &mut *f.borrow_mut())?;
+                        args_out(environment, args, add_newline, style, &mut *f.borrow_mut())?;
                     }
                     _ => {
                         return Err(io::Error::new(
@@ -411,40 +1417,59 @@ fn print_to_oe(
             if default_error {
                 let stdout = io::stderr();
                 let mut out = stdout.lock();
-                args_out(environment, args, add_newline, pretty, &mut out)?;
+                args_out(environment, args, add_newline, style, &mut out)?;
             } else {
                 let stdout = io::stdout();
                 let mut out = stdout.lock();
-                args_out(environment, args, add_newline, pretty, &mut out)?;
+                args_out(environment, args, add_newline, style, &mut out)?;
             }
         }
     }
     Ok(())
 }
 
-fn print(
+// Style for print and eprint, readable with the print-readably option.
+fn print_style(environment: &Environment) -> PrintStyle {
+    match get_option(environment, "print-readably") {
+        Expression::Atom(Atom::Nil) => PrintStyle::Pretty,
+        _ => PrintStyle::Readable,
+    }
+}
+
+fn print_styled(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
     add_newline: bool,
+    style: PrintStyle,
 ) -> io::Result<Expression> {
     match &environment.state.stdout_status {
         Some(IOState::Null) => { /* Nothing to do... */ }
         _ => {
-            print_to_oe(environment, args, add_newline, true, false, "*stdout*")?;
+            print_to_oe(environment, args, add_newline, style, false, "*stdout*")?;
         }
     };
     Ok(Expression::Atom(Atom::Nil))
 }
 
+fn print(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    add_newline: bool,
+) -> io::Result<Expression> {
+    let style = print_style(environment);
+    print_styled(environment, args, add_newline, style)
+}
+
 pub fn eprint(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
     add_newline: bool,
 ) -> io::Result<Expression> {
+    let style = print_style(environment);
     match &environment.state.stderr_status {
         Some(IOState::Null) => { /* Nothing to do... */ }
         _ => {
-            print_to_oe(environment, args, add_newline, true, true, "*stderr*")?;
+            print_to_oe(environment, args, add_newline, style, true, "*stderr*")?;
         }
     };
     Ok(Expression::Atom(Atom::Nil))
@@ -478,46 +1503,166 @@ fn builtin_eprintln(
     eprint(environment, args, true)
 }
 
-fn builtin_format(
+fn builtin_write(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    let mut res = String::new();
-    for a in args {
-        res.push_str(&eval(environment, a)?.as_string(environment)?);
-    }
-    Ok(Expression::Atom(Atom::String(res)))
+    print_styled(environment, args, false, PrintStyle::Readable)
 }
 
-pub fn builtin_progn(
+fn builtin_writeln(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    let mut ret = Expression::Atom(Atom::Nil);
-    for arg in args {
-        ret = eval(environment, &arg)?;
-    }
-    Ok(ret)
+    print_styled(environment, args, true, PrintStyle::Readable)
 }
 
-fn proc_set_vars2(
-    _environment: &mut Environment,
-    key: Expression,
-    mut val: Expression,
-) -> io::Result<(String, Expression)> {
-    let key = match key {
-        Expression::Atom(Atom::Symbol(s)) => s,
-        _ => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "first form (binding key) must evaluate to a symbol",
-            ));
+fn builtin_display(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    print_styled(environment, args, false, PrintStyle::Display)
+}
+
+fn builtin_displayln(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    print_styled(environment, args, true, PrintStyle::Display)
+}
+
+fn builtin_pprint(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let exp = eval(environment, arg)?;
+            if let Some(IOState::Null) = &environment.state.stdout_status {
+                return Ok(Expression::Atom(Atom::Nil));
+            }
+            let mut settings = pretty_settings(environment, true);
+            let out = get_expression(environment, "*stdout*");
+            if let Some(out) = out {
+                if let Expression::File(FileState::Write(f)) = &*out {
+                    settings.color = false;
+                    let mut f = f.borrow_mut();
+                    pretty_write(&exp, environment, &settings, &mut *f)?;
+                    f.write_all(b"\n")?;
+                    f.flush()?;
+                    return Ok(Expression::Atom(Atom::Nil));
+                }
+            }
+            settings.color = settings.color && unistd::isatty(1).unwrap_or(false);
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            pretty_write(&exp, environment, &settings, &mut out)?;
+            out.write_all(b"\n")?;
+            out.flush()?;
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "pprint takes one form",
+    ))
+}
+
+fn builtin_print_all(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let exp = eval(environment, arg)?;
+            if let Some(IOState::Null) = &environment.state.stdout_status {
+                return Ok(Expression::Atom(Atom::Nil));
+            }
+            if let Some(out) = get_expression(environment, "*stdout*") {
+                if let Expression::File(FileState::Write(f)) = &*out {
+                    let mut settings = pretty_settings(environment, false);
+                    settings.color = false;
+                    match &exp {
+                        Expression::Atom(Atom::String(s)) => {
+                            f.borrow_mut().write_all(s.as_bytes())?
+                        }
+                        _ => pretty_write(&exp, environment, &settings, &mut *f.borrow_mut())?,
+                    }
+                    f.borrow_mut().write_all(b"\n")?;
+                    f.borrow_mut().flush()?;
+                    return Ok(Expression::Atom(Atom::Nil));
+                }
+            }
+            exp.pretty_print(environment, true)?;
+            println!();
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "print-all takes one form",
+    ))
+}
+
+fn builtin_fmt_source(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let text = eval(environment, arg)?.as_string(environment)?;
+            return match format_source(&text) {
+                Ok(formatted) => Ok(Expression::Atom(Atom::String(formatted.into()))),
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "fmt-source takes one form",
+    ))
+}
+
+fn builtin_format(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut res = String::new();
+    for a in args {
+        res.push_str(&eval(environment, a)?.as_string(environment)?);
+    }
+    Ok(Expression::Atom(Atom::String(res.into())))
+}
+
+pub fn builtin_progn(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut ret = Expression::Atom(Atom::Nil);
+    for arg in args {
+        ret = eval(environment, &arg)?;
+    }
+    Ok(ret)
+}
+
+fn proc_set_vars2(
+    _environment: &mut Environment,
+    key: Expression,
+    mut val: Expression,
+) -> io::Result<(String, Expression)> {
+    let key = match key {
+        Expression::Atom(Atom::Symbol(s)) => s,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "first form (binding key) must evaluate to a symbol",
+            ));
         }
     };
     if let Expression::Atom(Atom::String(vs)) = val {
         val = Expression::Atom(Atom::String(vs));
     }
-    Ok((key, val))
+    Ok((key.to_string(), val))
 }
 
 fn proc_set_vars(
@@ -549,7 +1694,7 @@ fn builtin_set(
         entry.insert(Rc::new(val.clone()));
         Ok(val)
     } else if let Some(scope) = get_symbols_scope(environment, &key) {
-        scope.borrow_mut().data.insert(key, Rc::new(val.clone()));
+        scope.borrow_mut().insert(key, Rc::new(val.clone()));
         Ok(val)
     } else {
         Err(io::Error::new(
@@ -559,75 +1704,153 @@ fn builtin_set(
     }
 }
 
+// A scalar value as exported, strings as is and numbers etc as printed.
+fn export_item(environment: &Environment, val: &Expression, form: &str) -> io::Result<String> {
+    let val = match val {
+        Expression::Atom(Atom::Symbol(s)) => s.to_string(),
+        Expression::Atom(Atom::String(s)) => s.to_string(),
+        Expression::Atom(Atom::StringBuf(s)) => s.borrow().clone(),
+        Expression::Atom(Atom::Int(_))
+        | Expression::Atom(Atom::Float(_))
+        | Expression::Atom(Atom::Char(_))
+        | Expression::Atom(Atom::True) => val.as_string(environment)?,
+        Expression::Process(_) => val
+            .as_string(environment)
+            .unwrap_or_else(|_| "PROCESS FAILED".to_string()),
+        Expression::File(FileState::Stdin) => val
+            .as_string(environment)
+            .unwrap_or_else(|_| "STDIN FAILED".to_string()),
+        Expression::File(FileState::Read(_)) => val
+            .as_string(environment)
+            .unwrap_or_else(|_| "FILE READ FAILED".to_string()),
+        Expression::HashMap(_) => {
+            let msg = format!("{}: can not export a hashmap, make it a string first", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        _ => {
+            let msg = format!("{}: can not export a {}", form, val.display_type());
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    Ok(match expand_tilde(&val) {
+        Some(v) => v,
+        None => val,
+    })
+}
+
+// The items of a list or vector (nil is empty) as exported strings.
+fn export_items(
+    environment: &Environment,
+    val: &Expression,
+    form: &str,
+) -> io::Result<Option<Vec<String>>> {
+    let items: Vec<Expression> = match val {
+        Expression::Vector(list) => list.borrow().clone(),
+        Expression::Pair(_, _) => val.iter().cloned().collect(),
+        Expression::Atom(Atom::Nil) => Vec::new(),
+        _ => return Ok(None),
+    };
+    let mut strings = Vec::with_capacity(items.len());
+    for item in &items {
+        strings.push(export_item(environment, item, form)?);
+    }
+    Ok(Some(strings))
+}
+
+pub(crate) fn set_env_var(key: &str, val: &str) {
+    if !val.is_empty() {
+        env::set_var(key, val);
+    } else {
+        env::remove_var(key);
+    }
+}
+
+fn export_key(key: Expression, form: &str) -> io::Result<Rc<str>> {
+    match key {
+        Expression::Atom(Atom::Symbol(s)) => Ok(s),
+        _ => {
+            let msg = format!("{}: first form must evaluate to a symbol", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
 fn builtin_export(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let (Some(key), Some(val)) = (args.next(), args.next()) {
+        let separator = args.next();
+        if args.next().is_none() {
+            let key = export_key(eval(environment, key)?, "export")?;
+            let val = eval(environment, val)?;
+            // Lists and vectors are joined with the separator (or the
+            // export-separator option).
+            let val = match export_items(environment, &val, "export")? {
+                Some(items) => {
+                    let separator = match separator {
+                        Some(sep) => eval(environment, sep)?.as_string(environment)?,
+                        None => {
+                            get_option(environment, "export-separator").as_string(environment)?
+                        }
+                    };
+                    items.join(&separator)
+                }
+                None => export_item(environment, &val, "export")?,
+            };
+            set_env_var(&key, &val);
+            return Ok(Expression::Atom(Atom::String(val.into())));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "export: takes a symbol, a value and an optional separator for lists",
+    ))
+}
+
+fn builtin_export_path(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
     if let Some(key) = args.next() {
-        if let Some(val) = args.next() {
-            if args.next().is_none() {
-                let key = eval(environment, key)?;
-                let val = eval(environment, val)?;
-                let key = match key {
-                    Expression::Atom(Atom::Symbol(s)) => s,
-                    _ => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "export: first form must evaluate to a symbol",
-                        ));
-                    }
-                };
-                let val = match val {
-                    Expression::Atom(Atom::Symbol(s)) => Expression::Atom(Atom::String(s)),
-                    Expression::Atom(Atom::String(s)) => Expression::Atom(Atom::String(s)),
-                    Expression::Atom(Atom::StringBuf(s)) => {
-                        Expression::Atom(Atom::String(s.borrow().clone()))
-                    }
-                    Expression::Process(ProcessState::Running(_pid)) => {
-                        Expression::Atom(Atom::String(
-                            val.as_string(environment)
-                                .unwrap_or_else(|_| "PROCESS FAILED".to_string()),
-                        ))
-                    }
-                    Expression::Process(ProcessState::Over(_pid, _exit_status)) => {
-                        Expression::Atom(Atom::String(
-                            val.as_string(environment)
-                                .unwrap_or_else(|_| "PROCESS FAILED".to_string()),
-                        ))
-                    }
-                    Expression::File(FileState::Stdin) => Expression::Atom(Atom::String(
-                        val.as_string(environment)
-                            .unwrap_or_else(|_| "STDIN FAILED".to_string()),
-                    )),
-                    Expression::File(FileState::Read(_)) => Expression::Atom(Atom::String(
-                        val.as_string(environment)
-                            .unwrap_or_else(|_| "FILE READ FAILED".to_string()),
-                    )),
-                    _ => {
-                        println!("XXX {:?}", val);
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "export: value not valid",
-                        ));
+        let val = args.next();
+        if args.next().is_none() {
+            let key = export_key(eval(environment, key)?, "export-path")?;
+            let items = match val {
+                Some(val) => {
+                    let val = eval(environment, val)?;
+                    let items = match export_items(environment, &val, "export-path")? {
+                        Some(items) => items,
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                "export-path: value must be a list or vector",
+                            ))
+                        }
+                    };
+                    if let Some(item) = items.iter().find(|i| i.contains(':')) {
+                        let msg = format!("export-path: {} contains a :", item);
+                        return Err(io::Error::new(io::ErrorKind::Other, msg));
                     }
-                };
-                let val = val.as_string(environment)?;
-                let val = match expand_tilde(&val) {
-                    Some(v) => v,
-                    None => val,
-                };
-                if !val.is_empty() {
-                    env::set_var(key, val.clone());
-                } else {
-                    env::remove_var(key);
+                    set_env_var(&key, &items.join(":"));
+                    items
                 }
-                return Ok(Expression::Atom(Atom::String(val)));
-            }
+                None => match env::var(&*key) {
+                    Ok(val) if !val.is_empty() => val.split(':').map(|s| s.to_string()).collect(),
+                    _ => Vec::new(),
+                },
+            };
+            return Ok(Expression::with_list(
+                items
+                    .into_iter()
+                    .map(|i| Expression::Atom(Atom::String(i.into())))
+                    .collect(),
+            ));
         }
     }
     Err(io::Error::new(
         io::ErrorKind::Other,
-        "export: can only have two expressions",
+        "export-path: takes a symbol and an optional list or vector",
     ))
 }
 
@@ -639,7 +1862,7 @@ fn builtin_unexport(
         if args.next().is_none() {
             let key = eval(environment, key)?;
             if let Expression::Atom(Atom::Symbol(k)) = key {
-                env::remove_var(k);
+                env::remove_var(&*k);
                 return Ok(Expression::Atom(Atom::Nil));
             }
         }
@@ -650,11 +1873,59 @@ fn builtin_unexport(
     ))
 }
 
+// Key/value pairs (:doc "..." etc) for a symbol's metadata.
+fn meta_options(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<Vec<(String, Rc<Expression>)>> {
+    let mut meta = Vec::new();
+    while let Some(key) = args.next() {
+        match (key, args.next()) {
+            (Expression::Atom(Atom::Symbol(key)), Some(val)) if key.starts_with(':') => {
+                meta.push((key.to_string(), Rc::new(eval(environment, val)?)));
+            }
+            _ => {
+                let msg = format!("{}: metadata must be :key value pairs", form);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok(meta)
+}
+
+fn add_meta(
+    environment: &Environment,
+    scope: &Rc<RefCell<Scope>>,
+    key: &str,
+    meta: Vec<(String, Rc<Expression>)>,
+) {
+    let file = environment.state.load_file.clone();
+    if meta.is_empty() && file.is_none() {
+        return;
+    }
+    let mut scope = scope.borrow_mut();
+    let entry = scope
+        .meta
+        .entry(key.to_string())
+        .or_insert_with(HashMap::new);
+    if let Some(file) = file {
+        entry.insert(
+            ":file".to_string(),
+            Rc::new(Expression::Atom(Atom::String(file.into()))),
+        );
+    }
+    for (k, v) in meta {
+        entry.insert(k, v);
+    }
+}
+
 fn builtin_def(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    let (key, val) = proc_set_vars(environment, args, true)?;
+    let (key, val) = proc_set_vars(environment, args, false)?;
+    let meta = meta_options(environment, args, "def")?;
     if key.contains("::") {
         // namespace reference.
         let mut key_i = key.splitn(2, "::");
@@ -681,6 +1952,7 @@ fn builtin_def(
                                 .borrow_mut()
                                 .data
                                 .insert(key.to_string(), Rc::new(val.clone()));
+                            add_meta(environment, &in_scope, key, meta);
                             return Ok(val);
                         }
                     }
@@ -694,7 +1966,9 @@ fn builtin_def(
         );
         Err(io::Error::new(io::ErrorKind::Other, msg))
     } else {
-        set_expression_current(environment, key, Rc::new(val.clone()));
+        set_expression_current(environment, key.clone(), Rc::new(val.clone()));
+        let scope = environment.current_scope.last().unwrap().clone();
+        add_meta(environment, &scope, &key, meta);
         Ok(val)
     }
 }
@@ -745,6 +2019,50 @@ fn builtin_dyn(
     }
 }
 
+// Eval the body with key (*stdout* or *stderr*) bound to a capture file and
+// return what was written to it.
+fn capture_stream(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    key: &str,
+) -> io::Result<Expression> {
+    let body: Vec<Expression> = args.cloned().collect();
+    let file = capture_file()?;
+    let mut reader = file.try_clone()?;
+    let out = Rc::new(RefCell::new(io::BufWriter::new(file)));
+    let old_val = environment.dynamic_scope.remove(key);
+    environment.dynamic_scope.insert(
+        key.to_string(),
+        Rc::new(Expression::File(FileState::Write(out.clone()))),
+    );
+    let res = eval_body(environment, &body);
+    if let Some(old_val) = old_val {
+        environment.dynamic_scope.insert(key.to_string(), old_val);
+    } else {
+        environment.dynamic_scope.remove(key);
+    }
+    res?;
+    out.borrow_mut().flush()?;
+    let mut captured = String::new();
+    reader.seek(io::SeekFrom::Start(0))?;
+    reader.read_to_string(&mut captured)?;
+    Ok(Expression::Atom(Atom::String(captured.into())))
+}
+
+fn builtin_with_out_str(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    capture_stream(environment, args, "*stdout*")
+}
+
+fn builtin_with_err_str(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    capture_stream(environment, args, "*stderr*")
+}
+
 fn builtin_is_global_scope(
     environment: &mut Environment,
     args: &[Expression],
@@ -770,14 +2088,18 @@ fn builtin_to_symbol(environment: &mut Environment, args: &[Expression]) -> io::
         ))
     } else {
         match &args[0] {
-            Expression::Atom(Atom::String(s)) => Ok(Expression::Atom(Atom::Symbol(s.clone()))),
+            Expression::Atom(Atom::String(s)) => {
+                Ok(Expression::Atom(Atom::Symbol(s.clone().into())))
+            }
             Expression::Atom(Atom::StringBuf(s)) => {
-                Ok(Expression::Atom(Atom::Symbol(s.borrow().clone())))
+                Ok(Expression::Atom(Atom::Symbol(s.borrow().clone().into())))
             }
             Expression::Atom(Atom::Symbol(s)) => Ok(Expression::Atom(Atom::Symbol(s.clone()))),
-            Expression::Atom(Atom::Int(i)) => Ok(Expression::Atom(Atom::Symbol(format!("{}", i)))),
+            Expression::Atom(Atom::Int(i)) => {
+                Ok(Expression::Atom(Atom::Symbol(format!("{}", i).into())))
+            }
             Expression::Atom(Atom::Float(f)) => {
-                Ok(Expression::Atom(Atom::Symbol(format!("{}", f))))
+                Ok(Expression::Atom(Atom::Symbol(format!("{}", f).into())))
             }
             _ => Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -797,10 +2119,20 @@ fn builtin_fn(environment: &mut Environment, parts: &[Expression]) -> io::Result
         let mut parts = parts.iter();
         let params = parts.next().unwrap();
         let body = parts.next().unwrap();
+        let (body, slots) = match param_names(params) {
+            Some(names) => {
+                let names = Rc::new(names);
+                (resolve_body(environment, &names, body), Some(names))
+            }
+            None => (body.clone(), None),
+        };
+        let capture = environment.current_scope.last().unwrap().clone();
+        gc_track_capture(environment, &capture);
         Ok(Expression::Atom(Atom::Lambda(Lambda {
-            params: Box::new(params.clone()),
-            body: Box::new(body.clone()),
-            capture: environment.current_scope.last().unwrap().clone(),
+            params: Rc::new(params.clone()),
+            body: Rc::new(body),
+            capture,
+            slots,
         })))
     }
 }
@@ -834,9 +2166,9 @@ fn replace_commas(
             _ => exp.clone(),
         };
         if let Expression::Atom(Atom::Symbol(symbol)) = &exp {
-            if symbol == "," {
+            if &**symbol == "," {
                 comma_next = true;
-            } else if symbol == ",@" {
+            } else if &**symbol == ",@" {
                 amp_next = true;
             } else if comma_next {
                 output.push(eval(environment, &exp)?);
@@ -898,7 +2230,7 @@ fn builtin_bquote(
 ) -> io::Result<Expression> {
     let ret = if let Some(arg) = args.next() {
         match arg {
-            Expression::Atom(Atom::Symbol(s)) if s == "," => {
+            Expression::Atom(Atom::Symbol(s)) if &**s == "," => {
                 if let Some(exp) = args.next() {
                     Ok(eval(environment, exp)?)
                 } else {
@@ -955,10 +2287,10 @@ fn builtin_and(
     let mut last_exp = Expression::Atom(Atom::True);
     for arg in args {
         let arg = eval(environment, &arg)?;
-        match arg {
-            Expression::Atom(Atom::Nil) => return Ok(Expression::Atom(Atom::Nil)),
-            _ => last_exp = arg,
+        if !is_truthy(environment, &arg) {
+            return Ok(Expression::Atom(Atom::Nil));
         }
+        last_exp = arg;
     }
     Ok(last_exp)
 }
@@ -969,23 +2301,62 @@ fn builtin_or(
 ) -> io::Result<Expression> {
     for arg in args {
         let arg = eval(environment, &arg)?;
-        match arg {
-            Expression::Atom(Atom::Nil) => {}
-            _ => return Ok(arg),
+        if is_truthy(environment, &arg) {
+            return Ok(arg);
         }
     }
     Ok(Expression::Atom(Atom::Nil))
 }
 
+// A process succeeded if it exited 0 (or is still running) whatever
+// the status-as-bool option is, anything else if it is not nil.
+fn command_ok(exp: &Expression) -> bool {
+    match exp {
+        Expression::Process(proc) => proc.status().map_or(true, |status| status == 0),
+        Expression::Atom(Atom::Nil) => false,
+        _ => true,
+    }
+}
+
+// Like && in sh, stops at the first form that fails and returns it.
+fn builtin_and_run(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut last_exp = Expression::Atom(Atom::True);
+    for arg in args {
+        last_exp = eval(environment, arg)?;
+        if !command_ok(&last_exp) {
+            break;
+        }
+    }
+    Ok(last_exp)
+}
+
+// Like || in sh, stops at the first form that succeeds and returns it.
+fn builtin_or_run(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut last_exp = Expression::Atom(Atom::Nil);
+    for arg in args {
+        last_exp = eval(environment, arg)?;
+        if command_ok(&last_exp) {
+            break;
+        }
+    }
+    Ok(last_exp)
+}
+
 fn builtin_not(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
     let args = list_to_args(environment, args, true)?;
     if args.len() != 1 {
         return Err(io::Error::new(io::ErrorKind::Other, "not takes one form"));
     }
-    if let Expression::Atom(Atom::Nil) = &args[0] {
-        Ok(Expression::Atom(Atom::True))
-    } else {
+    if is_truthy(environment, &args[0]) {
         Ok(Expression::Atom(Atom::Nil))
+    } else {
+        Ok(Expression::Atom(Atom::True))
     }
 }
 
@@ -1019,8 +2390,8 @@ fn builtin_macro(
         if let Some(body) = args.next() {
             if args.next().is_none() {
                 return Ok(Expression::Atom(Atom::Macro(Macro {
-                    params: Box::new(params.clone()),
-                    body: Box::new(body.clone()),
+                    params: Rc::new(params.clone()),
+                    body: Rc::new(body.clone()),
                 })));
             }
         }
@@ -1039,25 +2410,19 @@ fn do_expansion(
     if let Expression::Atom(Atom::Symbol(command)) = command {
         if let Some(exp) = get_expression(environment, &command) {
             if let Expression::Atom(Atom::Macro(sh_macro)) = &*exp {
-                let new_scope = match environment.current_scope.last() {
-                    Some(last) => build_new_scope(Some(last.clone())),
-                    None => build_new_scope(None),
-                };
+                let outer = environment.current_scope.last().cloned();
+                let new_scope = pooled_scope(environment, outer);
                 environment.current_scope.push(new_scope);
-                let args: Vec<Expression> = parts.cloned().collect();
+                let args = unresolve_all(parts.cloned().collect());
                 let ib: Box<(dyn Iterator<Item = &Expression>)> = Box::new(args.iter());
-                if let Err(err) = setup_args(environment, None, &sh_macro.params, ib, false) {
-                    environment.current_scope.pop();
-                    return Err(err);
-                }
-                let expansion = eval(environment, &sh_macro.body);
-                if let Err(err) = expansion {
-                    environment.current_scope.pop();
-                    return Err(err);
+                let expansion = match setup_args(environment, None, &sh_macro.params, ib, false) {
+                    Ok(_) => eval(environment, &sh_macro.body),
+                    Err(err) => Err(err),
+                };
+                if let Some(scope) = environment.current_scope.pop() {
+                    release_scope(environment, scope);
                 }
-                let expansion = expansion.unwrap();
-                environment.current_scope.pop();
-                Ok(expansion)
+                expansion
             } else {
                 let msg = format!("expand-macro: {} not a macro", command);
                 Err(io::Error::new(io::ErrorKind::Other, msg))
@@ -1110,6 +2475,20 @@ fn builtin_expand_macro(
     ))
 }
 
+fn builtin_recompile(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "recompile takes no arguments",
+        ));
+    }
+    environment.macro_expansions.clear();
+    Ok(Expression::Atom(Atom::Nil))
+}
+
 fn builtin_recur(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
@@ -1134,10 +2513,9 @@ fn builtin_gensym(environment: &mut Environment, args: &[Expression]) -> io::Res
     } else {
         let gensym_count = &mut environment.state.gensym_count;
         *gensym_count += 1;
-        Ok(Expression::Atom(Atom::Symbol(format!(
-            "gs::{}",
-            *gensym_count
-        ))))
+        Ok(Expression::Atom(Atom::Symbol(
+            format!("gs::{}", *gensym_count).into(),
+        )))
     }
 }
 
@@ -1154,19 +2532,108 @@ fn builtin_jobs(environment: &mut Environment, _args: &[Expression]) -> io::Resu
     Ok(Expression::Atom(Atom::Nil))
 }
 
-fn get_stopped_pid(environment: &mut Environment, args: &[Expression]) -> Option<u32> {
-    if !args.is_empty() {
-        let arg = &args[0];
-        if let Expression::Atom(Atom::Int(ji)) = arg {
-            let ji = *ji as usize;
-            let jobs = &*environment.jobs.borrow();
-            if ji < jobs.len() {
-                let pid = jobs[ji].pids[0];
-                let mut stop_idx: Option<u32> = None;
-                for (i, sp) in environment.stopped_procs.borrow().iter().enumerate() {
-                    if *sp == pid {
-                        stop_idx = Some(i as u32);
-                        break;
+// A job as a hashmap, :id is its index in jobs (what fg and bg take).
+fn job_object(id: usize, job: &Job) -> Expression {
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    let int = |i: i64| Expression::Atom(Atom::Int(i));
+    map.insert(":id".to_string(), Rc::new(int(id as i64)));
+    map.insert(
+        ":pids".to_string(),
+        Rc::new(Expression::with_list(
+            job.pids.iter().map(|pid| int(i64::from(*pid))).collect(),
+        )),
+    );
+    map.insert(
+        ":names".to_string(),
+        Rc::new(Expression::with_list(
+            job.names
+                .iter()
+                .map(|name| Expression::Atom(Atom::String(name.clone().into())))
+                .collect(),
+        )),
+    );
+    map.insert(":status".to_string(), Rc::new(job_status_keyword(job)));
+    // Seconds since the epoch.
+    let start = job
+        .started
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    map.insert(":start".to_string(), Rc::new(int(start as i64)));
+    Expression::HashMap(Rc::new(RefCell::new(map)))
+}
+
+fn job_status_keyword(job: &Job) -> Expression {
+    let status = match job.status {
+        JobStatus::Running => ":running",
+        JobStatus::Stopped => ":stopped",
+    };
+    Expression::Atom(Atom::Symbol(status.into()))
+}
+
+fn builtin_jobs_list(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "jobs-list takes no forms",
+        ));
+    }
+    // Finished jobs are dropped when their processes are reaped.
+    reap_children(environment);
+    let jobs = environment.jobs.borrow();
+    Ok(Expression::with_list(
+        jobs.iter()
+            .enumerate()
+            .map(|(id, job)| job_object(id, job))
+            .collect(),
+    ))
+}
+
+fn builtin_job_status(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(id) = args.next() {
+        if args.next().is_none() {
+            let id = match eval(environment, id)? {
+                Expression::Atom(Atom::Int(id)) => id,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "job-status: job id must be an integer",
+                    ))
+                }
+            };
+            reap_children(environment);
+            let jobs = environment.jobs.borrow();
+            return Ok(if id >= 0 && (id as usize) < jobs.len() {
+                job_status_keyword(&jobs[id as usize])
+            } else {
+                Expression::Atom(Atom::Nil)
+            });
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "job-status takes one form (a job id)",
+    ))
+}
+
+fn get_stopped_pid(environment: &mut Environment, args: &[Expression]) -> Option<u32> {
+    if !args.is_empty() {
+        let arg = &args[0];
+        if let Expression::Atom(Atom::Int(ji)) = arg {
+            let ji = *ji as usize;
+            let jobs = &*environment.jobs.borrow();
+            if ji < jobs.len() {
+                let pid = jobs[ji].pids[0];
+                let mut stop_idx: Option<u32> = None;
+                for (i, sp) in environment.stopped_procs.borrow().iter().enumerate() {
+                    if *sp == pid {
+                        stop_idx = Some(i as u32);
+                        break;
                     }
                 }
                 if let Some(idx) = stop_idx {
@@ -1244,10 +2711,192 @@ fn builtin_version(
             "version takes no arguments",
         ))
     } else {
-        Ok(Expression::Atom(Atom::String(VERSION_STRING.to_string())))
+        Ok(Expression::Atom(Atom::String(
+            VERSION_STRING.to_string().into(),
+        )))
+    }
+}
+
+// Strip the overstrike (char backspace char) formatting man uses for bold/underline.
+fn strip_overstrike(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if ch == '\u{8}' {
+            ret.pop();
+        } else {
+            ret.push(ch);
+        }
+    }
+    ret
+}
+
+// Pull the NAME and SYNOPSIS sections out of a formatted man page.
+fn man_synopsis(page: &str) -> String {
+    let mut ret = String::new();
+    let mut in_section = false;
+    for line in page.lines() {
+        if !line.is_empty() && !line.starts_with(' ') && !line.starts_with('\t') {
+            in_section = line.trim() == "NAME" || line.trim() == "SYNOPSIS";
+        }
+        if in_section {
+            ret.push_str(line);
+            ret.push('\n');
+        }
+    }
+    ret
+}
+
+pub(crate) fn find_in_path(command: &str) -> Option<String> {
+    if command.contains('/') {
+        return if Path::new(command).is_file() {
+            Some(command.to_string())
+        } else {
+            None
+        };
+    }
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            let path = dir.join(command);
+            if path.is_file() {
+                return Some(path.to_string_lossy().to_string());
+            }
+        }
+    }
+    None
+}
+
+// Help for an external command, the man page synopsis if available
+// otherwise the first part of the commands --help output.
+fn external_doc(environment: &mut Environment, command: &str) -> Option<String> {
+    if let Some(doc) = environment.doc_cache.get(command) {
+        return Some(doc.clone());
+    }
+    let path = find_in_path(command)?;
+    let mut doc = String::new();
+    if let Ok(output) = Command::new("man")
+        .arg("-P")
+        .arg("cat")
+        .arg(command)
+        .env("MANWIDTH", "80")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        if output.status.success() {
+            doc = man_synopsis(&strip_overstrike(&String::from_utf8_lossy(&output.stdout)));
+        }
+    }
+    if doc.trim().is_empty() {
+        if let Ok(output) = Command::new(command)
+            .arg("--help")
+            .stdin(Stdio::null())
+            .output()
+        {
+            // Some commands print their help to stderr.
+            let out = if output.stdout.is_empty() {
+                output.stderr
+            } else {
+                output.stdout
+            };
+            for line in String::from_utf8_lossy(&out).lines().take(30) {
+                doc.push_str(line);
+                doc.push('\n');
+            }
+        }
+    }
+    if doc.trim().is_empty() {
+        doc = format!(
+            "{} is an external command ({}), no documentation found.\n",
+            command, path
+        );
+    }
+    environment
+        .doc_cache
+        .insert(command.to_string(), doc.clone());
+    Some(doc)
+}
+
+// The doc string for a defined symbol (builtin doc or lambda/macro doc string).
+pub fn symbol_doc(environment: &Environment, key: &str) -> Option<String> {
+    match get_expression(environment, key) {
+        Some(exp) => match &*exp {
+            Expression::Function(c) => Some(c.doc_str.clone()),
+            Expression::Atom(Atom::Lambda(l)) => lambda_doc(&l.body),
+            Expression::Atom(Atom::Macro(m)) => lambda_doc(&m.body),
+            _ => None,
+        },
+        None => None,
+    }
+}
+
+fn builtin_doc(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let key = match eval(environment, arg)? {
+                Expression::Atom(Atom::Symbol(s)) => s,
+                Expression::Atom(Atom::String(s)) => s.into(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "doc: requires a symbol or string",
+                    ));
+                }
+            };
+            if is_expression(environment, &key) {
+                return match symbol_doc(environment, &key) {
+                    Some(doc) => Ok(Expression::Atom(Atom::String(doc.into()))),
+                    None => Ok(Expression::Atom(Atom::Nil)),
+                };
+            }
+            return match external_doc(environment, &key) {
+                Some(doc) => Ok(Expression::Atom(Atom::String(doc.into()))),
+                None => Ok(Expression::Atom(Atom::Nil)),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "doc: requires one form (a symbol or string)",
+    ))
+}
+
+// Quote s for a POSIX shell (sh -c, ssh, etc), only if it needs it.
+pub fn shell_quote(s: &str) -> String {
+    let safe = |ch: char| ch.is_ascii_alphanumeric() || "-_./=:,+@%".contains(ch);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
     }
 }
 
+fn builtin_shell_quote(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut quoted = Vec::new();
+    for a in args {
+        let a = eval(environment, a)?;
+        match &a {
+            Expression::Vector(list) => {
+                for item in list.borrow().iter() {
+                    quoted.push(shell_quote(&item.as_string(environment)?));
+                }
+            }
+            Expression::Pair(_, _) => {
+                for item in a.iter() {
+                    quoted.push(shell_quote(&item.as_string(environment)?));
+                }
+            }
+            _ => quoted.push(shell_quote(&a.as_string(environment)?)),
+        }
+    }
+    Ok(Expression::Atom(Atom::String(quoted.join(" ").into())))
+}
+
 fn builtin_command(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
@@ -1283,126 +2932,644 @@ fn builtin_run_bg(
     last_eval
 }
 
-fn builtin_form(
+fn builtin_run_limited(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    let old_form = environment.form_type;
-    environment.form_type = FormType::FormOnly;
+    let limits = if let Some(options) = args.next() {
+        let options = eval(environment, options)?;
+        proc_limits(environment, &options)?
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "run-limited takes options and forms",
+        ));
+    };
+    let old_limits = std::mem::replace(&mut environment.state.limits, Some(limits));
     let mut last_eval = Ok(Expression::Atom(Atom::Nil));
     for a in args {
         last_eval = eval(environment, a);
-        if let Err(err) = last_eval {
-            environment.form_type = old_form;
-            return Err(err);
+        if last_eval.is_err() {
+            break;
         }
     }
-    environment.form_type = old_form;
+    environment.state.limits = old_limits;
     last_eval
 }
 
-fn builtin_loose_symbols(
+fn builtin_with_env(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    let old_loose_syms = environment.loose_symbols;
-    environment.loose_symbols = true;
-    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
-    for a in args {
-        last_eval = eval(environment, a);
-        if let Err(err) = last_eval {
-            environment.loose_symbols = old_loose_syms;
-            return Err(err);
+    let vars = if let Some(vars) = args.next() {
+        let vars = eval(environment, vars)?;
+        child_env_vars(environment, &vars)?
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "with-env takes vars and forms",
+        ));
+    };
+    with_child_env(environment, vars, args)
+}
+
+// With one form (not a bare word) run it as a line of /bin/sh, otherwise this
+// is the sh command as usual (sh -c ..., sh script.sh).
+fn builtin_sh(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args: Vec<Expression> = args.cloned().collect();
+    let script = match &args[..] {
+        [Expression::Atom(Atom::Symbol(s))] if get_expression(environment, s).is_none() => None,
+        // A literal is sh's to expand, not ours.
+        [Expression::Atom(Atom::String(s))] => Some(s.to_string()),
+        [arg] => Some(eval(environment, arg)?.as_string(environment)?),
+        _ => None,
+    };
+    match script {
+        Some(script) => {
+            // Quoted so the line gets to sh without any $ expansion here.
+            let line = Expression::with_list(vec![
+                Expression::Atom(Atom::Symbol("quote".into())),
+                Expression::Atom(Atom::String(script.into())),
+            ]);
+            let parts = [Expression::Atom(Atom::String("-c".into())), line];
+            do_command(environment, "/bin/sh", Box::new(parts.iter()))
         }
+        None => do_command(environment, "sh", Box::new(args.iter())),
     }
-    environment.loose_symbols = old_loose_syms;
-    last_eval
 }
 
-fn builtin_exit(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
-    let args = list_to_args(environment, args, true)?;
-    match args.len().cmp(&1) {
-        Ordering::Greater => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "exit can only take an optional integer (exit code- defaults to 0)",
-        )),
-        Ordering::Equal => {
-            if let Expression::Atom(Atom::Int(exit_code)) = &args[0] {
-                environment.exit_code = Some(*exit_code as i32);
-                Ok(Expression::Atom(Atom::Nil))
-            } else {
-                Err(io::Error::new(
+fn builtin_run_with_timeout(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let secs = match args.next() {
+        Some(secs) => match eval(environment, secs)? {
+            Expression::Atom(Atom::Int(i)) if i >= 0 => i as f64,
+            Expression::Atom(Atom::Float(f)) if f >= 0.0 => f,
+            _ => {
+                return Err(io::Error::new(
                     io::ErrorKind::Other,
-                    "exit can only take an optional integer (exit code- defaults to 0)",
+                    "run-with-timeout: seconds must be a positive number",
                 ))
             }
+        },
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "run-with-timeout takes seconds and forms",
+            ))
         }
-        Ordering::Less => {
-            environment.exit_code = Some(0);
-            Ok(Expression::Atom(Atom::Nil))
+    };
+    let old_deadline = environment.state.deadline;
+    let deadline = Instant::now() + Duration::from_millis((secs * 1000.0) as u64);
+    // An outer timeout that ends sooner still wins.
+    let deadline = match old_deadline {
+        Some(old) if old < deadline => old,
+        _ => deadline,
+    };
+    let before: HashSet<u32> = environment.procs.borrow().keys().cloned().collect();
+    environment.state.deadline = Some(deadline);
+    let old_hit = environment.state.deadline_hit.replace(false);
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for a in args {
+        last_eval = eval(environment, a);
+        if last_eval.is_err() {
+            break;
+        }
+    }
+    environment.state.deadline = old_deadline;
+    let mut hit = environment.state.deadline_hit.replace(old_hit);
+    // Kill and reap anything the form started that is still around at the
+    // deadline (earlier pipe stages, background commands) so nothing is
+    // orphaned.
+    let started: Vec<u32> = if Instant::now() < deadline {
+        Vec::new()
+    } else {
+        environment
+            .procs
+            .borrow()
+            .keys()
+            .filter(|pid| !before.contains(pid))
+            .cloned()
+            .collect()
+    };
+    hit = hit || !started.is_empty();
+    if !hit {
+        // Finished (or failed) on it's own, even if just after the deadline.
+        return last_eval;
+    }
+    for pid in &started {
+        let _ = kill_pgroup(*pid, Signal::SIGTERM);
+    }
+    let term_sent = Instant::now();
+    let mut killed = false;
+    for pid in started {
+        while !try_wait_pid(environment, pid).0 {
+            if !killed && term_sent.elapsed() >= KILL_GRACE {
+                // What is left ignored SIGTERM.
+                for pid in environment.procs.borrow().keys() {
+                    if !before.contains(pid) {
+                        let _ = kill_pgroup(*pid, Signal::SIGKILL);
+                    }
+                }
+                killed = true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
         }
     }
+    if old_deadline == Some(deadline) {
+        // The outer timeout expired, let it report it.
+        environment.state.deadline_hit.set(true);
+        return last_eval;
+    }
+    Ok(Expression::Atom(Atom::Symbol(":timeout".into())))
 }
 
-fn builtin_ns_create(
+fn builtin_with_new_pgroup(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    if environment
-        .current_scope
-        .last()
-        .unwrap()
-        .borrow()
-        .name
-        .is_none()
-    {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "ns-create can only create a namespace when not in a lexical scope",
-        ));
+    let old_pgroup = environment.state.new_pgroup.replace(0);
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for a in args {
+        last_eval = eval(environment, a);
+        if last_eval.is_err() {
+            break;
+        }
     }
-    if let Some(key) = args.next() {
-        if args.next().is_none() {
-            let key = match eval(environment, key)? {
-                Expression::Atom(Atom::Symbol(sym)) => sym,
-                Expression::Atom(Atom::String(s)) => s,
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "ns-create: namespace must be a symbol or string",
-                    ))
-                }
-            };
-            let scope = match build_new_namespace(environment, &key) {
-                Ok(scope) => scope,
-                Err(msg) => return Err(io::Error::new(io::ErrorKind::Other, msg)),
-            };
-            scope.borrow_mut().data.insert(
-                "*ns*".to_string(),
-                Rc::new(Expression::Atom(Atom::String(key))),
-            );
-            environment.current_scope.push(scope);
-            return Ok(Expression::Atom(Atom::Nil));
+    environment.state.new_pgroup = old_pgroup;
+    last_eval
+}
+
+// Runs in the forked child of subshell, it's output goes to fd and the exit
+// status is from the last form (1 for an error, the code for exit).
+fn run_subshell(environment: &mut Environment, forms: &[Expression], fd: RawFd) -> ! {
+    if unistd::dup2(fd, 1).is_err() {
+        unsafe { libc::_exit(1) }
+    }
+    let _ = unistd::close(fd);
+    environment.do_job_control = false;
+    environment.is_tty = false;
+    // Fd 1 is the capture, commands write to it directly.
+    environment.state.stdout_status = None;
+    environment.in_pipe = false;
+    environment.data_in = None;
+    let mut code = 0;
+    for form in forms {
+        match eval(environment, form) {
+            Ok(Expression::Process(proc)) => code = proc.status().unwrap_or(0),
+            Ok(_) => code = 0,
+            Err(err) => {
+                code = if is_exit(&err) {
+                    environment.exit_code.take().unwrap_or(0)
+                } else {
+                    eprintln!("subshell: {}", err);
+                    1
+                };
+                break;
+            }
         }
     }
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "ns-create takes one arg, the name of the new namespace",
-    ))
+    let _ = io::stdout().flush();
+    // Skip destructors, they belong to the parent.
+    unsafe { libc::_exit(code) }
 }
 
-fn builtin_ns_enter(
+fn builtin_subshell(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
-    if environment
-        .current_scope
-        .last()
-        .unwrap()
-        .borrow()
-        .name
-        .is_none()
-    {
+    let forms: Vec<Expression> = args.cloned().collect();
+    // Anything buffered would be written twice.
+    io::stdout().flush()?;
+    let (read_fd, write_fd) =
+        unistd::pipe().map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    match unistd::fork() {
+        Ok(ForkResult::Child) => {
+            let _ = unistd::close(read_fd);
+            run_subshell(environment, &forms, write_fd);
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let _ = unistd::close(write_fd);
+            let mut out = unsafe { fs::File::from_raw_fd(read_fd) };
+            let mut output = Vec::new();
+            let res = out.read_to_end(&mut output);
+            // Sets the last status to the subshell's.
+            wait_pid(environment, child.as_raw() as u32, None);
+            res?;
+            Ok(Expression::Atom(Atom::String(
+                String::from_utf8_lossy(&output).to_string().into(),
+            )))
+        }
+        Err(err) => {
+            let _ = unistd::close(read_fd);
+            let _ = unistd::close(write_fd);
+            let msg = format!("subshell: fork failed: {}", err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_setsid(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let old_session = environment.state.new_session;
+    environment.state.new_session = true;
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for a in args {
+        last_eval = eval(environment, a);
+        if last_eval.is_err() {
+            break;
+        }
+    }
+    environment.state.new_session = old_session;
+    last_eval
+}
+
+// A pid from an int or process, None if no arg.
+fn pgroup_pid(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<Option<Pid>> {
+    let pid = match (args.next(), args.next()) {
+        (None, None) => return Ok(None),
+        (Some(arg), None) => match eval(environment, arg)? {
+            Expression::Atom(Atom::Int(pid)) if pid > 0 => pid as i32,
+            Expression::Process(proc) => proc.pid() as i32,
+            _ => {
+                let msg = format!("{} requires a pid or process", form);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        },
+        _ => {
+            let msg = format!("{} takes an optional pid or process", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    Ok(Some(Pid::from_raw(pid)))
+}
+
+fn builtin_getpgid(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let pid = pgroup_pid(environment, args, "getpgid")?;
+    match unistd::getpgid(pid) {
+        Ok(pgid) => Ok(Expression::Atom(Atom::Int(i64::from(pgid.as_raw())))),
+        Err(err) => {
+            let msg = format!("getpgid: {}", err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_tcgetpgrp(
+    _environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "tcgetpgrp takes no arguments",
+        ));
+    }
+    match unistd::tcgetpgrp(nix::libc::STDIN_FILENO) {
+        Ok(pgid) => Ok(Expression::Atom(Atom::Int(i64::from(pgid.as_raw())))),
+        Err(err) => {
+            let msg = format!("tcgetpgrp: {}", err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_tcsetpgrp(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    // A pid may be any member of the group, use the group.
+    let pgid = match pgroup_pid(environment, args, "tcsetpgrp")? {
+        Some(pid) => match unistd::getpgid(Some(pid)) {
+            Ok(pgid) => pgid,
+            Err(_) => pid,
+        },
+        None => unistd::getpgrp(),
+    };
+    match unistd::tcsetpgrp(nix::libc::STDIN_FILENO, pgid) {
+        Ok(()) => Ok(Expression::Atom(Atom::True)),
+        Err(err) => {
+            let msg = format!("tcsetpgrp {}: {}", pgid, err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// Eval forms and return everything they output (stdout and stderr).
+// The output of forms followed by what they wrote to stderr (kept in a capture
+// file so a lot of it can not block a command on a full pipe).
+fn capture_output(environment: &mut Environment, forms: &[Expression]) -> io::Result<String> {
+    let file = capture_file()?;
+    let mut reader = file.try_clone()?;
+    let err_out = Rc::new(RefCell::new(io::BufWriter::new(file)));
+    let old_out = environment.state.stdout_status.clone();
+    let old_err = environment.state.stderr_status.take();
+    let old_val = environment.dynamic_scope.remove("*stderr*");
+    environment.dynamic_scope.insert(
+        "*stderr*".to_string(),
+        Rc::new(Expression::File(FileState::Write(err_out.clone()))),
+    );
+    environment.state.stdout_status = Some(IOState::Pipe);
+    let mut res = Ok(String::new());
+    for form in forms {
+        match eval(environment, form).and_then(|r| r.as_string(environment)) {
+            Ok(out) => {
+                if let Ok(res) = &mut res {
+                    res.push_str(&out);
+                }
+            }
+            Err(err) => {
+                res = Err(err);
+                break;
+            }
+        }
+    }
+    environment.state.stdout_status = old_out;
+    environment.state.stderr_status = old_err;
+    if let Some(old_val) = old_val {
+        environment
+            .dynamic_scope
+            .insert("*stderr*".to_string(), old_val);
+    } else {
+        environment.dynamic_scope.remove("*stderr*");
+    }
+    let mut res = res?;
+    err_out.borrow_mut().flush()?;
+    reader.seek(io::SeekFrom::Start(0))?;
+    reader.read_to_string(&mut res)?;
+    Ok(res)
+}
+
+fn builtin_watch(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let secs = match args.next() {
+        Some(secs) => match eval(environment, secs)? {
+            Expression::Atom(Atom::Int(i)) if i > 0 => i as f64,
+            Expression::Atom(Atom::Float(f)) if f > 0.0 => f,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "watch: seconds must be a positive number",
+                ))
+            }
+        },
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "watch takes seconds, options (:diff, :count n) and forms",
+            ))
+        }
+    };
+    let mut diff = false;
+    let mut count: Option<i64> = None;
+    let mut forms = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg {
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":diff" => diff = true,
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":count" => {
+                count = match args.next().map(|c| eval(environment, c)) {
+                    Some(Ok(Expression::Atom(Atom::Int(c)))) => Some(c),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "watch: :count takes an integer",
+                        ))
+                    }
+                }
+            }
+            _ => forms.push(arg.clone()),
+        }
+    }
+    let tty = unistd::isatty(1).unwrap_or(false);
+    let title: Vec<String> = forms.iter().map(|f| f.to_string()).collect();
+    let header = format!("Every {}s: {}", secs, title.join(" "));
+    let mut last: Vec<String> = Vec::new();
+    let mut runs = 0;
+    // Clear a pending SIGINT so it only stops a watch started after it.
+    environment
+        .sig_int
+        .store(false, std::sync::atomic::Ordering::Relaxed);
+    loop {
+        let output = capture_output(environment, &forms);
+        if environment
+            .sig_int
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            break;
+        }
+        let output = output?;
+        let lines: Vec<String> = output.lines().map(|l| l.to_string()).collect();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if tty {
+            out.write_all(b"\x1b[H\x1b[2J")?;
+        }
+        writeln!(out, "{}\n", header)?;
+        for (i, line) in lines.iter().enumerate() {
+            if diff && runs > 0 && tty && last.get(i) != Some(line) {
+                writeln!(out, "\x1b[7m{}\x1b[0m", line)?;
+            } else {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        out.flush()?;
+        last = lines;
+        runs += 1;
+        if count.map_or(false, |c| runs >= c) {
+            break;
+        }
+        let wake = Instant::now() + Duration::from_millis((secs * 1000.0) as u64);
+        while Instant::now() < wake {
+            if environment
+                .sig_int
+                .load(std::sync::atomic::Ordering::Relaxed)
+            {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        if environment
+            .sig_int
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            break;
+        }
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+fn builtin_form(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let old_form = environment.form_type;
+    environment.form_type = FormType::FormOnly;
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for a in args {
+        last_eval = eval(environment, a);
+        if let Err(err) = last_eval {
+            environment.form_type = old_form;
+            return Err(err);
+        }
+    }
+    environment.form_type = old_form;
+    last_eval
+}
+
+fn builtin_loose_symbols(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let old_loose_syms = environment.loose_symbols;
+    environment.loose_symbols = true;
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for a in args {
+        last_eval = eval(environment, a);
+        if let Err(err) = last_eval {
+            environment.loose_symbols = old_loose_syms;
+            return Err(err);
+        }
+    }
+    environment.loose_symbols = old_loose_syms;
+    last_eval
+}
+
+fn builtin_exit(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let mut args = list_to_args(environment, args, true)?;
+    if let Some(Expression::Atom(Atom::Symbol(s))) = args.last() {
+        if &**s == ":force" {
+            // Exit even with jobs running (see the exit-with-jobs option).
+            environment.force_exit = true;
+            args.pop();
+        }
+    }
+    match args.len().cmp(&1) {
+        Ordering::Greater => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "exit can only take an optional integer (exit code- defaults to 0) and :force",
+        )),
+        Ordering::Equal => {
+            if let Expression::Atom(Atom::Int(exit_code)) = &args[0] {
+                environment.exit_code = Some(*exit_code as i32);
+                Err(exit_error(*exit_code as i32))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "exit can only take an optional integer (exit code- defaults to 0) and :force",
+                ))
+            }
+        }
+        Ordering::Less => {
+            environment.exit_code = Some(0);
+            Err(exit_error(0))
+        }
+    }
+}
+
+fn builtin_on_exit(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(hook) = args.next() {
+        if args.next().is_none() {
+            let hook = eval(environment, hook)?;
+            environment.exit_hooks.push(hook.clone());
+            return Ok(hook);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "on-exit takes a function (with no parameters)",
+    ))
+}
+
+// Call the on-exit hooks, last added first.
+pub fn run_exit_hooks(environment: &mut Environment) {
+    let hooks = std::mem::replace(&mut environment.exit_hooks, Vec::new());
+    let no_args: Vec<Expression> = Vec::new();
+    for hook in hooks.iter().rev() {
+        if let Err(err) = run_cleanup(environment, |environment| {
+            fn_call(environment, hook, Box::new(no_args.iter()))
+        }) {
+            eprintln!("ERROR in on-exit hook: {}", err);
+        }
+    }
+}
+
+fn builtin_ns_create(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if environment
+        .current_scope
+        .last()
+        .unwrap()
+        .borrow()
+        .name
+        .is_none()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "ns-create can only create a namespace when not in a lexical scope",
+        ));
+    }
+    if let Some(key) = args.next() {
+        if args.next().is_none() {
+            let key = match eval(environment, key)? {
+                Expression::Atom(Atom::Symbol(sym)) => sym,
+                Expression::Atom(Atom::String(s)) => s.into(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "ns-create: namespace must be a symbol or string",
+                    ))
+                }
+            };
+            let scope = match build_new_namespace(environment, &key) {
+                Ok(scope) => scope,
+                Err(msg) => return Err(io::Error::new(io::ErrorKind::Other, msg)),
+            };
+            scope.borrow_mut().data.insert(
+                "*ns*".to_string(),
+                Rc::new(Expression::Atom(Atom::String(key.into()))),
+            );
+            environment.current_scope.push(scope);
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "ns-create takes one arg, the name of the new namespace",
+    ))
+}
+
+fn builtin_ns_enter(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if environment
+        .current_scope
+        .last()
+        .unwrap()
+        .borrow()
+        .name
+        .is_none()
+    {
         return Err(io::Error::new(
             io::ErrorKind::Other,
             "ns-enter can only enter a namespace when not in a lexical scope",
@@ -1412,7 +3579,7 @@ fn builtin_ns_enter(
         if args.next().is_none() {
             let key = match eval(environment, key)? {
                 Expression::Atom(Atom::Symbol(sym)) => sym,
-                Expression::Atom(Atom::String(s)) => s,
+                Expression::Atom(Atom::String(s)) => s.into(),
                 _ => {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
@@ -1444,8 +3611,8 @@ fn builtin_ns_exists(
     if let Some(key) = args.next() {
         if args.next().is_none() {
             let key = match eval(environment, key)? {
-                Expression::Atom(Atom::Symbol(sym)) => sym,
-                Expression::Atom(Atom::String(s)) => s,
+                Expression::Atom(Atom::Symbol(sym)) => sym.to_string(),
+                Expression::Atom(Atom::String(s)) => s.to_string(),
                 _ => {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
@@ -1466,20 +3633,108 @@ fn builtin_ns_exists(
     ))
 }
 
-fn builtin_ns_list(
+fn builtin_ns_list(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_none() {
+        let mut ns_list = Vec::with_capacity(environment.namespaces.len());
+        for ns in environment.namespaces.keys() {
+            ns_list.push(Expression::Atom(Atom::String(ns.to_string().into())));
+        }
+        return Ok(Expression::with_list(ns_list));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "ns-list takes no args",
+    ))
+}
+
+fn symbol_names(scope: &Scope) -> Expression {
+    let mut names: Vec<&String> = scope.data.keys().collect();
+    names.sort();
+    Expression::with_list(
+        names
+            .into_iter()
+            .map(|n| Expression::Atom(Atom::String(n.to_string().into())))
+            .collect(),
+    )
+}
+
+fn builtin_ns_symbols(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(key) = args.next() {
+        if args.next().is_none() {
+            let key = match eval(environment, key)? {
+                Expression::Atom(Atom::Symbol(sym)) => sym,
+                Expression::Atom(Atom::String(s)) => s.into(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "ns-symbols: namespace must be a symbol or string",
+                    ))
+                }
+            };
+            return match get_namespace(environment, &key) {
+                Some(scope) => Ok(symbol_names(&scope.borrow())),
+                None => {
+                    let msg = format!("Error, namespace {} does not exist!", key);
+                    Err(io::Error::new(io::ErrorKind::Other, msg))
+                }
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "ns-symbols takes one arg, the name of the namespace",
+    ))
+}
+
+fn builtin_scope_symbols(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_none() {
+        let mut levels = Vec::new();
+        let mut loop_scope = environment.current_scope.last().cloned();
+        while let Some(scope) = loop_scope {
+            let name = match &scope.borrow().name {
+                Some(name) => Expression::Atom(Atom::String(name.to_string().into())),
+                None => Expression::Atom(Atom::Nil),
+            };
+            levels.push(Expression::with_list(vec![
+                name,
+                symbol_names(&scope.borrow()),
+            ]));
+            loop_scope = scope.borrow().outer.clone();
+        }
+        return Ok(Expression::with_list(levels));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "scope-symbols takes no args",
+    ))
+}
+
+fn builtin_dynamic_symbols(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
     if args.next().is_none() {
-        let mut ns_list = Vec::with_capacity(environment.namespaces.len());
-        for ns in environment.namespaces.keys() {
-            ns_list.push(Expression::Atom(Atom::String(ns.to_string())));
-        }
-        return Ok(Expression::with_list(ns_list));
+        let mut names: Vec<&String> = environment.dynamic_scope.keys().collect();
+        names.sort();
+        return Ok(Expression::with_list(
+            names
+                .into_iter()
+                .map(|n| Expression::Atom(Atom::String(n.to_string().into())))
+                .collect(),
+        ));
     }
     Err(io::Error::new(
         io::ErrorKind::Other,
-        "ns-list takes no args",
+        "dynamic-symbols takes no args",
     ))
 }
 
@@ -1488,7 +3743,7 @@ fn builtin_error_stack_on(
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
     if args.next().is_none() {
-        environment.stack_on_error = true;
+        set_option(environment, "error-stack", Expression::Atom(Atom::True))?;
         return Ok(Expression::Atom(Atom::Nil));
     }
     Err(io::Error::new(
@@ -1502,7 +3757,7 @@ fn builtin_error_stack_off(
     args: &mut dyn Iterator<Item = &Expression>,
 ) -> io::Result<Expression> {
     if args.next().is_none() {
-        environment.stack_on_error = false;
+        set_option(environment, "error-stack", Expression::Atom(Atom::Nil))?;
         return Ok(Expression::Atom(Atom::Nil));
     }
     Err(io::Error::new(
@@ -1520,17 +3775,148 @@ fn builtin_get_error(
         match eval(environment, &arg) {
             Ok(exp) => ret = exp,
             Err(err) => {
-                let mut v = Vec::new();
-                v.push(Expression::Atom(Atom::Symbol(":error".to_string())));
-                let msg = format!("{}", err);
-                v.push(Expression::Atom(Atom::String(msg)));
-                return Ok(Expression::with_list(v));
+                // Let a throw get to it's catch and exit unwind.
+                if throw_tag(&err).is_some() || is_exit(&err) {
+                    return Err(err);
+                }
+                // Handled, so the SIGINT should not stop what comes next.
+                if err.kind() == io::ErrorKind::Interrupted {
+                    environment
+                        .sig_int
+                        .store(false, std::sync::atomic::Ordering::Relaxed);
+                }
+                return Ok(error_object(environment, &err));
             }
         }
     }
     Ok(ret)
 }
 
+// Hashmap describing an error (from get-error), clears the error state.
+fn error_object(environment: &mut Environment, err: &io::Error) -> Expression {
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    map.insert(
+        ":type".to_string(),
+        Rc::new(Expression::Atom(Atom::Symbol(error_type(err).into()))),
+    );
+    map.insert(
+        ":msg".to_string(),
+        Rc::new(Expression::Atom(Atom::String(err.to_string().into()))),
+    );
+    map.insert(
+        ":form".to_string(),
+        Rc::new(
+            environment
+                .error_expression
+                .take()
+                .unwrap_or(Expression::Atom(Atom::Nil)),
+        ),
+    );
+    let mut backtrace: Vec<Expression> = environment
+        .error_chain
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|call| Expression::Atom(Atom::String(call.into())))
+        .collect();
+    if environment.error_chain_elided > 0 {
+        let more = format!("... {} more", environment.error_chain_elided);
+        backtrace.push(Expression::Atom(Atom::String(more.into())));
+    }
+    map.insert(
+        ":backtrace".to_string(),
+        Rc::new(Expression::with_list(backtrace)),
+    );
+    map.insert(
+        ":data".to_string(),
+        Rc::new(
+            environment
+                .error_data
+                .take()
+                .unwrap_or(Expression::Atom(Atom::Nil)),
+        ),
+    );
+    clear_error(environment);
+    Expression::HashMap(Rc::new(RefCell::new(map)))
+}
+
+// The error object that is the only argument or an error.
+fn error_arg(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<Rc<RefCell<HashMap<String, Rc<Expression>>>>> {
+    if let (Some(arg), None) = (args.next(), args.next()) {
+        if let Expression::HashMap(map) = eval(environment, arg)? {
+            if is_error_object(&map.borrow()) {
+                return Ok(map);
+            }
+        }
+    }
+    let msg = format!("{} takes an error (from get-error)", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn is_error_object(map: &HashMap<String, Rc<Expression>>) -> bool {
+    match map.get(":type").map(|t| &**t) {
+        Some(Expression::Atom(Atom::Symbol(_))) => map.contains_key(":msg"),
+        _ => false,
+    }
+}
+
+fn builtin_is_error(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let (Some(arg), None) = (args.next(), args.next()) {
+        return Ok(match eval(environment, arg)? {
+            Expression::HashMap(map) if is_error_object(&map.borrow()) => {
+                Expression::Atom(Atom::True)
+            }
+            _ => Expression::Atom(Atom::Nil),
+        });
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "error? takes one form",
+    ))
+}
+
+fn error_field(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+    key: &str,
+) -> io::Result<Expression> {
+    let map = error_arg(environment, args, form)?;
+    let map = map.borrow();
+    Ok(match map.get(key) {
+        Some(val) => (**val).clone(),
+        None => Expression::Atom(Atom::Nil),
+    })
+}
+
+fn builtin_error_type(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    error_field(environment, args, "error-type", ":type")
+}
+
+fn builtin_error_msg(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    error_field(environment, args, "error-msg", ":msg")
+}
+
+fn builtin_error_data(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    error_field(environment, args, "error-data", ":data")
+}
+
 macro_rules! ensure_tonicity {
     ($check_fn:expr, $values:expr, $type:ty, $type_two:ty) => {{
         let first = $values.first().ok_or(io::Error::new(
@@ -1568,26 +3954,448 @@ macro_rules! ensure_tonicity_all {
     }};
 }
 
+// Returns a description of the arity for a lambda or macro parameter list.
+fn params_arity(params: &Expression) -> String {
+    let mut min = 0;
+    let mut rest = false;
+    for p in params.iter() {
+        if let Expression::Atom(Atom::Symbol(s)) = p {
+            if &**s == "&rest" {
+                rest = true;
+                break;
+            }
+        }
+        min += 1;
+    }
+    if rest {
+        format!("{} or more", min)
+    } else {
+        format!("{}", min)
+    }
+}
+
+// A lambda doc string is a string at the start of a progn body with more forms after it.
+fn lambda_doc(body: &Expression) -> Option<String> {
+    if let Expression::Pair(_, _) = body {
+        let mut forms = body.iter();
+        if let Some(Expression::Atom(Atom::Symbol(s))) = forms.next() {
+            if &**s == "progn" {
+                if let Some(Expression::Atom(Atom::String(doc))) = forms.next() {
+                    if forms.next().is_some() {
+                        return Some(doc.to_string());
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+fn symbol_namespace(environment: &Environment, key: &str) -> String {
+    if environment.dynamic_scope.contains_key(key) {
+        return "dynamic".to_string();
+    }
+    if key.contains("::") {
+        return key.splitn(2, "::").next().unwrap_or("").to_string();
+    }
+    match get_symbols_scope(environment, key) {
+        Some(scope) => match &scope.borrow().name {
+            Some(name) => name.to_string(),
+            None => "local".to_string(),
+        },
+        None => "".to_string(),
+    }
+}
+
+// The scope a symbol (possibly ns::sym) is defined in and it's name there.
+fn defining_scope(environment: &Environment, key: &str) -> Option<(Rc<RefCell<Scope>>, String)> {
+    if key.contains("::") {
+        let mut key_i = key.splitn(2, "::");
+        let namespace = key_i.next()?;
+        let key = key_i.next()?;
+        let scope = get_namespace(environment, namespace)?;
+        if scope.borrow().data.contains_key(key) {
+            return Some((scope, key.to_string()));
+        }
+        None
+    } else {
+        get_symbols_scope(environment, key).map(|scope| (scope, key.to_string()))
+    }
+}
+
+fn meta_key(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+) -> io::Result<String> {
+    if let Some(arg) = arg {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(s)) => return Ok(s.to_string()),
+            Expression::Atom(Atom::String(s)) => return Ok(s.to_string()),
+            _ => {}
+        }
+    }
+    let msg = format!("{} requires a symbol", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+// The file a symbol was defined in (its :file metadata).
+pub fn symbol_file(environment: &Environment, key: &str) -> Option<String> {
+    let (scope, name) = defining_scope(environment, key)?;
+    let scope = scope.borrow();
+    match scope.meta.get(&name)?.get(":file").map(|f| &**f) {
+        Some(Expression::Atom(Atom::String(file))) => Some(file.to_string()),
+        _ => None,
+    }
+}
+
+// Stored metadata plus the doc string of functions when there is no :doc.
+fn symbol_meta(environment: &Environment, key: &str) -> Option<HashMap<String, Rc<Expression>>> {
+    let (scope, name) = defining_scope(environment, key)?;
+    let scope = scope.borrow();
+    let mut meta = scope.meta.get(&name).cloned().unwrap_or_default();
+    if !meta.contains_key(":doc") {
+        let doc = match &**scope.data.get(&name)? {
+            Expression::Function(c) if !c.doc_str.is_empty() => Some(c.doc_str.clone()),
+            Expression::Atom(Atom::Lambda(l)) => lambda_doc(&l.body),
+            Expression::Atom(Atom::Macro(m)) => lambda_doc(&m.body),
+            _ => None,
+        };
+        if let Some(doc) = doc {
+            meta.insert(
+                ":doc".to_string(),
+                Rc::new(Expression::Atom(Atom::String(doc.into()))),
+            );
+        }
+    }
+    Some(meta)
+}
+
+fn builtin_set_meta(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let key = meta_key(environment, args.next(), "set-meta!")?;
+    let meta = match (args.next(), args.next()) {
+        (Some(map), None) => match eval(environment, map)? {
+            Expression::HashMap(map) => map.borrow().clone(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "set-meta! requires a symbol and a hashmap",
+                ))
+            }
+        },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "set-meta! requires a symbol and a hashmap",
+            ))
+        }
+    };
+    match defining_scope(environment, &key) {
+        Some((scope, name)) => {
+            let mut scope = scope.borrow_mut();
+            let entry = scope.meta.entry(name).or_insert_with(HashMap::new);
+            for (k, v) in meta {
+                entry.insert(k, v);
+            }
+            Ok(Expression::Atom(Atom::True))
+        }
+        None => {
+            let msg = format!("set-meta!: {} is not defined", key);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_meta(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let key = meta_key(environment, args.next(), "meta")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "meta takes one form (a symbol)",
+        ));
+    }
+    match symbol_meta(environment, &key) {
+        Some(meta) => Ok(Expression::HashMap(Rc::new(RefCell::new(meta)))),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+fn builtin_describe(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let key = match arg {
+                Expression::Atom(Atom::Symbol(s)) => s.clone(),
+                _ => match eval(environment, arg)? {
+                    Expression::Atom(Atom::Symbol(s)) => s,
+                    Expression::Atom(Atom::String(s)) => s.into(),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "describe: requires a symbol",
+                        ));
+                    }
+                },
+            };
+            let exp = match get_expression(environment, &key) {
+                Some(exp) => exp,
+                None => {
+                    let msg = format!("describe: {} is not defined", key);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            };
+            let (kind, params, arity, doc) = match &*exp {
+                Expression::Function(c) => {
+                    let kind = if c.is_special_form {
+                        "special form"
+                    } else {
+                        "builtin"
+                    };
+                    (kind, None, None, Some(c.doc_str.clone()))
+                }
+                Expression::Func(_) => ("builtin", None, None, None),
+                Expression::Atom(Atom::Lambda(l)) => (
+                    "lambda",
+                    Some(l.params.to_string()),
+                    Some(params_arity(&l.params)),
+                    lambda_doc(&l.body),
+                ),
+                Expression::Atom(Atom::Macro(m)) => (
+                    "macro",
+                    Some(m.params.to_string()),
+                    Some(params_arity(&m.params)),
+                    lambda_doc(&m.body),
+                ),
+                _ => ("value", None, None, None),
+            };
+            println!("{}", key);
+            if kind == "value" {
+                println!("    kind:      value ({})", exp.display_type());
+            } else {
+                println!("    kind:      {}", kind);
+            }
+            if let Some(params) = params {
+                println!("    params:    {}", params);
+            }
+            if let Some(arity) = arity {
+                println!("    arity:     {}", arity);
+            }
+            println!("    namespace: {}", symbol_namespace(environment, &key));
+            let meta = symbol_meta(environment, &key).unwrap_or_default();
+            let doc = match meta.get(":doc") {
+                Some(doc) => Some(doc.as_string(environment)?),
+                None => doc,
+            };
+            if let Some(doc) = doc {
+                if !doc.is_empty() {
+                    println!("    doc:       {}", doc);
+                }
+            }
+            let mut names: Vec<&String> = meta.keys().filter(|k| *k != ":doc").collect();
+            names.sort();
+            for name in names {
+                let label = format!("{}:", &name[1..]);
+                println!("    {:10} {}", label, meta[name]);
+            }
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "describe: requires one form (a symbol)",
+    ))
+}
+
+// The help categories (in the order they are first registered) and the
+// functions that add their builtins, from the table that fills the root scope.
+fn help_categories() -> Vec<(&'static str, Vec<AddBuiltins>)> {
+    let mut categories: Vec<(&'static str, Vec<AddBuiltins>)> = Vec::new();
+    for (name, add) in BUILTINS {
+        match categories.iter_mut().find(|(cat, _)| cat == name) {
+            Some((_, adders)) => adders.push(*add),
+            None => categories.push((name, vec![*add])),
+        }
+    }
+    categories
+}
+
+fn print_help_category(name: &str, adders: &[AddBuiltins]) {
+    let mut data: HashMap<String, Rc<Expression>> = HashMap::new();
+    for add in adders {
+        add(&mut data);
+    }
+    let mut names: Vec<&String> = data.keys().collect();
+    names.sort();
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+    println!("{}:", name);
+    for n in names {
+        let doc = match &*data[n] {
+            Expression::Function(c) => c.doc_str.lines().next().unwrap_or(""),
+            _ => "",
+        };
+        let line = format!("    {:width$}  {}", n, doc, width = width);
+        println!("{}", line.trim_end());
+    }
+}
+
+fn builtin_help(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let categories = help_categories();
+    if let Some(arg) = args.next() {
+        if args.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "help takes zero or one form (category)",
+            ));
+        }
+        let cat = match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(s)) => s,
+            Expression::Atom(Atom::String(s)) => s.into(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "help: category must be a symbol or string",
+                ));
+            }
+        };
+        let cat = cat.trim_start_matches(':');
+        for (name, adders) in &categories {
+            if *name == cat {
+                print_help_category(name, adders);
+                return Ok(Expression::Atom(Atom::Nil));
+            }
+        }
+        let names: Vec<&str> = categories.iter().map(|(name, _)| *name).collect();
+        let msg = format!(
+            "help: unknown category {}, valid categories: {}",
+            cat,
+            names.join(" ")
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    for (i, (name, adders)) in categories.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_help_category(name, adders);
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
 pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
     data.insert(
         "eval".to_string(),
         Rc::new(Expression::make_function(
             builtin_eval,
-            "Evalute the provided expression",
+            "Evalute the provided expression, in namespace if one is provided.",
+        )),
+    );
+    data.insert(
+        "read-string".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_read_string,
+            "Read the first form from a string without evaluating it, with :rest return a vector of the form and the unread text.",
+        )),
+    );
+    data.insert(
+        "read-all".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_read_all,
+            "Read all the forms in a string (without evaluating them) into a vector.",
+        )),
+    );
+    data.insert(
+        "default-read-hook".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_default_read_hook,
+            "Return a line of shell input as the string to parse, wrapped in parens unless it starts with ( ' ` or #.",
+        )),
+    );
+    data.insert(
+        "fncall".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_fncall,
+            "Call the provided function with the suplied arguments",
+        )),
+    );
+    data.insert(
+        "apply".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_apply,
+            "Call the provided function with the suplied arguments, last is a list that will be expanded",
+        )),
+    );
+    data.insert(
+        "partial".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_partial,
+            "Return a function that calls the first argument (a function) with the rest of the arguments followed by its own.",
+        )),
+    );
+    data.insert(
+        "compose".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_compose,
+            "Return a function that calls the last function with its arguments then each function before it with the result, (compose) is identity.",
+        )),
+    );
+    data.insert(
+        "complement".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_complement,
+            "Return a function that calls the function and returns true when it returns nil, otherwise nil.",
+        )),
+    );
+    data.insert(
+        "constantly".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_constantly,
+            "Return a function that takes any arguments and always returns the value.",
+        )),
+    );
+    data.insert(
+        "identity".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_identity,
+            "Return the argument.",
+        )),
+    );
+    data.insert(
+        "dynamic-wind".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_dynamic_wind,
+            "Call before, during and after (functions with no parameters) in order, after is called however control leaves during (error, throw, exit or SIGINT).  Returns the result of during.",
+        )),
+    );
+    data.insert(
+        "defer".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_defer,
+            "Run forms when the enclosing function, let or file body exits (last defer first, even on error, throw or exit).",
         )),
     );
     data.insert(
-        "fncall".to_string(),
+        "catch".to_string(),
         Rc::new(Expression::make_function(
-            builtin_fncall,
-            "Call the provided function with the suplied arguments",
+            builtin_catch,
+            "Evaluate forms (like progn) but a throw to tag in them returns the thrown value from catch.",
         )),
     );
     data.insert(
-        "apply".to_string(),
+        "throw".to_string(),
         Rc::new(Expression::make_function(
-            builtin_apply,
-            "Call the provided function with the suplied arguments, last is a list that will be expanded",
+            builtin_throw,
+            "Unwind to the catch for tag (running unwind-protect cleanup forms) which returns value.",
         )),
     );
     data.insert(
@@ -1601,7 +4409,7 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "err".to_string(),
         Rc::new(Expression::make_function(
             builtin_err,
-            "Raise an error with the supplied message",
+            "Raise an error with the supplied message, or with a type keyword, message and optional data: (err :bad-input \"msg\" data).",
         )),
     );
     data.insert(
@@ -1625,6 +4433,80 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
             "If then else conditional.",
         )),
     );
+    data.insert(
+        "cond".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_cond,
+            "Usage: (cond (test body...)... (else body...)) Evaluate the body of the first clause whose test is not nil (else always is), returns the last body value, the test value if the body is empty or nil if no clause matches.",
+        )),
+    );
+    data.insert(
+        "case".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_case,
+            "Usage: (case key (value body...)... ((value value...) body...)... (else body...)) Evaluate key and then the body of the clause with an equal value (int, string, symbol/keyword or char, not evaluated), else if none does or nil without an else.  Uses a jump table built the first time the form runs.",
+        )),
+    );
+    data.insert(
+        "match-equal".to_string(),
+        Rc::new(Expression::make_special(builtin_case, "Same as case.")),
+    );
+    data.insert(
+        "when".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_when,
+            "Usage: (when test body...) Evaluate the body forms if test is not nil, returns the last or nil.",
+        )),
+    );
+    data.insert(
+        "unless".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_unless,
+            "Usage: (unless test body...) Evaluate the body forms if test is nil, returns the last or nil.",
+        )),
+    );
+    data.insert(
+        "while".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_while,
+            "Usage: (while test body...) Evaluate the body forms until test is nil, returns nil.",
+        )),
+    );
+    data.insert(
+        "dotimes".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_dotimes,
+            "Usage: (dotimes times body...) Evaluate the body forms times times, returns nil.",
+        )),
+    );
+    data.insert(
+        "for".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_for,
+            "Usage: (for bind seq body...) Evaluate the body forms with bind set to each item of seq (list, vector, string, hashmap, file or process like map), returns nil.",
+        )),
+    );
+    data.insert(
+        "->".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_thread_first,
+            "Usage: (-> form step...) Thread form through the steps as their first argument, (-> x f (g a)) is (g (f x) a).  A step can be a symbol or a list, the rewrite is done before evaluating so macros work as steps.",
+        )),
+    );
+    data.insert(
+        "->>".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_thread_last,
+            "Usage: (->> form step...) Thread form through the steps as their last argument, (->> x f (g a)) is (g a (f x)).",
+        )),
+    );
+    data.insert(
+        "as->".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_as_thread,
+            "Usage: (as-> form name step...) Evaluate each step with name bound to form's value and then to the previous step's value, returns the last.",
+        )),
+    );
     data.insert(
         "print".to_string(),
         Rc::new(Expression::make_function(
@@ -1639,6 +4521,27 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
             "Print the arguments and then a newline.",
         )),
     );
+    data.insert(
+        "pprint".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pprint,
+            "Pretty print the form (using the print-* options) and then a newline.",
+        )),
+    );
+    data.insert(
+        "print-all".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_print_all,
+            "Print the form like a REPL result but without the print-length, print-level and print-lines truncation and then a newline.",
+        )),
+    );
+    data.insert(
+        "fmt-source".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_fmt_source,
+            "Format lisp source (a string) with canonical indentation, comments are kept.",
+        )),
+    );
     data.insert(
         "eprint".to_string(),
         Rc::new(Expression::make_function(
@@ -1653,6 +4556,34 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
             "Print the arguments to stderr and then a newline.",
         )),
     );
+    data.insert(
+        "write".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_write,
+            "Print the arguments so they read back, strings quoted and escaped and chars as #\\x.",
+        )),
+    );
+    data.insert(
+        "writeln".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_writeln,
+            "Like write then a newline.",
+        )),
+    );
+    data.insert(
+        "display".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_display,
+            "Print the arguments for people, strings and chars as their plain text.",
+        )),
+    );
+    data.insert(
+        "displayln".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_displayln,
+            "Like display then a newline.",
+        )),
+    );
     data.insert(
         "format".to_string(),
         Rc::new(Expression::make_function(
@@ -1675,24 +4606,24 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         )),
     );
     data.insert(
-        "export".to_string(),
+        "def".to_string(),
         Rc::new(Expression::make_function(
-            builtin_export,
-            "Export a key and value to the shell environment.",
+            builtin_def,
+            "Adds an expression to the current scope, optionally followed by metadata (:doc \"...\" :author ...).",
         )),
     );
     data.insert(
-        "unexport".to_string(),
+        "set-meta!".to_string(),
         Rc::new(Expression::make_function(
-            builtin_unexport,
-            "Remove a var from the current shell environment.",
+            builtin_set_meta,
+            "Merge a hashmap into a defined symbol's metadata.",
         )),
     );
     data.insert(
-        "def".to_string(),
+        "meta".to_string(),
         Rc::new(Expression::make_function(
-            builtin_def,
-            "Adds an expression to the current scope.",
+            builtin_meta,
+            "Returns a hashmap of a symbol's metadata (nil if not defined).",
         )),
     );
     data.insert(
@@ -1709,6 +4640,20 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
             "Creates a dynamic binding and evals a form under it.",
         )),
     );
+    data.insert(
+        "with-out-str".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_with_out_str,
+            "Evaluate the forms with *stdout* bound to a buffer and return what was printed as a string.",
+        )),
+    );
+    data.insert(
+        "with-err-str".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_with_err_str,
+            "Evaluate the forms with *stderr* bound to a buffer and return what was printed as a string.",
+        )),
+    );
     data.insert(
         "global-scope?".to_string(),
         Rc::new(Expression::Func(builtin_is_global_scope)),
@@ -1738,6 +4683,20 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "or".to_string(),
         Rc::new(Expression::make_special(builtin_or, "")),
     );
+    data.insert(
+        "and-run".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_and_run,
+            "Eval forms until one fails (a command that exits non-zero or nil) and return it, otherwise the last result, like && in sh.",
+        )),
+    );
+    data.insert(
+        "or-run".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_or_run,
+            "Eval forms until one succeeds (a command that exits 0 or not nil) and return it, otherwise the last result, like || in sh.",
+        )),
+    );
     data.insert("not".to_string(), Rc::new(Expression::Func(builtin_not)));
     data.insert("null".to_string(), Rc::new(Expression::Func(builtin_not)));
     data.insert(
@@ -1752,6 +4711,13 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "expand-macro".to_string(),
         Rc::new(Expression::make_special(builtin_expand_macro, "")),
     );
+    data.insert(
+        "recompile".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_recompile,
+            "Forget the cached macro expansions so every macro call expands again.",
+        )),
+    );
     data.insert(
         "recur".to_string(),
         Rc::new(Expression::make_function(builtin_recur, "")),
@@ -1760,9 +4726,6 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "gensym".to_string(),
         Rc::new(Expression::Func(builtin_gensym)),
     );
-    data.insert("jobs".to_string(), Rc::new(Expression::Func(builtin_jobs)));
-    data.insert("bg".to_string(), Rc::new(Expression::Func(builtin_bg)));
-    data.insert("fg".to_string(), Rc::new(Expression::Func(builtin_fg)));
     data.insert(
         "version".to_string(),
         Rc::new(Expression::make_function(
@@ -1771,24 +4734,24 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         )),
     );
     data.insert(
-        "command".to_string(),
-        Rc::new(Expression::make_special(
-            builtin_command,
-            "Only execute system commands not forms within this form.",
+        "doc".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_doc,
+            "Return the doc string for a symbol, for an external command the man page synopsis or --help output.",
         )),
     );
     data.insert(
-        "run-bg".to_string(),
+        "describe".to_string(),
         Rc::new(Expression::make_special(
-            builtin_run_bg,
-            "Any system commands started within form will be in the background.",
+            builtin_describe,
+            "Print the kind, parameters, arity, namespace and doc string of a symbol's value.",
         )),
     );
     data.insert(
-        "form".to_string(),
-        Rc::new(Expression::make_special(
-            builtin_form,
-            "Do not execute system commands within this form.",
+        "help".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_help,
+            "Print the builtins grouped by category with their docs, optionally only the given category.",
         )),
     );
     data.insert(
@@ -1798,7 +4761,6 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
             "Within this form any undefined symbols become strings.",
         )),
     );
-    data.insert("exit".to_string(), Rc::new(Expression::Func(builtin_exit)));
     data.insert(
         "ns-create".to_string(),
         Rc::new(Expression::make_function(
@@ -1827,6 +4789,27 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
             "Returns a vector of all namespaces.",
         )),
     );
+    data.insert(
+        "ns-symbols".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_ns_symbols,
+            "Returns a sorted vector of the symbols defined in a namespace.",
+        )),
+    );
+    data.insert(
+        "scope-symbols".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_scope_symbols,
+            "Returns a vector of #(name symbols) for each visible scope, innermost first (name is nil for lexical scopes).",
+        )),
+    );
+    data.insert(
+        "dynamic-symbols".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_dynamic_symbols,
+            "Returns a sorted vector of the symbols with dynamic bindings.",
+        )),
+    );
     data.insert(
         "error-stack-on".to_string(),
         Rc::new(Expression::make_function(
@@ -1845,7 +4828,35 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "get-error".to_string(),
         Rc::new(Expression::make_function(
             builtin_get_error,
-            "Evaluate each form (like progn) but on error return #(:error msg) instead of aborting.",
+            "Evaluate each form (like progn) but on error return an error object (hashmap of :type :msg :form :backtrace :data) instead of aborting.",
+        )),
+    );
+    data.insert(
+        "error?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_is_error,
+            "True if form is an error object (from get-error).",
+        )),
+    );
+    data.insert(
+        "error-type".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_error_type,
+            "The type keyword of an error object, from err or the kind of system error (:error if none).",
+        )),
+    );
+    data.insert(
+        "error-msg".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_error_msg,
+            "The message of an error object.",
+        )),
+    );
+    data.insert(
+        "error-data".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_error_data,
+            "The data given to err with an error object (nil if none).",
         )),
     );
 
@@ -1882,3 +4893,157 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         Rc::new(Expression::Func(ensure_tonicity_all!(|a, b| a <= b))),
     );
 }
+
+pub fn add_shell_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "export".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_export,
+            "Export a key and value to the shell environment, a list or vector is joined with separator (default the export-separator option).",
+        )),
+    );
+    data.insert(
+        "export-path".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_export_path,
+            "Export a list or vector as a : separated (PATH style) var or get a var as a vector of it's entries.",
+        )),
+    );
+    data.insert(
+        "unexport".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_unexport,
+            "Remove a var from the current shell environment.",
+        )),
+    );
+    data.insert(
+        "shell-quote".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_shell_quote,
+            "Quote each argument (or the items of a list/vector) for a POSIX shell and join them with spaces.",
+        )),
+    );
+    data.insert("jobs".to_string(), Rc::new(Expression::Func(builtin_jobs)));
+    data.insert(
+        "jobs-list".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_jobs_list,
+            "Vector of the jobs as hashmaps with :id (for fg and bg), :pids, :names, :status (:running or :stopped) and :start (seconds since the epoch).",
+        )),
+    );
+    data.insert(
+        "job-status".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_job_status,
+            "Status of the job with id (:running or :stopped), nil if there is no such job (it finished).",
+        )),
+    );
+    data.insert("bg".to_string(), Rc::new(Expression::Func(builtin_bg)));
+    data.insert("fg".to_string(), Rc::new(Expression::Func(builtin_fg)));
+    data.insert(
+        "command".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_command,
+            "Only execute system commands not forms within this form.",
+        )),
+    );
+    data.insert(
+        "run-bg".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_run_bg,
+            "Any system commands started within form will be in the background.",
+        )),
+    );
+    data.insert(
+        "with-new-pgroup".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_with_new_pgroup,
+            "System commands started within forms share a new process group (led by the first one).",
+        )),
+    );
+    data.insert(
+        "setsid".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_setsid,
+            "System commands started within forms each run in a new session without a controlling terminal.",
+        )),
+    );
+    data.insert(
+        "subshell".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_subshell,
+            "Evaluate forms in a forked copy of the shell (cwd, environment and definitions are its own) and return its output, the last status is its status.",
+        )),
+    );
+    data.insert(
+        "getpgid".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_getpgid,
+            "Return the process group of a pid or process (the shell's if none).",
+        )),
+    );
+    data.insert(
+        "tcgetpgrp".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_tcgetpgrp,
+            "Return the foreground process group of the terminal.",
+        )),
+    );
+    data.insert(
+        "tcsetpgrp".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_tcsetpgrp,
+            "Make the process group of a pid or process the terminal's foreground group (the shell's if none).",
+        )),
+    );
+    data.insert(
+        "run-limited".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_run_limited,
+            "System commands started within forms get the limits from options (hashmap or key value list of :nice :mem :cpu :nofile :cgroup).",
+        )),
+    );
+    data.insert(
+        "with-env".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_with_env,
+            "Eval forms with environment variables from vars (hashmap or name value list, nil unsets one) set only for the system commands they start.",
+        )),
+    );
+    data.insert(
+        "sh".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_sh,
+            "Run a line with /bin/sh -c (the !cmd prefix at the prompt), with other args it is the sh command.",
+        )),
+    );
+    data.insert(
+        "run-with-timeout".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_run_with_timeout,
+            "Eval forms but kill (process group, SIGTERM then SIGKILL) any commands still running after seconds, returns :timeout if it timed out.",
+        )),
+    );
+    data.insert(
+        "watch".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_watch,
+            "Clear the screen and show the output (then stderr) of forms every seconds until ctrl-c, :diff highlights changed lines and :count n stops after n runs.",
+        )),
+    );
+    data.insert(
+        "form".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_form,
+            "Do not execute system commands within this form.",
+        )),
+    );
+    data.insert("exit".to_string(), Rc::new(Expression::Func(builtin_exit)));
+    data.insert(
+        "on-exit".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_on_exit,
+            "Call function (no parameters) when the shell or script ends (EOF, exit or the terminal hanging up), the last one added is called first.",
+        )),
+    );
+}
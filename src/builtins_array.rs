@@ -0,0 +1,626 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::builtins_seq::{eval_seq, sequence};
+use crate::builtins_util::*;
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// Typed numeric arrays (farray of f64 and iarray of i64).  The numbers are
+// kept in a plain Vec so sum, dot, etc. are simple loops with no Atoms.
+
+fn eval_args(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Vec<Expression>> {
+    let mut list = Vec::new();
+    for arg in args {
+        list.push(eval(environment, arg)?);
+    }
+    Ok(list)
+}
+
+fn not_a_number(exp: &Expression, form: &str) -> io::Error {
+    let msg = format!("{}: {} is not a number", form, exp);
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn not_an_int(exp: &Expression, form: &str) -> io::Error {
+    let msg = format!("{}: {} is not an integer", form, exp);
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+// Numbers and strings that parse as numbers (i.e. fields of command output).
+fn to_f64(exp: &Expression, form: &str) -> io::Result<f64> {
+    match exp {
+        Expression::Atom(Atom::Float(f)) => Ok(*f),
+        Expression::Atom(Atom::Int(i)) => Ok(*i as f64),
+        Expression::Atom(Atom::String(s)) => s.trim().parse().map_err(|_| not_a_number(exp, form)),
+        Expression::Atom(Atom::StringBuf(s)) => s
+            .borrow()
+            .trim()
+            .parse()
+            .map_err(|_| not_a_number(exp, form)),
+        _ => Err(not_a_number(exp, form)),
+    }
+}
+
+// Like to_f64 but floats have to be whole numbers.
+fn to_i64(exp: &Expression, form: &str) -> io::Result<i64> {
+    match exp {
+        Expression::Atom(Atom::Int(i)) => Ok(*i),
+        Expression::Atom(Atom::Float(f)) if f.fract() == 0.0 => Ok(*f as i64),
+        Expression::Atom(Atom::String(s)) => s.trim().parse().map_err(|_| not_an_int(exp, form)),
+        Expression::Atom(Atom::StringBuf(s)) => {
+            s.borrow().trim().parse().map_err(|_| not_an_int(exp, form))
+        }
+        _ => Err(not_an_int(exp, form)),
+    }
+}
+
+fn make_array(items: &[Expression], float: bool, form: &str) -> io::Result<NumArray> {
+    if float {
+        let mut v = Vec::with_capacity(items.len());
+        for item in items {
+            v.push(to_f64(item, form)?);
+        }
+        Ok(NumArray::Float(v))
+    } else {
+        let mut v = Vec::with_capacity(items.len());
+        for item in items {
+            v.push(to_i64(item, form)?);
+        }
+        Ok(NumArray::Int(v))
+    }
+}
+
+fn array_expression(arr: NumArray) -> Expression {
+    Expression::Array(Rc::new(RefCell::new(arr)))
+}
+
+fn array_arg(exp: &Expression, form: &str) -> io::Result<Rc<RefCell<NumArray>>> {
+    match exp {
+        Expression::Array(arr) => Ok(arr.clone()),
+        _ => {
+            let msg = format!("{} needs an array, got {}", form, exp.display_type());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn index_arg(exp: &Expression, len: usize, form: &str) -> io::Result<usize> {
+    match exp {
+        Expression::Atom(Atom::Int(i)) if *i >= 0 && (*i as usize) < len => Ok(*i as usize),
+        Expression::Atom(Atom::Int(_)) => {
+            let msg = format!("{} index out of range", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+        _ => {
+            let msg = format!("{} index must be an int", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn one_array(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<Rc<RefCell<NumArray>>> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 1 {
+        let msg = format!("{} takes one array", form);
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    array_arg(&args[0], form)
+}
+
+fn overflow(form: &str) -> io::Error {
+    let msg = format!("{}: integer overflow", form);
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn builtin_farray(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let items = eval_args(environment, args)?;
+    Ok(array_expression(make_array(&items, true, "farray")?))
+}
+
+fn builtin_iarray(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let items = eval_args(environment, args)?;
+    Ok(array_expression(make_array(&items, false, "iarray")?))
+}
+
+fn make_filled(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    float: bool,
+    form: &str,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    let len = match args.first() {
+        Some(Expression::Atom(Atom::Int(i))) if *i >= 0 && args.len() <= 2 => *i as usize,
+        _ => {
+            let msg = format!("{} takes a length and an optional default", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let arr = match (float, args.get(1)) {
+        (true, Some(default)) => NumArray::Float(vec![to_f64(default, form)?; len]),
+        (true, None) => NumArray::Float(vec![0.0; len]),
+        (false, Some(default)) => NumArray::Int(vec![to_i64(default, form)?; len]),
+        (false, None) => NumArray::Int(vec![0; len]),
+    };
+    Ok(array_expression(arr))
+}
+
+fn builtin_make_farray(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    make_filled(environment, args, true, "make-farray")
+}
+
+fn builtin_make_iarray(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    make_filled(environment, args, false, "make-iarray")
+}
+
+// An array from a sequence, a string is split on whitespace and blank items
+// (empty lines etc) are skipped.
+fn seq_to_array(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    float: bool,
+    form: &str,
+) -> io::Result<Expression> {
+    let seq = match (args.next(), args.next()) {
+        (Some(seq), None) => eval_seq(environment, seq)?,
+        _ => {
+            let msg = format!("{} takes one sequence", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let mut items = Vec::new();
+    match &seq {
+        Expression::Atom(Atom::String(_)) | Expression::Atom(Atom::StringBuf(_)) => {
+            for field in seq.as_string(environment)?.split_whitespace() {
+                items.push(Expression::Atom(Atom::String(field.into())));
+            }
+        }
+        _ => {
+            let mut seq = sequence(environment, &seq, form)?;
+            while let Some(item) = seq.next_item()? {
+                match &item {
+                    Expression::Atom(Atom::String(s)) if s.trim().is_empty() => {}
+                    _ => items.push(item),
+                }
+            }
+        }
+    }
+    Ok(array_expression(make_array(&items, float, form)?))
+}
+
+fn builtin_to_farray(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    seq_to_array(environment, args, true, "->farray")
+}
+
+fn builtin_to_iarray(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    seq_to_array(environment, args, false, "->iarray")
+}
+
+fn builtin_array_to_vec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let arr = one_array(environment, args, "array->vec")?;
+    let items = arr.borrow().to_vec();
+    Ok(Expression::with_list(items))
+}
+
+fn builtin_array_nth(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-nth takes an index and an array",
+        ));
+    }
+    let arr = array_arg(&args[1], "array-nth")?;
+    let arr = arr.borrow();
+    let idx = index_arg(&args[0], arr.len(), "array-nth")?;
+    Ok(arr.get(idx).unwrap())
+}
+
+fn builtin_array_set(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-set! takes an index, a number and an array",
+        ));
+    }
+    let arr = array_arg(&args[2], "array-set!")?;
+    let idx = index_arg(&args[0], arr.borrow().len(), "array-set!")?;
+    match &mut *arr.borrow_mut() {
+        NumArray::Float(v) => v[idx] = to_f64(&args[1], "array-set!")?,
+        NumArray::Int(v) => v[idx] = to_i64(&args[1], "array-set!")?,
+    }
+    Ok(Expression::Array(arr))
+}
+
+fn builtin_array_push(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-push! takes an array and numbers to push",
+        ));
+    }
+    let arr = array_arg(&args[0], "array-push!")?;
+    match &mut *arr.borrow_mut() {
+        NumArray::Float(v) => {
+            for item in &args[1..] {
+                v.push(to_f64(item, "array-push!")?);
+            }
+        }
+        NumArray::Int(v) => {
+            for item in &args[1..] {
+                v.push(to_i64(item, "array-push!")?);
+            }
+        }
+    }
+    Ok(Expression::Array(arr))
+}
+
+fn builtin_array_slice(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 && args.len() != 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-slice takes an array, start and optional end",
+        ));
+    }
+    let arr = array_arg(&args[0], "array-slice")?;
+    let arr = arr.borrow();
+    let bound = |exp: &Expression| match exp {
+        Expression::Atom(Atom::Int(i)) if *i >= 0 && (*i as usize) <= arr.len() => Ok(*i as usize),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-slice start and end must be ints in the array",
+        )),
+    };
+    let start = bound(&args[1])?;
+    let end = match args.get(2) {
+        Some(end) => bound(end)?,
+        None => arr.len(),
+    };
+    if start > end {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-slice start is after end",
+        ));
+    }
+    Ok(array_expression(match &*arr {
+        NumArray::Float(v) => NumArray::Float(v[start..end].to_vec()),
+        NumArray::Int(v) => NumArray::Int(v[start..end].to_vec()),
+    }))
+}
+
+fn builtin_array_sum(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let arr = one_array(environment, args, "array-sum")?;
+    let arr = arr.borrow();
+    match &*arr {
+        NumArray::Float(v) => Ok(Expression::Atom(Atom::Float(v.iter().sum()))),
+        NumArray::Int(v) => {
+            let mut sum: i64 = 0;
+            for i in v {
+                sum = sum.checked_add(*i).ok_or_else(|| overflow("array-sum"))?;
+            }
+            Ok(Expression::Atom(Atom::Int(sum)))
+        }
+    }
+}
+
+fn builtin_array_mean(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let arr = one_array(environment, args, "array-mean")?;
+    let arr = arr.borrow();
+    if arr.is_empty() {
+        return Ok(Expression::Atom(Atom::Nil));
+    }
+    let sum: f64 = match &*arr {
+        NumArray::Float(v) => v.iter().sum(),
+        NumArray::Int(v) => v.iter().map(|i| *i as f64).sum(),
+    };
+    Ok(Expression::Atom(Atom::Float(sum / arr.len() as f64)))
+}
+
+fn min_max(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    max: bool,
+    form: &str,
+) -> io::Result<Expression> {
+    let arr = one_array(environment, args, form)?;
+    let arr = arr.borrow();
+    let res = match &*arr {
+        NumArray::Float(v) => v
+            .iter()
+            .cloned()
+            .fold(None, |acc: Option<f64>, f| match acc {
+                Some(a) if (max && a >= f) || (!max && a <= f) => Some(a),
+                _ => Some(f),
+            })
+            .map(|f| Expression::Atom(Atom::Float(f))),
+        NumArray::Int(v) => {
+            let res = if max { v.iter().max() } else { v.iter().min() };
+            res.map(|i| Expression::Atom(Atom::Int(*i)))
+        }
+    };
+    Ok(res.unwrap_or(Expression::Atom(Atom::Nil)))
+}
+
+fn builtin_array_min(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    min_max(environment, args, false, "array-min")
+}
+
+fn builtin_array_max(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    min_max(environment, args, true, "array-max")
+}
+
+fn builtin_array_dot(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-dot takes two arrays",
+        ));
+    }
+    let a = array_arg(&args[0], "array-dot")?;
+    let b = array_arg(&args[1], "array-dot")?;
+    let (a, b) = (a.borrow(), b.borrow());
+    if a.len() != b.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-dot arrays must be the same length",
+        ));
+    }
+    Ok(match (&*a, &*b) {
+        (NumArray::Int(a), NumArray::Int(b)) => {
+            let mut dot: i64 = 0;
+            for (x, y) in a.iter().zip(b.iter()) {
+                dot = x
+                    .checked_mul(*y)
+                    .and_then(|p| dot.checked_add(p))
+                    .ok_or_else(|| overflow("array-dot"))?;
+            }
+            Expression::Atom(Atom::Int(dot))
+        }
+        (NumArray::Float(a), NumArray::Float(b)) => Expression::Atom(Atom::Float(
+            a.iter().zip(b.iter()).map(|(x, y)| x * y).sum(),
+        )),
+        (NumArray::Float(f), NumArray::Int(i)) | (NumArray::Int(i), NumArray::Float(f)) => {
+            Expression::Atom(Atom::Float(
+                f.iter().zip(i.iter()).map(|(x, y)| x * *y as f64).sum(),
+            ))
+        }
+    })
+}
+
+fn builtin_array_map(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array-map! takes a function and an array",
+        ));
+    }
+    let arr = array_arg(&args[1], "array-map!")?;
+    let len = arr.borrow().len();
+    // Do not hold the borrow while calling, the function may look at the array.
+    for idx in 0..len {
+        check_interrupted(environment)?;
+        let item = match arr.borrow().get(idx) {
+            Some(item) => item,
+            None => break,
+        };
+        let res = call_with(environment, &args[0], vec![item])?;
+        match &mut *arr.borrow_mut() {
+            NumArray::Float(v) if idx < v.len() => v[idx] = to_f64(&res, "array-map!")?,
+            NumArray::Int(v) if idx < v.len() => v[idx] = to_i64(&res, "array-map!")?,
+            _ => break,
+        }
+    }
+    Ok(Expression::Array(arr))
+}
+
+fn builtin_is_array(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    match args.as_slice() {
+        [Expression::Array(_)] => Ok(Expression::Atom(Atom::True)),
+        [_] => Ok(Expression::Atom(Atom::Nil)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "array? takes one form",
+        )),
+    }
+}
+
+pub fn add_array_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "farray".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_farray,
+            "Produces a float array from the numbers (or strings that parse as numbers).",
+        )),
+    );
+    data.insert(
+        "iarray".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_iarray,
+            "Produces an int array from the ints (or strings that parse as ints).",
+        )),
+    );
+    data.insert(
+        "make-farray".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_make_farray,
+            "Make a float array of length with all values set to default (0.0 if not given).",
+        )),
+    );
+    data.insert(
+        "make-iarray".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_make_iarray,
+            "Make an int array of length with all values set to default (0 if not given).",
+        )),
+    );
+    data.insert(
+        "->farray".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_to_farray,
+            "Produces a float array from a seq (i.e. the lines of a process) or the whitespace separated fields of a string.",
+        )),
+    );
+    data.insert(
+        "->iarray".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_to_iarray,
+            "Produces an int array from a seq (i.e. the lines of a process) or the whitespace separated fields of a string.",
+        )),
+    );
+    data.insert(
+        "array->vec".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_to_vec,
+            "Returns a vector with the numbers in the array.",
+        )),
+    );
+    data.insert(
+        "array-nth".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_nth,
+            "Produces the number at the index (0 based), error if out of bounds.",
+        )),
+    );
+    data.insert(
+        "array-set!".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_set,
+            "Sets the number at index, (array-set! idx num array).",
+        )),
+    );
+    data.insert(
+        "array-push!".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_push,
+            "Pushes the numbers onto the end of the array.",
+        )),
+    );
+    data.insert(
+        "array-slice".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_slice,
+            "Returns a new array with the numbers from start (inclusive) to end (exclusive, defaults to the length).",
+        )),
+    );
+    data.insert(
+        "array-sum".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_sum,
+            "Sum of the numbers in the array (an int for an int array).",
+        )),
+    );
+    data.insert(
+        "array-mean".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_mean,
+            "Mean of the numbers in the array, nil if empty.",
+        )),
+    );
+    data.insert(
+        "array-min".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_min,
+            "Smallest number in the array, nil if empty.",
+        )),
+    );
+    data.insert(
+        "array-max".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_max,
+            "Largest number in the array, nil if empty.",
+        )),
+    );
+    data.insert(
+        "array-dot".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_dot,
+            "Dot product of two arrays of the same length.",
+        )),
+    );
+    data.insert(
+        "array-map!".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_array_map,
+            "Replaces each number in the array with the result of calling the function on it.",
+        )),
+    );
+    data.insert(
+        "array?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_is_array,
+            "True if obj is a float or int array.",
+        )),
+    );
+}
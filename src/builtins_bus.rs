@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::hash::BuildHasher;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use nix::unistd;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// Topic that receives every message.
+const ALL_TOPICS: &str = "*";
+
+// Largest message (topic, a \0 and the payload) a receiver reads whole.
+const MAX_MESSAGE: usize = 65536;
+
+// This instance's socket on the bus, the socket file is removed on drop.
+#[derive(Debug)]
+pub struct BusSocket {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Drop for BusSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// A private (per user) directory for sockets, created if needed.  In /tmp
+// another user could make it first so it must be a real directory (not a
+// link) owned by us that only we can use.
+pub fn runtime_dir(name: &str) -> io::Result<PathBuf> {
+    let dir = match env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime) if !runtime.is_empty() => {
+            PathBuf::from(runtime).join(format!("sl-sh-{}", name))
+        }
+        _ => PathBuf::from(format!("/tmp/sl-sh-{}-{}", name, unistd::getuid())),
+    };
+    if let Err(err) = DirBuilder::new().recursive(true).mode(0o700).create(&dir) {
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(err);
+        }
+    }
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.file_type().is_dir()
+        || meta.uid() != unistd::getuid().as_raw()
+        || meta.mode() & 0o777 != 0o700
+    {
+        let msg = format!(
+            "{} is not a directory owned by this user with mode 0700",
+            dir.display()
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    Ok(dir)
+}
+
+// Each subscribed slsh has a datagram socket in this directory, sending is
+// delivering to all of them.
+fn bus_dir() -> io::Result<PathBuf> {
+    runtime_dir("bus")
+}
+
+fn bus_socket(environment: &mut Environment) -> io::Result<Rc<BusSocket>> {
+    if let Some(socket) = &environment.bus_socket {
+        return Ok(socket.clone());
+    }
+    let path = bus_dir()?.join(format!("{}.sock", unistd::getpid()));
+    // Left over from a dead process with our pid.
+    let _ = fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path)?;
+    socket.set_nonblocking(true)?;
+    let socket = Rc::new(BusSocket { socket, path });
+    environment.bus_socket = Some(socket.clone());
+    Ok(socket)
+}
+
+fn quoted(exp: Expression) -> Expression {
+    Expression::cons_from_vec(&mut vec![
+        Expression::Atom(Atom::Symbol("quote".into())),
+        exp,
+    ])
+}
+
+// Run the handlers for any messages that have arrived, called between
+// interactive evaluations (or with bus-poll).  Returns the number of messages.
+pub fn bus_poll(environment: &mut Environment) -> io::Result<usize> {
+    let socket = match &environment.bus_socket {
+        Some(socket) => socket.clone(),
+        None => return Ok(0),
+    };
+    let mut messages = Vec::new();
+    let mut buf = vec![0; MAX_MESSAGE];
+    loop {
+        match socket.socket.recv(&mut buf) {
+            Ok(len) => {
+                let msg = String::from_utf8_lossy(&buf[..len]).to_string();
+                let mut parts = msg.splitn(2, '\0');
+                let topic = parts.next().unwrap_or("").to_string();
+                let payload = parts.next().unwrap_or("").to_string();
+                messages.push((topic, payload));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+    for (topic, payload) in &messages {
+        let mut handlers = Vec::new();
+        for key in &[&topic[..], ALL_TOPICS] {
+            if let Some(h) = environment.bus_handlers.get(*key) {
+                handlers.extend(h.iter().cloned());
+            }
+        }
+        for handler in handlers {
+            let args = vec![
+                quoted(Expression::Atom(Atom::String(topic.clone().into()))),
+                quoted(Expression::Atom(Atom::String(payload.clone().into()))),
+            ];
+            if let Err(err) = fn_call(environment, &handler, Box::new(args.iter())) {
+                if is_exit(&err) {
+                    return Err(err);
+                }
+                eprintln!("Error in bus handler for {}: {}", topic, err);
+            }
+        }
+    }
+    Ok(messages.len())
+}
+
+fn topic_arg(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+) -> io::Result<String> {
+    if let Some(arg) = arg {
+        let topic = eval(environment, arg)?.as_string(environment)?;
+        if !topic.is_empty() && !topic.contains('\0') {
+            return Ok(topic);
+        }
+    }
+    let msg = format!("{} requires a topic (non-empty string)", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_bus_send(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let topic = topic_arg(environment, args.next(), "bus-send")?;
+    if topic == ALL_TOPICS {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "bus-send: can not send to *",
+        ));
+    }
+    let payload = if let Some(arg) = args.next() {
+        eval(environment, arg)?.as_string(environment)?
+    } else {
+        String::new()
+    };
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "bus-send takes a topic and optional message",
+        ));
+    }
+    let own_path = environment.bus_socket.as_ref().map(|s| s.path.clone());
+    let msg = format!("{}\0{}", topic, payload);
+    if msg.len() > MAX_MESSAGE {
+        let msg = format!(
+            "bus-send: topic and message are {} bytes, the limit is {}",
+            msg.len() - 1,
+            MAX_MESSAGE - 1
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    let sender = UnixDatagram::unbound()?;
+    // A full queue drops the message for that receiver instead of waiting.
+    sender.set_nonblocking(true)?;
+    let mut sent = 0;
+    for entry in fs::read_dir(bus_dir()?)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |e| e != "sock") || Some(&path) == own_path.as_ref() {
+            continue;
+        }
+        match sender.send_to(msg.as_bytes(), &path) {
+            Ok(_) => sent += 1,
+            Err(ref err)
+                if err.kind() == io::ErrorKind::ConnectionRefused
+                    || err.kind() == io::ErrorKind::NotFound =>
+            {
+                // Nobody home (the slsh died without cleaning up).
+                let _ = fs::remove_file(&path);
+            }
+            // The receiver's queue is full, drop the message for it.
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Expression::Atom(Atom::Int(sent)))
+}
+
+fn builtin_bus_subscribe(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let topic = topic_arg(environment, args.next(), "bus-subscribe")?;
+    if let Some(handler) = args.next() {
+        if args.next().is_none() {
+            let handler = eval(environment, handler)?;
+            bus_socket(environment)?;
+            environment
+                .bus_handlers
+                .entry(topic)
+                .or_insert_with(Vec::new)
+                .push(handler.clone());
+            return Ok(handler);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "bus-subscribe takes a topic and a handler",
+    ))
+}
+
+fn builtin_bus_unsubscribe(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let topic = topic_arg(environment, args.next(), "bus-unsubscribe")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "bus-unsubscribe takes a topic",
+        ));
+    }
+    let removed = environment.bus_handlers.remove(&topic).is_some();
+    if environment.bus_handlers.is_empty() {
+        environment.bus_socket = None;
+    }
+    Ok(if removed {
+        Expression::Atom(Atom::True)
+    } else {
+        Expression::Atom(Atom::Nil)
+    })
+}
+
+fn builtin_bus_poll(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "bus-poll takes no forms",
+        ));
+    }
+    Ok(Expression::Atom(Atom::Int(bus_poll(environment)? as i64)))
+}
+
+pub fn add_bus_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "bus-send".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_bus_send,
+            "Send a message (string) on topic to the other subscribed slsh sessions of this user, returns the number of sessions sent to.  The topic and message together can be up to 65535 bytes.",
+        )),
+    );
+    data.insert(
+        "bus-subscribe".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_bus_subscribe,
+            "Call handler with (topic message) for messages on topic (* for all) between interactive evaluations.",
+        )),
+    );
+    data.insert(
+        "bus-unsubscribe".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_bus_unsubscribe,
+            "Remove the handlers for topic.",
+        )),
+    );
+    data.insert(
+        "bus-poll".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_bus_poll,
+            "Run the handlers for received bus messages now (for scripts), returns the number of messages.",
+        )),
+    );
+}
@@ -1,16 +1,25 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
 use std::hash::BuildHasher;
-use std::io::{self, Write};
-use std::path::Path;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::SystemTime;
 
-use glob::glob;
+use glob::{glob, Pattern};
+use nix::sys::signal::{self, Signal};
+use nix::sys::statvfs::statvfs;
+use nix::unistd::{self, Pid};
 
+use crate::builtins_options::*;
 use crate::builtins_util::*;
 use crate::environment::*;
 use crate::eval::*;
 use crate::process::*;
+use crate::suggest::*;
 use crate::types::*;
 
 fn cd_expand_all_dots(cd: String) -> String {
@@ -37,6 +46,42 @@ fn cd_expand_all_dots(cd: String) -> String {
     }
 }
 
+// Report a failed cd, the cd-correct option is :suggest (the default) to add the
+// closest directory to the error, :ask to offer to use it, :auto to use it or
+// :off.  Returns the directory to change to instead.
+fn cd_correction(environment: &Environment, dir: &str, err: &io::Error) -> Option<String> {
+    let policy = get_option_keyword(environment, "cd-correct");
+    let policy = match &policy[..] {
+        ":ask" if !environment.is_tty => ":suggest",
+        policy => policy,
+    };
+    let correction = if err.kind() == io::ErrorKind::NotFound && policy != ":off" {
+        correct_dir(dir)
+    } else {
+        None
+    };
+    match (correction, policy) {
+        (Some(correction), ":auto") => {
+            eprintln!("cd: {} not found, using {}", dir, correction);
+            return Some(correction);
+        }
+        (Some(correction), ":ask") => {
+            if confirm_correction(dir, &correction) {
+                return Some(correction);
+            }
+            eprintln!("Error changing to {}, {}", dir, err);
+        }
+        (Some(correction), _) => {
+            eprintln!(
+                "Error changing to {}, {}. Did you mean {}?",
+                dir, err, correction
+            )
+        }
+        (None, _) => eprintln!("Error changing to {}, {}", dir, err),
+    }
+    None
+}
+
 fn builtin_cd(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
@@ -71,7 +116,12 @@ fn builtin_cd(
     let root = Path::new(&new_dir);
     env::set_var("OLDPWD", env::current_dir()?);
     if let Err(e) = env::set_current_dir(&root) {
-        eprintln!("Error changing to {}, {}", root.display(), e);
+        if let Some(dir) = cd_correction(environment, &new_dir, &e) {
+            if env::set_current_dir(&dir).is_ok() {
+                env::set_var("PWD", env::current_dir()?);
+                return Ok(Expression::Atom(Atom::True));
+            }
+        }
         Ok(Expression::Atom(Atom::Nil))
     } else {
         env::set_var("PWD", env::current_dir()?);
@@ -146,7 +196,9 @@ fn pipe_write_file(environment: &Environment, writer: &mut dyn Write) -> io::Res
         Some(Expression::Atom(_atom)) => {
             do_write = true;
         }
-        Some(Expression::Process(ProcessState::Running(_pid))) => {
+        // Running or reaped while the pipe was started, either way it's
+        // output is still there.
+        Some(Expression::Process(proc)) if proc.has_stdout() => {
             do_write = true;
         }
         Some(Expression::File(FileState::Stdin)) => {
@@ -170,7 +222,17 @@ fn pipe_write_file(environment: &Environment, writer: &mut dyn Write) -> io::Res
             .unwrap()
             .writef(environment, writer)?;
     }
-    Ok(())
+    writer.flush()
+}
+
+// Process output to a file is spliced straight in if it can be.
+fn pipe_write_to_file(environment: &Environment, file: &mut BufWriter<fs::File>) -> io::Result<()> {
+    if let Some(Expression::Process(proc)) = &environment.data_in {
+        if splice_process_to_file(environment, proc, file)? {
+            return Ok(());
+        }
+    }
+    pipe_write_file(environment, file)
 }
 
 fn builtin_pipe(
@@ -188,6 +250,7 @@ fn builtin_pipe(
     let mut out = Expression::Atom(Atom::Nil);
     environment.state.stdout_status = Some(IOState::Pipe);
     let mut error: Option<io::Result<Expression>> = None;
+    let mut stages: Vec<ProcessHandle> = Vec::new();
     let mut i = 1; // Meant 1 here.
     let mut pipe = args.next();
     while let Some(p) = pipe {
@@ -202,15 +265,11 @@ fn builtin_pipe(
             error = Some(Err(err));
             break;
         }
-        if let Ok(Expression::Process(ProcessState::Running(pid))) = res {
+        if let Ok(Expression::Process(proc)) = &res {
             if environment.state.pipe_pgid.is_none() {
-                environment.state.pipe_pgid = Some(pid);
-            }
-        }
-        if let Ok(Expression::Process(ProcessState::Over(pid, _exit_status))) = res {
-            if environment.state.pipe_pgid.is_none() {
-                environment.state.pipe_pgid = Some(pid);
+                environment.state.pipe_pgid = Some(proc.pid());
             }
+            stages.push(proc.clone());
         }
         if let Ok(Expression::File(FileState::Stdout)) = &res {
             let stdout = io::stdout();
@@ -229,7 +288,7 @@ fn builtin_pipe(
             }
         }
         if let Ok(Expression::File(FileState::Write(f))) = &res {
-            if let Err(err) = pipe_write_file(environment, &mut *f.borrow_mut()) {
+            if let Err(err) = pipe_write_to_file(environment, &mut *f.borrow_mut()) {
                 error = Some(Err(err));
                 break;
             }
@@ -254,10 +313,40 @@ fn builtin_pipe(
     if let Some(error) = error {
         error
     } else {
+        pipeline_status(environment, &stages);
         Ok(out)
     }
 }
 
+// Once the last command of a pipeline is done wait for the others (like other
+// shells) and make the last status the pipeline's, it's status object has each
+// command's status under :pipeline.
+fn pipeline_status(environment: &Environment, stages: &[ProcessHandle]) {
+    let last = match stages.last().and_then(|proc| proc.exit()) {
+        Some(last) if stages.len() > 1 => last,
+        _ => return,
+    };
+    for proc in stages {
+        if proc.status().is_none() {
+            wait_pid(environment, proc.pid(), None);
+        }
+    }
+    if environment.save_exit_status {
+        let pipeline = stages
+            .iter()
+            .map(|proc| match proc.exit() {
+                Some(exit) => status_object(&exit, None),
+                None => Expression::Atom(Atom::Nil),
+            })
+            .collect();
+        set_last_status(
+            environment,
+            last.status(),
+            status_object(&last, Some(pipeline)),
+        );
+    }
+}
+
 fn builtin_wait(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
@@ -266,17 +355,17 @@ fn builtin_wait(
         if args.next().is_none() {
             let arg0 = eval(environment, arg0)?;
             return match arg0 {
-                Expression::Process(ProcessState::Running(pid)) => {
-                    match wait_pid(environment, pid, None) {
+                Expression::Process(proc) => match proc.state() {
+                    ProcessState::Running(pid) => match wait_pid(environment, pid, None) {
                         Some(exit_status) => {
                             Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
                         }
                         None => Ok(Expression::Atom(Atom::Nil)),
+                    },
+                    ProcessState::Over(_pid, exit_status) => {
+                        Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
                     }
-                }
-                Expression::Process(ProcessState::Over(_pid, exit_status)) => {
-                    Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
-                }
+                },
                 Expression::Atom(Atom::Int(pid)) => match wait_pid(environment, pid as u32, None) {
                     Some(exit_status) => Ok(Expression::Atom(Atom::Int(i64::from(exit_status)))),
                     None => Ok(Expression::Atom(Atom::Nil)),
@@ -302,12 +391,7 @@ fn builtin_pid(
         if args.next().is_none() {
             let arg0 = eval(environment, arg0)?;
             return match arg0 {
-                Expression::Process(ProcessState::Running(pid)) => {
-                    Ok(Expression::Atom(Atom::Int(i64::from(pid))))
-                }
-                Expression::Process(ProcessState::Over(pid, _exit_status)) => {
-                    Ok(Expression::Atom(Atom::Int(i64::from(pid))))
-                }
+                Expression::Process(proc) => Ok(Expression::Atom(Atom::Int(i64::from(proc.pid())))),
                 _ => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "pid error: not a process",
@@ -321,6 +405,239 @@ fn builtin_pid(
     ))
 }
 
+// The process a proc-* form is about.
+fn proc_arg(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+) -> io::Result<ProcessHandle> {
+    if let Some(arg) = arg {
+        if let Expression::Process(proc) = eval(environment, arg)? {
+            return Ok(proc);
+        }
+    }
+    let msg = format!("{} requires a process", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_proc_pid(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let proc = proc_arg(environment, args.next(), "proc-pid")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "proc-pid takes one form (a process)",
+        ));
+    }
+    Ok(Expression::Atom(Atom::Int(i64::from(proc.pid()))))
+}
+
+fn builtin_proc_status(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let proc = proc_arg(environment, args.next(), "proc-status")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "proc-status takes one form (a process)",
+        ));
+    }
+    if proc.status().is_none() && environment.procs.borrow().contains_key(&proc.pid()) {
+        // Do not wait on the next SIGCHLD to notice it is done.
+        reap_children(environment);
+    }
+    Ok(match proc.status() {
+        Some(status) => Expression::Atom(Atom::Int(i64::from(status))),
+        None if environment.stopped_procs.borrow().contains(&proc.pid()) => {
+            Expression::Atom(Atom::Symbol(":stopped".into()))
+        }
+        None => Expression::Atom(Atom::Symbol(":running".into())),
+    })
+}
+
+fn builtin_proc_stdin(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let proc = proc_arg(environment, args.next(), "proc-stdin")?;
+    let mut close = true;
+    for arg in args {
+        close = false;
+        let data = eval(environment, arg)?.as_string(environment)?;
+        if !proc.write_stdin(data.as_bytes())? {
+            let msg = format!(
+                "proc-stdin: the stdin of process {} is not piped",
+                proc.pid()
+            );
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    if close {
+        proc.close_stdin();
+    }
+    Ok(Expression::Process(proc))
+}
+
+// A signal from an int or name (TERM, SIGTERM or :term).
+fn signal_arg(environment: &mut Environment, arg: &Expression) -> io::Result<Signal> {
+    let sig = match eval(environment, arg)? {
+        Expression::Atom(Atom::Int(i)) => Signal::from_c_int(i as i32).ok(),
+        Expression::Atom(atom @ Atom::Symbol(_)) | Expression::Atom(atom @ Atom::String(_)) => {
+            let name = atom.as_string().trim_start_matches(':').to_uppercase();
+            if name.starts_with("SIG") {
+                name.parse().ok()
+            } else {
+                format!("SIG{}", name).parse().ok()
+            }
+        }
+        _ => None,
+    };
+    sig.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "proc-kill: signal must be a signal number or name (TERM, SIGTERM or :term)",
+        )
+    })
+}
+
+fn builtin_proc_kill(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let proc = proc_arg(environment, args.next(), "proc-kill")?;
+    let sig = match (args.next(), args.next()) {
+        (None, None) => Signal::SIGTERM,
+        (Some(sig), None) => signal_arg(environment, sig)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "proc-kill takes a process and optional signal",
+            ))
+        }
+    };
+    if proc.status().is_some() {
+        return Ok(Expression::Atom(Atom::Nil));
+    }
+    // The whole group if it has it's own (a pipe for instance).
+    let pid = Pid::from_raw(proc.pid() as i32);
+    let pgid = Pid::from_raw(proc.pgid() as i32);
+    let res = if pgid == unistd::getpgrp() {
+        signal::kill(pid, sig)
+    } else {
+        signal::killpg(pgid, sig)
+    };
+    match res {
+        Ok(()) => Ok(Expression::Atom(Atom::True)),
+        // Exited but not reaped yet.
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => Ok(Expression::Atom(Atom::Nil)),
+        Err(err) => {
+            let msg = format!("proc-kill: {}", err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_proc_exit(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let proc = proc_arg(environment, args.next(), "proc-exit")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "proc-exit takes one form (a process)",
+        ));
+    }
+    if proc.status().is_none() && environment.procs.borrow().contains_key(&proc.pid()) {
+        reap_children(environment);
+    }
+    Ok(match proc.exit() {
+        Some(exit) => status_object(&exit, None),
+        None => Expression::Atom(Atom::Nil),
+    })
+}
+
+fn is_status_object(map: &HashMap<String, Rc<Expression>>) -> bool {
+    match map.get(":status").map(|s| &**s) {
+        Some(Expression::Atom(Atom::Int(_))) => map.contains_key(":rusage"),
+        _ => false,
+    }
+}
+
+// The key of the status object that is the only argument.
+fn status_field(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+    key: &str,
+) -> io::Result<Expression> {
+    if let (Some(arg), None) = (args.next(), args.next()) {
+        if let Expression::HashMap(map) = eval(environment, arg)? {
+            let map = map.borrow();
+            if is_status_object(&map) {
+                return Ok(match map.get(key) {
+                    Some(val) => (**val).clone(),
+                    None => Expression::Atom(Atom::Nil),
+                });
+            }
+        }
+    }
+    let msg = format!(
+        "{} takes a status object (*last-status-info* or from proc-exit)",
+        form
+    );
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_is_status(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let (Some(arg), None) = (args.next(), args.next()) {
+        return Ok(match eval(environment, arg)? {
+            Expression::HashMap(map) if is_status_object(&map.borrow()) => {
+                Expression::Atom(Atom::True)
+            }
+            _ => Expression::Atom(Atom::Nil),
+        });
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "status? takes one form",
+    ))
+}
+
+fn builtin_status_code(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    status_field(environment, args, "status-code", ":code")
+}
+
+fn builtin_status_signal(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    status_field(environment, args, "status-signal", ":signal-name")
+}
+
+fn builtin_status_core_dumped(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    status_field(environment, args, "status-core-dumped?", ":core-dumped")
+}
+
+fn builtin_status_rusage(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    status_field(environment, args, "status-rusage", ":rusage")
+}
+
 fn builtin_glob(
     environment: &mut Environment,
     args: &mut dyn Iterator<Item = &Expression>,
@@ -329,7 +646,7 @@ fn builtin_glob(
     for pat in args {
         let pat = match eval(environment, pat)? {
             Expression::Atom(Atom::String(s)) => s,
-            Expression::Atom(Atom::StringBuf(s)) => s.borrow().to_string(),
+            Expression::Atom(Atom::StringBuf(s)) => s.borrow().to_string().into(),
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
@@ -339,7 +656,7 @@ fn builtin_glob(
         };
         let pat = match expand_tilde(&pat) {
             Some(p) => p,
-            None => pat,
+            None => pat.to_string(),
         };
         match glob(&pat) {
             Ok(paths) => {
@@ -347,7 +664,7 @@ fn builtin_glob(
                     match p {
                         Ok(p) => {
                             if let Some(p) = p.to_str() {
-                                files.push(Expression::Atom(Atom::String(p.to_string())));
+                                files.push(Expression::Atom(Atom::String(p.into())));
                             }
                         }
                         Err(err) => {
@@ -366,6 +683,335 @@ fn builtin_glob(
     Ok(Expression::with_list(files))
 }
 
+// The tests fs-find applies to each path.
+#[derive(Default)]
+struct FindOptions {
+    name: Option<Pattern>,
+    path: Option<Pattern>,
+    prune: Vec<Pattern>,
+    file_type: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer: Option<u64>,
+    older: Option<u64>,
+    min_depth: usize,
+    max_depth: Option<usize>,
+    filter: Option<Expression>,
+    each: Option<Expression>,
+}
+
+fn find_pattern(opt: &str, val: &Expression, environment: &Environment) -> io::Result<Pattern> {
+    let pat = val.as_string(environment)?;
+    Pattern::new(&pat).map_err(|err| {
+        let msg = format!("fs-find: {} glob error on {}, {}", opt, pat, err);
+        io::Error::new(io::ErrorKind::Other, msg)
+    })
+}
+
+fn find_number(opt: &str, val: &Expression, environment: &Environment) -> io::Result<u64> {
+    let num = match val {
+        Expression::Atom(Atom::Int(i)) if *i >= 0 => Some(*i as u64),
+        _ => parse_size(&val.as_string(environment)?),
+    };
+    num.ok_or_else(|| {
+        let msg = format!(
+            "fs-find: {} requires a number (sizes can end in K, M, G or T)",
+            opt
+        );
+        io::Error::new(io::ErrorKind::Other, msg)
+    })
+}
+
+fn find_options(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<FindOptions> {
+    let mut opts = FindOptions::default();
+    while let Some(opt) = args.next() {
+        let opt = eval(environment, opt)?.as_string(environment)?;
+        let val = match args.next() {
+            Some(val) => eval(environment, val)?,
+            None => {
+                let msg = format!("fs-find: {} requires a value", opt);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        };
+        match &opt[..] {
+            ":name" => opts.name = Some(find_pattern(&opt, &val, environment)?),
+            ":path" => opts.path = Some(find_pattern(&opt, &val, environment)?),
+            ":prune" => opts.prune.push(find_pattern(&opt, &val, environment)?),
+            ":type" => {
+                let t = val.as_string(environment)?;
+                let t = t.trim_start_matches(':');
+                opts.file_type = Some(match t {
+                    "f" | "file" => "file".to_string(),
+                    "d" | "dir" => "dir".to_string(),
+                    "l" | "link" => "link".to_string(),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "fs-find: :type is one of :file, :dir or :link",
+                        ))
+                    }
+                });
+            }
+            ":min-size" => opts.min_size = Some(find_number(&opt, &val, environment)?),
+            ":max-size" => opts.max_size = Some(find_number(&opt, &val, environment)?),
+            ":newer" => opts.newer = Some(find_number(&opt, &val, environment)?),
+            ":older" => opts.older = Some(find_number(&opt, &val, environment)?),
+            ":min-depth" => opts.min_depth = find_number(&opt, &val, environment)? as usize,
+            ":max-depth" => opts.max_depth = Some(find_number(&opt, &val, environment)? as usize),
+            ":filter" => opts.filter = Some(val),
+            ":each" => opts.each = Some(val),
+            _ => {
+                let msg = format!("fs-find: unknown option {}", opt);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok(opts)
+}
+
+fn find_matches(path: &Path, meta: &fs::Metadata, depth: usize, opts: &FindOptions) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string_lossy().to_string());
+    if depth < opts.min_depth
+        || opts.name.as_ref().map_or(false, |p| !p.matches(&name))
+        || opts
+            .path
+            .as_ref()
+            .map_or(false, |p| !p.matches(&path.to_string_lossy()))
+    {
+        return false;
+    }
+    let file_type = meta.file_type();
+    match opts.file_type.as_ref().map(|t| &t[..]) {
+        Some("file") if !file_type.is_file() => return false,
+        Some("dir") if !file_type.is_dir() => return false,
+        Some("link") if !file_type.is_symlink() => return false,
+        _ => {}
+    }
+    if opts.min_size.map_or(false, |s| meta.len() < s)
+        || opts.max_size.map_or(false, |s| meta.len() > s)
+    {
+        return false;
+    }
+    if opts.newer.is_some() || opts.older.is_some() {
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .map_or(0, |d| d.as_secs());
+        if opts.newer.map_or(false, |s| age > s) || opts.older.map_or(false, |s| age < s) {
+            return false;
+        }
+    }
+    true
+}
+
+fn builtin_fs_find(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let root = match args.next() {
+        Some(root) => eval(environment, root)?.as_string(environment)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "fs-find takes a root directory and options",
+            ))
+        }
+    };
+    let root = match expand_tilde(&root) {
+        Some(r) => r,
+        None => root,
+    };
+    let opts = find_options(environment, args)?;
+    let mut found = Vec::new();
+    // Depth first, each directory's entries in name order.
+    let mut stack = vec![(PathBuf::from(&root), 0)];
+    while let Some((path, depth)) = stack.pop() {
+        check_interrupted(environment)?;
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(err) if depth == 0 => {
+                let msg = format!("fs-find: {}: {}", root, err);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+            Err(_) => continue,
+        };
+        if depth > 0 {
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string());
+            if opts
+                .prune
+                .iter()
+                .any(|p| name.as_ref().map_or(false, |n| p.matches(n)))
+            {
+                continue;
+            }
+        }
+        if find_matches(&path, &meta, depth, &opts) {
+            let path_str =
+                Expression::Atom(Atom::String(path.to_string_lossy().to_string().into()));
+            let mut keep = true;
+            if let Some(filter) = &opts.filter {
+                let args = vec![path_str.clone()];
+                if let Expression::Atom(Atom::Nil) =
+                    fn_call(environment, filter, Box::new(args.iter()))?
+                {
+                    keep = false;
+                }
+            }
+            if keep {
+                if let Some(each) = &opts.each {
+                    let args = vec![path_str];
+                    match fn_call(environment, each, Box::new(args.iter()))? {
+                        Expression::Atom(Atom::Symbol(ref s)) if &**s == ":stop" => break,
+                        _ => {}
+                    }
+                } else {
+                    found.push(path_str);
+                }
+            }
+        }
+        if meta.is_dir() && opts.max_depth.map_or(true, |max| depth < max) {
+            if let Ok(entries) = fs::read_dir(&path) {
+                let mut entries: Vec<PathBuf> =
+                    entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+                entries.sort();
+                for entry in entries.into_iter().rev() {
+                    stack.push((entry, depth + 1));
+                }
+            }
+        }
+    }
+    if opts.each.is_some() {
+        Ok(Expression::Atom(Atom::Nil))
+    } else {
+        Ok(Expression::with_list(found))
+    }
+}
+
+// Total size of path and everything under it (links are not followed and
+// hard linked files are only counted once).
+fn tree_size(path: &Path, disk: bool, seen: &mut HashSet<(u64, u64)>) -> u64 {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return 0,
+    };
+    if meta.nlink() > 1 && !meta.is_dir() && !seen.insert((meta.dev(), meta.ino())) {
+        return 0;
+    }
+    let mut size = if disk {
+        meta.blocks() * 512
+    } else {
+        meta.len()
+    };
+    if meta.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                size += tree_size(&entry.path(), disk, seen);
+            }
+        }
+    }
+    size
+}
+
+fn builtin_fs_size(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let path = match args.next() {
+        Some(path) => eval(environment, path)?.as_string(environment)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "fs-size takes a path and options :breakdown and :disk",
+            ))
+        }
+    };
+    let path = match expand_tilde(&path) {
+        Some(p) => p,
+        None => path,
+    };
+    let mut breakdown = false;
+    let mut disk = false;
+    for arg in args {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":breakdown" => breakdown = true,
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":disk" => disk = true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "fs-size: options are :breakdown and :disk",
+                ))
+            }
+        }
+    }
+    let path = Path::new(&path);
+    if let Err(err) = fs::symlink_metadata(path) {
+        let msg = format!("fs-size: {}: {}", path.display(), err);
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    let mut seen = HashSet::new();
+    if !breakdown || !path.is_dir() {
+        return Ok(Expression::Atom(Atom::Int(
+            tree_size(path, disk, &mut seen) as i64,
+        )));
+    }
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    for entry in fs::read_dir(path)?.filter_map(|e| e.ok()) {
+        let size = tree_size(&entry.path(), disk, &mut seen);
+        map.insert(
+            entry.file_name().to_string_lossy().to_string(),
+            Rc::new(Expression::Atom(Atom::Int(size as i64))),
+        );
+    }
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+fn builtin_fs_free(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let path = match (args.next(), args.next()) {
+        (Some(path), None) => eval(environment, path)?.as_string(environment)?,
+        (None, None) => ".".to_string(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "fs-free takes an optional path",
+            ))
+        }
+    };
+    let path = match expand_tilde(&path) {
+        Some(p) => p,
+        None => path,
+    };
+    let stat = statvfs(&path[..]).map_err(|err| {
+        let msg = format!("fs-free: {}: {}", path, err);
+        io::Error::new(io::ErrorKind::Other, msg)
+    })?;
+    let block = stat.fragment_size() as i64;
+    let total = stat.blocks() as i64 * block;
+    let free = stat.blocks_free() as i64 * block;
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    for (key, val) in &[
+        (":total", total),
+        (":free", free),
+        (":avail", stat.blocks_available() as i64 * block),
+        (":used", total - free),
+    ] {
+        map.insert(
+            (*key).to_string(),
+            Rc::new(Expression::Atom(Atom::Int(*val))),
+        );
+    }
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
 pub fn add_file_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
     data.insert(
         "cd".to_string(),
@@ -413,6 +1059,76 @@ pub fn add_file_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expressio
             "Return the pid of a process.",
         )),
     );
+    data.insert(
+        "proc-pid".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_proc_pid,
+            "Return the pid of a process.",
+        )),
+    );
+    data.insert(
+        "proc-status".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_proc_status,
+            "Return the exit status of a process, :running or :stopped if it is not done.",
+        )),
+    );
+    data.insert(
+        "proc-stdin".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_proc_stdin,
+            "Write each form (as a string) to the stdin of a process, with no forms close it.",
+        )),
+    );
+    data.insert(
+        "proc-kill".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_proc_kill,
+            "Send a signal (default TERM) to a process (it's process group if it has it's own).",
+        )),
+    );
+    data.insert(
+        "proc-exit".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_proc_exit,
+            "Return the status object of a process (nil if it is not done).",
+        )),
+    );
+    data.insert(
+        "status?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_is_status,
+            "True if form is a status object.",
+        )),
+    );
+    data.insert(
+        "status-code".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_status_code,
+            "The exit code of a status object, nil if the process was killed by a signal.",
+        )),
+    );
+    data.insert(
+        "status-signal".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_status_signal,
+            "The name of the signal that killed the process of a status object (nil if none).",
+        )),
+    );
+    data.insert(
+        "status-core-dumped?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_status_core_dumped,
+            "True if the process of a status object dumped core.",
+        )),
+    );
+    data.insert(
+        "status-rusage".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_status_rusage,
+            "Hashmap of the :user-time, :sys-time (seconds) and :max-rss (bytes) of a status object.",
+        )),
+    );
     data.insert(
         "glob".to_string(),
         Rc::new(Expression::make_function(
@@ -420,4 +1136,25 @@ pub fn add_file_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expressio
             "Takes a list of globs and return the list of them expanded.",
         )),
     );
+    data.insert(
+        "fs-find".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_fs_find,
+            "Recursively find paths under root that pass the options (:name glob, :path glob, :type :file/:dir/:link, :min-size, :max-size, :newer secs, :older secs, :min-depth, :max-depth, :prune glob, :filter fn).  Returns a vector of paths or with :each fn calls fn on each path as it is found (return :stop to end).",
+        )),
+    );
+    data.insert(
+        "fs-size".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_fs_size,
+            "Size in bytes of path and everything under it, :disk for space used on disk (like du) and :breakdown for a hashmap of each entry's size.",
+        )),
+    );
+    data.insert(
+        "fs-free".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_fs_free,
+            "Hashmap with the :total, :used, :free and :avail (for non-root users) bytes of the filesystem path (default .) is on.",
+        )),
+    );
 }
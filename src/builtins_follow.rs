@@ -0,0 +1,415 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::ChildStdout;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    poll::{poll, PollFd, PollFlags},
+};
+
+use crate::builtins_util::expand_tilde;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::process::*;
+use crate::types::*;
+
+// The output of a background process being passed a line at a time to it's
+// on-output handlers.
+#[derive(Debug)]
+pub struct OutputFollower {
+    stdout: ChildStdout,
+    partial: Vec<u8>,
+    handlers: Vec<Expression>,
+}
+
+impl OutputFollower {
+    // Read what is available, returns the complete lines and true at end of output.
+    fn read_lines(&mut self) -> io::Result<(Vec<String>, bool)> {
+        let mut buf = [0; 4096];
+        let mut eof = false;
+        loop {
+            match self.stdout.read(&mut buf) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(n) => self.partial.extend_from_slice(&buf[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let mut lines = Vec::new();
+        while let Some(i) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=i).collect();
+            lines.push(String::from_utf8_lossy(&line[..i]).to_string());
+        }
+        if eof && !self.partial.is_empty() {
+            let line: Vec<u8> = self.partial.drain(..).collect();
+            lines.push(String::from_utf8_lossy(&line).to_string());
+        }
+        Ok((lines, eof))
+    }
+}
+
+// Milliseconds between checks for new lines (and rotation or Ctrl-C) by follow.
+const FOLLOW_WAIT_MS: i32 = 200;
+
+// Wakes a follow when the file changes instead of waiting the full interval.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug)]
+struct FileWatch {
+    inotify: nix::sys::inotify::Inotify,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl FileWatch {
+    fn new(path: &PathBuf) -> Option<FileWatch> {
+        use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC).ok()?;
+        let flags = AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_ATTRIB
+            | AddWatchFlags::IN_MOVE_SELF
+            | AddWatchFlags::IN_DELETE_SELF;
+        let watch = FileWatch { inotify };
+        watch.inotify.add_watch(path, flags).ok()?;
+        Some(watch)
+    }
+
+    fn wait(&self, timeout: i32) {
+        let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+        if let Ok(n) = poll(&mut fds, timeout) {
+            if n > 0 {
+                let _ = self.inotify.read_events();
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for FileWatch {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.inotify.as_raw_fd());
+    }
+}
+
+// Without inotify follow polls.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[derive(Debug)]
+struct FileWatch {}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl FileWatch {
+    fn new(_path: &PathBuf) -> Option<FileWatch> {
+        None
+    }
+
+    fn wait(&self, _timeout: i32) {}
+}
+
+// A file being read by follow, reading a line waits for one to be appended.
+#[derive(Debug)]
+pub struct FollowFile {
+    path: PathBuf,
+    reader: BufReader<File>,
+    ino: u64,
+    partial: String,
+    watch: Option<FileWatch>,
+}
+
+impl FollowFile {
+    fn open(path: PathBuf, from_start: bool) -> io::Result<FollowFile> {
+        let mut file = File::open(&path)?;
+        let ino = file.metadata()?.ino();
+        if !from_start {
+            file.seek(SeekFrom::End(0))?;
+        }
+        let watch = FileWatch::new(&path);
+        Ok(FollowFile {
+            path,
+            reader: BufReader::new(file),
+            ino,
+            partial: String::new(),
+            watch,
+        })
+    }
+
+    // Start over if the file was truncated or replaced (log rotation), returns
+    // true if it did.
+    fn check_replaced(&mut self) -> io::Result<bool> {
+        let md = match fs::metadata(&self.path) {
+            Ok(md) => md,
+            // Gone for now, it may be recreated.
+            Err(_) => return Ok(false),
+        };
+        if md.ino() != self.ino {
+            let partial = std::mem::replace(&mut self.partial, String::new());
+            *self = FollowFile::open(self.path.clone(), true)?;
+            self.partial = partial;
+            Ok(true)
+        } else if md.len() < self.reader.seek(SeekFrom::Current(0))? {
+            self.reader.seek(SeekFrom::Start(0))?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // The next line, waits until there is one.
+    pub fn read_line(&mut self, sig_int: &AtomicBool) -> io::Result<String> {
+        loop {
+            let mut buf = String::new();
+            if self.reader.read_line(&mut buf)? > 0 {
+                self.partial.push_str(&buf);
+                if self.partial.ends_with('\n') {
+                    return Ok(std::mem::replace(&mut self.partial, String::new()));
+                }
+                continue;
+            }
+            if self.check_replaced()? {
+                continue;
+            }
+            if sig_int.swap(false, Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "follow: interrupted by SIGINT",
+                ));
+            }
+            match &self.watch {
+                Some(watch) => watch.wait(FOLLOW_WAIT_MS),
+                None => std::thread::sleep(std::time::Duration::from_millis(FOLLOW_WAIT_MS as u64)),
+            }
+        }
+    }
+}
+
+fn builtin_follow(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage = "follow takes a file name and optional :from-start";
+    let path = match args.next() {
+        Some(path) => eval(environment, path)?.as_string(environment)?,
+        None => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let from_start = match args.next() {
+        Some(opt) => match eval(environment, opt)? {
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":from-start" => true,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+        },
+        None => false,
+    };
+    if args.next().is_some() {
+        return Err(io::Error::new(io::ErrorKind::Other, usage));
+    }
+    let path = match expand_tilde(&path) {
+        Some(p) => p,
+        None => path,
+    };
+    let follow = FollowFile::open(PathBuf::from(&path), from_start).map_err(|err| {
+        let msg = format!("follow: {}: {}", path, err);
+        io::Error::new(io::ErrorKind::Other, msg)
+    })?;
+    Ok(Expression::File(FileState::Follow(Rc::new(RefCell::new(
+        follow,
+    )))))
+}
+
+fn quoted(exp: Expression) -> Expression {
+    Expression::cons_from_vec(&mut vec![
+        Expression::Atom(Atom::Symbol("quote".into())),
+        exp,
+    ])
+}
+
+// Pass the lines that have arrived from followed processes (only pid if given)
+// to their handlers, called between interactive evaluations (or with
+// output-poll).  Returns the number of lines and if pid's output has ended.
+pub fn output_poll(environment: &mut Environment, pid: Option<u32>) -> io::Result<(usize, bool)> {
+    let mut pids: Vec<u32> = environment.output_followers.keys().cloned().collect();
+    if let Some(pid) = pid {
+        pids.retain(|p| *p == pid);
+    }
+    let mut count = 0;
+    let mut ended = pid.is_some() && pids.is_empty();
+    for pid in pids {
+        let (lines, eof, handlers) = match environment.output_followers.get(&pid) {
+            Some(follower) => {
+                let mut follower = follower.borrow_mut();
+                let (lines, eof) = follower.read_lines()?;
+                (lines, eof, follower.handlers.clone())
+            }
+            None => continue,
+        };
+        if eof {
+            environment.output_followers.remove(&pid);
+            ended = true;
+        }
+        for line in lines {
+            count += 1;
+            for handler in &handlers {
+                let args = vec![quoted(Expression::Atom(Atom::String(line.clone().into())))];
+                if let Err(err) = fn_call(environment, handler, Box::new(args.iter())) {
+                    if is_exit(&err) {
+                        return Err(err);
+                    }
+                    eprintln!("Error in output handler for {}: {}", pid, err);
+                }
+            }
+        }
+    }
+    Ok((count, ended))
+}
+
+fn follower_pid(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+) -> io::Result<u32> {
+    if let Some(arg) = arg {
+        if let Expression::Process(proc) = eval(environment, arg)? {
+            return Ok(proc.pid());
+        }
+    }
+    let msg = format!("{} requires a process", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_on_output(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage = "on-output takes a process (or command to run in the background) and a function";
+    let (proc_form, handler) = match (args.next(), args.next(), args.next()) {
+        (Some(proc_form), Some(handler), None) => (proc_form, handler),
+        _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    // A command is started in the background with it's output piped to us.
+    let old_out = environment.state.stdout_status.clone();
+    let old_background = environment.run_background;
+    environment.state.stdout_status = Some(IOState::Pipe);
+    environment.run_background = true;
+    let process = eval(environment, proc_form);
+    environment.state.stdout_status = old_out;
+    environment.run_background = old_background;
+    let process = process?;
+    let proc = match &process {
+        Expression::Process(proc) if proc.status().is_none() => proc.clone(),
+        _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let pid = proc.pid();
+    let handler = eval(environment, handler)?;
+    if let Some(follower) = environment.output_followers.get(&pid) {
+        follower.borrow_mut().handlers.push(handler);
+        return Ok(process);
+    }
+    let stdout = match proc.take_stdout() {
+        Some(stdout) => stdout,
+        None => {
+            let msg = format!("on-output: the output of process {} is not piped", pid);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    fcntl(stdout.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    environment.output_followers.insert(
+        pid,
+        Rc::new(RefCell::new(OutputFollower {
+            stdout,
+            partial: Vec::new(),
+            handlers: vec![handler],
+        })),
+    );
+    Ok(process)
+}
+
+fn builtin_output_poll(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "output-poll takes no forms",
+        ));
+    }
+    let (count, _) = output_poll(environment, None)?;
+    Ok(Expression::Atom(Atom::Int(count as i64)))
+}
+
+fn builtin_output_wait(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let pid = follower_pid(environment, args.next(), "output-wait")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "output-wait takes a process",
+        ));
+    }
+    loop {
+        let fd = match environment.output_followers.get(&pid) {
+            Some(follower) => follower.borrow().stdout.as_raw_fd(),
+            None => break,
+        };
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        match poll(&mut fds, 100) {
+            Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        }
+        if environment.sig_int.swap(false, Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "output-wait: interrupted by SIGINT",
+            ));
+        }
+        if output_poll(environment, Some(pid))?.1 {
+            break;
+        }
+    }
+    match wait_pid(environment, pid, None) {
+        Some(exit_status) => Ok(Expression::Atom(Atom::Int(i64::from(exit_status)))),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+pub fn add_follow_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "follow".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_follow,
+            "Open a file to read the lines appended to it (like tail -f), read-line waits for the next line and Ctrl-C stops it.  With :from-start the existing lines are read first.",
+        )),
+    );
+    data.insert(
+        "on-output".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_on_output,
+            "Call a function with each line of output from a process as it arrives (between interactive evaluations or with output-poll/output-wait), a command is started in the background with it's output piped.  Returns the process.",
+        )),
+    );
+    data.insert(
+        "output-poll".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_output_poll,
+            "Run the on-output handlers for the lines that have arrived now (for scripts), returns the number of lines.",
+        )),
+    );
+    data.insert(
+        "output-wait".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_output_wait,
+            "Run the on-output handlers of a process until it's output ends then wait for it, returns the exit status.",
+        )),
+    );
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// Type name of the method used when there is none for the argument's type.
+const DEFAULT_METHOD: &str = "t";
+
+fn symbol(name: &str) -> Expression {
+    Expression::Atom(Atom::Symbol(name.into()))
+}
+
+fn quoted(exp: Expression) -> Expression {
+    Expression::cons_from_vec(&mut vec![symbol("quote"), exp])
+}
+
+fn generic_name(arg: Option<&Expression>, form: &str) -> io::Result<String> {
+    match arg {
+        Some(Expression::Atom(Atom::Symbol(s))) if !s.starts_with(':') => Ok(s.to_string()),
+        _ => {
+            let msg = format!("{} requires a name (symbol)", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// Define name as a function that dispatches to it's methods.
+fn define_generic(
+    environment: &mut Environment,
+    name: &str,
+    doc: Option<&Expression>,
+) -> io::Result<()> {
+    environment
+        .generics
+        .entry(name.to_string())
+        .or_insert_with(HashMap::new);
+    let body = Expression::cons_from_vec(&mut vec![
+        symbol("generic-apply"),
+        quoted(symbol(name)),
+        symbol("args"),
+    ]);
+    let lambda = Expression::cons_from_vec(&mut vec![
+        symbol("fn"),
+        Expression::cons_from_vec(&mut vec![symbol("&rest"), symbol("args")]),
+        body,
+    ]);
+    let mut def = vec![symbol("def"), quoted(symbol(name)), lambda];
+    if let Some(doc) = doc {
+        def.push(symbol(":doc"));
+        def.push(doc.clone());
+    }
+    eval(environment, &Expression::cons_from_vec(&mut def))?;
+    Ok(())
+}
+
+fn builtin_defgeneric(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let name = generic_name(args.next(), "defgeneric")?;
+    let doc = args.next();
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "defgeneric takes a name and an optional doc string",
+        ));
+    }
+    define_generic(environment, &name, doc)?;
+    Ok(symbol(&name))
+}
+
+fn builtin_defmethod(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage = "defmethod takes a generic name, a type name, params and a body";
+    let name = generic_name(args.next(), "defmethod")?;
+    let type_name = match args.next() {
+        Some(Expression::Atom(Atom::Symbol(s))) => s.clone(),
+        Some(Expression::Atom(Atom::String(s))) => s.clone().into(),
+        Some(Expression::Atom(Atom::Nil)) => "Nil".into(),
+        Some(Expression::Atom(Atom::True)) => DEFAULT_METHOD.to_string().into(),
+        _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let params = match args.next() {
+        Some(params) => params.clone(),
+        None => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let mut body: Vec<Expression> = args.cloned().collect();
+    let body = match body.len() {
+        0 => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+        1 => body.remove(0),
+        _ => {
+            body.insert(0, symbol("progn"));
+            Expression::cons_from_vec(&mut body)
+        }
+    };
+    let method = eval(
+        environment,
+        &Expression::cons_from_vec(&mut vec![symbol("fn"), params, body]),
+    )?;
+    if !environment.generics.contains_key(&name) {
+        define_generic(environment, &name, None)?;
+    }
+    if let Some(methods) = environment.generics.get_mut(&name) {
+        methods.insert(type_name.to_string(), method);
+    }
+    Ok(symbol(&name))
+}
+
+fn builtin_generic_apply(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (name, call_args) = match (args.next(), args.next(), args.next()) {
+        (Some(name), Some(call_args), None) => {
+            (eval(environment, name)?, eval(environment, call_args)?)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "generic-apply takes a generic name and a list of arguments",
+            ))
+        }
+    };
+    let name = match name {
+        Expression::Atom(Atom::Symbol(s)) => s.to_string(),
+        Expression::Atom(Atom::String(s)) => s.to_string(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "generic-apply requires a generic name",
+            ))
+        }
+    };
+    let call_args: Vec<Expression> = match call_args {
+        Expression::Vector(list) => list.borrow().clone(),
+        Expression::Pair(_, _) => call_args.iter().cloned().collect(),
+        _ => Vec::new(),
+    };
+    let type_name = match call_args.get(0) {
+        Some(arg) => arg.display_type(),
+        None => {
+            let msg = format!("{}: requires an argument to dispatch on", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let method = match environment.generics.get(&name) {
+        Some(methods) => match methods.get(&type_name) {
+            Some(method) => method.clone(),
+            None => match methods.get(DEFAULT_METHOD) {
+                Some(method) => method.clone(),
+                None => {
+                    let msg = format!("{}: no method for type {}", name, type_name);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            },
+        },
+        None => {
+            let msg = format!("generic-apply: {} is not a generic function", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let call_args: Vec<Expression> = call_args.into_iter().map(quoted).collect();
+    fn_call(environment, &method, Box::new(call_args.iter()))
+}
+
+fn builtin_generic_methods(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(name) = args.next() {
+        if args.next().is_none() {
+            let name = eval(environment, name)?.as_string(environment)?;
+            if let Some(methods) = environment.generics.get(&name) {
+                let mut types: Vec<&String> = methods.keys().collect();
+                types.sort();
+                let types = types
+                    .into_iter()
+                    .map(|t| Expression::Atom(Atom::Symbol(t.clone().into())))
+                    .collect();
+                return Ok(Expression::with_list(types));
+            }
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "generic-methods takes a generic name",
+    ))
+}
+
+pub fn add_generic_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "defgeneric".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_defgeneric,
+            "Define a generic function that calls the method (see defmethod) for the type of it's first argument.",
+        )),
+    );
+    data.insert(
+        "defmethod".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_defmethod,
+            "Define the method of a generic function for a type name (as returned by type, a struct name or t for any other type), (defmethod name type (params) body).",
+        )),
+    );
+    data.insert(
+        "generic-apply".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_generic_apply,
+            "Call the method of a generic function for the type of the first item in a list of arguments.",
+        )),
+    );
+    data.insert(
+        "generic-methods".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_generic_methods,
+            "Vector of the type names a generic function has methods for, nil if not a generic.",
+        )),
+    );
+}
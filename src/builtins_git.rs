@@ -0,0 +1,316 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::builtins_util::*;
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// How long a git status result is reused if nothing in .git changed, edits
+// to the work tree show up after this.
+const STATUS_CACHE_SECS: u64 = 2;
+
+// Result of git status for a repo, cached so prompts only run git now and then.
+#[derive(Clone, Debug)]
+pub struct GitStatus {
+    stamp: Vec<Option<SystemTime>>,
+    taken: Instant,
+    staged: i64,
+    unstaged: i64,
+    untracked: i64,
+    conflicts: i64,
+    upstream: Option<String>,
+    ahead: Option<i64>,
+    behind: Option<i64>,
+}
+
+struct GitRepo {
+    root: PathBuf,
+    // The .git dir (the worktree's own dir for a linked worktree).
+    git_dir: PathBuf,
+    // Where refs and packed-refs live, the main .git dir for a linked worktree.
+    common_dir: PathBuf,
+}
+
+// Find the repo containing dir (.git may be a dir or a file with gitdir: for
+// worktrees and submodules).
+fn find_repo(dir: &Path) -> Option<GitRepo> {
+    for root in dir.ancestors() {
+        let dot_git = root.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            let contents = fs::read_to_string(&dot_git).ok()?;
+            let path = contents.trim().trim_start_matches("gitdir:").trim();
+            root.join(path)
+        } else {
+            continue;
+        };
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common) => git_dir.join(common.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        return Some(GitRepo {
+            root: root.to_path_buf(),
+            git_dir,
+            common_dir,
+        });
+    }
+    None
+}
+
+// The commit hash of a ref from its file or packed-refs.
+fn resolve_ref(repo: &GitRepo, name: &str) -> Option<String> {
+    if let Ok(hash) = fs::read_to_string(repo.common_dir.join(name)) {
+        return Some(hash.trim().to_string());
+    }
+    let packed = fs::read_to_string(repo.common_dir.join("packed-refs")).ok()?;
+    for line in packed.lines() {
+        let mut parts = line.splitn(2, ' ');
+        if let (Some(hash), Some(ref_name)) = (parts.next(), parts.next()) {
+            if ref_name == name {
+                return Some(hash.to_string());
+            }
+        }
+    }
+    None
+}
+
+// Branch (None if detached) and commit (None in a new repo) from HEAD.
+fn read_head(repo: &GitRepo) -> (Option<String>, Option<String>) {
+    let head = match fs::read_to_string(repo.git_dir.join("HEAD")) {
+        Ok(head) => head.trim().to_string(),
+        Err(_) => return (None, None),
+    };
+    if head.starts_with("ref: ") {
+        let ref_name = head[5..].trim();
+        let branch = ref_name.trim_start_matches("refs/heads/").to_string();
+        (Some(branch), resolve_ref(repo, ref_name))
+    } else {
+        (None, Some(head))
+    }
+}
+
+// An operation in progress (merge, rebase, etc) from the files git leaves.
+fn repo_state(repo: &GitRepo) -> Option<&'static str> {
+    let git_dir = &repo.git_dir;
+    if git_dir.join("rebase-merge").is_dir() || git_dir.join("rebase-apply").is_dir() {
+        Some(":rebase")
+    } else if git_dir.join("MERGE_HEAD").is_file() {
+        Some(":merge")
+    } else if git_dir.join("CHERRY_PICK_HEAD").is_file() {
+        Some(":cherry-pick")
+    } else if git_dir.join("REVERT_HEAD").is_file() {
+        Some(":revert")
+    } else if git_dir.join("BISECT_LOG").is_file() {
+        Some(":bisect")
+    } else {
+        None
+    }
+}
+
+// Modify times of the files git changes on add, commit, checkout, fetch etc.
+fn status_stamp(repo: &GitRepo, branch: &Option<String>) -> Vec<Option<SystemTime>> {
+    let mut files = vec![
+        repo.git_dir.join("index"),
+        repo.git_dir.join("HEAD"),
+        repo.common_dir.join("packed-refs"),
+        repo.common_dir.join("FETCH_HEAD"),
+    ];
+    if let Some(branch) = branch {
+        files.push(repo.common_dir.join("refs/heads").join(branch));
+    }
+    files
+        .iter()
+        .map(|f| fs::metadata(f).and_then(|md| md.modified()).ok())
+        .collect()
+}
+
+// Run git status once and count the entries.
+fn run_status(repo: &GitRepo, stamp: Vec<Option<SystemTime>>) -> io::Result<GitStatus> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(&repo.root)
+        .args(&["status", "--porcelain=v2", "--branch"])
+        // Do not take the index lock, would get in the way of git commands.
+        .env("GIT_OPTIONAL_LOCKS", "0")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "git-info: git status failed",
+        ));
+    }
+    let mut status = GitStatus {
+        stamp,
+        taken: Instant::now(),
+        staged: 0,
+        unstaged: 0,
+        untracked: 0,
+        conflicts: 0,
+        upstream: None,
+        ahead: None,
+        behind: None,
+    };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if line.starts_with("# branch.upstream ") {
+            status.upstream = Some(line[18..].to_string());
+        } else if line.starts_with("# branch.ab ") {
+            let mut counts = line[12..]
+                .split(' ')
+                .map(|c| c.get(1..).and_then(|n| n.parse::<i64>().ok()));
+            status.ahead = counts.next().unwrap_or(None);
+            status.behind = counts.next().unwrap_or(None);
+        } else if line.starts_with("1 ") || line.starts_with("2 ") {
+            let xy = line[2..].as_bytes();
+            if xy.len() > 1 {
+                if xy[0] != b'.' {
+                    status.staged += 1;
+                }
+                if xy[1] != b'.' {
+                    status.unstaged += 1;
+                }
+            }
+        } else if line.starts_with("u ") {
+            status.conflicts += 1;
+        } else if line.starts_with("? ") {
+            status.untracked += 1;
+        }
+    }
+    Ok(status)
+}
+
+// Cached git status for repo, only runs git if something changed or it is stale.
+fn git_status(
+    environment: &mut Environment,
+    repo: &GitRepo,
+    branch: &Option<String>,
+) -> Option<GitStatus> {
+    let stamp = status_stamp(repo, branch);
+    if let Some(status) = environment.git_status.get(&repo.git_dir) {
+        if status.stamp == stamp && status.taken.elapsed() < Duration::from_secs(STATUS_CACHE_SECS)
+        {
+            return Some(status.clone());
+        }
+    }
+    match run_status(repo, stamp) {
+        Ok(status) => {
+            environment
+                .git_status
+                .insert(repo.git_dir.clone(), status.clone());
+            Some(status)
+        }
+        Err(_) => {
+            environment.git_status.remove(&repo.git_dir);
+            None
+        }
+    }
+}
+
+fn string_or_nil(s: Option<String>) -> Rc<Expression> {
+    Rc::new(match s {
+        Some(s) => Expression::Atom(Atom::String(s.into())),
+        None => Expression::Atom(Atom::Nil),
+    })
+}
+
+fn int_or_nil(i: Option<i64>) -> Rc<Expression> {
+    Rc::new(match i {
+        Some(i) => Expression::Atom(Atom::Int(i)),
+        None => Expression::Atom(Atom::Nil),
+    })
+}
+
+fn builtin_git_info(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut dir = None;
+    let mut fast = false;
+    for arg in args {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":fast" => fast = true,
+            arg => {
+                if dir.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "git-info takes an optional directory and :fast",
+                    ));
+                }
+                let path = arg.as_string(environment)?;
+                dir = Some(match expand_tilde(&path) {
+                    Some(path) => path,
+                    None => path,
+                });
+            }
+        }
+    }
+    let dir = match dir {
+        Some(dir) => PathBuf::from(dir),
+        None => env::current_dir()?,
+    };
+    let repo = match find_repo(&dir) {
+        Some(repo) => repo,
+        None => return Ok(Expression::Atom(Atom::Nil)),
+    };
+    let (branch, head) = read_head(&repo);
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    map.insert(
+        ":root".to_string(),
+        string_or_nil(Some(repo.root.to_string_lossy().to_string())),
+    );
+    map.insert(":branch".to_string(), string_or_nil(branch.clone()));
+    map.insert(":head".to_string(), string_or_nil(head));
+    map.insert(
+        ":state".to_string(),
+        Rc::new(match repo_state(&repo) {
+            Some(state) => Expression::Atom(Atom::Symbol(state.into())),
+            None => Expression::Atom(Atom::Nil),
+        }),
+    );
+    let status = if fast {
+        None
+    } else {
+        git_status(environment, &repo, &branch)
+    };
+    let (staged, unstaged, untracked, conflicts, upstream, ahead, behind) = match status {
+        Some(s) => (
+            Some(s.staged),
+            Some(s.unstaged),
+            Some(s.untracked),
+            Some(s.conflicts),
+            s.upstream,
+            s.ahead,
+            s.behind,
+        ),
+        None => (None, None, None, None, None, None, None),
+    };
+    map.insert(":staged".to_string(), int_or_nil(staged));
+    map.insert(":unstaged".to_string(), int_or_nil(unstaged));
+    map.insert(":untracked".to_string(), int_or_nil(untracked));
+    map.insert(":conflicts".to_string(), int_or_nil(conflicts));
+    map.insert(":upstream".to_string(), string_or_nil(upstream));
+    map.insert(":ahead".to_string(), int_or_nil(ahead));
+    map.insert(":behind".to_string(), int_or_nil(behind));
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+pub fn add_git_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "git-info".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_git_info,
+            "Return a hashmap describing the git repo containing dir (default current directory) or nil if none: :root :branch :head :state :staged :unstaged :untracked :conflicts :upstream :ahead :behind.  The counts come from a cached git status, :fast only reads .git (counts are nil).",
+        )),
+    );
+}
@@ -6,11 +6,14 @@ use std::rc::Rc;
 
 use crate::environment::*;
 use crate::eval::*;
+use crate::gc::*;
 use crate::types::*;
 
-fn build_map(
+// Add the (key . val) pairs to map, form is used in errors.
+pub fn build_map(
     mut map: HashMap<String, Rc<Expression>>,
     assocs: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
 ) -> io::Result<Expression> {
     for key_val in assocs {
         if let Expression::Pair(key, val) = key_val {
@@ -25,17 +28,13 @@ fn build_map(
                     map.insert(s.borrow().to_string(), Rc::new(val.borrow().clone()))
                 }
                 _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "make-hash key can only be a symbol or string",
-                    ))
+                    let msg = format!("{} key can only be a symbol or string", form);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
                 }
             };
         } else {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "make-hash each association must be a pair (key . val)",
-            ));
+            let msg = format!("{} each association must be a pair (key . val)", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
         }
     }
     Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
@@ -50,8 +49,10 @@ fn builtin_make_hash(
         if args.next().is_none() {
             let assocs = eval(environment, assocs)?;
             return match assocs {
-                Expression::Pair(_, _) => build_map(map, &mut *assocs.iter()),
-                Expression::Vector(list) => build_map(map, &mut *Box::new(list.borrow().iter())),
+                Expression::Pair(_, _) => build_map(map, &mut *assocs.iter(), "make-hash"),
+                Expression::Vector(list) => {
+                    build_map(map, &mut *Box::new(list.borrow().iter()), "make-hash")
+                }
                 Expression::Atom(Atom::Nil) => Ok(Expression::HashMap(Rc::new(RefCell::new(map)))),
                 _ => Err(io::Error::new(
                     io::ErrorKind::Other,
@@ -75,13 +76,16 @@ fn builtin_hash_set(
                     let key = eval(environment, key)?;
                     let val = eval(environment, val)?;
                     if let Expression::HashMap(map) = map {
+                        if gc_may_cycle(&val) {
+                            gc_track(environment, GcCandidate::HashMap(Rc::downgrade(&map)));
+                        }
                         match key {
                             Expression::Atom(Atom::Symbol(sym)) => {
-                                map.borrow_mut().insert(sym, Rc::new(val));
+                                map.borrow_mut().insert(sym.to_string(), Rc::new(val));
                                 return Ok(Expression::HashMap(map));
                             }
                             Expression::Atom(Atom::String(s)) => {
-                                map.borrow_mut().insert(s, Rc::new(val));
+                                map.borrow_mut().insert(s.to_string(), Rc::new(val));
                                 return Ok(Expression::HashMap(map));
                             }
                             Expression::Atom(Atom::StringBuf(s)) => {
@@ -254,7 +258,7 @@ fn builtin_hash_keys(
             if let Expression::HashMap(map) = map {
                 let mut key_list = Vec::with_capacity(map.borrow().len());
                 for key in map.borrow().keys() {
-                    key_list.push(Expression::Atom(Atom::Symbol(key.to_string())));
+                    key_list.push(Expression::Atom(Atom::Symbol(key.to_string().into())));
                 }
                 return Ok(Expression::with_list(key_list));
             }
@@ -1,10 +1,18 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::fs::{self, File, OpenOptions};
 use std::hash::BuildHasher;
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::process::{Command, Stdio};
 use std::rc::Rc;
+use std::thread;
+use std::time::Instant;
 
+use nix::fcntl::OFlag;
+use nix::unistd;
+
+use crate::builtins_hashmap::build_map;
 use crate::builtins_util::*;
 use crate::environment::*;
 use crate::eval::*;
@@ -101,7 +109,7 @@ fn builtin_open(environment: &mut Environment, args: &[Expression]) -> io::Resul
         if !is_write {
             opts.read(true);
         }
-        let file = match opts.open(file_name) {
+        let file = match opts.open(&*file_name) {
             Ok(file) => file,
             Err(err) => {
                 if error_nil {
@@ -162,22 +170,59 @@ fn builtin_flush(environment: &mut Environment, args: &[Expression]) -> io::Resu
     }
 }
 
+// Where read-line reads without a file: the input of a pipe (a process's
+// output becomes a file so the following reads continue it) or *stdin*.
+fn stdin_file(environment: &mut Environment) -> io::Result<Expression> {
+    match &environment.data_in {
+        Some(Expression::File(FileState::Read(file))) => {
+            return Ok(Expression::File(FileState::Read(file.clone())))
+        }
+        Some(Expression::Process(proc)) => {
+            if let Some(out) = proc.take_stdout() {
+                let out = unsafe { File::from_raw_fd(out.into_raw_fd()) };
+                let file =
+                    Expression::File(FileState::Read(Rc::new(RefCell::new(BufReader::new(out)))));
+                environment.data_in = Some(file.clone());
+                return Ok(file);
+            }
+        }
+        _ => {}
+    }
+    match get_expression(environment, "*stdin*") {
+        Some(exp) => Ok((*exp).clone()),
+        None => Ok(Expression::File(FileState::Stdin)),
+    }
+}
+
 fn builtin_read_line(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
-    let args = list_to_args(environment, args, true)?;
+    let mut args = list_to_args(environment, args, true)?;
+    if args.is_empty() {
+        args.push(stdin_file(environment)?);
+    }
     if args.len() != 1 {
         Err(io::Error::new(
             io::ErrorKind::Other,
-            "read-line takes one form (file)",
+            "read-line takes one form (file, default *stdin*)",
         ))
     } else {
         let exp = &args[0];
-        if let Expression::File(FileState::Read(file)) = &exp {
+        if let Expression::File(FileState::Stdin) = &exp {
+            let mut line = String::new();
+            if 0 == io::stdin().lock().read_line(&mut line)? {
+                Ok(Expression::Atom(Atom::Nil))
+            } else {
+                Ok(Expression::Atom(Atom::String(line.into())))
+            }
+        } else if let Expression::File(FileState::Read(file)) = &exp {
             let mut line = String::new();
             if 0 == file.borrow_mut().read_line(&mut line)? {
                 Ok(Expression::Atom(Atom::Nil))
             } else {
-                Ok(Expression::Atom(Atom::String(line)))
+                Ok(Expression::Atom(Atom::String(line.into())))
             }
+        } else if let Expression::File(FileState::Follow(file)) = &exp {
+            let line = file.borrow_mut().read_line(&environment.sig_int)?;
+            Ok(Expression::Atom(Atom::String(line.into())))
         } else {
             Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -273,6 +318,660 @@ fn builtin_write_string(
     }
 }
 
+// The program and it's arguments to compress and decompress (stdin to stdout).
+struct Codec {
+    program: &'static str,
+    compress: &'static [&'static str],
+    decompress: &'static [&'static str],
+}
+
+const GZIP: Codec = Codec {
+    program: "gzip",
+    compress: &["-c", "-q"],
+    decompress: &["-d", "-c", "-q"],
+};
+
+const ZSTD: Codec = Codec {
+    program: "zstd",
+    compress: &["-c", "-q"],
+    decompress: &["-d", "-c", "-q"],
+};
+
+fn to_bytes(exp: &Expression, form: &str) -> io::Result<Vec<u8>> {
+    match exp {
+        Expression::Atom(Atom::String(s)) => Ok(s.as_bytes().to_vec()),
+        Expression::Atom(Atom::StringBuf(s)) => Ok(s.borrow().as_bytes().to_vec()),
+        Expression::Vector(list) => {
+            let mut bytes = Vec::with_capacity(list.borrow().len());
+            for b in list.borrow().iter() {
+                match b {
+                    Expression::Atom(Atom::Int(i)) if *i >= 0 && *i < 256 => bytes.push(*i as u8),
+                    _ => {
+                        let msg = format!("{}: a byte vector can only contain ints 0-255", form);
+                        return Err(io::Error::new(io::ErrorKind::Other, msg));
+                    }
+                }
+            }
+            Ok(bytes)
+        }
+        _ => {
+            let msg = format!(
+                "{} takes a string, byte vector or file, got {}",
+                form,
+                exp.display_type()
+            );
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn byte_vector(bytes: Vec<u8>) -> Expression {
+    Expression::with_list(
+        bytes
+            .into_iter()
+            .map(|b| Expression::Atom(Atom::Int(i64::from(b))))
+            .collect(),
+    )
+}
+
+// Streams: a file being read becomes a file reading the program's output and a
+// file being written becomes a file writing the program's input.
+fn codec_stream(
+    environment: &mut Environment,
+    mut command: Command,
+    file: &FileState,
+    form: &str,
+) -> io::Result<Expression> {
+    let reading = match file {
+        FileState::Stdin => {
+            command.stdin(Stdio::inherit()).stdout(Stdio::piped());
+            true
+        }
+        FileState::Read(f) => {
+            let f = f.borrow();
+            if !f.buffer().is_empty() {
+                let msg = format!("{}: the file has already been partly read", form);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+            command
+                .stdin(Stdio::from(f.get_ref().try_clone()?))
+                .stdout(Stdio::piped());
+            true
+        }
+        FileState::Stdout => {
+            io::stdout().flush()?;
+            command.stdin(Stdio::piped()).stdout(Stdio::inherit());
+            false
+        }
+        FileState::Stderr => {
+            let fd = unistd::dup(2)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::from(unsafe { File::from_raw_fd(fd) }));
+            false
+        }
+        FileState::Write(f) => {
+            let mut f = f.borrow_mut();
+            f.flush()?;
+            command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::from(f.get_ref().try_clone()?));
+            false
+        }
+        FileState::Follow(_) => {
+            let msg = format!("{}: can not stream a followed file", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        FileState::Closed => {
+            let msg = format!("{}: the file is closed", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let mut child = command.spawn()?;
+    let res = if reading {
+        let out = child.stdout.take().unwrap();
+        let out = unsafe { File::from_raw_fd(out.into_raw_fd()) };
+        Expression::File(FileState::Read(Rc::new(RefCell::new(BufReader::new(out)))))
+    } else {
+        let input = child.stdin.take().unwrap();
+        let input = unsafe { File::from_raw_fd(input.into_raw_fd()) };
+        Expression::File(FileState::Write(Rc::new(RefCell::new(BufWriter::new(
+            input,
+        )))))
+    };
+    // Runs in the shell's process group.
+    let pgid = unistd::getpgrp().as_raw() as u32;
+    add_process(environment, ProcessHandle::new(child, pgid, Instant::now()));
+    Ok(res)
+}
+
+// Start form's command in the background with it's stdin and stdout piped to
+// the shell, returns a hashmap of the :proc and the files to write it's
+// input (:in) and read it's output (:out).
+fn builtin_coproc(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let form = match (args.next(), args.next()) {
+        (Some(form), None) => form,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "coproc takes one form (a command)",
+            ))
+        }
+    };
+    let old_in = environment.state.stdin_status.replace(IOState::Pipe);
+    let old_out = environment.state.stdout_status.replace(IOState::Pipe);
+    let old_background = environment.run_background;
+    let old_data_in = environment.data_in.take();
+    environment.run_background = true;
+    let res = eval(environment, form);
+    environment.state.stdin_status = old_in;
+    environment.state.stdout_status = old_out;
+    environment.run_background = old_background;
+    environment.data_in = old_data_in;
+    let proc = match res? {
+        Expression::Process(proc) => proc,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "coproc: form did not start a command",
+            ))
+        }
+    };
+    let (input, output) = match (proc.take_stdin(), proc.take_stdout()) {
+        (Some(input), Some(output)) => (input, output),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "coproc: the command's stdin and stdout are not piped",
+            ))
+        }
+    };
+    let input = unsafe { File::from_raw_fd(input.into_raw_fd()) };
+    let output = unsafe { File::from_raw_fd(output.into_raw_fd()) };
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    map.insert(":proc".to_string(), Rc::new(Expression::Process(proc)));
+    // Unbuffered, a line written is a line the command sees.
+    map.insert(
+        ":in".to_string(),
+        Rc::new(Expression::File(FileState::Write(Rc::new(RefCell::new(
+            BufWriter::with_capacity(0, input),
+        ))))),
+    );
+    map.insert(
+        ":out".to_string(),
+        Rc::new(Expression::File(FileState::Read(Rc::new(RefCell::new(
+            BufReader::new(output),
+        ))))),
+    );
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+// The files for tee targets, a path (truncated), an open file for writing
+// (append mode if it was opened that way) or a list or vector of them.
+fn tee_targets(target: &Expression, form: &str, files: &mut Vec<File>) -> io::Result<()> {
+    match target {
+        Expression::Atom(atom @ Atom::String(_)) | Expression::Atom(atom @ Atom::Symbol(_)) => {
+            let path = atom.as_string();
+            let path = match expand_tilde(&path) {
+                Some(p) => p,
+                None => path,
+            };
+            files.push(File::create(&path).map_err(|err| {
+                let msg = format!("{}: {}: {}", form, path, err);
+                io::Error::new(io::ErrorKind::Other, msg)
+            })?);
+        }
+        Expression::File(FileState::Write(f)) => {
+            let mut f = f.borrow_mut();
+            f.flush()?;
+            files.push(f.get_ref().try_clone()?);
+        }
+        Expression::Vector(list) => {
+            for t in list.borrow().iter() {
+                tee_targets(t, form, files)?;
+            }
+        }
+        Expression::Pair(_, _) => {
+            for t in target.iter() {
+                tee_targets(t, form, files)?;
+            }
+        }
+        _ => {
+            let msg = format!(
+                "{}: targets must be file names, files open for writing or a list of them",
+                form
+            );
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    Ok(())
+}
+
+// A copy of where key (*stdout* or *stderr*) writes now.
+fn tee_dest(environment: &Environment, key: &str) -> io::Result<File> {
+    let dup = |fd| -> io::Result<File> {
+        let fd = unistd::dup(fd).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    };
+    match get_expression(environment, key).as_deref() {
+        Some(Expression::File(FileState::Stdout)) => {
+            io::stdout().flush()?;
+            dup(io::stdout().as_raw_fd())
+        }
+        Some(Expression::File(FileState::Stderr)) => dup(io::stderr().as_raw_fd()),
+        Some(Expression::File(FileState::Write(f))) => {
+            let mut f = f.borrow_mut();
+            f.flush()?;
+            f.get_ref().try_clone()
+        }
+        _ => {
+            let msg = format!("tee: {} is not writable", key);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// Eval forms with key (*stdout* or *stderr*) going through a pipe, a thread
+// copies it to where key went before and the targets.  Waits for everything
+// written to the pipe, including by commands started in the forms.
+fn tee_run(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    is_err: bool,
+) -> io::Result<Expression> {
+    let (form, key) = if is_err {
+        ("tee-err", "*stderr*")
+    } else {
+        ("tee", "*stdout*")
+    };
+    let mut targets = Vec::new();
+    match args.next() {
+        Some(target) => {
+            let target = eval(environment, target)?;
+            tee_targets(&target, form, &mut targets)?;
+        }
+        None => {
+            let msg = format!("{} takes targets (files) and forms", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    let mut dest = tee_dest(environment, key)?;
+    let (read_fd, write_fd) =
+        unistd::pipe2(OFlag::O_CLOEXEC).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let mut reader = unsafe { File::from_raw_fd(read_fd) };
+    let writer = unsafe { File::from_raw_fd(write_fd) };
+    let copier = thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            // Keep draining even if a target fails so writers never block.
+            let _ = dest.write_all(&buf[..n]);
+            for target in &mut targets {
+                let _ = target.write_all(&buf[..n]);
+            }
+        }
+    });
+    let tee_file = Rc::new(RefCell::new(BufWriter::new(writer)));
+    let old_val = environment.dynamic_scope.insert(
+        key.to_string(),
+        Rc::new(Expression::File(FileState::Write(tee_file.clone()))),
+    );
+    let old_status = if is_err {
+        environment.state.stderr_status.replace(IOState::Inherit)
+    } else {
+        environment.state.stdout_status.replace(IOState::Inherit)
+    };
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for a in args {
+        last_eval = eval(environment, a);
+        if last_eval.is_err() {
+            break;
+        }
+    }
+    if is_err {
+        environment.state.stderr_status = old_status;
+    } else {
+        environment.state.stdout_status = old_status;
+    }
+    match old_val {
+        Some(old_val) => environment.dynamic_scope.insert(key.to_string(), old_val),
+        None => environment.dynamic_scope.remove(key),
+    };
+    let _ = tee_file.borrow_mut().flush();
+    // Close the shell's end so the copier sees EOF once the commands are done.
+    drop(tee_file);
+    let _ = copier.join();
+    last_eval
+}
+
+fn builtin_tee(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    tee_run(environment, args, false)
+}
+
+fn builtin_tee_err(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    tee_run(environment, args, true)
+}
+
+fn codec_run(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    codec: &Codec,
+    compress: bool,
+    form: &str,
+) -> io::Result<Expression> {
+    let arg = match (args.next(), args.next()) {
+        (Some(arg), None) => eval(environment, arg)?,
+        _ => {
+            let msg = format!("{} takes one form (string, byte vector or file)", form);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let mut command = Command::new(codec.program);
+    command.args(if compress {
+        codec.compress
+    } else {
+        codec.decompress
+    });
+    if let Expression::File(file) = &arg {
+        return codec_stream(environment, command, file, form).map_err(|err| {
+            let msg = format!("{}: {}: {}", form, codec.program, err);
+            io::Error::new(io::ErrorKind::Other, msg)
+        });
+    }
+    let input = to_bytes(&arg, form)?;
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| {
+            let msg = format!("{}: {}: {}", form, codec.program, err);
+            io::Error::new(io::ErrorKind::Other, msg)
+        })?;
+    // Feed the input from another thread so a full output pipe can not block it.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+    if !output.status.success() {
+        let msg = format!(
+            "{}: {}",
+            form,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    if compress {
+        Ok(byte_vector(output.stdout))
+    } else {
+        match String::from_utf8(output.stdout) {
+            Ok(s) => Ok(Expression::Atom(Atom::String(s.into()))),
+            Err(err) => Ok(byte_vector(err.into_bytes())),
+        }
+    }
+}
+
+fn builtin_gzip_compress(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    codec_run(environment, args, &GZIP, true, "gzip-compress")
+}
+
+fn builtin_gzip_decompress(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    codec_run(environment, args, &GZIP, false, "gzip-decompress")
+}
+
+fn builtin_zstd_compress(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    codec_run(environment, args, &ZSTD, true, "zstd-compress")
+}
+
+fn builtin_zstd_decompress(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    codec_run(environment, args, &ZSTD, false, "zstd-decompress")
+}
+
+// Write exp in the write-data format: what the reader reads with hashmaps as
+// (make-hash ((key . val)...)) with sorted keys and lists starting with quote
+// or make-hash quoted so they read back as lists.
+fn write_data(exp: &Expression, out: &mut String) -> io::Result<()> {
+    match exp {
+        Expression::Atom(Atom::Nil) => out.push_str("nil"),
+        Expression::Atom(Atom::True) => out.push_str("t"),
+        Expression::Atom(Atom::Float(f)) => out.push_str(&float_str(*f, None)),
+        Expression::Atom(Atom::Int(_))
+        | Expression::Atom(Atom::Symbol(_))
+        | Expression::Atom(Atom::String(_))
+        | Expression::Atom(Atom::StringBuf(_))
+        | Expression::Atom(Atom::Char(_)) => out.push_str(&exp.to_string()),
+        Expression::Vector(list) => {
+            out.push_str("#(");
+            for (i, item) in list.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_data(item, out)?;
+            }
+            out.push(')');
+        }
+        Expression::Pair(car, _) => {
+            let quote = match &*car.borrow() {
+                Expression::Atom(Atom::Symbol(s)) => &**s == "quote" || &**s == "make-hash",
+                _ => false,
+            };
+            if quote {
+                out.push_str("(quote ");
+            }
+            out.push('(');
+            let mut current = exp.clone();
+            let mut first = true;
+            while let Expression::Pair(car, cdr) = current {
+                if !first {
+                    out.push(' ');
+                }
+                first = false;
+                write_data(&car.borrow(), out)?;
+                current = cdr.borrow().clone();
+            }
+            match current {
+                Expression::Atom(Atom::Nil) => {}
+                tail => {
+                    out.push_str(" . ");
+                    write_data(&tail, out)?;
+                }
+            }
+            out.push(')');
+            if quote {
+                out.push(')');
+            }
+        }
+        Expression::HashMap(map) => {
+            let map = map.borrow();
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push_str("(make-hash (");
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                out.push('(');
+                if key.starts_with(':') && !key.contains(char::is_whitespace) {
+                    out.push_str(key);
+                } else {
+                    out.push_str(&escape_str(key));
+                }
+                out.push_str(" . ");
+                write_data(&map[*key], out)?;
+                out.push(')');
+            }
+            out.push_str("))");
+        }
+        _ => {
+            let msg = format!("write-data: can not write a {} as data", exp.display_type());
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    Ok(())
+}
+
+// Turn what the reader read from a write-data file back into the data.
+fn data_from_form(exp: &Expression) -> io::Result<Expression> {
+    Ok(match exp {
+        Expression::Vector(list) => {
+            let mut items = Vec::with_capacity(list.borrow().len());
+            for item in list.borrow().iter() {
+                items.push(data_from_form(item)?);
+            }
+            Expression::with_list(items)
+        }
+        Expression::Pair(car, cdr) => {
+            let head = match &*car.borrow() {
+                Expression::Atom(Atom::Symbol(s)) => s.clone(),
+                _ => String::new().into(),
+            };
+            let args: Vec<Expression> = if is_proper_list(exp) {
+                cdr.borrow().iter().cloned().collect()
+            } else {
+                Vec::new()
+            };
+            if head == "quote".into() && args.len() == 1 {
+                args[0].clone()
+            } else if head == "make-hash".into() && args.len() == 1 {
+                let mut assocs = Vec::new();
+                for assoc in args[0].iter() {
+                    match assoc {
+                        Expression::Pair(key, val) => assocs.push(Expression::Pair(
+                            key.clone(),
+                            Rc::new(RefCell::new(data_from_form(&val.borrow())?)),
+                        )),
+                        _ => assocs.push(assoc.clone()),
+                    }
+                }
+                build_map(HashMap::new(), &mut assocs.iter(), "read-data")?
+            } else {
+                let mut items = Vec::new();
+                let mut current = exp.clone();
+                while let Expression::Pair(car, cdr) = current {
+                    items.push(data_from_form(&car.borrow())?);
+                    current = cdr.borrow().clone();
+                }
+                let mut list = data_from_form(&current)?;
+                for item in items.into_iter().rev() {
+                    list =
+                        Expression::Pair(Rc::new(RefCell::new(item)), Rc::new(RefCell::new(list)));
+                }
+                list
+            }
+        }
+        _ => exp.clone(),
+    })
+}
+
+fn data_path(environment: &Environment, exp: &Expression) -> io::Result<String> {
+    let path = exp.as_string(environment)?;
+    Ok(match expand_tilde(&path) {
+        Some(path) => path,
+        None => path,
+    })
+}
+
+fn builtin_write_data(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(file) = args.next() {
+        if let Some(data) = args.next() {
+            if args.next().is_none() {
+                let file = eval(environment, file)?;
+                let data = eval(environment, data)?;
+                if has_cycle(&data) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "write-data: can not write data that contains itself",
+                    ));
+                }
+                let mut out = String::new();
+                write_data(&data, &mut out)?;
+                out.push('\n');
+                if let Expression::File(FileState::Write(f)) = &file {
+                    f.borrow_mut().write_all(out.as_bytes())?;
+                } else {
+                    // Write a temp file and rename so the old data is never
+                    // half replaced.
+                    let path = data_path(environment, &file)?;
+                    let tmp = format!("{}.tmp{}", path, std::process::id());
+                    fs::write(&tmp, out)?;
+                    fs::rename(&tmp, &path)?;
+                }
+                return Ok(Expression::Atom(Atom::Nil));
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "write-data takes a file (path or file opened for writing) and the data",
+    ))
+}
+
+fn builtin_read_data(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(file) = args.next() {
+        if args.next().is_none() {
+            let text = match eval(environment, file)? {
+                Expression::File(FileState::Read(f)) => {
+                    let mut text = String::new();
+                    f.borrow_mut().read_to_string(&mut text)?;
+                    text
+                }
+                file => fs::read_to_string(data_path(environment, &file)?)?,
+            };
+            let bad_data = |reason: String| {
+                let msg = format!("read-data: {}", reason);
+                io::Error::new(io::ErrorKind::Other, msg)
+            };
+            return match read_form(&text) {
+                Ok(Some((form, end))) => {
+                    if !text[end..].trim().is_empty() {
+                        return Err(bad_data("more than one form in file".to_string()));
+                    }
+                    data_from_form(&form)
+                }
+                Ok(None) => Err(bad_data("no data in file".to_string())),
+                Err(err) => Err(bad_data(err.reason)),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "read-data takes a file (path or file opened for reading)",
+    ))
+}
+
 pub fn add_io_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
     data.insert("open".to_string(), Rc::new(Expression::Func(builtin_open)));
     data.insert(
@@ -296,4 +995,67 @@ pub fn add_io_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>
         "write-string".to_string(),
         Rc::new(Expression::Func(builtin_write_string)),
     );
+    data.insert(
+        "coproc".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_coproc,
+            "Start a command in the background with it's stdin and stdout connected to the shell, returns a hashmap of :proc, :in (file to write) and :out (file to read).",
+        )),
+    );
+    data.insert(
+        "tee".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_tee,
+            "Eval forms with their output (prints and commands) going to stdout and also to targets, a file name, a file open for writing or a list of them.",
+        )),
+    );
+    data.insert(
+        "tee-err".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_tee_err,
+            "Eval forms with their error output going to stderr and also to targets, a file name, a file open for writing or a list of them.",
+        )),
+    );
+    data.insert(
+        "gzip-compress".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_gzip_compress,
+            "Compress a string or byte vector to a byte vector, or wrap a file so what is read (or written) is compressed.",
+        )),
+    );
+    data.insert(
+        "gzip-decompress".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_gzip_decompress,
+            "Decompress a byte vector (or string) to a string (byte vector if not UTF-8), or wrap a file so what is read (or written) is decompressed.",
+        )),
+    );
+    data.insert(
+        "zstd-compress".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_zstd_compress,
+            "Like gzip-compress using zstd.",
+        )),
+    );
+    data.insert(
+        "zstd-decompress".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_zstd_decompress,
+            "Like gzip-decompress using zstd.",
+        )),
+    );
+    data.insert(
+        "write-data".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_write_data,
+            "Usage: (write-data file data) Write data (nil, true, numbers, strings, chars, symbols, lists, vectors and hashmaps) to file (a path, replaced all at once, or a file opened for writing) as text read-data reads back.",
+        )),
+    );
+    data.insert(
+        "read-data".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_read_data,
+            "Usage: (read-data file) Read data written by write-data from file (a path or file opened for reading), nothing in it is evaluated.",
+        )),
+    );
 }
@@ -0,0 +1,380 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::lsp::{parse_json, Json};
+use crate::types::*;
+
+// Objects become hashmaps (string keys), arrays vectors, false and null nil.
+// Numbers with a fraction or exponent (or too big for an int) are floats.
+fn json_to_expression(json: Json) -> Expression {
+    match json {
+        Json::Null | Json::Bool(false) => Expression::Atom(Atom::Nil),
+        Json::Bool(true) => Expression::Atom(Atom::True),
+        Json::Int(i) => Expression::Atom(Atom::Int(i)),
+        Json::Number(n) => Expression::Atom(Atom::Float(n)),
+        Json::Str(s) => Expression::Atom(Atom::String(s.into())),
+        Json::Array(items) => {
+            Expression::with_list(items.into_iter().map(json_to_expression).collect())
+        }
+        Json::Object(fields) => {
+            let mut map = HashMap::with_capacity(fields.len());
+            for (key, val) in fields {
+                map.insert(key, Rc::new(json_to_expression(val)));
+            }
+            Expression::HashMap(Rc::new(RefCell::new(map)))
+        }
+    }
+}
+
+fn builtin_json_parse(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            // Capture the output of a command given directly.
+            let old_out = environment.state.stdout_status.clone();
+            environment.state.stdout_status = Some(IOState::Pipe);
+            let text = eval(environment, arg);
+            environment.state.stdout_status = old_out;
+            let text = text?.as_string(environment)?;
+            return match parse_json(&text) {
+                Ok(json) => Ok(json_to_expression(json)),
+                Err(err) => {
+                    let msg = format!("json-parse: {}", err);
+                    Err(io::Error::new(io::ErrorKind::Other, msg))
+                }
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "json-parse takes one form (a string or process)",
+    ))
+}
+
+#[derive(Debug)]
+enum Step {
+    Key(String),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>),
+    Iterate,
+    Keys,
+    Length,
+}
+
+fn query_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("query: {}", msg))
+}
+
+struct QueryParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    query: &'a str,
+}
+
+impl<'a> QueryParser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().map_or(false, char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, what: &str) -> io::Error {
+        query_error(format!("{} at {} in {}", what, self.pos, self.query))
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while self
+            .peek()
+            .map_or(false, |ch| ch == '_' || ch.is_alphanumeric())
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        // Skip the opening quote.
+        self.pos += 1;
+        let mut res = String::new();
+        while let Some(ch) = self.peek() {
+            self.pos += 1;
+            match ch {
+                '"' => return Ok(res),
+                '\\' => {
+                    if let Some(ch) = self.peek() {
+                        self.pos += 1;
+                        res.push(ch);
+                    }
+                }
+                _ => res.push(ch),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn int(&mut self) -> io::Result<Option<i64>> {
+        self.skip_ws();
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        while self.peek().map_or(false, |ch| ch.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let num: String = self.chars[start..self.pos].iter().collect();
+        match num.parse::<i64>() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(self.error("invalid index")),
+        }
+    }
+
+    fn expect(&mut self, ch: char) -> io::Result<()> {
+        self.skip_ws();
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", ch)))
+        }
+    }
+
+    // What is inside [], the [ has been read.
+    fn bracket(&mut self) -> io::Result<Step> {
+        self.skip_ws();
+        let step = match self.peek() {
+            Some(']') => Step::Iterate,
+            Some('"') => Step::Key(self.string()?),
+            _ => {
+                let start = self.int()?;
+                self.skip_ws();
+                if self.peek() == Some(':') {
+                    self.pos += 1;
+                    Step::Slice(start, self.int()?)
+                } else {
+                    match start {
+                        Some(idx) => Step::Index(idx),
+                        None => return Err(self.error("expected an index, slice or string")),
+                    }
+                }
+            }
+        };
+        self.expect(']')?;
+        Ok(step)
+    }
+
+    fn parse(&mut self) -> io::Result<Vec<Step>> {
+        let mut steps = Vec::new();
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                None => return Ok(steps),
+                Some('|') => self.pos += 1,
+                Some('.') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('"') => steps.push(Step::Key(self.string()?)),
+                        Some(ch) if ch == '_' || ch.is_alphabetic() => {
+                            steps.push(Step::Key(self.ident()))
+                        }
+                        // Just . (identity) or .[...].
+                        _ => {}
+                    }
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    steps.push(self.bracket()?);
+                }
+                Some(ch) if ch.is_alphabetic() => match &self.ident()[..] {
+                    "keys" => steps.push(Step::Keys),
+                    "length" => steps.push(Step::Length),
+                    name => return Err(self.error(&format!("unknown function {}", name))),
+                },
+                Some(ch) => return Err(self.error(&format!("unexpected {}", ch))),
+            }
+        }
+    }
+}
+
+fn items(val: &Expression) -> Option<Vec<Expression>> {
+    match val {
+        Expression::Vector(list) => Some(list.borrow().clone()),
+        Expression::Pair(_, _) => Some(val.iter().cloned().collect()),
+        Expression::Atom(Atom::Nil) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+// Index from the end if negative, None if out of range.
+fn position(idx: i64, len: usize) -> Option<usize> {
+    let idx = if idx < 0 { len as i64 + idx } else { idx };
+    if idx >= 0 && (idx as usize) < len {
+        Some(idx as usize)
+    } else {
+        None
+    }
+}
+
+fn clamp(idx: Option<i64>, default: usize, len: usize) -> usize {
+    match idx {
+        Some(idx) if idx < 0 => (len as i64 + idx).max(0) as usize,
+        Some(idx) => (idx as usize).min(len),
+        None => default,
+    }
+}
+
+// Run the steps on val, each result (more than one after []) goes in out.
+fn run_steps(steps: &[Step], val: Expression, out: &mut Vec<Expression>) -> io::Result<()> {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => {
+            out.push(val);
+            return Ok(());
+        }
+    };
+    let cant = |what: &str| query_error(format!("can not {} a {}", what, val.display_type()));
+    let next = match step {
+        Step::Key(key) => match &val {
+            Expression::HashMap(map) => {
+                let map = map.borrow();
+                // Keyword keyed hashmaps work without the colon.
+                match map.get(key).or_else(|| map.get(&format!(":{}", key))) {
+                    Some(v) => (**v).clone(),
+                    None => Expression::Atom(Atom::Nil),
+                }
+            }
+            Expression::Atom(Atom::Nil) => Expression::Atom(Atom::Nil),
+            _ => return Err(cant(&format!("get .{} of", key))),
+        },
+        Step::Index(idx) => match items(&val) {
+            Some(list) => match position(*idx, list.len()) {
+                Some(i) => list[i].clone(),
+                None => Expression::Atom(Atom::Nil),
+            },
+            None => return Err(cant("index")),
+        },
+        Step::Slice(start, end) => match items(&val) {
+            Some(list) => {
+                let start = clamp(*start, 0, list.len());
+                let end = clamp(*end, list.len(), list.len());
+                if start < end {
+                    Expression::with_list(list[start..end].to_vec())
+                } else {
+                    Expression::with_list(Vec::new())
+                }
+            }
+            None => return Err(cant("slice")),
+        },
+        Step::Iterate => {
+            let all: Vec<Expression> = match &val {
+                Expression::HashMap(map) => {
+                    let map = map.borrow();
+                    let mut keys: Vec<&String> = map.keys().collect();
+                    keys.sort();
+                    keys.iter().map(|k| (*map[*k]).clone()).collect()
+                }
+                _ => match items(&val) {
+                    Some(list) => list,
+                    None => return Err(cant("iterate")),
+                },
+            };
+            for item in all {
+                run_steps(rest, item, out)?;
+            }
+            return Ok(());
+        }
+        Step::Keys => match &val {
+            Expression::HashMap(map) => {
+                let mut keys: Vec<String> = map.borrow().keys().cloned().collect();
+                keys.sort();
+                Expression::with_list(
+                    keys.into_iter()
+                        .map(|k| Expression::Atom(Atom::String(k.into())))
+                        .collect(),
+                )
+            }
+            _ => match items(&val) {
+                Some(list) => Expression::with_list(
+                    (0..list.len())
+                        .map(|i| Expression::Atom(Atom::Int(i as i64)))
+                        .collect(),
+                ),
+                None => return Err(cant("get the keys of")),
+            },
+        },
+        Step::Length => {
+            let len = match &val {
+                Expression::HashMap(map) => map.borrow().len(),
+                Expression::Atom(Atom::String(s)) => s.chars().count(),
+                Expression::Atom(Atom::StringBuf(s)) => s.borrow().chars().count(),
+                _ => match items(&val) {
+                    Some(list) => list.len(),
+                    None => return Err(cant("get the length of")),
+                },
+            };
+            Expression::Atom(Atom::Int(len as i64))
+        }
+    };
+    run_steps(rest, next, out)
+}
+
+fn builtin_query(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let (Some(data), Some(query)) = (args.next(), args.next()) {
+        if args.next().is_none() {
+            let data = eval(environment, data)?;
+            let query = eval(environment, query)?.as_string(environment)?;
+            let steps = QueryParser {
+                chars: query.chars().collect(),
+                pos: 0,
+                query: &query,
+            }
+            .parse()?;
+            let mut out = Vec::new();
+            run_steps(&steps, data, &mut out)?;
+            // With [] there can be any number of results.
+            return if steps.iter().any(|s| matches!(s, Step::Iterate)) {
+                Ok(Expression::with_list(out))
+            } else {
+                Ok(out.pop().unwrap_or(Expression::Atom(Atom::Nil)))
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "query takes data and a query string",
+    ))
+}
+
+pub fn add_json_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "json-parse".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_json_parse,
+            "Parse a JSON string (or process output), objects are hashmaps, arrays vectors and false and null nil.",
+        )),
+    );
+    data.insert(
+        "query".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_query,
+            "Get part of nested hashmaps, vectors and lists with a jq style path like .a.b[0] or .items[].name, with [] the result is a vector of every match.",
+        )),
+    );
+}
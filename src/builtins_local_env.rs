@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::builtins::load;
+use crate::builtins_util::expand_tilde;
+use crate::environment::*;
+use crate::eval::*;
+use crate::paths::data_dir;
+use crate::types::*;
+
+// Loaded from a trusted directory, the first one found.
+const LOCAL_ENV_FILES: &[&str] = &[".slshrc.local", ".envrc.slsh"];
+
+// Set by the shell itself, not something the file exported.
+const UNTRACKED_VARS: &[&str] = &["LAST_STATUS", "PWD", "OLDPWD"];
+
+// A loaded local env file and the environment variables it changed with their
+// old values (None if it was not set), put back when the directory is left.
+#[derive(Clone, Debug)]
+pub struct LocalEnv {
+    pub dir: PathBuf,
+    pub file: PathBuf,
+    saved: Vec<(String, Option<String>)>,
+}
+
+fn trusted_file() -> PathBuf {
+    data_dir().join("trusted-dirs")
+}
+
+// The directory trees allowed with trust-dir, one path per line.
+fn trusted_dirs() -> Vec<PathBuf> {
+    match fs::read_to_string(trusted_file()) {
+        Ok(text) => text
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn write_trusted_dirs(dirs: &[PathBuf]) -> io::Result<()> {
+    let file = trusted_file();
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut text = String::new();
+    for dir in dirs {
+        text.push_str(&dir.to_string_lossy());
+        text.push('\n');
+    }
+    fs::write(file, text)
+}
+
+// The directories (and their file) from dir up to the top of its trusted tree
+// that have a local env file, outermost first.
+fn local_env_files(dir: &Path, trusted: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+    let mut found = Vec::new();
+    for dir in dir.ancestors() {
+        // The parents of an untrusted directory are not trusted either.
+        if !trusted.iter().any(|t| dir.starts_with(t)) {
+            break;
+        }
+        let file = LOCAL_ENV_FILES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|file| file.is_file());
+        if let Some(file) = file {
+            found.push((dir.to_path_buf(), file));
+        }
+    }
+    found.reverse();
+    found
+}
+
+fn env_snapshot() -> HashMap<String, String> {
+    env::vars()
+        .filter(|(key, _)| !UNTRACKED_VARS.contains(&&key[..]))
+        .collect()
+}
+
+// Load file and note what it changed in the environment.
+fn apply_local_env(environment: &mut Environment, dir: PathBuf, file: PathBuf) -> LocalEnv {
+    let before = env_snapshot();
+    eprintln!("Loading {}", file.display());
+    if let Err(err) = load(environment, &file.to_string_lossy()) {
+        eprintln!("Error loading {}: {}", file.display(), err);
+    }
+    let after = env_snapshot();
+    let mut saved = Vec::new();
+    for (key, val) in &after {
+        match before.get(key) {
+            Some(old) if old == val => {}
+            old => saved.push((key.clone(), old.cloned())),
+        }
+    }
+    for (key, old) in before {
+        if !after.contains_key(&key) {
+            saved.push((key, Some(old)));
+        }
+    }
+    LocalEnv { dir, file, saved }
+}
+
+fn unwind_local_env(local: &LocalEnv) {
+    eprintln!("Unloading {}", local.file.display());
+    for (key, old) in &local.saved {
+        match old {
+            Some(old) => env::set_var(key, old),
+            None => env::remove_var(key),
+        }
+    }
+}
+
+// Unwind the local envs of the directories left and load the ones for the
+// directories entered since the last call, the interactive shell calls this
+// before each prompt.
+pub fn update_local_envs(environment: &mut Environment) {
+    let dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+    if environment.local_env_dir.as_ref() == Some(&dir) {
+        return;
+    }
+    environment.local_env_dir = Some(dir.clone());
+    let wanted = local_env_files(&dir, &trusted_dirs());
+    let keep = environment
+        .local_envs
+        .iter()
+        .zip(wanted.iter())
+        .take_while(|(local, (dir, file))| local.dir == *dir && local.file == *file)
+        .count();
+    while environment.local_envs.len() > keep {
+        if let Some(local) = environment.local_envs.pop() {
+            unwind_local_env(&local);
+        }
+    }
+    for (dir, file) in wanted.into_iter().skip(keep) {
+        let local = apply_local_env(environment, dir, file);
+        environment.local_envs.push(local);
+    }
+}
+
+// The directory given (or the current one) as an absolute path without links.
+fn dir_arg(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<PathBuf> {
+    let dir = match args.next() {
+        Some(arg) => {
+            if args.next().is_some() {
+                let msg = format!("{} takes zero or one form (a directory)", form);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+            let dir = eval(environment, arg)?.as_string(environment)?;
+            PathBuf::from(expand_tilde(&dir).unwrap_or(dir))
+        }
+        None => env::current_dir()?,
+    };
+    match fs::canonicalize(&dir) {
+        Ok(dir) if dir.is_dir() => Ok(dir),
+        Ok(_) => {
+            let msg = format!("{}: {} is not a directory", form, dir.display());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+        Err(err) => {
+            let msg = format!("{}: {}: {}", form, dir.display(), err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_trust_dir(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let dir = dir_arg(environment, args, "trust-dir")?;
+    let mut trusted = trusted_dirs();
+    if !trusted.contains(&dir) {
+        trusted.push(dir.clone());
+        write_trusted_dirs(&trusted)?;
+    }
+    // Check again at the next prompt.
+    environment.local_env_dir = None;
+    Ok(Expression::Atom(Atom::String(
+        dir.to_string_lossy().to_string().into(),
+    )))
+}
+
+fn builtin_untrust_dir(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let dir = dir_arg(environment, args, "untrust-dir")?;
+    let mut trusted = trusted_dirs();
+    let len = trusted.len();
+    trusted.retain(|t| *t != dir);
+    if trusted.len() == len {
+        return Ok(Expression::Atom(Atom::Nil));
+    }
+    write_trusted_dirs(&trusted)?;
+    environment.local_env_dir = None;
+    Ok(Expression::Atom(Atom::True))
+}
+
+fn builtin_trusted_dirs(
+    _environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "trusted-dirs takes no arguments",
+        ));
+    }
+    Ok(Expression::with_list(
+        trusted_dirs()
+            .iter()
+            .map(|dir| Expression::Atom(Atom::String(dir.to_string_lossy().to_string().into())))
+            .collect(),
+    ))
+}
+
+pub fn add_local_env_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "trust-dir".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_trust_dir,
+            "Allow the interactive shell to load the .slshrc.local (or .envrc.slsh) files in a directory tree (the current directory if none given), returns the directory.",
+        )),
+    );
+    data.insert(
+        "untrust-dir".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_untrust_dir,
+            "Stop loading local env files in a directory tree allowed by trust-dir (the current directory if none given), nil if it was not trusted.",
+        )),
+    );
+    data.insert(
+        "trusted-dirs".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_trusted_dirs,
+            "Vector of the directory trees allowed by trust-dir.",
+        )),
+    );
+}
@@ -5,8 +5,193 @@ use std::rc::Rc;
 
 use crate::builtins_util::*;
 use crate::environment::*;
+use crate::eval::*;
 use crate::types::*;
 
+// Put sep between each group of three digits of the integer part of num.
+fn group_thousands(num: &str, sep: &str) -> String {
+    let (sign, rest) = if num.starts_with('-') {
+        num.split_at(1)
+    } else {
+        ("", num)
+    };
+    let (int_part, frac_part) = match rest.find('.') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    let mut grouped = String::new();
+    for (i, ch) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 {
+            grouped.push_str(sep);
+        }
+        grouped.push(ch);
+    }
+    format!("{}{}{}", sign, grouped, frac_part)
+}
+
+fn builtin_num_format(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let num = match args.next() {
+        Some(num) => eval(environment, num)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "num-format takes a number and options",
+            ))
+        }
+    };
+    let mut places = None;
+    let mut sep = ",".to_string();
+    let mut percent = false;
+    let mut radix = None;
+    while let Some(arg) = args.next() {
+        let opt = eval(environment, arg)?;
+        match &opt {
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":places" => {
+                places = match args.next().map(|a| eval(environment, a)) {
+                    Some(Ok(Expression::Atom(Atom::Int(p)))) if p >= 0 => Some(p as usize),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "num-format: :places takes a positive integer",
+                        ))
+                    }
+                }
+            }
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":sep" => {
+                sep = match args.next().map(|a| eval(environment, a)) {
+                    Some(Ok(Expression::Atom(Atom::String(s)))) => s.to_string(),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "num-format: :sep takes a string",
+                        ))
+                    }
+                }
+            }
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":percent" => percent = true,
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":hex" => radix = Some(16),
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":oct" => radix = Some(8),
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":bin" => radix = Some(2),
+            _ => {
+                let msg = format!(
+                    "num-format: unknown option {}, expected :places n, :sep string, :percent, :hex, :oct or :bin",
+                    opt
+                );
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    if let Some(radix) = radix {
+        return match num {
+            Expression::Atom(Atom::Int(i)) => Ok(Expression::Atom(Atom::String(
+                int_to_radix(i, radix).into(),
+            ))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "num-format: :hex, :oct and :bin need an integer",
+            )),
+        };
+    }
+    let mut res = match (&num, places, percent) {
+        (Expression::Atom(Atom::Int(i)), None, false) => i.to_string(),
+        _ => {
+            let mut f = num.make_float(environment)?;
+            if percent {
+                f *= 100.0;
+            }
+            match places {
+                Some(places) => format!("{:.*}", places, f),
+                None => f.to_string(),
+            }
+        }
+    };
+    res = group_thousands(&res, &sep);
+    if percent {
+        res.push('%');
+    }
+    Ok(Expression::Atom(Atom::String(res.into())))
+}
+
+fn builtin_bytes_human(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(bytes) = args.next() {
+        let si = match args.next() {
+            Some(arg) => match eval(environment, arg)? {
+                Expression::Atom(Atom::Symbol(ref s)) if &**s == ":si" => true,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "bytes-human: the only option is :si",
+                    ))
+                }
+            },
+            None => false,
+        };
+        if args.next().is_none() {
+            let bytes = eval(environment, bytes)?.make_float(environment)?;
+            let unit = if si { 1000.0 } else { 1024.0 };
+            let mut size = bytes.abs();
+            let mut suffix = "";
+            for s in &["K", "M", "G", "T", "P", "E"] {
+                if size < unit {
+                    break;
+                }
+                size /= unit;
+                suffix = s;
+            }
+            let sign = if bytes < 0.0 { "-" } else { "" };
+            // Like ls -h, one decimal place for small numbers.
+            let res = if suffix.is_empty() {
+                format!("{}{}", sign, size.round())
+            } else if size < 10.0 {
+                format!("{}{:.1}{}", sign, size, suffix)
+            } else {
+                format!("{}{}{}", sign, size.round(), suffix)
+            };
+            return Ok(Expression::Atom(Atom::String(res.into())));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "bytes-human takes a number of bytes and optionally :si",
+    ))
+}
+
+fn builtin_float_str(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(num) = args.next() {
+        let precision = match args.next() {
+            Some(precision) => match eval(environment, precision)? {
+                Expression::Atom(Atom::Int(p)) if p >= 0 => Some(p as usize),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "float-str: precision must be a positive integer",
+                    ))
+                }
+            },
+            None => None,
+        };
+        if args.next().is_none() {
+            let num = eval(environment, num)?.make_float(environment)?;
+            return Ok(Expression::Atom(Atom::String(
+                float_str(num, precision).into(),
+            )));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "float-str takes a number and an optional precision",
+    ))
+}
+
 pub fn add_math_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
     data.insert(
         "+".to_string(),
@@ -136,4 +321,26 @@ pub fn add_math_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expressio
             },
         )),
     );
+
+    data.insert(
+        "num-format".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_num_format,
+            "Format a number, options are :places n (fixed decimal places), :sep string (between thousands, default \",\" and \"\" for none), :percent (times 100 with a %) and :hex, :oct or :bin for an integer.",
+        )),
+    );
+    data.insert(
+        "bytes-human".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_bytes_human,
+            "Format a number of bytes like ls -h (1.4G), :si uses powers of 1000 instead of 1024.",
+        )),
+    );
+    data.insert(
+        "float-str".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_float_str,
+            "Return a number as a float string with precision digits after the point, or the shortest form that reads back as the same float.",
+        )),
+    );
 }
@@ -0,0 +1,657 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::suggest::*;
+use crate::types::*;
+
+#[derive(Clone, Debug)]
+pub enum OptionType {
+    Bool,
+    Int,
+    String,
+    // One of these keywords.
+    Enum(Vec<String>),
+}
+
+impl OptionType {
+    fn describe(&self) -> String {
+        match self {
+            OptionType::Bool => "t or nil".to_string(),
+            OptionType::Int => "an integer".to_string(),
+            OptionType::String => "a string".to_string(),
+            OptionType::Enum(choices) => format!("one of {}", choices.join(" ")),
+        }
+    }
+
+    fn valid(&self, value: &Expression) -> bool {
+        match (self, value) {
+            (OptionType::Bool, Expression::Atom(Atom::True)) => true,
+            (OptionType::Bool, Expression::Atom(Atom::Nil)) => true,
+            (OptionType::Int, Expression::Atom(Atom::Int(_))) => true,
+            (OptionType::String, Expression::Atom(Atom::String(_))) => true,
+            (OptionType::Enum(choices), Expression::Atom(Atom::Symbol(s))) => {
+                choices.iter().any(|c| **c == **s)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShellOption {
+    pub kind: OptionType,
+    pub value: Expression,
+    pub doc: String,
+    // Functions called with (name old new) after the option is set.
+    pub hooks: Vec<Expression>,
+    // Keeps an environment field in sync for the builtin options that need it.
+    pub apply: Option<fn(&mut Environment, &Expression)>,
+}
+
+fn builtin_option(
+    kind: OptionType,
+    value: Expression,
+    doc: &str,
+    apply: Option<fn(&mut Environment, &Expression)>,
+) -> ShellOption {
+    ShellOption {
+        kind,
+        value,
+        doc: doc.to_string(),
+        hooks: Vec::new(),
+        apply,
+    }
+}
+
+fn keywords(choices: &[&str]) -> OptionType {
+    OptionType::Enum(choices.iter().map(|c| c.to_string()).collect())
+}
+
+fn keyword(name: &str) -> Expression {
+    Expression::Atom(Atom::Symbol(name.into()))
+}
+
+fn apply_error_stack(environment: &mut Environment, value: &Expression) {
+    environment.stack_on_error = match value {
+        Expression::Atom(Atom::Nil) => false,
+        _ => true,
+    };
+}
+
+fn apply_max_eval_depth(environment: &mut Environment, value: &Expression) {
+    environment.max_eval_depth = match value {
+        Expression::Atom(Atom::Int(i)) if *i > 0 => *i as u32,
+        // Only the stack limits it.
+        _ => u32::max_value(),
+    };
+}
+
+fn apply_print_float_precision(_environment: &mut Environment, value: &Expression) {
+    if let Expression::Atom(Atom::Int(i)) = value {
+        set_float_precision(*i);
+    }
+}
+
+// The options every environment starts with.
+pub fn default_options() -> HashMap<String, ShellOption> {
+    let mut options = HashMap::new();
+    options.insert(
+        "auto-correct".to_string(),
+        builtin_option(
+            keywords(&[":off", ":ask"]),
+            keyword(":off"),
+            "Ask whether to run the closest match to a command that is not found (:ask) or not (:off).",
+            None,
+        ),
+    );
+    options.insert(
+        "capture-spill-bytes".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(16 * 1024 * 1024)),
+            "Captured process output bigger than this is kept in an unlinked temp file instead of memory until it is read, 0 to always keep it in memory.",
+            None,
+        ),
+    );
+    options.insert(
+        "cd-correct".to_string(),
+        builtin_option(
+            keywords(&[":suggest", ":ask", ":auto", ":off"]),
+            keyword(":suggest"),
+            "What cd does with the closest match to a directory that does not exist, add it to the error (:suggest), ask to change to it (:ask), change to it (:auto) or nothing (:off).",
+            None,
+        ),
+    );
+    options.insert(
+        "exit-jobs".to_string(),
+        builtin_option(
+            keywords(&[":on-hangup", ":hup", ":term", ":disown"]),
+            keyword(":on-hangup"),
+            "What happens to jobs when the shell exits, SIGHUP only if the terminal hung up (:on-hangup), SIGHUP (:hup), SIGTERM (:term) or nothing (:disown).",
+            None,
+        ),
+    );
+    options.insert(
+        "exit-with-jobs".to_string(),
+        builtin_option(
+            keywords(&[":warn", ":ok"]),
+            keyword(":warn"),
+            "Warn and only exit on the second try when there are jobs (:warn) or just exit (:ok).",
+            None,
+        ),
+    );
+    options.insert(
+        "error-stack".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::Nil),
+            "Print the eval stack on error.",
+            Some(apply_error_stack),
+        ),
+    );
+    options.insert(
+        "export-separator".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String(":".into())),
+            "What export joins the items of a list or vector with.",
+            None,
+        ),
+    );
+    options.insert(
+        "keybindings".to_string(),
+        builtin_option(
+            keywords(&[":emacs", ":vi"]),
+            keyword(":emacs"),
+            "Line editor key bindings, :emacs or :vi.",
+            None,
+        ),
+    );
+    options.insert(
+        "loose-symbols".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::True),
+            "Evaluate unbound symbols as their name in forms typed at the prompt, given with -c or read from stdin so command arguments need no quotes.",
+            None,
+        ),
+    );
+    options.insert(
+        "max-eval-depth".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(i64::from(DEFAULT_MAX_EVAL_DEPTH))),
+            "Deepest nesting of evals before a :stack-depth error, 0 for no limit.  Evals also stop with this error before running out of stack.",
+            Some(apply_max_eval_depth),
+        ),
+    );
+    options.insert(
+        "max-history".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(1000)),
+            "Commands kept in the history and the history file, 0 (or less) to not save history or trim the file.",
+            None,
+        ),
+    );
+    options.insert(
+        "print-color".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::Nil),
+            "Color REPL results and pprint output written to a terminal.",
+            None,
+        ),
+    );
+    options.insert(
+        "print-float-precision".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(-1)),
+            "Digits after the point when printing floats, negative for the shortest form that reads back as the same float.",
+            Some(apply_print_float_precision),
+        ),
+    );
+    options.insert(
+        "print-indent".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(4)),
+            "Spaces each level of a pretty printed form that does not fit on a line is indented.",
+            None,
+        ),
+    );
+    options.insert(
+        "print-length".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(1000)),
+            "Items of a list, vector or hashmap shown in REPL results and pprint output before the rest is left out, 0 for no limit.",
+            None,
+        ),
+    );
+    options.insert(
+        "print-level".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(0)),
+            "Nesting shown in REPL results and pprint output before deeper forms are printed as ..., 0 for no limit.",
+            None,
+        ),
+    );
+    options.insert(
+        "print-lines".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(1000)),
+            "Lines of a long string or result shown in REPL results and pprint output, 0 for no limit.",
+            None,
+        ),
+    );
+    options.insert(
+        "print-readably".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::Nil),
+            "Make print, println, eprint and eprintln print like write (strings quoted and escaped).",
+            None,
+        ),
+    );
+    options.insert(
+        "print-width".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(0)),
+            "Pretty printing keeps lines shorter than this, 0 for the terminal width.",
+            None,
+        ),
+    );
+    options.insert(
+        "prompt".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String("".into())),
+            "The prompt when there is no __prompt function, \"\" for the default (host:dir(sl-sh::namespace)>).",
+            None,
+        ),
+    );
+    options.insert(
+        "remote-completion".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::Nil),
+            "Complete host:path arguments to scp, sftp and rsync by listing the path on the host over ssh (shares the ssh-run master connection).",
+            None,
+        ),
+    );
+    options.insert(
+        "remote-completion-cache".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(30)),
+            "Seconds a remote completion listing is reused before asking the host again.",
+            None,
+        ),
+    );
+    options.insert(
+        "share-history".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::True),
+            "Add the commands other sessions save to the history file to this session's history before each prompt.",
+            None,
+        ),
+    );
+    options.insert(
+        "status-as-bool".to_string(),
+        builtin_option(
+            OptionType::Bool,
+            Expression::Atom(Atom::True),
+            "A finished process is true in a conditional only if it exited 0, nil makes every process true.",
+            None,
+        ),
+    );
+    options.insert(
+        "vi-esc-sequence".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String("".into())),
+            "Two keys that act as escape in vi insert mode when typed within vi-esc-timeout of each other (like \"jk\"), \"\" for none.",
+            None,
+        ),
+    );
+    options.insert(
+        "vi-esc-timeout".to_string(),
+        builtin_option(
+            OptionType::Int,
+            Expression::Atom(Atom::Int(200)),
+            "Milliseconds to wait for the second key of vi-esc-sequence.",
+            None,
+        ),
+    );
+    options.insert(
+        "vi-insert-prompt-prefix".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String("".into())),
+            "Put before the last line of the prompt in vi insert mode.",
+            None,
+        ),
+    );
+    options.insert(
+        "vi-insert-prompt-suffix".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String("".into())),
+            "Put after the last line of the prompt in vi insert mode.",
+            None,
+        ),
+    );
+    options.insert(
+        "vi-normal-prompt-prefix".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String("".into())),
+            "Put before the last line of the prompt in vi normal mode.",
+            None,
+        ),
+    );
+    options.insert(
+        "vi-normal-prompt-suffix".to_string(),
+        builtin_option(
+            OptionType::String,
+            Expression::Atom(Atom::String("".into())),
+            "Put after the last line of the prompt in vi normal mode.",
+            None,
+        ),
+    );
+    options
+}
+
+fn unknown_option(form: &str, name: &str, environment: &Environment) -> io::Error {
+    let suggestions = closest(name, environment.options.keys().cloned());
+    let msg = format!(
+        "{}: unknown option {}.{}",
+        form,
+        name,
+        did_you_mean(&suggestions)
+    );
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+// Value of an option, nil if there is no such option.
+pub fn get_option(environment: &Environment, name: &str) -> Expression {
+    match environment.options.get(name) {
+        Some(option) => option.value.clone(),
+        None => Expression::Atom(Atom::Nil),
+    }
+}
+
+// Value of an enum option as a string (the keyword), empty if not an enum.
+pub fn get_option_keyword(environment: &Environment, name: &str) -> String {
+    match get_option(environment, name) {
+        Expression::Atom(Atom::Symbol(s)) => s.to_string(),
+        _ => String::new(),
+    }
+}
+
+// Validate and set an option then call it's change hooks.
+pub fn set_option(environment: &mut Environment, name: &str, value: Expression) -> io::Result<()> {
+    let (old, hooks, apply) = match environment.options.get_mut(name) {
+        Some(option) => {
+            if !option.kind.valid(&value) {
+                let msg = format!(
+                    "set-option!: {} must be {}, not {}",
+                    name,
+                    option.kind.describe(),
+                    value
+                );
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+            let old = std::mem::replace(&mut option.value, value.clone());
+            (old, option.hooks.clone(), option.apply)
+        }
+        None => return Err(unknown_option("set-option!", name, environment)),
+    };
+    if let Some(apply) = apply {
+        apply(environment, &value);
+    }
+    for hook in hooks {
+        let args = vec![
+            quoted(keyword(name)),
+            quoted(old.clone()),
+            quoted(value.clone()),
+        ];
+        if let Err(err) = fn_call(environment, &hook, Box::new(args.iter())) {
+            if is_exit(&err) {
+                return Err(err);
+            }
+            eprintln!("Error in change hook for option {}: {}", name, err);
+        }
+    }
+    Ok(())
+}
+
+fn quoted(exp: Expression) -> Expression {
+    Expression::cons_from_vec(&mut vec![keyword("quote"), exp])
+}
+
+fn option_name(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+) -> io::Result<String> {
+    if let Some(arg) = arg {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(s)) => return Ok(s.to_string()),
+            Expression::Atom(Atom::String(s)) => return Ok(s.to_string()),
+            _ => {}
+        }
+    }
+    let msg = format!("{} requires an option name", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_get_option(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let name = option_name(environment, args.next(), "option")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "option takes an option name",
+        ));
+    }
+    match environment.options.get(&name) {
+        Some(option) => Ok(option.value.clone()),
+        None => Err(unknown_option("option", &name, environment)),
+    }
+}
+
+fn builtin_set_option(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let name = option_name(environment, args.next(), "set-option!")?;
+    if let Some(value) = args.next() {
+        if args.next().is_none() {
+            let value = eval(environment, value)?;
+            set_option(environment, &name, value.clone())?;
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "set-option! takes an option name and a value",
+    ))
+}
+
+fn builtin_defoption(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage = "defoption takes a name, a type (:bool, :int, :string or a list of keywords), a default and an optional doc string";
+    let name = option_name(environment, args.next(), "defoption")?;
+    let kind = match args.next() {
+        Some(kind) => match eval(environment, kind)? {
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":bool" => OptionType::Bool,
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":int" => OptionType::Int,
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":string" => OptionType::String,
+            choices @ Expression::Vector(_) | choices @ Expression::Pair(_, _) => {
+                let mut names = Vec::new();
+                for choice in choices.iter() {
+                    match choice {
+                        Expression::Atom(Atom::Symbol(s)) if s.starts_with(':') => {
+                            names.push(s.to_string())
+                        }
+                        _ => {
+                            let msg = format!("defoption: {} choices must be keywords", name);
+                            return Err(io::Error::new(io::ErrorKind::Other, msg));
+                        }
+                    }
+                }
+                OptionType::Enum(names)
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+        },
+        None => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let value = match args.next() {
+        Some(value) => eval(environment, value)?,
+        None => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let doc = match args.next() {
+        Some(doc) => eval(environment, doc)?.as_string(environment)?,
+        None => String::new(),
+    };
+    if args.next().is_some() {
+        return Err(io::Error::new(io::ErrorKind::Other, usage));
+    }
+    if !kind.valid(&value) {
+        let msg = format!(
+            "defoption: {} default must be {}, not {}",
+            name,
+            kind.describe(),
+            value
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    // Redefining (reloading a config) keeps the hooks.
+    let hooks = match environment.options.get(&name) {
+        Some(_) if default_options().contains_key(&name) => {
+            let msg = format!("defoption: can not redefine builtin option {}", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        Some(option) => option.hooks.clone(),
+        None => Vec::new(),
+    };
+    environment.options.insert(
+        name.clone(),
+        ShellOption {
+            kind,
+            value,
+            doc,
+            hooks,
+            apply: None,
+        },
+    );
+    Ok(keyword(&name))
+}
+
+fn builtin_on_option_change(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let name = option_name(environment, args.next(), "on-option-change")?;
+    if let Some(hook) = args.next() {
+        if args.next().is_none() {
+            let hook = eval(environment, hook)?;
+            return match environment.options.get_mut(&name) {
+                Some(option) => {
+                    option.hooks.push(hook.clone());
+                    Ok(hook)
+                }
+                None => Err(unknown_option("on-option-change", &name, environment)),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "on-option-change takes an option name and a function",
+    ))
+}
+
+fn builtin_options(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(name) = args.next() {
+        let name = option_name(environment, Some(name), "options")?;
+        if args.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "options takes an optional option name",
+            ));
+        }
+        return match environment.options.get(&name) {
+            Some(option) => Ok(Expression::Atom(Atom::String(
+                format!(
+                    "{} ({}) is {}\n{}",
+                    name,
+                    option.kind.describe(),
+                    option.value,
+                    option.doc
+                )
+                .into(),
+            ))),
+            None => Err(unknown_option("options", &name, environment)),
+        };
+    }
+    let mut names: Vec<&String> = environment.options.keys().collect();
+    names.sort();
+    let names = names.into_iter().map(|n| keyword(n)).collect();
+    Ok(Expression::with_list(names))
+}
+
+pub fn add_options_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "option".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_get_option,
+            "Value of a shell option, (option 'cd-correct).",
+        )),
+    );
+    data.insert(
+        "set-option!".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_set_option,
+            "Set a shell option after checking the value is valid for it, then call it's change hooks, returns the value.",
+        )),
+    );
+    data.insert(
+        "defoption".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_defoption,
+            "Define an option with a type (:bool, :int, :string or a list of keywords), a default and an optional doc string.",
+        )),
+    );
+    data.insert(
+        "on-option-change".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_on_option_change,
+            "Call a function with (name old-value new-value) after an option is set.",
+        )),
+    );
+    data.insert(
+        "options".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_options,
+            "Vector of the option names, or with a name a description of the option.",
+        )),
+    );
+}
@@ -6,6 +6,7 @@ use std::rc::Rc;
 
 use crate::environment::*;
 use crate::eval::*;
+use crate::gc::*;
 use crate::types::*;
 
 fn builtin_join(
@@ -121,6 +122,9 @@ fn builtin_xar(
                         }
                     }
                     Expression::Pair(e1, _e2) => {
+                        if gc_may_cycle(&arg) {
+                            gc_track(environment, GcCandidate::Cell(Rc::downgrade(e1)));
+                        }
                         e1.replace(arg);
                     }
                     _ => {
@@ -171,6 +175,9 @@ fn builtin_xdr(
                         }
                     }
                     Expression::Pair(_e1, e2) => {
+                        if gc_may_cycle(&arg) {
+                            gc_track(environment, GcCandidate::Cell(Rc::downgrade(e2)));
+                        }
                         e2.replace(arg);
                     }
                     _ => {
@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::{self, Read};
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        signal::{self, Signal},
+        wait::waitpid,
+    },
+    unistd::{self, ForkResult, Pid},
+};
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::process::{spawn_argv, try_wait_pid};
+use crate::reader::*;
+use crate::types::*;
+
+// A forked worker, its result arrives on fd.
+struct Worker {
+    pid: Pid,
+    fd: RawFd,
+    index: usize,
+    output: Vec<u8>,
+}
+
+fn nix_err(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+fn seq_items(seq: &Expression, form: &str) -> io::Result<Vec<Expression>> {
+    match seq {
+        Expression::Vector(list) => Ok(list.borrow().clone()),
+        Expression::Pair(_, _) => Ok(seq.iter().cloned().collect()),
+        Expression::Atom(Atom::Nil) => Ok(Vec::new()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} requires a list or vector, got {}",
+                form,
+                seq.display_type()
+            ),
+        )),
+    }
+}
+
+fn write_all(fd: RawFd, mut buf: &[u8]) {
+    while !buf.is_empty() {
+        match unistd::write(fd, buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf = &buf[n..],
+        }
+    }
+}
+
+// Runs in the forked child, sends the result to the parent and exits.
+// A command's output is its result, strings are sent raw and anything else as
+// its printed form (tagged with S, V or E for an error).
+fn run_worker(environment: &mut Environment, func: &Expression, item: Expression, fd: RawFd) -> ! {
+    environment.do_job_control = false;
+    environment.is_tty = false;
+    environment.state.stdout_status = Some(IOState::Pipe);
+    let args = vec![Expression::cons_from_vec(&mut vec![
+        Expression::Atom(Atom::Symbol("quote".into())),
+        item,
+    ])];
+    let res = fn_call(environment, func, Box::new(args.iter())).and_then(|res| match res {
+        Expression::Process(_) => Ok(Expression::Atom(Atom::String(
+            res.as_string(environment)?.into(),
+        ))),
+        _ => Ok(res),
+    });
+    let (msg, code) = match res {
+        Ok(Expression::Atom(Atom::String(s))) => (format!("S{}", s), 0),
+        Ok(res) => (format!("V{}", res), 0),
+        Err(err) => (format!("E{}", err), 1),
+    };
+    write_all(fd, msg.as_bytes());
+    let _ = io::Write::flush(&mut io::stdout());
+    // Skip destructors, they belong to the parent.
+    unsafe { libc::_exit(code) }
+}
+
+fn decode(output: &[u8]) -> Result<Expression, String> {
+    let msg = String::from_utf8_lossy(output).to_string();
+    if msg.is_empty() {
+        return Err("worker exited without a result".to_string());
+    }
+    let (tag, body) = msg.split_at(1);
+    match tag {
+        "S" => Ok(Expression::Atom(Atom::String(body.into()))),
+        "V" => match read_form(body) {
+            Ok(Some((res, _))) => Ok(res),
+            Ok(None) => Ok(Expression::Atom(Atom::Nil)),
+            Err(err) => Err(err.reason),
+        },
+        _ => Err(body.to_string()),
+    }
+}
+
+fn stop_workers(workers: &[Worker]) {
+    for worker in workers {
+        let _ = signal::kill(worker.pid, Signal::SIGKILL);
+        let _ = unistd::close(worker.fd);
+        let _ = waitpid(worker.pid, None);
+    }
+}
+
+fn builtin_pmap(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (max, func, seq) = match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(max), Some(func), Some(seq), None) => (
+            eval(environment, max)?,
+            eval(environment, func)?,
+            eval(environment, seq)?,
+        ),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pmap takes a max number of workers, a function and a sequence",
+            ))
+        }
+    };
+    let max = match max {
+        Expression::Atom(Atom::Int(i)) if i > 0 => i as usize,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pmap: workers must be a positive integer",
+            ))
+        }
+    };
+    let is_vec = if let Expression::Vector(_) = seq {
+        true
+    } else {
+        false
+    };
+    let mut items = seq_items(&seq, "pmap")?.into_iter().enumerate();
+    let mut results: Vec<Expression> = Vec::new();
+    let mut workers: Vec<Worker> = Vec::new();
+    let mut error: Option<String> = None;
+    loop {
+        while error.is_none() && workers.len() < max {
+            let (index, item) = match items.next() {
+                Some(next) => next,
+                None => break,
+            };
+            let (read_fd, write_fd) = unistd::pipe().map_err(nix_err)?;
+            match unistd::fork() {
+                Ok(ForkResult::Child) => {
+                    let _ = unistd::close(read_fd);
+                    run_worker(environment, &func, item, write_fd);
+                }
+                Ok(ForkResult::Parent { child }) => {
+                    let _ = unistd::close(write_fd);
+                    results.push(Expression::Atom(Atom::Nil));
+                    workers.push(Worker {
+                        pid: child,
+                        fd: read_fd,
+                        index,
+                        output: Vec::new(),
+                    });
+                }
+                Err(err) => {
+                    let _ = unistd::close(read_fd);
+                    let _ = unistd::close(write_fd);
+                    error = Some(format!("fork failed: {}", err));
+                }
+            }
+        }
+        if workers.is_empty() {
+            break;
+        }
+        if environment.sig_int.swap(false, Ordering::Relaxed) {
+            error = Some("interrupted by SIGINT".to_string());
+        }
+        if error.is_some() {
+            stop_workers(&workers);
+            break;
+        }
+        let mut fds: Vec<PollFd> = workers
+            .iter()
+            .map(|w| PollFd::new(w.fd, PollFlags::POLLIN))
+            .collect();
+        match poll(&mut fds, 100) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(err) => {
+                stop_workers(&workers);
+                return Err(nix_err(err));
+            }
+        }
+        let mut done = Vec::new();
+        for (i, fd) in fds.iter().enumerate() {
+            if fd.revents().map_or(true, |r| r.is_empty()) {
+                continue;
+            }
+            let mut buf = [0; 4096];
+            match unistd::read(workers[i].fd, &mut buf) {
+                Ok(0) | Err(_) => done.push(i),
+                Ok(n) => workers[i].output.extend_from_slice(&buf[..n]),
+            }
+        }
+        for i in done.into_iter().rev() {
+            let worker = workers.remove(i);
+            let _ = unistd::close(worker.fd);
+            let _ = waitpid(worker.pid, None);
+            match decode(&worker.output) {
+                Ok(res) => results[worker.index] = res,
+                Err(err) => {
+                    if error.is_none() {
+                        error = Some(format!("item {}: {}", worker.index, err));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(err) = error {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("pmap: {}", err),
+        ));
+    }
+    if is_vec {
+        Ok(Expression::with_list(results))
+    } else {
+        Ok(Expression::cons_from_vec(&mut results))
+    }
+}
+
+// Placeholder in an each-arg template for the items.
+const PLACEHOLDER: &str = "{}";
+
+// The argv for one batch: a word that is the placeholder becomes the items, a
+// word containing it is repeated for each item and with no placeholder the
+// items are appended.
+fn batch_argv(template: &[String], batch: &[String]) -> Vec<String> {
+    let mut argv = Vec::new();
+    let mut placed = false;
+    for word in template {
+        if word == PLACEHOLDER {
+            argv.extend(batch.iter().cloned());
+            placed = true;
+        } else if word.contains(PLACEHOLDER) && !argv.is_empty() {
+            for item in batch {
+                argv.push(word.replace(PLACEHOLDER, item));
+            }
+            placed = true;
+        } else {
+            argv.push(word.clone());
+        }
+    }
+    if !placed {
+        argv.extend(batch.iter().cloned());
+    }
+    argv
+}
+
+// A running each-arg command and the thread reading it's output when stdout
+// is piped.
+struct Batch {
+    proc: ProcessHandle,
+    index: usize,
+    output: Option<thread::JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+// Kill and reap the running commands (an error or interrupt).
+fn stop_batches(environment: &Environment, running: &mut Vec<Batch>) {
+    for batch in running.drain(..) {
+        let pid = Pid::from_raw(batch.proc.pid() as i32);
+        let _ = signal::kill(pid, Signal::SIGKILL);
+        let _ = waitpid(pid, None);
+        // Drops it from the shell's processes and jobs.
+        try_wait_pid(environment, batch.proc.pid());
+    }
+}
+
+fn builtin_each_arg(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage =
+        "each-arg takes a command template (list), a sequence and options :batch n, :parallel n";
+    let (template, seq) = match (args.next(), args.next()) {
+        (Some(template), Some(seq)) => (eval(environment, template)?, eval(environment, seq)?),
+        _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+    };
+    let mut batch = 1;
+    let mut parallel = 1;
+    while let Some(arg) = args.next() {
+        let key = eval(environment, arg)?;
+        let val = match args.next().map(|v| eval(environment, v)) {
+            Some(Ok(Expression::Atom(Atom::Int(i)))) if i > 0 => i as usize,
+            Some(Err(err)) => return Err(err),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+        };
+        match key {
+            Expression::Atom(Atom::Symbol(ref k)) if &**k == ":batch" => batch = val,
+            Expression::Atom(Atom::Symbol(ref k)) if &**k == ":parallel" => parallel = val,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, usage)),
+        }
+    }
+    let mut words = Vec::new();
+    for word in seq_items(&template, "each-arg")? {
+        words.push(word.as_string(environment)?);
+    }
+    if words.is_empty() || words[0] == PLACEHOLDER {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "each-arg: the template must start with a command",
+        ));
+    }
+    let mut items = Vec::new();
+    for item in seq_items(&seq, "each-arg")? {
+        items.push(item.as_string(environment)?);
+    }
+    let piped = matches!(environment.state.stdout_status, Some(IOState::Pipe));
+    let mut batches = items.chunks(batch).enumerate();
+    let mut running: Vec<Batch> = Vec::new();
+    let mut outputs: Vec<Vec<u8>> = Vec::new();
+    let mut ok = true;
+    loop {
+        while running.len() < parallel {
+            let (index, argv) = match batches.next() {
+                Some((index, b)) => (index, batch_argv(&words, b)),
+                None => break,
+            };
+            match spawn_argv(environment, &argv) {
+                Ok(proc) => {
+                    // Read while it runs or it blocks once the pipe is full.
+                    let output = proc.take_stdout().map(|mut out| {
+                        thread::spawn(move || {
+                            let mut buf = Vec::new();
+                            out.read_to_end(&mut buf).map(|_| buf)
+                        })
+                    });
+                    outputs.push(Vec::new());
+                    running.push(Batch {
+                        proc,
+                        index,
+                        output,
+                    });
+                }
+                Err(err) => {
+                    stop_batches(environment, &mut running);
+                    let msg = format!("each-arg: {}", err);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            }
+        }
+        if running.is_empty() {
+            break;
+        }
+        if environment.sig_int.swap(false, Ordering::Relaxed) {
+            stop_batches(environment, &mut running);
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "each-arg: interrupted by SIGINT",
+            ));
+        }
+        let mut i = 0;
+        let mut reaped = false;
+        while i < running.len() {
+            let (stop, exit) = try_wait_pid(environment, running[i].proc.pid());
+            let pid = running[i].proc.pid();
+            // A stopped batch is still running (its output reader would block) so keep it going.
+            let stopped = stop && environment.procs.borrow().contains_key(&pid);
+            if stopped {
+                environment.stopped_procs.borrow_mut().retain(|p| *p != pid);
+                mark_job_running(environment, pid);
+                let _ = signal::kill(Pid::from_raw(pid as i32), Signal::SIGCONT);
+            }
+            if !stop || stopped {
+                i += 1;
+                continue;
+            }
+            let batch = running.remove(i);
+            reaped = true;
+            ok = ok && exit.map_or(false, |exit| exit.status() == 0);
+            if let Some(output) = batch.output {
+                match output.join() {
+                    Ok(Ok(buf)) => outputs[batch.index] = buf,
+                    Ok(Err(err)) => {
+                        stop_batches(environment, &mut running);
+                        return Err(err);
+                    }
+                    Err(_) => {
+                        stop_batches(environment, &mut running);
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "each-arg: failed to read command output",
+                        ));
+                    }
+                }
+            }
+        }
+        if !reaped {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    if piped {
+        // In a pipe (or captured) the output is the result, in batch order.
+        let output = String::from_utf8_lossy(&outputs.concat()).to_string();
+        return Ok(Expression::Atom(Atom::String(output.into())));
+    }
+    Ok(if ok {
+        Expression::Atom(Atom::True)
+    } else {
+        Expression::Atom(Atom::Nil)
+    })
+}
+
+pub fn add_parallel_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "pmap".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pmap,
+            "Call function on each item of a list or vector in up to n forked shells at once, the results are returned in order (a command's result is its output).",
+        )),
+    );
+    data.insert(
+        "each-arg".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_each_arg,
+            "Run a command template (list of words, {} marks where the items go) for batches of items from a sequence, options :batch n and :parallel n.  Output goes to the shell's stdout (out>, err> apply), returns t if every run succeeded or the output when stdout is piped.",
+        )),
+    );
+}
@@ -0,0 +1,487 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::persistent::*;
+use crate::types::*;
+
+// Builtins for the persistent vector (pvec) and map (phash).  None of these
+// change their arguments, updates produce a new pvec or phash that shares
+// most of it's structure with the old one.
+
+fn eval_args(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Vec<Expression>> {
+    let mut list = Vec::new();
+    for arg in args {
+        list.push(eval(environment, arg)?);
+    }
+    Ok(list)
+}
+
+fn phash_key(key: &Expression, form: &str) -> io::Result<String> {
+    match key {
+        Expression::Atom(Atom::Symbol(s)) => Ok(s.to_string()),
+        Expression::Atom(Atom::String(s)) => Ok(s.to_string()),
+        Expression::Atom(Atom::StringBuf(s)) => Ok(s.borrow().to_string()),
+        _ => {
+            let msg = format!("{} key can only be a symbol or string", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn pvec_arg<'a>(exp: &'a Expression, form: &str) -> io::Result<&'a PVec> {
+    match exp {
+        Expression::PVec(pvec) => Ok(pvec),
+        _ => {
+            let msg = format!("{} needs a pvec, got {}", form, exp.display_type());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn phash_arg<'a>(exp: &'a Expression, form: &str) -> io::Result<&'a PMap> {
+    match exp {
+        Expression::PMap(phash) => Ok(phash),
+        _ => {
+            let msg = format!("{} needs a phash, got {}", form, exp.display_type());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn pvec_index(idx: &Expression, pvec: &PVec, form: &str) -> io::Result<usize> {
+    match idx {
+        Expression::Atom(Atom::Int(i)) if *i >= 0 && (*i as usize) < pvec.len() => Ok(*i as usize),
+        Expression::Atom(Atom::Int(_)) => {
+            let msg = format!("{} index out of range", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+        _ => {
+            let msg = format!("{} index must be an int", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_pvec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let items = eval_args(environment, args)?;
+    Ok(Expression::PVec(PVec::from_vec(items)))
+}
+
+fn builtin_pvec_nth(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pvec-nth takes an index and a pvec",
+        ));
+    }
+    let pvec = pvec_arg(&args[1], "pvec-nth")?;
+    let idx = pvec_index(&args[0], pvec, "pvec-nth")?;
+    Ok(pvec.get(idx).unwrap().clone())
+}
+
+fn builtin_pvec_set(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut args = eval_args(environment, args)?;
+    if args.len() != 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pvec-set takes an index, a value and a pvec",
+        ));
+    }
+    let pvec = pvec_arg(&args[2], "pvec-set")?.clone();
+    let idx = pvec_index(&args[0], &pvec, "pvec-set")?;
+    let item = std::mem::replace(&mut args[1], Expression::Atom(Atom::Nil));
+    Ok(Expression::PVec(pvec.set(idx, item).unwrap()))
+}
+
+fn builtin_pvec_push(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pvec-push takes a pvec and values to push",
+        ));
+    }
+    let mut pvec = pvec_arg(&args[0], "pvec-push")?.clone();
+    for item in args.into_iter().skip(1) {
+        pvec = pvec.push(item);
+    }
+    Ok(Expression::PVec(pvec))
+}
+
+fn builtin_pvec_pop(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pvec-pop takes a pvec",
+        ));
+    }
+    match pvec_arg(&args[0], "pvec-pop")?.pop() {
+        Some(pvec) => Ok(Expression::PVec(pvec)),
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pvec-pop on an empty pvec",
+        )),
+    }
+}
+
+fn builtin_pvec_to_vec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pvec->vec takes a pvec",
+        ));
+    }
+    Ok(Expression::with_list(
+        pvec_arg(&args[0], "pvec->vec")?.to_vec(),
+    ))
+}
+
+fn builtin_vec_to_pvec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "vec->pvec takes a vector or list",
+        ));
+    }
+    match &args[0] {
+        Expression::Vector(list) => Ok(Expression::PVec(PVec::from_vec(list.borrow().clone()))),
+        Expression::Pair(_, _) | Expression::Atom(Atom::Nil) => Ok(Expression::PVec(
+            PVec::from_vec(args[0].iter().cloned().collect()),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "vec->pvec takes a vector or list",
+        )),
+    }
+}
+
+fn builtin_is_pvec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    match args.as_slice() {
+        [Expression::PVec(_)] => Ok(Expression::Atom(Atom::True)),
+        [_] => Ok(Expression::Atom(Atom::Nil)),
+        _ => Err(io::Error::new(io::ErrorKind::Other, "pvec? takes one form")),
+    }
+}
+
+// Add the key value pairs in args to phash.
+fn phash_assoc(mut phash: PMap, args: Vec<Expression>, form: &str) -> io::Result<PMap> {
+    if args.len() % 2 != 0 {
+        let msg = format!("{} needs a value for each key", form);
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    let mut args = args.into_iter();
+    while let (Some(key), Some(val)) = (args.next(), args.next()) {
+        phash = phash.insert(phash_key(&key, form)?, val);
+    }
+    Ok(phash)
+}
+
+fn builtin_phash(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    Ok(Expression::PMap(phash_assoc(
+        PMap::default(),
+        args,
+        "phash",
+    )?))
+}
+
+fn builtin_phash_get(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 && args.len() != 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash-get takes a phash, a key and an optional default",
+        ));
+    }
+    let phash = phash_arg(&args[0], "phash-get")?;
+    match phash.get(&phash_key(&args[1], "phash-get")?) {
+        Some(val) => Ok(val.clone()),
+        None => Ok(args.get(2).cloned().unwrap_or(Expression::Atom(Atom::Nil))),
+    }
+}
+
+fn builtin_phash_haskey(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash-haskey takes a phash and a key",
+        ));
+    }
+    let phash = phash_arg(&args[0], "phash-haskey")?;
+    match phash.get(&phash_key(&args[1], "phash-haskey")?) {
+        Some(_) => Ok(Expression::Atom(Atom::True)),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+fn builtin_phash_assoc(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut args = eval_args(environment, args)?;
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash-assoc takes a phash and key value pairs",
+        ));
+    }
+    let phash = phash_arg(&args.remove(0), "phash-assoc")?.clone();
+    Ok(Expression::PMap(phash_assoc(phash, args, "phash-assoc")?))
+}
+
+fn builtin_phash_dissoc(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash-dissoc takes a phash and keys to remove",
+        ));
+    }
+    let mut phash = phash_arg(&args[0], "phash-dissoc")?.clone();
+    for key in &args[1..] {
+        phash = phash.remove(&phash_key(key, "phash-dissoc")?);
+    }
+    Ok(Expression::PMap(phash))
+}
+
+fn builtin_phash_keys(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash-keys takes a phash",
+        ));
+    }
+    let keys = phash_arg(&args[0], "phash-keys")?
+        .items()
+        .into_iter()
+        .map(|(key, _)| Expression::Atom(Atom::Symbol(key.as_str().into())))
+        .collect();
+    Ok(Expression::with_list(keys))
+}
+
+fn builtin_phash_to_hash(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash->hash takes a phash",
+        ));
+    }
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    for (key, val) in phash_arg(&args[0], "phash->hash")?.items() {
+        map.insert(key.clone(), Rc::new(val.clone()));
+    }
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+fn builtin_hash_to_phash(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    if let [Expression::HashMap(map)] = args.as_slice() {
+        let mut phash = PMap::default();
+        for (key, val) in map.borrow().iter() {
+            phash = phash.insert(key.clone(), (**val).clone());
+        }
+        return Ok(Expression::PMap(phash));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "hash->phash takes a hashmap",
+    ))
+}
+
+fn builtin_is_phash(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args = eval_args(environment, args)?;
+    match args.as_slice() {
+        [Expression::PMap(_)] => Ok(Expression::Atom(Atom::True)),
+        [_] => Ok(Expression::Atom(Atom::Nil)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "phash? takes one form",
+        )),
+    }
+}
+
+pub fn add_persistent_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "pvec".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pvec,
+            "Produces a persistent vector with the provided objects as elements.",
+        )),
+    );
+    data.insert(
+        "pvec-nth".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pvec_nth,
+            "Produces the element of a pvec at the index (0 based), error if out of bounds.",
+        )),
+    );
+    data.insert(
+        "pvec-set".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pvec_set,
+            "Produces a new pvec with the element at index set to the value, (pvec-set idx val pvec).",
+        )),
+    );
+    data.insert(
+        "pvec-push".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pvec_push,
+            "Produces a new pvec with the values added to the end.",
+        )),
+    );
+    data.insert(
+        "pvec-pop".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pvec_pop,
+            "Produces a new pvec without the last element.",
+        )),
+    );
+    data.insert(
+        "pvec->vec".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pvec_to_vec,
+            "Returns a (mutable) vector with the items of the pvec.",
+        )),
+    );
+    data.insert(
+        "vec->pvec".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_vec_to_pvec,
+            "Returns a pvec with the items of the vector or list.",
+        )),
+    );
+    data.insert(
+        "pvec?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_is_pvec,
+            "True if obj is a persistent vector.",
+        )),
+    );
+    data.insert(
+        "phash".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash,
+            "Produces a persistent map from keys (symbols or strings) and values, (phash :a 1 :b 2).",
+        )),
+    );
+    data.insert(
+        "phash-get".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash_get,
+            "Get the value of key in a phash, the default (or nil) if not there.",
+        )),
+    );
+    data.insert(
+        "phash-haskey".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash_haskey,
+            "True if the phash has key.",
+        )),
+    );
+    data.insert(
+        "phash-assoc".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash_assoc,
+            "Produces a new phash with the key value pairs set, (phash-assoc m :a 1 :b 2).",
+        )),
+    );
+    data.insert(
+        "phash-dissoc".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash_dissoc,
+            "Produces a new phash without the keys.",
+        )),
+    );
+    data.insert(
+        "phash-keys".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash_keys,
+            "Returns a vector of the keys of a phash (sorted).",
+        )),
+    );
+    data.insert(
+        "phash->hash".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_phash_to_hash,
+            "Returns a (mutable) hashmap with the keys and values of the phash.",
+        )),
+    );
+    data.insert(
+        "hash->phash".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_hash_to_phash,
+            "Returns a phash with the keys and values of the hashmap.",
+        )),
+    );
+    data.insert(
+        "phash?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_is_phash,
+            "True if obj is a persistent map.",
+        )),
+    );
+}
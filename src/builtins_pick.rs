@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+
+use nix::sys::termios::{self, SpecialCharacterIndices, Termios};
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// Lines used by the query and status lines at the top of the picker.
+const HEADER_LINES: usize = 2;
+
+// Puts the controlling terminal in raw mode on the alternate screen and
+// restores it when dropped so every exit path leaves the terminal sane.
+struct RawTerm {
+    tty: File,
+    orig: Termios,
+}
+
+impl RawTerm {
+    fn new() -> io::Result<RawTerm> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let fd = tty.as_raw_fd();
+        let orig = termios::tcgetattr(fd).map_err(to_io_error)?;
+        let mut raw = orig.clone();
+        termios::cfmakeraw(&mut raw);
+        // Reads return after 100ms with no input so a lone escape can be detected.
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+        termios::tcsetattr(fd, termios::SetArg::TCSANOW, &raw).map_err(to_io_error)?;
+        let mut term = RawTerm { tty, orig };
+        term.tty.write_all(b"\x1b[?1049h\x1b[H\x1b[2J")?;
+        Ok(term)
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let res = unsafe { libc::ioctl(self.tty.as_raw_fd(), libc::TIOCGWINSZ, &mut ws) };
+        if res == 0 && ws.ws_row > 0 && ws.ws_col > 0 {
+            (ws.ws_row as usize, ws.ws_col as usize)
+        } else {
+            (24, 80)
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0; 1];
+        match self.tty.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+}
+
+impl Drop for RawTerm {
+    fn drop(&mut self) {
+        let _ = self.tty.write_all(b"\x1b[?1049l");
+        let _ = self.tty.flush();
+        if let Err(err) =
+            termios::tcsetattr(self.tty.as_raw_fd(), termios::SetArg::TCSANOW, &self.orig)
+        {
+            eprintln!("Error resetting terminal settings after pick: {}", err);
+        }
+    }
+}
+
+fn to_io_error(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{}", err))
+}
+
+enum Key {
+    Char(char),
+    Backspace,
+    Enter,
+    Tab,
+    Up,
+    Down,
+    ClearQuery,
+    Cancel,
+}
+
+fn read_key(term: &mut RawTerm) -> io::Result<Option<Key>> {
+    let byte = match term.read_byte()? {
+        Some(b) => b,
+        None => return Ok(None),
+    };
+    let key = match byte {
+        3 | 7 => Some(Key::Cancel),      // ctrl-c, ctrl-g
+        8 | 127 => Some(Key::Backspace), // ctrl-h, del
+        9 => Some(Key::Tab),             // tab
+        10 | 13 => Some(Key::Enter),     // ctrl-j, enter
+        11 | 16 => Some(Key::Up),        // ctrl-k, ctrl-p
+        14 => Some(Key::Down),           // ctrl-n
+        21 => Some(Key::ClearQuery),     // ctrl-u
+        27 => match term.read_byte()? {
+            // Lone escape cancels, otherwise look for the arrow keys.
+            None => Some(Key::Cancel),
+            Some(b'[') | Some(b'O') => match term.read_byte()? {
+                Some(b'A') => Some(Key::Up),
+                Some(b'B') => Some(Key::Down),
+                _ => None,
+            },
+            Some(_) => None,
+        },
+        b if b < 32 => None,
+        b if b < 128 => Some(Key::Char(b as char)),
+        b => {
+            // Collect the rest of a utf8 sequence.
+            let len = if b >= 0xf0 {
+                4
+            } else if b >= 0xe0 {
+                3
+            } else {
+                2
+            };
+            let mut bytes = vec![b];
+            while bytes.len() < len {
+                match term.read_byte()? {
+                    Some(b) => bytes.push(b),
+                    None => break,
+                }
+            }
+            match String::from_utf8(bytes) {
+                Ok(s) => s.chars().next().map(Key::Char),
+                Err(_) => None,
+            }
+        }
+    };
+    Ok(key)
+}
+
+// Score a candidate against the (lowercase) pattern, None if it does not match.
+// Every pattern char must appear in order, consecutive matches and matches at
+// the start of a word score higher and earlier matches beat later ones.
+fn fuzzy_score(pattern: &[char], candidate: &str) -> Option<i64> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    let mut score = 0;
+    let mut pi = 0;
+    let mut last_match: Option<usize> = None;
+    let mut prev_ch = ' ';
+    for (i, ch) in candidate.chars().enumerate() {
+        if pi == pattern.len() {
+            break;
+        }
+        let lower = ch.to_lowercase().next().unwrap_or(ch);
+        if lower == pattern[pi] {
+            score += 1;
+            if let Some(last) = last_match {
+                if last + 1 == i {
+                    score += 5;
+                }
+            }
+            if i == 0 || !prev_ch.is_alphanumeric() {
+                score += 3;
+            }
+            if pi == 0 {
+                score -= i.min(10) as i64;
+            }
+            last_match = Some(i);
+            pi += 1;
+        }
+        prev_ch = ch;
+    }
+    if pi == pattern.len() {
+        Some(score)
+    } else {
+        None
+    }
+}
+
+fn filter_items(query: &str, items: &[String]) -> Vec<usize> {
+    let pattern: Vec<char> = query.to_lowercase().chars().collect();
+    let mut scored: Vec<(i64, usize)> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| fuzzy_score(&pattern, item).map(|s| (s, i)))
+        .collect();
+    // Stable sort so equal scores keep their original order.
+    scored.sort_by(|a, b| b.0.cmp(&a.0));
+    scored.drain(..).map(|(_, i)| i).collect()
+}
+
+fn truncate_line(line: &str, width: usize) -> String {
+    line.chars()
+        .map(|ch| if ch.is_control() { ' ' } else { ch })
+        .take(width)
+        .collect()
+}
+
+struct PickState {
+    prompt: String,
+    query: String,
+    matches: Vec<usize>,
+    selected: usize,
+    offset: usize,
+    marked: Vec<bool>,
+    multi: bool,
+}
+
+fn draw(term: &mut RawTerm, state: &mut PickState, items: &[String]) -> io::Result<()> {
+    let (rows, cols) = term.size();
+    let list_rows = if rows > HEADER_LINES {
+        rows - HEADER_LINES
+    } else {
+        1
+    };
+    if state.selected < state.offset {
+        state.offset = state.selected;
+    } else if state.selected >= state.offset + list_rows {
+        state.offset = state.selected + 1 - list_rows;
+    }
+    let mut out = String::new();
+    out.push_str("\x1b[H\x1b[2J");
+    let query_line = format!("{}{}", state.prompt, state.query);
+    out.push_str(&truncate_line(&query_line, cols));
+    out.push_str("\r\n");
+    let marked = state.marked.iter().filter(|m| **m).count();
+    let status = if state.multi {
+        format!(
+            "  {}/{} ({} marked)",
+            state.matches.len(),
+            items.len(),
+            marked
+        )
+    } else {
+        format!("  {}/{}", state.matches.len(), items.len())
+    };
+    out.push_str("\x1b[2m");
+    out.push_str(&truncate_line(&status, cols));
+    out.push_str("\x1b[0m");
+    for (row, idx) in state
+        .matches
+        .iter()
+        .enumerate()
+        .skip(state.offset)
+        .take(list_rows)
+    {
+        out.push_str("\r\n");
+        let mark = if state.marked[*idx] { "* " } else { "  " };
+        let line = truncate_line(&format!("{}{}", mark, items[*idx]), cols);
+        if row == state.selected {
+            out.push_str("\x1b[7m");
+            out.push_str(&line);
+            out.push_str("\x1b[0m");
+        } else {
+            out.push_str(&line);
+        }
+    }
+    let cursor_col = query_line.chars().count().min(cols.saturating_sub(1)) + 1;
+    out.push_str(&format!("\x1b[1;{}H", cursor_col));
+    term.tty.write_all(out.as_bytes())?;
+    term.tty.flush()
+}
+
+// Run the picker, returns the indexes of the chosen items (empty if cancelled).
+fn run_pick(items: &[String], prompt: &str, multi: bool) -> io::Result<Vec<usize>> {
+    let mut term = RawTerm::new()?;
+    let mut state = PickState {
+        prompt: prompt.to_string(),
+        query: String::new(),
+        matches: filter_items("", items),
+        selected: 0,
+        offset: 0,
+        marked: vec![false; items.len()],
+        multi,
+    };
+    draw(&mut term, &mut state, items)?;
+    loop {
+        let key = match read_key(&mut term)? {
+            Some(key) => key,
+            None => continue,
+        };
+        match key {
+            Key::Char(ch) => {
+                state.query.push(ch);
+                state.matches = filter_items(&state.query, items);
+                state.selected = 0;
+            }
+            Key::Backspace => {
+                state.query.pop();
+                state.matches = filter_items(&state.query, items);
+                state.selected = 0;
+            }
+            Key::ClearQuery => {
+                state.query.clear();
+                state.matches = filter_items(&state.query, items);
+                state.selected = 0;
+            }
+            Key::Up => {
+                if state.selected > 0 {
+                    state.selected -= 1;
+                }
+            }
+            Key::Down => {
+                if state.selected + 1 < state.matches.len() {
+                    state.selected += 1;
+                }
+            }
+            Key::Tab => {
+                if multi {
+                    if let Some(idx) = state.matches.get(state.selected) {
+                        state.marked[*idx] = !state.marked[*idx];
+                    }
+                    if state.selected + 1 < state.matches.len() {
+                        state.selected += 1;
+                    }
+                }
+            }
+            Key::Enter => {
+                let mut chosen: Vec<usize> =
+                    (0..items.len()).filter(|i| state.marked[*i]).collect();
+                if chosen.is_empty() {
+                    if let Some(idx) = state.matches.get(state.selected) {
+                        chosen.push(*idx);
+                    }
+                }
+                return Ok(chosen);
+            }
+            Key::Cancel => return Ok(Vec::new()),
+        }
+        draw(&mut term, &mut state, items)?;
+    }
+}
+
+fn builtin_pick(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let items = if let Some(items) = args.next() {
+        eval(environment, items)?
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pick takes a sequence of items to choose from",
+        ));
+    };
+    let mut multi = false;
+    let mut prompt = "> ".to_string();
+    while let Some(arg) = args.next() {
+        match arg {
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":multi" => multi = true,
+            Expression::Atom(Atom::Symbol(s)) if &**s == ":prompt" => {
+                if let Some(p) = args.next() {
+                    prompt = eval(environment, p)?.as_string(environment)?;
+                } else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "pick: :prompt requires a string",
+                    ));
+                }
+            }
+            _ => {
+                let msg = format!("pick: invalid option {}", arg);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    let choices: Vec<Expression> = match &items {
+        Expression::Vector(list) => list.borrow().iter().cloned().collect(),
+        Expression::Pair(_, _) => items.iter().cloned().collect(),
+        Expression::Atom(Atom::Nil) => Vec::new(),
+        Expression::Atom(Atom::String(s)) => s
+            .lines()
+            .map(|l| Expression::Atom(Atom::String(l.into())))
+            .collect(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pick: items must be a list, vector or string (one item per line)",
+            ))
+        }
+    };
+    if choices.is_empty() {
+        return Ok(Expression::Atom(Atom::Nil));
+    }
+    let mut labels = Vec::with_capacity(choices.len());
+    for c in &choices {
+        labels.push(c.as_string(environment)?);
+    }
+    let chosen = run_pick(&labels, &prompt, multi)?;
+    if multi {
+        if chosen.is_empty() {
+            Ok(Expression::Atom(Atom::Nil))
+        } else {
+            Ok(Expression::with_list(
+                chosen.iter().map(|i| choices[*i].clone()).collect(),
+            ))
+        }
+    } else if let Some(i) = chosen.first() {
+        Ok(choices[*i].clone())
+    } else {
+        Ok(Expression::Atom(Atom::Nil))
+    }
+}
+
+// Line prompts (ask, choose and input) talk to the terminal directly so they
+// work when stdin or stdout are redirected.
+struct TtyPrompt {
+    tty: File,
+    reader: BufReader<File>,
+}
+
+impl TtyPrompt {
+    fn new(form: &str) -> io::Result<TtyPrompt> {
+        let tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .map_err(|err| {
+                let msg = format!("{}: can not open the terminal: {}", form, err);
+                io::Error::new(io::ErrorKind::Other, msg)
+            })?;
+        let reader = BufReader::new(tty.try_clone()?);
+        Ok(TtyPrompt { tty, reader })
+    }
+
+    fn say(&mut self, msg: &str) -> io::Result<()> {
+        self.tty.write_all(msg.as_bytes())?;
+        self.tty.flush()
+    }
+
+    // The trimmed answer, None at end of file (ctrl-d).
+    fn read_answer(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        self.say(prompt)?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            self.say("\n")?;
+            return Ok(None);
+        }
+        Ok(Some(line.trim().to_string()))
+    }
+}
+
+// Keyword options (:default etc) after the required arguments, only the
+// ones in allowed are valid.
+fn prompt_options(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+    allowed: &[&str],
+) -> io::Result<HashMap<String, Expression>> {
+    let mut options = HashMap::new();
+    while let Some(arg) = args.next() {
+        match (arg, args.next()) {
+            (Expression::Atom(Atom::Symbol(key)), Some(val)) if allowed.contains(&&**key) => {
+                options.insert(key.to_string(), eval(environment, val)?);
+            }
+            _ => {
+                let msg = format!(
+                    "{}: options are {} followed by a value",
+                    form,
+                    allowed.join(" ")
+                );
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn prompt_arg(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+    usage: &str,
+) -> io::Result<Expression> {
+    match arg {
+        Some(arg) => eval(environment, arg),
+        None => {
+            let msg = format!("{} takes {}", form, usage);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn builtin_ask(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let question = prompt_arg(
+        environment,
+        args.next(),
+        "ask",
+        "a question and [:default t/nil]",
+    )?
+    .as_string(environment)?;
+    let options = prompt_options(environment, args, "ask", &[":default"])?;
+    let default = options
+        .get(":default")
+        .map(|d| !matches!(d, Expression::Atom(Atom::Nil)));
+    let hint = match default {
+        Some(true) => "[Y/n]",
+        Some(false) => "[y/N]",
+        None => "[y/n]",
+    };
+    let mut tty = TtyPrompt::new("ask")?;
+    let prompt = format!("{} {} ", question, hint);
+    loop {
+        let yes = match tty.read_answer(&prompt)? {
+            None => default.unwrap_or(false),
+            Some(answer) => match &answer.to_lowercase()[..] {
+                "y" | "yes" => true,
+                "n" | "no" => false,
+                "" if default.is_some() => default.unwrap_or(false),
+                _ => {
+                    tty.say("Please answer y or n.\n")?;
+                    continue;
+                }
+            },
+        };
+        return Ok(if yes {
+            Expression::Atom(Atom::True)
+        } else {
+            Expression::Atom(Atom::Nil)
+        });
+    }
+}
+
+fn builtin_choose(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage = "a prompt, a list or vector of choices and [:default choice]";
+    let prompt = prompt_arg(environment, args.next(), "choose", usage)?.as_string(environment)?;
+    let items = prompt_arg(environment, args.next(), "choose", usage)?;
+    let choices: Vec<Expression> = match &items {
+        Expression::Vector(list) => list.borrow().iter().cloned().collect(),
+        Expression::Pair(_, _) => items.iter().cloned().collect(),
+        Expression::Atom(Atom::Nil) => Vec::new(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "choose: choices must be a list or vector",
+            ))
+        }
+    };
+    if choices.is_empty() {
+        return Ok(Expression::Atom(Atom::Nil));
+    }
+    let mut labels = Vec::with_capacity(choices.len());
+    for c in &choices {
+        labels.push(c.as_string(environment)?);
+    }
+    let options = prompt_options(environment, args, "choose", &[":default"])?;
+    let default = match options.get(":default") {
+        Some(default) => {
+            let default = default.as_string(environment)?;
+            match labels.iter().position(|l| *l == default) {
+                Some(idx) => Some(idx),
+                None => {
+                    let msg = format!("choose: default {} is not one of the choices", default);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            }
+        }
+        None => None,
+    };
+    let mut tty = TtyPrompt::new("choose")?;
+    let mut menu = String::new();
+    for (i, label) in labels.iter().enumerate() {
+        menu.push_str(&format!("{:>3}) {}\n", i + 1, label));
+    }
+    let prompt = match default {
+        Some(idx) => format!("{} [{}]: ", prompt, idx + 1),
+        None => format!("{} [1-{}]: ", prompt, labels.len()),
+    };
+    tty.say(&menu)?;
+    loop {
+        let idx = match tty.read_answer(&prompt)? {
+            None => return Ok(Expression::Atom(Atom::Nil)),
+            Some(answer) if answer.is_empty() && default.is_some() => default,
+            Some(answer) => match answer.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= labels.len() => Some(n - 1),
+                // A choice can be typed out as well.
+                _ => labels.iter().position(|l| *l == answer),
+            },
+        };
+        match idx {
+            Some(idx) => return Ok(choices[idx].clone()),
+            None => tty.say(&format!("Enter a number from 1 to {}.\n", labels.len()))?,
+        }
+    }
+}
+
+fn builtin_input(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let usage = "a prompt and [:default string] [:validate function]";
+    let prompt = prompt_arg(environment, args.next(), "input", usage)?.as_string(environment)?;
+    let options = prompt_options(environment, args, "input", &[":default", ":validate"])?;
+    let default = match options.get(":default") {
+        Some(default) => Some(default.as_string(environment)?),
+        None => None,
+    };
+    let validate = options.get(":validate");
+    let prompt = match &default {
+        Some(default) => format!("{} [{}]: ", prompt, default),
+        None => format!("{}: ", prompt),
+    };
+    let mut tty = TtyPrompt::new("input")?;
+    loop {
+        let answer = match tty.read_answer(&prompt)? {
+            None => return Ok(Expression::Atom(Atom::Nil)),
+            Some(answer) => match &default {
+                Some(default) if answer.is_empty() => default.clone(),
+                _ => answer,
+            },
+        };
+        // The validate function returns nil or a message to ask again.
+        if let Some(validate) = validate {
+            let arg = Expression::cons_from_vec(&mut vec![
+                Expression::Atom(Atom::Symbol("quote".into())),
+                Expression::Atom(Atom::String(answer.clone().into())),
+            ]);
+            let args = vec![arg];
+            match fn_call(environment, validate, Box::new(args.iter()))? {
+                Expression::Atom(Atom::Nil) => {
+                    tty.say("Invalid value, try again.\n")?;
+                    continue;
+                }
+                Expression::Atom(Atom::String(msg)) => {
+                    tty.say(&format!("{}\n", msg))?;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        return Ok(Expression::Atom(Atom::String(answer.into())));
+    }
+}
+
+pub fn add_pick_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "pick".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pick,
+            "Interactively fuzzy filter a sequence and return the selected item (nil if cancelled), :multi to mark several with tab and return a vector, :prompt to set the prompt.",
+        )),
+    );
+    data.insert(
+        "ask".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_ask,
+            "Ask a yes or no question on the terminal and return t or nil, :default is the answer for just enter.",
+        )),
+    );
+    data.insert(
+        "choose".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_choose,
+            "Show a numbered menu of choices on the terminal and return the chosen one (nil at end of input), :default is the choice for just enter.",
+        )),
+    );
+    data.insert(
+        "input".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_input,
+            "Read a line from the terminal, :default is used for an empty line and :validate is a function of the line that returns nil or a message to ask again.",
+        )),
+    );
+}
@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    poll::{poll, PollFd, PollFlags},
+    pty::openpty,
+    sys::signal::{self, SigHandler, Signal},
+    unistd,
+};
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// Seconds expect waits without a :timeout.
+const DEFAULT_TIMEOUT: f64 = 10.0;
+
+// The controlling side of a process started with pty-spawn and the output
+// read from it that expect has not consumed yet.
+#[derive(Debug)]
+pub struct PtySession {
+    master: File,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl PtySession {
+    // Read whatever is available, waiting at most timeout.
+    // Returns false once the process has closed the terminal.
+    fn fill(&mut self, timeout: Duration) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut fds = [PollFd::new(self.master.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout.as_millis() as i32) {
+            Ok(0) => return Ok(true),
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => return Ok(true),
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        }
+        let mut buf = [0; 4096];
+        match unistd::read(self.master.as_raw_fd(), &mut buf) {
+            Ok(0) | Err(nix::Error::Sys(nix::errno::Errno::EIO)) => {
+                // EIO is how Linux reports the other side closed.
+                self.eof = true;
+                Ok(false)
+            }
+            Ok(n) => {
+                self.buffer.extend_from_slice(&buf[..n]);
+                Ok(true)
+            }
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        }
+    }
+
+    // Remove and return the buffer through the end of the first pattern found.
+    fn take_match(&mut self, patterns: &[String]) -> Option<String> {
+        let end = patterns
+            .iter()
+            .filter_map(|p| {
+                let p = p.as_bytes();
+                self.buffer
+                    .windows(p.len())
+                    .position(|w| w == p)
+                    .map(|i| i + p.len())
+            })
+            .min()?;
+        let taken: Vec<u8> = self.buffer.drain(..end).collect();
+        Some(String::from_utf8_lossy(&taken).to_string())
+    }
+}
+
+fn session_pid(
+    environment: &mut Environment,
+    arg: Option<&Expression>,
+    form: &str,
+) -> io::Result<u32> {
+    if let Some(arg) = arg {
+        if let Expression::Process(proc) = eval(environment, arg)? {
+            let pid = proc.pid();
+            if environment.ptys.contains_key(&pid) {
+                return Ok(pid);
+            }
+        }
+    }
+    let msg = format!("{} requires a process started with pty-spawn", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_pty_spawn(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut argv = Vec::new();
+    for arg in args {
+        argv.push(eval(environment, arg)?.as_string(environment)?);
+    }
+    if argv.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pty-spawn takes a command and it's arguments",
+        ));
+    }
+    let pty = openpty(None, None).map_err(|err| {
+        let msg = format!("pty-spawn: could not open a pty: {}", err);
+        io::Error::new(io::ErrorKind::Other, msg)
+    })?;
+    // The child only gets the slave side.
+    let _ = fcntl(pty.master, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+    let master = unsafe { File::from_raw_fd(pty.master) };
+    let slave = unsafe { File::from_raw_fd(pty.slave) };
+    let mut command = Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    unsafe {
+        command.pre_exec(|| -> io::Result<()> {
+            // New session with the pty as it's controlling terminal.
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            for sig in &[
+                Signal::SIGINT,
+                Signal::SIGHUP,
+                Signal::SIGTERM,
+                Signal::SIGQUIT,
+                Signal::SIGTSTP,
+                Signal::SIGTTIN,
+                Signal::SIGTTOU,
+                Signal::SIGCHLD,
+            ] {
+                let _ = signal::signal(*sig, SigHandler::SigDfl);
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().map_err(|err| {
+        let msg = format!("pty-spawn: {}: {}", argv[0], err);
+        io::Error::new(io::ErrorKind::Other, msg)
+    })?;
+    // The child is a session leader (and so group leader).
+    let pgid = child.id();
+    let handle = ProcessHandle::new(child, pgid, Instant::now());
+    let pid = add_process(environment, handle.clone());
+    environment.ptys.insert(
+        pid,
+        Rc::new(RefCell::new(PtySession {
+            master,
+            buffer: Vec::new(),
+            eof: false,
+        })),
+    );
+    Ok(Expression::Process(handle))
+}
+
+fn builtin_send(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let pid = session_pid(environment, args.next(), "send")?;
+    let mut text = String::new();
+    for arg in args {
+        text.push_str(&eval(environment, arg)?.as_string(environment)?);
+    }
+    let session = environment.ptys[&pid].clone();
+    let mut session = session.borrow_mut();
+    session.master.write_all(text.as_bytes())?;
+    session.master.flush()?;
+    Ok(Expression::Atom(Atom::True))
+}
+
+fn builtin_expect(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let pid = session_pid(environment, args.next(), "expect")?;
+    let mut patterns = Vec::new();
+    let mut to_eof = false;
+    let mut timeout = DEFAULT_TIMEOUT;
+    while let Some(arg) = args.next() {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":eof" => to_eof = true,
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":timeout" => {
+                timeout = match args.next().map(|t| eval(environment, t)) {
+                    Some(Ok(Expression::Atom(Atom::Int(i)))) if i >= 0 => i as f64,
+                    Some(Ok(Expression::Atom(Atom::Float(f)))) if f >= 0.0 => f,
+                    Some(Err(err)) => return Err(err),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "expect: :timeout requires seconds",
+                        ))
+                    }
+                }
+            }
+            pattern => {
+                let pattern = pattern.as_string(environment)?;
+                if !pattern.is_empty() {
+                    patterns.push(pattern);
+                }
+            }
+        }
+    }
+    if patterns.is_empty() && !to_eof {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "expect takes a pty process and patterns (strings or :eof) with an optional :timeout",
+        ));
+    }
+    let deadline = Instant::now() + Duration::from_millis((timeout * 1000.0) as u64);
+    let session = environment.ptys[&pid].clone();
+    let mut session = session.borrow_mut();
+    loop {
+        if let Some(text) = session.take_match(&patterns) {
+            return Ok(Expression::Atom(Atom::String(text.into())));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+        let wait = (deadline - now).min(Duration::from_millis(100));
+        if !session.fill(wait)? {
+            if to_eof {
+                let rest: Vec<u8> = session.buffer.drain(..).collect();
+                return Ok(Expression::Atom(Atom::String(
+                    String::from_utf8_lossy(&rest).to_string().into(),
+                )));
+            }
+            return Ok(Expression::Atom(Atom::Nil));
+        }
+        if environment.sig_int.swap(false, Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "expect: interrupted by SIGINT",
+            ));
+        }
+    }
+}
+
+fn builtin_pty_close(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let pid = session_pid(environment, args.next(), "pty-close")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pty-close takes one pty process",
+        ));
+    }
+    // Dropping the master hangs up the terminal.
+    environment.ptys.remove(&pid);
+    Ok(Expression::Atom(Atom::True))
+}
+
+pub fn add_pty_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "pty-spawn".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pty_spawn,
+            "Start a command on a new pseudo terminal for send and expect, returns the process.",
+        )),
+    );
+    data.insert(
+        "send".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_send,
+            "Write strings to the terminal of a pty-spawn process (include \\n to end a line).",
+        )),
+    );
+    data.insert(
+        "expect".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_expect,
+            "Wait for a pty-spawn process to output one of the patterns (strings, :eof for end of output) and return the output through the match, nil on :timeout (default 10 seconds).",
+        )),
+    );
+    data.insert(
+        "pty-close".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pty_close,
+            "Hang up the terminal of a pty-spawn process.",
+        )),
+    );
+}
@@ -0,0 +1,625 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader};
+use std::process::ChildStdout;
+use std::rc::Rc;
+
+use crate::builtins_hashmap::build_map;
+use crate::builtins_util::*;
+use crate::environment::*;
+use crate::eval::*;
+use crate::persistent::PMap;
+use crate::process::Captured;
+use crate::types::*;
+
+// Anything map, filter and reduce can walk, one item at a time so files and
+// process output do not have to be read in first.
+pub trait Sequence {
+    // The next item, None when there are no more.
+    fn next_item(&mut self) -> io::Result<Option<Expression>>;
+}
+
+struct VectorSeq {
+    list: Rc<RefCell<Vec<Expression>>>,
+    index: usize,
+}
+
+impl Sequence for VectorSeq {
+    fn next_item(&mut self) -> io::Result<Option<Expression>> {
+        let item = self.list.borrow().get(self.index).cloned();
+        self.index += 1;
+        Ok(item)
+    }
+}
+
+// A list, ends at the first cdr that is not a pair.
+struct PairSeq {
+    current: Expression,
+}
+
+impl Sequence for PairSeq {
+    fn next_item(&mut self) -> io::Result<Option<Expression>> {
+        let (item, next) = match &self.current {
+            Expression::Pair(car, cdr) => (car.borrow().clone(), cdr.borrow().clone()),
+            _ => return Ok(None),
+        };
+        self.current = next;
+        Ok(Some(item))
+    }
+}
+
+// Chars of a string or (key . value) pairs of a hashmap.
+struct ItemSeq {
+    items: std::vec::IntoIter<Expression>,
+}
+
+impl Sequence for ItemSeq {
+    fn next_item(&mut self) -> io::Result<Option<Expression>> {
+        Ok(self.items.next())
+    }
+}
+
+enum LineSource {
+    File(Rc<RefCell<BufReader<std::fs::File>>>),
+    Stdin,
+    Process(BufReader<ChildStdout>),
+    Captured(Captured),
+}
+
+// Lines (without the newline) of a file, stdin or a process's output.
+struct LineSeq {
+    source: LineSource,
+}
+
+impl Sequence for LineSeq {
+    fn next_item(&mut self) -> io::Result<Option<Expression>> {
+        let mut line = String::new();
+        let read = match &mut self.source {
+            LineSource::File(file) => file.borrow_mut().read_line(&mut line)?,
+            LineSource::Stdin => io::stdin().lock().read_line(&mut line)?,
+            LineSource::Process(out) => out.read_line(&mut line)?,
+            LineSource::Captured(out) => out.read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(Expression::Atom(Atom::String(line.into()))))
+    }
+}
+
+// A Sequence over exp, form is used in the error if it is not one.
+pub fn sequence(
+    _environment: &mut Environment,
+    exp: &Expression,
+    form: &str,
+) -> io::Result<Box<dyn Sequence>> {
+    Ok(match exp {
+        Expression::Vector(list) => Box::new(VectorSeq {
+            list: list.clone(),
+            index: 0,
+        }),
+        Expression::Pair(_, _) | Expression::Atom(Atom::Nil) => Box::new(PairSeq {
+            current: exp.clone(),
+        }),
+        Expression::Atom(Atom::String(s)) => Box::new(ItemSeq {
+            items: chars(s).into_iter(),
+        }),
+        Expression::Atom(Atom::StringBuf(s)) => Box::new(ItemSeq {
+            items: chars(&s.borrow()).into_iter(),
+        }),
+        Expression::HashMap(map) => Box::new(ItemSeq {
+            items: hash_pairs(&map.borrow()).into_iter(),
+        }),
+        Expression::PVec(pvec) => Box::new(ItemSeq {
+            items: pvec.to_vec().into_iter(),
+        }),
+        Expression::Array(arr) => Box::new(ItemSeq {
+            items: arr.borrow().to_vec().into_iter(),
+        }),
+        Expression::PMap(pmap) => Box::new(ItemSeq {
+            items: pmap_pairs(pmap).into_iter(),
+        }),
+        Expression::File(FileState::Read(file)) => Box::new(LineSeq {
+            source: LineSource::File(file.clone()),
+        }),
+        Expression::File(FileState::Stdin) => Box::new(LineSeq {
+            source: LineSource::Stdin,
+        }),
+        Expression::Process(proc) => {
+            if let Some(captured) = proc.take_captured() {
+                return Ok(Box::new(LineSeq {
+                    source: LineSource::Captured(captured),
+                }));
+            }
+            match proc.take_stdout() {
+                Some(stdout) => Box::new(LineSeq {
+                    source: LineSource::Process(BufReader::new(stdout)),
+                }),
+                // Output not captured (or already read).
+                None => Box::new(ItemSeq {
+                    items: Vec::new().into_iter(),
+                }),
+            }
+        }
+        _ => {
+            let msg = format!(
+                "{}: {} is not a sequence (list, vector, string, hashmap, pvec, phash, array, file or process)",
+                form,
+                exp.display_type()
+            );
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    })
+}
+
+fn chars(s: &str) -> Vec<Expression> {
+    s.chars().map(|c| Expression::Atom(Atom::Char(c))).collect()
+}
+
+// The (key . value) pairs of a hashmap sorted by key so the order is the same
+// every time, keys are symbols like hash-keys gives.
+fn hash_pairs(map: &HashMap<String, Rc<Expression>>) -> Vec<Expression> {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    keys.iter()
+        .map(|k| {
+            Expression::Pair(
+                Rc::new(RefCell::new(Expression::Atom(Atom::Symbol(
+                    k.to_string().into(),
+                )))),
+                Rc::new(RefCell::new((*map[*k]).clone())),
+            )
+        })
+        .collect()
+}
+
+fn pmap_pairs(pmap: &PMap) -> Vec<Expression> {
+    pmap.items()
+        .into_iter()
+        .map(|(k, v)| {
+            Expression::Pair(
+                Rc::new(RefCell::new(Expression::Atom(Atom::Symbol(
+                    k.as_str().into(),
+                )))),
+                Rc::new(RefCell::new(v.clone())),
+            )
+        })
+        .collect()
+}
+
+// All the items of a sequence.
+fn seq_items(
+    environment: &mut Environment,
+    exp: &Expression,
+    form: &str,
+) -> io::Result<Vec<Expression>> {
+    let mut seq = sequence(environment, exp, form)?;
+    let mut items = Vec::new();
+    while let Some(item) = seq.next_item()? {
+        items.push(item);
+    }
+    Ok(items)
+}
+
+// Results are a list for a list (or nil) and a vector for anything else.
+fn collect(from: &Expression, mut items: Vec<Expression>) -> Expression {
+    match from {
+        Expression::Pair(_, _) | Expression::Atom(Atom::Nil) => {
+            if items.is_empty() {
+                Expression::Atom(Atom::Nil)
+            } else {
+                Expression::cons_from_vec(&mut items)
+            }
+        }
+        _ => Expression::with_list(items),
+    }
+}
+
+// Eval the sequence arg with stdout piped so a process in it can be read a
+// line at a time.
+pub fn eval_seq(environment: &mut Environment, exp: &Expression) -> io::Result<Expression> {
+    let old_out = environment.state.stdout_status.clone();
+    environment.state.stdout_status = Some(IOState::Pipe);
+    let res = eval(environment, exp);
+    environment.state.stdout_status = old_out;
+    res
+}
+
+// The function and sequence for map and filter.
+fn fn_and_seq(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<(Expression, Expression)> {
+    if let Some(fun) = args.next() {
+        if let Some(items) = args.next() {
+            if args.next().is_none() {
+                let fun = eval(environment, fun)?;
+                let items = eval_seq(environment, items)?;
+                return Ok((fun, items));
+            }
+        }
+    }
+    let msg = format!("{} takes a function and a sequence", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_map(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (fun, items) = fn_and_seq(environment, args, "map")?;
+    let mut seq = sequence(environment, &items, "map")?;
+    let mut result = Vec::new();
+    while let Some(item) = seq.next_item()? {
+        check_interrupted(environment)?;
+        result.push(call_with(environment, &fun, vec![item])?);
+    }
+    Ok(collect(&items, result))
+}
+
+fn builtin_filter(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (fun, items) = fn_and_seq(environment, args, "filter")?;
+    let mut seq = sequence(environment, &items, "filter")?;
+    let mut result = Vec::new();
+    while let Some(item) = seq.next_item()? {
+        check_interrupted(environment)?;
+        match call_with(environment, &fun, vec![item.clone()])? {
+            Expression::Atom(Atom::Nil) => {}
+            _ => result.push(item),
+        }
+    }
+    Ok(collect(&items, result))
+}
+
+fn builtin_reduce(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let args: Vec<&Expression> = args.collect();
+    let mut evaled = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if i == args.len() - 1 {
+            evaled.push(eval_seq(environment, arg)?);
+        } else {
+            evaled.push(eval(environment, arg)?);
+        }
+    }
+    if evaled.len() != 2 && evaled.len() != 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "reduce takes a function, an optional initial value and a sequence",
+        ));
+    }
+    let items = evaled.pop().unwrap();
+    let mut seq = sequence(environment, &items, "reduce")?;
+    let fun = evaled.remove(0);
+    let mut acc = match evaled.pop() {
+        Some(init) => init,
+        None => match seq.next_item()? {
+            Some(first) => first,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "reduce: empty sequence and no initial value",
+                ))
+            }
+        },
+    };
+    while let Some(item) = seq.next_item()? {
+        check_interrupted(environment)?;
+        acc = call_with(environment, &fun, vec![acc, item])?;
+    }
+    Ok(acc)
+}
+
+fn string_like(exp: &Expression) -> bool {
+    match exp {
+        Expression::Atom(Atom::String(_))
+        | Expression::Atom(Atom::StringBuf(_))
+        | Expression::Atom(Atom::Symbol(_))
+        | Expression::Atom(Atom::Char(_)) => true,
+        _ => false,
+    }
+}
+
+// Order of two values for sort without a comparator, numbers by value and
+// strings, symbols and chars as strings.
+fn default_less(environment: &Environment, a: &Expression, b: &Expression) -> io::Result<bool> {
+    match (a, b) {
+        (Expression::Atom(Atom::Int(a)), Expression::Atom(Atom::Int(b))) => Ok(a < b),
+        (Expression::Atom(Atom::Int(_)), Expression::Atom(Atom::Float(_)))
+        | (Expression::Atom(Atom::Float(_)), Expression::Atom(Atom::Int(_)))
+        | (Expression::Atom(Atom::Float(_)), Expression::Atom(Atom::Float(_))) => {
+            Ok(a.make_float(environment)? < b.make_float(environment)?)
+        }
+        _ if string_like(a) && string_like(b) => {
+            Ok(a.as_string(environment)? < b.as_string(environment)?)
+        }
+        _ => {
+            let msg = format!(
+                "sort: can not compare {} and {} without a comparator",
+                a.display_type(),
+                b.display_type()
+            );
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// True if a sorts before b, the comparator returns a number (negative for
+// before like str-collate) or true/nil (like <).
+fn sort_less(
+    environment: &mut Environment,
+    compare: &Option<Expression>,
+    a: &Expression,
+    b: &Expression,
+) -> io::Result<bool> {
+    match compare {
+        None => default_less(environment, a, b),
+        Some(compare) => Ok(
+            match call_with(environment, compare, vec![a.clone(), b.clone()])? {
+                Expression::Atom(Atom::Int(i)) => i < 0,
+                Expression::Atom(Atom::Float(f)) => f < 0.0,
+                Expression::Atom(Atom::Nil) => false,
+                _ => true,
+            },
+        ),
+    }
+}
+
+// Stable merge sort that can stop on an error from the comparator (and does
+// not care if it is inconsistent).
+fn merge_sort(
+    environment: &mut Environment,
+    compare: &Option<Expression>,
+    mut items: Vec<Expression>,
+) -> io::Result<Vec<Expression>> {
+    if items.len() < 2 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(environment, compare, items)?;
+    let right = merge_sort(environment, compare, right)?;
+    let mut sorted = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let take_right = match (left.peek(), right.peek()) {
+            (Some(l), Some(r)) => sort_less(environment, compare, r, l)?,
+            _ => break,
+        };
+        check_interrupted(environment)?;
+        if take_right {
+            sorted.push(right.next().unwrap());
+        } else {
+            sorted.push(left.next().unwrap());
+        }
+    }
+    sorted.extend(left);
+    sorted.extend(right);
+    Ok(sorted)
+}
+
+fn builtin_sort(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(items) = args.next() {
+        let compare = match args.next() {
+            Some(compare) => Some(eval(environment, compare)?),
+            None => None,
+        };
+        if args.next().is_none() {
+            let items = eval_seq(environment, items)?;
+            let all = seq_items(environment, &items, "sort")?;
+            let sorted = merge_sort(environment, &compare, all)?;
+            return Ok(collect(&items, sorted));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "sort takes a sequence and an optional comparator",
+    ))
+}
+
+// The one arg of a conversion, evaluated.
+fn one_arg(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+    takes: &str,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            return eval(environment, arg);
+        }
+    }
+    let msg = format!("{} takes {}", form, takes);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_vec_to_list(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    match one_arg(environment, args, "vec->list", "a vector")? {
+        Expression::Vector(list) => {
+            let mut items = list.borrow().clone();
+            Ok(if items.is_empty() {
+                Expression::Atom(Atom::Nil)
+            } else {
+                Expression::cons_from_vec(&mut items)
+            })
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "vec->list takes a vector",
+        )),
+    }
+}
+
+fn builtin_list_to_vec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    match one_arg(environment, args, "list->vec", "a list")? {
+        list @ Expression::Pair(_, _) | list @ Expression::Atom(Atom::Nil) => Ok(
+            Expression::with_list(seq_items(environment, &list, "list->vec")?),
+        ),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "list->vec takes a list",
+        )),
+    }
+}
+
+fn builtin_str_to_vec(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    match one_arg(environment, args, "str->vec", "a string")? {
+        Expression::Atom(Atom::String(s)) => Ok(Expression::with_list(chars(&s))),
+        Expression::Atom(Atom::StringBuf(s)) => Ok(Expression::with_list(chars(&s.borrow()))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "str->vec takes a string",
+        )),
+    }
+}
+
+fn builtin_vec_to_str(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    match one_arg(environment, args, "vec->str", "a vector")? {
+        Expression::Vector(list) => {
+            let mut res = String::new();
+            for item in list.borrow().iter() {
+                res.push_str(&item.as_string(environment)?);
+            }
+            Ok(Expression::Atom(Atom::String(res.into())))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "vec->str takes a vector",
+        )),
+    }
+}
+
+fn builtin_hash_to_pairs(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    match one_arg(environment, args, "hash->pairs", "a hashmap")? {
+        Expression::HashMap(map) => {
+            let mut pairs = hash_pairs(&map.borrow());
+            Ok(if pairs.is_empty() {
+                Expression::Atom(Atom::Nil)
+            } else {
+                Expression::cons_from_vec(&mut pairs)
+            })
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "hash->pairs takes a hashmap",
+        )),
+    }
+}
+
+fn builtin_pairs_to_hash(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let pairs = one_arg(
+        environment,
+        args,
+        "pairs->hash",
+        "a list or vector of pairs",
+    )?;
+    let pairs = seq_items(environment, &pairs, "pairs->hash")?;
+    build_map(HashMap::new(), &mut pairs.iter(), "pairs->hash")
+}
+
+pub fn add_seq_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "map".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_map,
+            "Call the function on each item of the sequence and return the results, a list for a list otherwise a vector.  Sequences are lists, vectors, strings (chars), hashmaps ((key . value) pairs), files and processes (lines).",
+        )),
+    );
+    data.insert(
+        "filter".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_filter,
+            "Return the items of the sequence the function returns non-nil for, a list for a list otherwise a vector (see map for sequences).",
+        )),
+    );
+    data.insert(
+        "reduce".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_reduce,
+            "Combine the items of the sequence with the function (called with the result so far and the next item) starting from the initial value or the first item (see map for sequences).",
+        )),
+    );
+    data.insert(
+        "sort".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_sort,
+            "Return the items of the sequence sorted (stable), a list for a list otherwise a vector.  Without a comparator numbers sort by value and strings, symbols and chars as strings.  The comparator is called with two items and returns a number (negative if the first goes first, like str-collate or str-natural) or true if the first goes first (like <).",
+        )),
+    );
+    data.insert(
+        "vec->list".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_vec_to_list,
+            "Return a list with the items of the vector.",
+        )),
+    );
+    data.insert(
+        "list->vec".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_list_to_vec,
+            "Return a vector with the items of the list.",
+        )),
+    );
+    data.insert(
+        "str->vec".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_str_to_vec,
+            "Return a vector of the chars in the string.",
+        )),
+    );
+    data.insert(
+        "vec->str".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_vec_to_str,
+            "Return a string made from the items (chars, strings or anything else as str would) of the vector.",
+        )),
+    );
+    data.insert(
+        "hash->pairs".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_hash_to_pairs,
+            "Return a list of the (key . value) pairs in the hashmap, sorted by key.",
+        )),
+    );
+    data.insert(
+        "pairs->hash".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_pairs_to_hash,
+            "Return a hashmap made from a list or vector of (key . value) pairs.",
+        )),
+    );
+}
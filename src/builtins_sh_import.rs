@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::builtins::{find_in_path, set_env_var};
+use crate::builtins_util::expand_tilde;
+use crate::environment::*;
+use crate::eval::*;
+use crate::process::env_assignment;
+use crate::types::*;
+
+// How deep . and source can nest (a file sourcing itself stops here).
+const MAX_SOURCE_DEPTH: usize = 16;
+
+// Compound commands are skipped whole, these open and close them.
+const BLOCK_OPEN: &[&str] = &["if", "case", "for", "while", "until", "{"];
+const BLOCK_CLOSE: &[&str] = &["fi", "esac", "done", "}"];
+
+// Words keep their quotes until expanded, anything else (pipes, redirects,
+// && etc) is an operator and makes the command unsupported.
+enum Token {
+    Word(String),
+    Op(String),
+}
+
+enum Split {
+    Done(Vec<Token>),
+    // An open quote or a trailing \, the command continues on the next line.
+    Incomplete,
+}
+
+fn tokenize(text: &str) -> Split {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            '\'' => match chars[i + 1..].iter().position(|c| *c == '\'') {
+                Some(end) => {
+                    word.extend(&chars[i..i + end + 2]);
+                    i += end + 2;
+                    continue;
+                }
+                None => return Split::Incomplete,
+            },
+            '"' | '`' => {
+                let mut j = i + 1;
+                while j < chars.len() && chars[j] != ch {
+                    if chars[j] == '\\' {
+                        j += 1;
+                    }
+                    j += 1;
+                }
+                if j >= chars.len() {
+                    return Split::Incomplete;
+                }
+                word.extend(&chars[i..=j]);
+                i = j + 1;
+                continue;
+            }
+            '\\' => {
+                if i + 1 >= chars.len() {
+                    return Split::Incomplete;
+                }
+                if chars[i + 1] != '\n' {
+                    word.push(ch);
+                    word.push(chars[i + 1]);
+                }
+                i += 2;
+                continue;
+            }
+            '#' if word.is_empty() => {
+                // Comment to the end of the line.
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if ch.is_whitespace() || ";|&<>()".contains(ch) {
+            if !word.is_empty() {
+                tokens.push(Token::Word(word.split_off(0)));
+            }
+            if ch == '\n' {
+                tokens.push(Token::Op(";".to_string()));
+            } else if !ch.is_whitespace() {
+                // Doubled operators (&& || ;; >>) are one token.
+                let mut op = ch.to_string();
+                if i + 1 < chars.len() && chars[i + 1] == ch && "&|;<>".contains(ch) {
+                    op.push(ch);
+                    i += 1;
+                }
+                tokens.push(Token::Op(op));
+            }
+        } else {
+            word.push(ch);
+        }
+        i += 1;
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    Split::Done(tokens)
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(ch) if ch == '_' || ch.is_ascii_alphabetic() => {
+            chars.all(|ch| ch == '_' || ch.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+struct ShImport<'a> {
+    environment: &'a mut Environment,
+    // Variables set but not exported, only used to expand later lines.
+    vars: HashMap<String, String>,
+    // Environment variables and aliases set, in order.
+    changed: Vec<String>,
+    file: String,
+    line: usize,
+    depth: usize,
+}
+
+impl<'a> ShImport<'a> {
+    fn skip(&self, what: &str) {
+        eprintln!(
+            "sh-import: {}:{}: skipped {} (not supported)",
+            self.file, self.line, what
+        );
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        match self.vars.get(name) {
+            Some(val) => Some(val.clone()),
+            None => env::var(name).ok(),
+        }
+    }
+
+    fn set_changed(&mut self, name: &str) {
+        if !self.changed.iter().any(|n| n == name) {
+            self.changed.push(name.to_string());
+        }
+    }
+
+    // $NAME, ${NAME}, ${NAME-word} or ${NAME:-word}, chars[i] is the $.
+    // Returns the value and the index after it, None if not supported.
+    fn parameter(&self, chars: &[char], i: usize) -> Option<(String, usize)> {
+        match chars.get(i + 1) {
+            Some('{') => {
+                let end = i + 2 + chars[i + 2..].iter().position(|c| *c == '}')?;
+                let inner: String = chars[i + 2..end].iter().collect();
+                let (name, default) = match inner.find('-') {
+                    Some(idx) => (&inner[..idx], Some(&inner[idx + 1..])),
+                    None => (&inner[..], None),
+                };
+                let (name, colon) = match name.strip_suffix(':') {
+                    Some(name) if default.is_some() => (name, true),
+                    _ => (name, false),
+                };
+                if !is_name(name) {
+                    return None;
+                }
+                let val = self.var(name);
+                let val = match (val, default) {
+                    (Some(val), Some(default)) if colon && val.is_empty() => {
+                        self.expand(default, false)?.0
+                    }
+                    (Some(val), _) => val,
+                    (None, Some(default)) => self.expand(default, false)?.0,
+                    (None, None) => String::new(),
+                };
+                Some((val, end + 1))
+            }
+            Some(ch) if *ch == '_' || ch.is_ascii_alphabetic() => {
+                let mut end = i + 1;
+                while end < chars.len() && (chars[end] == '_' || chars[end].is_ascii_alphanumeric())
+                {
+                    end += 1;
+                }
+                let name: String = chars[i + 1..end].iter().collect();
+                Some((self.var(&name).unwrap_or_default(), end))
+            }
+            // $( $1 $? etc.
+            Some(ch) if "(0123456789?@*#$!-".contains(*ch) => None,
+            _ => Some(("$".to_string(), i + 1)),
+        }
+    }
+
+    // Remove the quotes from a word and expand variables and ~, also
+    // returns if any of it was quoted.  None if it needs something not
+    // supported (command substitution for instance).
+    fn expand(&self, word: &str, assignment: bool) -> Option<(String, bool)> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut out = String::new();
+        let mut quoted = false;
+        let mut in_double = false;
+        let mut i = 0;
+        while i < chars.len() {
+            let ch = chars[i];
+            match ch {
+                '\'' if !in_double => {
+                    let end = i + 1 + chars[i + 1..].iter().position(|c| *c == '\'')?;
+                    out.extend(&chars[i + 1..end]);
+                    quoted = true;
+                    i = end + 1;
+                }
+                '"' => {
+                    in_double = !in_double;
+                    quoted = true;
+                    i += 1;
+                }
+                '\\' => {
+                    let next = *chars.get(i + 1)?;
+                    if in_double && !"$`\"\\".contains(next) {
+                        out.push('\\');
+                    }
+                    out.push(next);
+                    i += 2;
+                }
+                '`' => return None,
+                '$' => {
+                    let (val, end) = self.parameter(&chars, i)?;
+                    out.push_str(&val);
+                    i = end;
+                }
+                // A leading ~ (or after : in an assignment, PATH=~/bin:~/.local/bin).
+                '~' if !in_double
+                    && (i == 0 || (assignment && chars[i - 1] == ':'))
+                    && (i + 1 == chars.len() || chars[i + 1] == '/' || chars[i + 1] == ':') =>
+                {
+                    out.push_str(&env::var("HOME").unwrap_or_else(|_| "/".to_string()));
+                    i += 1;
+                }
+                _ => {
+                    out.push(ch);
+                    i += 1;
+                }
+            }
+        }
+        Some((out, quoted))
+    }
+
+    fn assign(&mut self, name: &str, raw: &str, export: bool) -> bool {
+        let val = match self.expand(raw, true) {
+            Some((val, _)) => val,
+            None => return false,
+        };
+        // Already in the environment means it is exported (PATH=...:$PATH).
+        if export || env::var_os(name).is_some() {
+            self.vars.remove(name);
+            set_env_var(name, &val);
+            self.set_changed(name);
+        } else {
+            self.vars.insert(name.to_string(), val);
+        }
+        true
+    }
+
+    fn export(&mut self, args: &[String]) {
+        for arg in args {
+            if arg == "-p" {
+                continue;
+            }
+            match env_assignment(arg) {
+                Some((name, raw)) => {
+                    if !self.assign(name, raw, true) {
+                        self.skip(&format!("export {}", arg));
+                    }
+                }
+                None if is_name(arg) => {
+                    if let Some(val) = self.vars.remove(arg.as_str()) {
+                        set_env_var(arg, &val);
+                        self.set_changed(arg);
+                    }
+                }
+                None => self.skip(&format!("export {}", arg)),
+            }
+        }
+    }
+
+    fn unset(&mut self, args: &[String]) {
+        if args.first().map(|a| a == "-f").unwrap_or(false) {
+            return;
+        }
+        for arg in args.iter().filter(|a| *a != "-v") {
+            if let Some((name, _)) = self.expand(arg, false) {
+                self.vars.remove(&name);
+                if env::var_os(&name).is_some() {
+                    env::remove_var(&name);
+                    self.set_changed(&name);
+                }
+            }
+        }
+    }
+
+    // alias name='command args', becomes a shell alias.
+    fn alias(&mut self, args: &[String]) -> io::Result<()> {
+        for arg in args {
+            let def = match self.expand(arg, false) {
+                Some((def, _)) => def,
+                None => {
+                    self.skip(&format!("alias {}", arg));
+                    continue;
+                }
+            };
+            let (name, body) = match def.find('=') {
+                Some(idx) => (&def[..idx], &def[idx + 1..]),
+                // Just printing an alias.
+                None => continue,
+            };
+            let words = match tokenize(body) {
+                Split::Done(tokens) => tokens
+                    .into_iter()
+                    .map(|t| match t {
+                        Token::Word(w) => self.expand(&w, false),
+                        Token::Op(_) => None,
+                    })
+                    .collect::<Option<Vec<(String, bool)>>>(),
+                Split::Incomplete => None,
+            };
+            let words = match words {
+                Some(words) if !words.is_empty() => words,
+                _ => {
+                    self.skip(&format!("alias {}", name));
+                    continue;
+                }
+            };
+            let mut body = Vec::with_capacity(words.len());
+            for (i, (word, quoted)) in words.into_iter().enumerate() {
+                // alias ls='ls -F' would expand forever, use the executable.
+                let word = if i == 0 && word == name {
+                    find_in_path(&word).unwrap_or(word)
+                } else {
+                    word
+                };
+                if quoted {
+                    body.push(Expression::Atom(Atom::String(word.into())));
+                } else {
+                    body.push(Expression::Atom(Atom::Symbol(word.into())));
+                }
+            }
+            let exp = Expression::with_list(vec![
+                Expression::Atom(Atom::Symbol("shell::alias".into())),
+                Expression::Atom(Atom::Symbol(name.into())),
+                Expression::with_list(body),
+            ]);
+            eval(self.environment, &exp)?;
+            self.set_changed(name);
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, tokens: &[Token]) -> io::Result<()> {
+        let mut words = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            match token {
+                Token::Word(word) => words.push(word.clone()),
+                Token::Op(op) => {
+                    self.skip(&format!("command with {}", op));
+                    return Ok(());
+                }
+            }
+        }
+        // Leading NAME=value words, on their own they set variables.
+        let assignments = words
+            .iter()
+            .take_while(|w| env_assignment(w).is_some())
+            .count();
+        if assignments == words.len() {
+            for word in &words {
+                if let Some((name, raw)) = env_assignment(word) {
+                    if !self.assign(name, raw, false) {
+                        self.skip(&format!("assignment to {}", name));
+                    }
+                }
+            }
+            return Ok(());
+        }
+        if assignments > 0 {
+            self.skip(&format!("command {}", words[assignments]));
+            return Ok(());
+        }
+        let args = &words[1..];
+        match &words[0][..] {
+            ":" | "true" => {}
+            "export" => self.export(args),
+            "unset" => self.unset(args),
+            "alias" => self.alias(args)?,
+            "." | "source" => match args.first().and_then(|a| self.expand(a, false)) {
+                Some((file, _)) if self.depth < MAX_SOURCE_DEPTH => {
+                    let (old_file, old_line) = (self.file.clone(), self.line);
+                    self.depth += 1;
+                    let res = self.import(&file);
+                    self.depth -= 1;
+                    self.file = old_file;
+                    self.line = old_line;
+                    res?;
+                }
+                _ => self.skip(&words.join(" ")),
+            },
+            cmd => self.skip(&format!("command {}", cmd)),
+        }
+        Ok(())
+    }
+
+    fn import(&mut self, file: &str) -> io::Result<()> {
+        let text = match fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(err) => {
+                let msg = format!("sh-import: {}: {}", file, err);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        };
+        self.file = file.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        // Nesting of the compound command being skipped.
+        let mut block = 0usize;
+        let mut i = 0;
+        while i < lines.len() {
+            self.line = i + 1;
+            // Join lines until the quotes close and there is no trailing \.
+            let mut text = lines[i].to_string();
+            let tokens = loop {
+                match tokenize(&text) {
+                    Split::Done(tokens) => break tokens,
+                    Split::Incomplete if i + 1 < lines.len() => {
+                        i += 1;
+                        text.push('\n');
+                        text.push_str(lines[i]);
+                    }
+                    Split::Incomplete => {
+                        let msg = format!(
+                            "sh-import: {}:{}: unterminated quote or \\",
+                            self.file, self.line
+                        );
+                        return Err(io::Error::new(io::ErrorKind::Other, msg));
+                    }
+                }
+            };
+            i += 1;
+            for command in tokens.split(|t| matches!(t, Token::Op(op) if op == ";")) {
+                let (first, last) = match (command.first(), command.last()) {
+                    (Some(Token::Word(first)), Some(last)) => (first, last),
+                    (Some(_), _) if block == 0 => {
+                        self.skip("command");
+                        continue;
+                    }
+                    _ => continue,
+                };
+                // A function definition ends with the { (name() {).
+                let function = matches!(last, Token::Word(w) if w == "{");
+                if function || BLOCK_OPEN.contains(&first.as_str()) {
+                    if block == 0 && BLOCK_OPEN.contains(&first.as_str()) {
+                        self.skip(&format!("{} block", first));
+                    } else if block == 0 {
+                        self.skip(&format!("function {}", first));
+                    }
+                    block += 1;
+                } else if BLOCK_CLOSE.contains(&first.as_str()) {
+                    block = block.saturating_sub(1);
+                } else if block == 0 {
+                    self.command(command)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn builtin_sh_import(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(file) = args.next() {
+        if args.next().is_none() {
+            let file = eval(environment, file)?.as_string(environment)?;
+            let file = expand_tilde(&file).unwrap_or(file);
+            let mut import = ShImport {
+                environment,
+                vars: HashMap::new(),
+                changed: Vec::new(),
+                file: String::new(),
+                line: 0,
+                depth: 0,
+            };
+            import.import(&file)?;
+            return Ok(Expression::with_list(
+                import
+                    .changed
+                    .into_iter()
+                    .map(|n| Expression::Atom(Atom::String(n.into())))
+                    .collect(),
+            ));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "sh-import takes one form (a file name)",
+    ))
+}
+
+pub fn add_sh_import_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "sh-import".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_sh_import,
+            "Apply the variable assignments, exports, unsets and aliases in a POSIX sh file (a .profile fragment for instance) without running it, returns a vector of the names set.",
+        )),
+    );
+}
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::process::{Command, Output, Stdio};
+use std::rc::Rc;
+
+use crate::builtins::shell_quote;
+use crate::builtins_bus::runtime_dir;
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// Seconds an idle master connection is kept for reuse.
+const CONTROL_PERSIST: u32 = 60;
+
+// Options so every ssh/scp to a host shares one master connection.
+pub(crate) fn control_args() -> io::Result<Vec<String>> {
+    let path = runtime_dir("ssh")?.join("%C");
+    Ok(vec![
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        format!("ControlPath={}", path.display()),
+        "-o".to_string(),
+        format!("ControlPersist={}", CONTROL_PERSIST),
+    ])
+}
+
+// A string is a remote shell command as is, a list or vector is a command and
+// it's arguments which are quoted for the remote shell.
+fn remote_command(environment: &Environment, command: &Expression) -> io::Result<String> {
+    let words: Vec<Expression> = match command {
+        Expression::Vector(list) => list.borrow().clone(),
+        Expression::Pair(_, _) => command.iter().cloned().collect(),
+        _ => return command.as_string(environment),
+    };
+    let mut quoted = Vec::new();
+    for word in &words {
+        quoted.push(shell_quote(&word.as_string(environment)?));
+    }
+    Ok(quoted.join(" "))
+}
+
+fn run_output(command: &mut Command, form: &str) -> io::Result<Output> {
+    command.stdin(Stdio::null()).output().map_err(|err| {
+        let msg = format!("{}: could not run: {}", form, err);
+        io::Error::new(io::ErrorKind::Other, msg)
+    })
+}
+
+fn builtin_ssh_run(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (host, command) = match (args.next(), args.next()) {
+        (Some(host), Some(command)) => (
+            eval(environment, host)?.as_string(environment)?,
+            eval(environment, command)?,
+        ),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "ssh-run takes a host, a command (string or list) and optional :port n",
+            ))
+        }
+    };
+    let mut ssh = Command::new("ssh");
+    ssh.args(control_args()?);
+    while let Some(arg) = args.next() {
+        match (eval(environment, arg)?, args.next()) {
+            (Expression::Atom(Atom::Symbol(ref s)), Some(port)) if &**s == ":port" => {
+                let port = eval(environment, port)?.as_string(environment)?;
+                ssh.arg("-p").arg(port);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "ssh-run: the only option is :port n",
+                ))
+            }
+        }
+    }
+    let command = remote_command(environment, &command)?;
+    ssh.arg("--").arg(&host).arg(command);
+    let output = run_output(&mut ssh, "ssh-run")?;
+    let status = match output.status.code() {
+        Some(code) => Expression::Atom(Atom::Int(i64::from(code))),
+        None => Expression::Atom(Atom::Nil),
+    };
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    map.insert(
+        ":stdout".to_string(),
+        Rc::new(Expression::Atom(Atom::String(
+            String::from_utf8_lossy(&output.stdout).to_string().into(),
+        ))),
+    );
+    map.insert(
+        ":stderr".to_string(),
+        Rc::new(Expression::Atom(Atom::String(
+            String::from_utf8_lossy(&output.stderr).to_string().into(),
+        ))),
+    );
+    map.insert(":status".to_string(), Rc::new(status));
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+fn builtin_ssh_copy(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut scp = Command::new("scp");
+    scp.args(control_args()?).arg("-q");
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":recursive" => {
+                scp.arg("-r");
+            }
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":port" => {
+                let port = match args.next() {
+                    Some(port) => eval(environment, port)?.as_string(environment)?,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "ssh-copy: :port requires a port",
+                        ))
+                    }
+                };
+                scp.arg("-P").arg(port);
+            }
+            path => paths.push(path.as_string(environment)?),
+        }
+    }
+    if paths.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "ssh-copy takes source path(s) and a destination (remote paths are host:path) with options :recursive and :port n",
+        ));
+    }
+    scp.arg("--").args(&paths);
+    let output = run_output(&mut scp, "ssh-copy")?;
+    if output.status.success() {
+        Ok(Expression::Atom(Atom::True))
+    } else {
+        let msg = format!(
+            "ssh-copy: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(io::Error::new(io::ErrorKind::Other, msg))
+    }
+}
+
+pub fn add_ssh_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "ssh-run".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_ssh_run,
+            "Run a command (string for the remote shell or a list of command and arguments) on host with ssh, returns a hashmap with :stdout, :stderr and :status.",
+        )),
+    );
+    data.insert(
+        "ssh-copy".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_ssh_copy,
+            "Copy files with scp, remote paths are host:path.  Options :recursive and :port n.",
+        )),
+    );
+}
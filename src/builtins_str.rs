@@ -1,9 +1,15 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::hash::BuildHasher;
 use std::io;
+use std::iter::Peekable;
 use std::rc::Rc;
+use std::str::Chars;
+use std::sync::Once;
 
+use crate::builtins_util::*;
 use crate::environment::*;
 use crate::eval::*;
 use crate::types::*;
@@ -20,7 +26,9 @@ fn builtin_str_trim(
         if args.next().is_none() {
             let arg = eval(environment, arg)?;
             let arg = arg.as_string(environment)?;
-            return Ok(Expression::Atom(Atom::String(arg.trim().to_string())));
+            return Ok(Expression::Atom(Atom::String(
+                arg.trim().to_string().into(),
+            )));
         }
     }
     Err(io::Error::new(
@@ -37,7 +45,9 @@ fn builtin_str_ltrim(
         if args.next().is_none() {
             let arg = eval(environment, arg)?;
             let arg = arg.as_string(environment)?;
-            return Ok(Expression::Atom(Atom::String(arg.trim_start().to_string())));
+            return Ok(Expression::Atom(Atom::String(
+                arg.trim_start().to_string().into(),
+            )));
         }
     }
     Err(io::Error::new(
@@ -54,7 +64,9 @@ fn builtin_str_rtrim(
         if args.next().is_none() {
             let arg = eval(environment, arg)?;
             let arg = arg.as_string(environment)?;
-            return Ok(Expression::Atom(Atom::String(arg.trim_end().to_string())));
+            return Ok(Expression::Atom(Atom::String(
+                arg.trim_end().to_string().into(),
+            )));
         }
     }
     Err(io::Error::new(
@@ -78,7 +90,7 @@ fn builtin_str_replace(
                     let arg2 = &eval(environment, arg2)?;
                     let arg2 = arg2.as_string(environment)?;
                     let new_str = arg0.replace(&arg1, &arg2);
-                    return Ok(Expression::Atom(Atom::String(new_str)));
+                    return Ok(Expression::Atom(Atom::String(new_str.into())));
                 }
             }
         }
@@ -103,11 +115,11 @@ fn builtin_str_split(
                 let mut split_list: Vec<Expression> = Vec::new();
                 if pat == ":whitespace" {
                     for s in text.split_whitespace() {
-                        split_list.push(Expression::Atom(Atom::String(s.to_string())));
+                        split_list.push(Expression::Atom(Atom::String(s.into())));
                     }
                 } else {
                     for s in text.split(&pat) {
-                        split_list.push(Expression::Atom(Atom::String(s.to_string())));
+                        split_list.push(Expression::Atom(Atom::String(s.into())));
                     }
                 }
                 return Ok(Expression::with_list(split_list));
@@ -133,7 +145,7 @@ fn builtin_str_rsplit(
                 let text = as_string(environment, &text)?;
                 let mut split_list: Vec<Expression> = Vec::new();
                 for s in text.rsplit(&pat) {
-                    split_list.push(Expression::Atom(Atom::String(s.to_string())));
+                    split_list.push(Expression::Atom(Atom::String(s.into())));
                 }
                 return Ok(Expression::with_list(split_list));
             }
@@ -173,7 +185,7 @@ fn builtin_str_splitn(
                     let text = as_string(environment, &text)?;
                     let mut split_list: Vec<Expression> = Vec::new();
                     for s in text.splitn(n as usize, &pat) {
-                        split_list.push(Expression::Atom(Atom::String(s.to_string())));
+                        split_list.push(Expression::Atom(Atom::String(s.into())));
                     }
                     return Ok(Expression::with_list(split_list));
                 }
@@ -214,7 +226,7 @@ fn builtin_str_rsplitn(
                     let text = as_string(environment, &text)?;
                     let mut split_list: Vec<Expression> = Vec::new();
                     for s in text.rsplitn(n as usize, &pat) {
-                        split_list.push(Expression::Atom(Atom::String(s.to_string())));
+                        split_list.push(Expression::Atom(Atom::String(s.into())));
                     }
                     return Ok(Expression::with_list(split_list));
                 }
@@ -267,7 +279,7 @@ fn builtin_str_cat_list(
                         ));
                     }
                 }
-                return Ok(Expression::Atom(Atom::String(new_str)));
+                return Ok(Expression::Atom(Atom::String(new_str.into())));
             }
         }
     }
@@ -307,7 +319,7 @@ fn builtin_str_sub(
                     if let Expression::Atom(Atom::String(s)) = &arg2 {
                         if (start + len) <= s.len() {
                             return Ok(Expression::Atom(Atom::String(
-                                s.as_str()[start..(start + len)].to_string(),
+                                s[start..(start + len)].into(),
                             )));
                         } else {
                             return Err(io::Error::new(
@@ -345,7 +357,7 @@ fn builtin_str_append(
                         let mut new_string = String::with_capacity(start.len() + end.len());
                         new_string.push_str(&start);
                         new_string.push_str(&end);
-                        return Ok(Expression::Atom(Atom::String(new_string)));
+                        return Ok(Expression::Atom(Atom::String(new_string.into())));
                     } else {
                         return Err(io::Error::new(
                             io::ErrorKind::Other,
@@ -410,7 +422,248 @@ fn builtin_str(
     environment.data_in = data_in;
     environment.in_pipe = in_pipe;
     environment.state.pipe_pgid = pipe_pgid;
-    Ok(Expression::Atom(Atom::String(res)))
+    Ok(Expression::Atom(Atom::String(res.into())))
+}
+
+// Text of a number from command output, whitespace around it and _ between
+// digits are ignored.
+fn number_text(s: &str) -> String {
+    s.trim().chars().filter(|c| *c != '_').collect()
+}
+
+// Parse an integer in radix (or the radix of a 0x, 0o or 0b prefix if none).
+fn parse_int(s: &str, radix: Option<u32>) -> Option<i64> {
+    let text = number_text(s);
+    let (sign, digits) = if text.starts_with('-') || text.starts_with('+') {
+        text.split_at(1)
+    } else {
+        ("", &text[..])
+    };
+    let lower = digits.to_lowercase();
+    let (radix, digits) = match radix {
+        None | Some(16) if lower.starts_with("0x") => (16, &digits[2..]),
+        None | Some(8) if lower.starts_with("0o") => (8, &digits[2..]),
+        None | Some(2) if lower.starts_with("0b") => (2, &digits[2..]),
+        None => (10, digits),
+        Some(radix) => (radix, digits),
+    };
+    // from_str_radix would take a second sign.
+    if digits.is_empty() || digits.starts_with('-') || digits.starts_with('+') {
+        return None;
+    }
+    let sign = if sign == "-" { "-" } else { "" };
+    i64::from_str_radix(&format!("{}{}", sign, digits), radix).ok()
+}
+
+// Radix and whether to return nil instead of an error from the optional args
+// of str->int and str->float.
+fn parse_options(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+    allow_radix: bool,
+) -> io::Result<(Option<u32>, bool)> {
+    let mut radix = None;
+    let mut nil_on_error = false;
+    for arg in args {
+        match eval(environment, arg)? {
+            Expression::Atom(Atom::Int(r)) if allow_radix && r >= 2 && r <= 36 => {
+                radix = Some(r as u32)
+            }
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":nil" => nil_on_error = true,
+            Expression::Atom(Atom::Symbol(ref s)) if &**s == ":error" => nil_on_error = false,
+            arg => {
+                let msg = format!(
+                    "{}: invalid option {}, expected {}:nil or :error",
+                    form,
+                    arg,
+                    if allow_radix { "a radix (2-36), " } else { "" }
+                );
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok((radix, nil_on_error))
+}
+
+fn builtin_str_to_int(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        let arg = eval(environment, arg)?;
+        let text = arg.as_string(environment)?;
+        let (radix, nil_on_error) = parse_options(environment, args, "str->int", true)?;
+        return match parse_int(&text, radix) {
+            Some(i) => Ok(Expression::Atom(Atom::Int(i))),
+            None if nil_on_error => Ok(Expression::Atom(Atom::Nil)),
+            None => {
+                let msg = format!(
+                    "str->int: can not parse {:?} as an integer{}",
+                    text,
+                    radix.map_or(String::new(), |r| format!(" (radix {})", r))
+                );
+                Err(io::Error::new(io::ErrorKind::Other, msg))
+            }
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "str->int takes a string, an optional radix and :nil or :error",
+    ))
+}
+
+fn builtin_str_to_float(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        let arg = eval(environment, arg)?;
+        let text = arg.as_string(environment)?;
+        let (_, nil_on_error) = parse_options(environment, args, "str->float", false)?;
+        return match number_text(&text).parse::<f64>() {
+            Ok(f) => Ok(Expression::Atom(Atom::Float(f))),
+            Err(_) if nil_on_error => Ok(Expression::Atom(Atom::Nil)),
+            Err(_) => {
+                let msg = format!("str->float: can not parse {:?} as a float", text);
+                Err(io::Error::new(io::ErrorKind::Other, msg))
+            }
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "str->float takes a string and optionally :nil or :error",
+    ))
+}
+
+fn builtin_int_to_str(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        let radix = match args.next() {
+            Some(radix) => Some(eval(environment, radix)?),
+            None => None,
+        };
+        if args.next().is_none() {
+            let i = eval(environment, arg)?.make_int(environment)?;
+            let radix = match radix {
+                None => 10,
+                Some(Expression::Atom(Atom::Int(r))) if r >= 2 && r <= 36 => r as u32,
+                Some(r) => {
+                    let msg = format!("int->str: radix must be 2-36, not {}", r);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            };
+            return Ok(Expression::Atom(Atom::String(
+                int_to_radix(i, radix).into(),
+            )));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "int->str takes an integer and an optional radix",
+    ))
+}
+
+// Compare with the collation of the locale (LC_ALL, LC_COLLATE or LANG), the
+// locale is taken from the environment the first time.
+pub fn collate_cmp(a: &str, b: &str) -> Ordering {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        libc::setlocale(libc::LC_COLLATE, b"\0".as_ptr() as *const libc::c_char);
+    });
+    match (CString::new(a), CString::new(b)) {
+        (Ok(ca), Ok(cb)) => unsafe { libc::strcoll(ca.as_ptr(), cb.as_ptr()) }
+            .cmp(&0)
+            .then_with(|| a.cmp(b)),
+        _ => a.cmp(b),
+    }
+}
+
+// Natural order, runs of digits compare as numbers (file2 < file10) and case
+// is ignored, ties are broken by plain comparison.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    fn digits(chars: &mut Peekable<Chars>) -> String {
+        let mut run = String::new();
+        while let Some(ch) = chars.peek() {
+            if !ch.is_ascii_digit() {
+                break;
+            }
+            run.push(*ch);
+            chars.next();
+        }
+        run
+    }
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        let (ca, cb) = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) => (*ca, *cb),
+        };
+        let order = if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let da = digits(&mut a_chars);
+            let db = digits(&mut b_chars);
+            let na = da.trim_start_matches('0');
+            let nb = db.trim_start_matches('0');
+            na.len().cmp(&nb.len()).then_with(|| na.cmp(nb))
+        } else {
+            a_chars.next();
+            b_chars.next();
+            ca.to_lowercase().cmp(cb.to_lowercase())
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+fn ordering_int(order: Ordering) -> Expression {
+    Expression::Atom(Atom::Int(match order {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }))
+}
+
+// The two string args of a comparison.
+fn two_strings(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<(String, String)> {
+    if let Some(a) = args.next() {
+        if let Some(b) = args.next() {
+            if args.next().is_none() {
+                let a = eval(environment, a)?;
+                let a = a.as_string(environment)?;
+                let b = eval(environment, b)?;
+                let b = b.as_string(environment)?;
+                return Ok((a, b));
+            }
+        }
+    }
+    let msg = format!("{} takes two strings", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_str_collate(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (a, b) = two_strings(environment, args, "str-collate")?;
+    Ok(ordering_int(collate_cmp(&a, &b)))
+}
+
+fn builtin_str_natural(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (a, b) = two_strings(environment, args, "str-natural")?;
+    Ok(ordering_int(natural_cmp(&a, &b)))
 }
 
 fn builtin_str_empty(
@@ -421,8 +674,8 @@ fn builtin_str_empty(
         if args.next().is_none() {
             let string = match eval(environment, &string)? {
                 Expression::Atom(Atom::String(string)) => string,
-                Expression::Atom(Atom::StringBuf(string)) => string.borrow().to_string(),
-                _ => "".to_string(),
+                Expression::Atom(Atom::StringBuf(string)) => string.borrow().to_string().into(),
+                _ => "".into(),
             };
             return if string.is_empty() {
                 Ok(Expression::Atom(Atom::True))
@@ -447,8 +700,10 @@ fn builtin_str_nth(
                 if let Expression::Atom(Atom::Int(idx)) = eval(environment, &idx)? {
                     let string = match eval(environment, &string)? {
                         Expression::Atom(Atom::String(string)) => string,
-                        Expression::Atom(Atom::StringBuf(string)) => string.borrow().to_string(),
-                        _ => "".to_string(),
+                        Expression::Atom(Atom::StringBuf(string)) => {
+                            string.borrow().to_string().into()
+                        }
+                        _ => "".into(),
                     };
                     for (i, ch) in string.chars().enumerate() {
                         if i as i64 == idx {
@@ -477,11 +732,13 @@ fn builtin_str_lower(
         if args.next().is_none() {
             match eval(environment, &string)? {
                 Expression::Atom(Atom::String(string)) => {
-                    return Ok(Expression::Atom(Atom::String(string.to_ascii_lowercase())))
+                    return Ok(Expression::Atom(Atom::String(
+                        string.to_ascii_lowercase().into(),
+                    )))
                 }
                 Expression::Atom(Atom::StringBuf(string)) => {
                     return Ok(Expression::Atom(Atom::String(
-                        string.borrow().to_ascii_lowercase(),
+                        string.borrow().to_ascii_lowercase().into(),
                     )))
                 }
                 _ => {}
@@ -502,11 +759,13 @@ fn builtin_str_upper(
         if args.next().is_none() {
             match eval(environment, &string)? {
                 Expression::Atom(Atom::String(string)) => {
-                    return Ok(Expression::Atom(Atom::String(string.to_ascii_uppercase())))
+                    return Ok(Expression::Atom(Atom::String(
+                        string.to_ascii_uppercase().into(),
+                    )))
                 }
                 Expression::Atom(Atom::StringBuf(string)) => {
                     return Ok(Expression::Atom(Atom::String(
-                        string.borrow().to_ascii_uppercase(),
+                        string.borrow().to_ascii_uppercase().into(),
                     )))
                 }
                 _ => {}
@@ -722,18 +981,14 @@ fn builtin_str_map(
                 if let Expression::Atom(Atom::Lambda(func)) = func {
                     match string {
                         Expression::Atom(Atom::String(string)) => {
-                            return Ok(Expression::Atom(Atom::String(str_map_inner(
-                                environment,
-                                func,
-                                &string,
-                            )?)));
+                            return Ok(Expression::Atom(Atom::String(
+                                str_map_inner(environment, func, &string)?.into(),
+                            )));
                         }
                         Expression::Atom(Atom::StringBuf(string)) => {
-                            return Ok(Expression::Atom(Atom::String(str_map_inner(
-                                environment,
-                                func,
-                                &string.borrow(),
-                            )?)));
+                            return Ok(Expression::Atom(Atom::String(
+                                str_map_inner(environment, func, &string.borrow())?.into(),
+                            )));
                         }
                         _ => {}
                     }
@@ -965,6 +1220,41 @@ pub fn add_str_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression
             "Make a new string with it's arguments.",
         )),
     );
+    data.insert(
+        "str->int".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_str_to_int,
+            "Parse a string as an integer in radix (default 10 or from a 0x, 0o or 0b prefix), whitespace around it and _ are ignored.  An error if it can not be parsed unless :nil is given.",
+        )),
+    );
+    data.insert(
+        "str->float".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_str_to_float,
+            "Parse a string as a float, whitespace around it and _ are ignored.  An error if it can not be parsed unless :nil is given.",
+        )),
+    );
+    data.insert(
+        "int->str".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_int_to_str,
+            "Return the integer as a string in radix (2-36, default 10).",
+        )),
+    );
+    data.insert(
+        "str-collate".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_str_collate,
+            "Compare two strings with the locale's collation, -1 if the first sorts before the second, 0 if equal and 1 if after.  Can be the comparator for sort.",
+        )),
+    );
+    data.insert(
+        "str-natural".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_str_natural,
+            "Compare two strings in natural order (numbers in them by value, file2 before file10, and ignoring case), -1, 0 or 1 like str-collate.  Can be the comparator for sort.",
+        )),
+    );
     data.insert(
         "str-empty?".to_string(),
         Rc::new(Expression::make_function(
@@ -0,0 +1,353 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::eval::*;
+use crate::gc::*;
+use crate::reader::*;
+use crate::types::*;
+
+// The functions defstruct makes for a type as (name, params, body).
+pub fn struct_functions(name: &str, fields: &[String]) -> Vec<(String, Vec<String>, String)> {
+    let mut fns = vec![
+        (
+            format!("make-{}", name),
+            fields.to_vec(),
+            format!("(struct-make '{} {})", name, fields.join(" ")),
+        ),
+        (
+            format!("{}?", name),
+            vec!["v".to_string()],
+            format!("(struct? v '{})", name),
+        ),
+    ];
+    for field in fields {
+        let getter = format!("{}-{}", name, field);
+        let check = format!(
+            "(if (not (struct? v '{})) (err \"{} requires a {}\"))",
+            name, getter, name
+        );
+        fns.push((
+            getter.clone(),
+            vec!["v".to_string()],
+            format!("(progn {} (struct-get v '{}))", check, field),
+        ));
+        let check = format!(
+            "(if (not (struct? v '{})) (err \"set-{}! requires a {}\"))",
+            name, getter, name
+        );
+        fns.push((
+            format!("set-{}!", getter),
+            vec!["v".to_string(), "val".to_string()],
+            format!("(progn {} (struct-set! v '{} val))", check, field),
+        ));
+    }
+    fns
+}
+
+fn type_name(environment: &mut Environment, arg: &Expression, form: &str) -> io::Result<String> {
+    match eval(environment, arg)? {
+        Expression::Atom(Atom::Symbol(s)) => Ok(s.to_string()),
+        Expression::Atom(Atom::String(s)) => Ok(s.to_string()),
+        _ => {
+            let msg = format!("{} requires a struct type name", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn field_index(stype: &StructType, field: &str, form: &str) -> io::Result<usize> {
+    let field = if field.starts_with(':') {
+        &field[1..]
+    } else {
+        field
+    };
+    match stype.fields.iter().position(|f| f == field) {
+        Some(i) => Ok(i),
+        None => {
+            let msg = format!("{}: {} has no field {}", form, stype.name, field);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// The struct and field index for struct-get and struct-set!.
+fn struct_field(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+    form: &str,
+) -> io::Result<(Rc<StructType>, Rc<RefCell<Vec<Expression>>>, usize)> {
+    if let (Some(st), Some(field)) = (args.next(), args.next()) {
+        if let Expression::Struct(stype, vals) = eval(environment, st)? {
+            let field = type_name(environment, field, form)?;
+            let index = field_index(&stype, &field, form)?;
+            return Ok((stype, vals, index));
+        }
+    }
+    let msg = format!("{} requires a struct and a field name", form);
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn builtin_defstruct(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let mut names = Vec::new();
+    for arg in args {
+        match arg {
+            Expression::Atom(Atom::Symbol(s))
+                if !s.starts_with(':') && !s.starts_with('&') && !s.contains("::") =>
+            {
+                if names.iter().any(|n: &String| n == &**s) {
+                    let msg = format!("defstruct: duplicate field {}", s);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+                names.push(s.to_string());
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "defstruct takes a name and field names (symbols)",
+                ))
+            }
+        }
+    }
+    if names.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "defstruct takes a name and field names (symbols)",
+        ));
+    }
+    let name = names.remove(0);
+    let mut source = String::from("(progn");
+    for (fn_name, params, body) in struct_functions(&name, &names) {
+        source.push_str(&format!(
+            " (def '{} (fn ({}) {}))",
+            fn_name,
+            params.join(" "),
+            body
+        ));
+    }
+    source.push(')');
+    let defs = match read_form(&source) {
+        Ok(Some((defs, _))) => defs,
+        Ok(None) => return Err(io::Error::new(io::ErrorKind::Other, "defstruct: no forms")),
+        Err(err) => {
+            let msg = format!("defstruct: invalid name: {}", err.reason);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    environment.struct_types.insert(
+        name.to_string(),
+        Rc::new(StructType {
+            name: name.to_string(),
+            fields: names,
+        }),
+    );
+    eval(environment, &defs)?;
+    Ok(Expression::Atom(Atom::Symbol(name.into())))
+}
+
+fn builtin_struct_make(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let name = match args.next() {
+        Some(name) => type_name(environment, name, "struct-make")?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "struct-make takes a struct type name and the field values",
+            ))
+        }
+    };
+    let stype = match environment.struct_types.get(&name) {
+        Some(stype) => stype.clone(),
+        None => {
+            let msg = format!("struct-make: {} is not a struct type", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let mut vals = Vec::new();
+    for arg in args {
+        vals.push(eval(environment, arg)?);
+    }
+    if vals.len() != stype.fields.len() {
+        let msg = format!(
+            "struct-make: {} takes {} values ({}), got {}",
+            name,
+            stype.fields.len(),
+            stype.fields.join(" "),
+            vals.len()
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    Ok(Expression::Struct(stype, Rc::new(RefCell::new(vals))))
+}
+
+fn builtin_is_struct(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        let arg = eval(environment, arg)?;
+        let name = match args.next() {
+            Some(name) => Some(type_name(environment, name, "struct?")?),
+            None => None,
+        };
+        if args.next().is_none() {
+            return Ok(match (arg, name) {
+                (Expression::Struct(_, _), None) => Expression::Atom(Atom::True),
+                (Expression::Struct(stype, _), Some(ref name)) if &stype.name == name => {
+                    Expression::Atom(Atom::True)
+                }
+                _ => Expression::Atom(Atom::Nil),
+            });
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "struct? takes a form and an optional struct type name",
+    ))
+}
+
+fn builtin_struct_type(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            return Ok(match eval(environment, arg)? {
+                Expression::Struct(stype, _) => {
+                    Expression::Atom(Atom::String(stype.name.clone().into()))
+                }
+                _ => Expression::Atom(Atom::Nil),
+            });
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "struct-type takes one form",
+    ))
+}
+
+fn builtin_struct_fields(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let stype = match eval(environment, arg)? {
+                Expression::Struct(stype, _) => Some(stype),
+                Expression::Atom(atom @ Atom::Symbol(_))
+                | Expression::Atom(atom @ Atom::String(_)) => {
+                    environment.struct_types.get(&*atom.as_string()).cloned()
+                }
+                _ => None,
+            };
+            if let Some(stype) = stype {
+                let fields = stype
+                    .fields
+                    .iter()
+                    .map(|f| Expression::Atom(Atom::Symbol(f.clone().into())))
+                    .collect();
+                return Ok(Expression::with_list(fields));
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "struct-fields takes a struct or struct type name",
+    ))
+}
+
+fn builtin_struct_get(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (_, vals, index) = struct_field(environment, args, "struct-get")?;
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "struct-get takes a struct and a field name",
+        ));
+    }
+    let val = vals.borrow()[index].clone();
+    Ok(val)
+}
+
+fn builtin_struct_set(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let (_, vals, index) = struct_field(environment, args, "struct-set!")?;
+    if let Some(val) = args.next() {
+        if args.next().is_none() {
+            let val = eval(environment, val)?;
+            if gc_may_cycle(&val) {
+                gc_track(environment, GcCandidate::Vector(Rc::downgrade(&vals)));
+            }
+            vals.borrow_mut()[index] = val.clone();
+            return Ok(val);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "struct-set! takes a struct, a field name and a value",
+    ))
+}
+
+pub fn add_struct_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "defstruct".to_string(),
+        Rc::new(Expression::make_special(
+            builtin_defstruct,
+            "Define a record type from a name and field names, (defstruct point x y) defines make-point, point?, point-x, set-point-x! and so on.",
+        )),
+    );
+    data.insert(
+        "struct-make".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_struct_make,
+            "Make a struct of a defstruct type from a value for each field in order.",
+        )),
+    );
+    data.insert(
+        "struct?".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_is_struct,
+            "True if form is a struct, of the type if a type name is given.",
+        )),
+    );
+    data.insert(
+        "struct-type".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_struct_type,
+            "The type name of a struct or nil if not a struct.",
+        )),
+    );
+    data.insert(
+        "struct-fields".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_struct_fields,
+            "The field names of a struct or struct type name as a vector.",
+        )),
+    );
+    data.insert(
+        "struct-get".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_struct_get,
+            "Get a field (symbol or keyword) of a struct.",
+        )),
+    );
+    data.insert(
+        "struct-set!".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_struct_set,
+            "Set a field (symbol or keyword) of a struct, returns the value.",
+        )),
+    );
+}
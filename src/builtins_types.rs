@@ -15,7 +15,7 @@ fn builtin_type(
     if let Some(arg) = args.next() {
         if args.next().is_none() {
             let arg = eval(environment, arg)?;
-            return Ok(Expression::Atom(Atom::String(arg.display_type())));
+            return Ok(Expression::Atom(Atom::String(arg.display_type().into())));
         }
     }
     Err(io::Error::new(io::ErrorKind::Other, "type takes one form"))
@@ -3,17 +3,54 @@ use std::io;
 use std::iter::FromIterator;
 use std::rc::Rc;
 
+use crate::builtins_options::get_option;
 use crate::environment::*;
 use crate::eval::*;
 use crate::types::*;
 
+// Truth of a value for conditionals, only nil is false except that a finished
+// process is true if it exited 0 (unless the status-as-bool option is nil).
+pub fn is_truthy(environment: &Environment, exp: &Expression) -> bool {
+    match exp {
+        Expression::Atom(Atom::Nil) => false,
+        Expression::Process(proc) => match proc.status() {
+            Some(status) => match get_option(environment, "status-as-bool") {
+                Expression::Atom(Atom::Nil) => true,
+                _ => status == 0,
+            },
+            None => true,
+        },
+        _ => true,
+    }
+}
+
 pub fn is_proper_list(exp: &Expression) -> bool {
     // does not detect empty (nil) lists on purpose.
-    if let Expression::Pair(_e1, e2) = exp {
-        if let Expression::Atom(Atom::Nil) = *e2.borrow() {
-            true
+    // Walks with a slow and fast cursor so a circular list (not proper) ends.
+    fn cdr(exp: &Expression) -> Option<Expression> {
+        if let Expression::Pair(_e1, e2) = exp {
+            Some(e2.borrow().clone())
         } else {
-            is_proper_list(&e2.borrow())
+            None
+        }
+    }
+    if let Expression::Pair(_e1, _e2) = exp {
+        let mut slow = exp.clone();
+        let mut fast = exp.clone();
+        loop {
+            for _ in 0..2 {
+                fast = match cdr(&fast) {
+                    Some(Expression::Atom(Atom::Nil)) => return true,
+                    Some(next) => next,
+                    None => return false,
+                };
+            }
+            slow = cdr(&slow).unwrap();
+            if let (Expression::Pair(s1, _), Expression::Pair(f1, _)) = (&slow, &fast) {
+                if Rc::ptr_eq(s1, f1) {
+                    return false;
+                }
+            }
         }
     } else {
         false
@@ -198,7 +235,7 @@ fn setup_args_final<'a>(
                 v.unwrap().clone()
             };
             if let Some(scope) = scope {
-                scope.data.insert(k.unwrap().clone(), Rc::new(v2));
+                scope.insert(k.unwrap().clone(), Rc::new(v2));
             } else {
                 set_expression_current(environment, k.unwrap().clone(), Rc::new(v2));
             }
@@ -215,9 +252,7 @@ fn setup_args_final<'a>(
         }
         if rest_data.is_empty() {
             if let Some(scope) = scope {
-                scope
-                    .data
-                    .insert(rest_name, Rc::new(Expression::Atom(Atom::Nil)));
+                scope.insert(rest_name, Rc::new(Expression::Atom(Atom::Nil)));
             } else {
                 set_expression_current(
                     environment,
@@ -226,9 +261,7 @@ fn setup_args_final<'a>(
                 );
             }
         } else if let Some(scope) = scope {
-            scope
-                .data
-                .insert(rest_name, Rc::new(Expression::with_list(rest_data)));
+            scope.insert(rest_name, Rc::new(Expression::with_list(rest_data)));
         } else {
             set_expression_current(
                 environment,
@@ -261,7 +294,7 @@ fn setup_args_final<'a>(
                 v.unwrap().clone()
             };
             if let Some(scope) = scope {
-                scope.data.insert(k.unwrap().clone(), Rc::new(v2));
+                scope.insert(k.unwrap().clone(), Rc::new(v2));
             } else {
                 set_expression_current(environment, k.unwrap().clone(), Rc::new(v2));
             }
@@ -314,7 +347,7 @@ pub fn setup_args<'a>(
                     } else {
                         min_params += 1;
                     }
-                    var_names.push(s.clone());
+                    var_names.push(s.to_string());
                 }
             }
         } else {
@@ -339,3 +372,45 @@ pub fn setup_args<'a>(
     )?;
     Ok(())
 }
+
+// A value as an argument for fn_call, quoted unless it evaluates to itself.
+pub fn call_arg(value: Expression) -> Expression {
+    match value {
+        Expression::Atom(Atom::Nil)
+        | Expression::Atom(Atom::True)
+        | Expression::Atom(Atom::Int(_))
+        | Expression::Atom(Atom::Float(_))
+        | Expression::Atom(Atom::Char(_)) => value,
+        _ => Expression::cons_from_vec(&mut vec![
+            Expression::Atom(Atom::Symbol("quote".into())),
+            value,
+        ]),
+    }
+}
+
+// Call f with args that are already evaluated.
+pub fn call_with(
+    environment: &mut Environment,
+    f: &Expression,
+    args: Vec<Expression>,
+) -> io::Result<Expression> {
+    let args: Vec<Expression> = args.into_iter().map(call_arg).collect();
+    fn_call(environment, f, Box::new(args.iter()))
+}
+
+// An integer in radix (2-36) with lower case digits.
+pub fn int_to_radix(i: i64, radix: u32) -> String {
+    let mut n = i128::from(i).abs() as u128;
+    let mut digits = Vec::new();
+    loop {
+        digits.push(std::char::from_digit((n % u128::from(radix)) as u32, radix).unwrap());
+        n /= u128::from(radix);
+        if n == 0 {
+            break;
+        }
+    }
+    if i < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
@@ -8,6 +8,7 @@ use std::rc::Rc;
 use crate::builtins_util::*;
 use crate::environment::*;
 use crate::eval::*;
+use crate::gc::*;
 use crate::types::*;
 
 fn builtin_vec(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
@@ -38,7 +39,10 @@ fn builtin_make_vec(environment: &mut Environment, args: &[Expression]) -> io::R
     let mut list = Vec::with_capacity(cap as usize);
     if args.len() == 2 {
         let v = &args[1];
-        for _ in 0..cap {
+        for i in 0..cap {
+            if i % 4096 == 0 {
+                check_interrupted(environment)?;
+            }
             list.push(v.clone());
         }
     }
@@ -164,6 +168,9 @@ fn builtin_vec_setnth(
                     "vec-setnth! index out of range",
                 ));
             }
+            if gc_may_cycle(&new_element) {
+                gc_track(environment, GcCandidate::Vector(Rc::downgrade(&list)));
+            }
             list.borrow_mut()[idx as usize] = new_element;
             Ok(Expression::Vector(list))
         }
@@ -187,6 +194,9 @@ fn builtin_vec_push(environment: &mut Environment, args: &[Expression]) -> io::R
     let old_list = args.pop().unwrap();
     match old_list {
         Expression::Vector(list) => {
+            if gc_may_cycle(&new_item) {
+                gc_track(environment, GcCandidate::Vector(Rc::downgrade(&list)));
+            }
             list.borrow_mut().push(new_item);
             Ok(Expression::Vector(list))
         }
@@ -343,6 +353,9 @@ fn builtin_vec_insert_nth(
                     "vec-insert-nth! index out of range",
                 ));
             }
+            if gc_may_cycle(&new_element) {
+                gc_track(environment, GcCandidate::Vector(Rc::downgrade(&list)));
+            }
             list.borrow_mut().insert(idx as usize, new_element);
             Ok(Expression::Vector(list))
         }
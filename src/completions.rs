@@ -1,10 +1,16 @@
 use glob::{glob, glob_with, MatchOptions};
 use liner::{Completer, CursorPosition, Event, EventKind};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::path::Path;
+use std::process::{Command, Stdio};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use crate::builtins_options::get_option;
+use crate::builtins_ssh::control_args;
 use crate::builtins_util::compress_tilde;
 use crate::builtins_util::expand_tilde;
 use crate::environment::*;
@@ -78,6 +84,10 @@ pub struct ShellCompleter {
     environment: Rc<RefCell<Environment>>,
     comp_type: CompType,
     args: Vec<String>,
+    // The command the word being completed is an argument to (if any).
+    command: Option<String>,
+    // Remote listings by host and glob pattern with when they were made.
+    remote_cache: HashMap<(String, String), (Instant, Vec<String>)>,
 }
 
 impl ShellCompleter {
@@ -86,9 +96,76 @@ impl ShellCompleter {
             environment,
             comp_type: CompType::Nothing,
             args: Vec::new(),
+            command: None,
+            remote_cache: HashMap::new(),
         }
     }
 
+    // Completions that depend on the command being completed for, None if
+    // there is no built in provider for the command.
+    fn command_matches(&mut self, start: &str) -> Option<Vec<String>> {
+        let command = match &self.command {
+            Some(command) => command.clone(),
+            None => return None,
+        };
+        match &command[..] {
+            "scp" | "sftp" | "rsync" if is_remote_path(start) => {
+                Some(self.get_remote_matches(start))
+            }
+            // Either side can be local so paths and hosts both match.
+            "scp" | "sftp" | "rsync" => {
+                let mut ret = get_dir_matches(start);
+                ret.append(&mut get_host_matches(start));
+                Some(ret)
+            }
+            "ssh" | "mosh" => Some(get_host_matches(start)),
+            "kill" => Some(get_pid_matches(start)),
+            "pkill" | "killall" | "pgrep" | "pidof" => Some(get_process_name_matches(start)),
+            "fg" | "bg" => Some(get_job_matches(&self.environment.borrow(), start)),
+            _ => None,
+        }
+    }
+
+    // Complete host:path by expanding path* with the host's shell over the
+    // shared master connection, only if the remote-completion option is set.
+    fn get_remote_matches(&mut self, start: &str) -> Vec<String> {
+        let (ttl, enabled) = {
+            let environment = self.environment.borrow();
+            let ttl = match get_option(&environment, "remote-completion-cache") {
+                Expression::Atom(Atom::Int(secs)) if secs > 0 => secs as u64,
+                _ => 0,
+            };
+            let enabled = !matches!(
+                get_option(&environment, "remote-completion"),
+                Expression::Atom(Atom::Nil)
+            );
+            (ttl, enabled)
+        };
+        if !enabled {
+            return Vec::new();
+        }
+        let idx = start.find(':').unwrap_or(0);
+        let host = &start[..idx];
+        let path = unescape(&start[idx + 1..]);
+        let pattern = remote_pattern(&path);
+        let key = (host.to_string(), pattern.clone());
+        let listing = match self.remote_cache.get(&key) {
+            Some((made, listing)) if made.elapsed() < Duration::from_secs(ttl) => listing.clone(),
+            _ => {
+                let listing = list_remote(host, &pattern);
+                self.remote_cache
+                    .retain(|_, (made, _)| made.elapsed() < Duration::from_secs(ttl));
+                self.remote_cache
+                    .insert(key, (Instant::now(), listing.clone()));
+                listing
+            }
+        };
+        listing
+            .iter()
+            .map(|p| format!("{}:{}", host, escape(p, false)))
+            .collect()
+    }
+
     fn run_hook(&mut self) -> HookResult {
         if self.args.is_empty() {
             return HookResult::Default;
@@ -98,11 +175,9 @@ impl ShellCompleter {
             let exp = match *comp_exp {
                 Expression::Atom(Atom::Lambda(_)) => {
                     let mut v = Vec::with_capacity(1 + self.args.len());
-                    v.push(Expression::Atom(Atom::Symbol(
-                        "__completion_hook".to_string(),
-                    )));
+                    v.push(Expression::Atom(Atom::Symbol("__completion_hook".into())));
                     for a in self.args.drain(..) {
-                        v.push(Expression::Atom(Atom::String(a)));
+                        v.push(Expression::Atom(Atom::String(a.into())));
                     }
                     Rc::new(Expression::with_list(v))
                 }
@@ -115,7 +190,9 @@ impl ShellCompleter {
             match eval(envir, &exp) {
                 Ok(res) => {
                     match res {
-                        Expression::Atom(Atom::String(s)) | Expression::Atom(Atom::Symbol(s)) => {
+                        Expression::Atom(atom @ Atom::String(_))
+                        | Expression::Atom(atom @ Atom::Symbol(_)) => {
+                            let s = atom.as_string();
                             match s.as_ref() {
                                 "path" => HookResult::Path,
                                 "default" => HookResult::Default,
@@ -169,6 +246,9 @@ impl Completer for ShellCompleter {
     fn completions(&mut self, start: &str) -> Vec<String> {
         match self.comp_type {
             CompType::Nothing => Vec::new(),
+            CompType::Command | CompType::CommandParen if start.starts_with('$') => {
+                get_env_matches(start)
+            }
             CompType::Command => {
                 let mut ret = get_dir_matches(start);
                 find_lisp_fns(&self.environment.borrow(), &mut ret, start);
@@ -196,7 +276,11 @@ impl Completer for ShellCompleter {
                 HookResult::UseList(list) => list,
             },
             CompType::Other => match self.run_hook() {
+                HookResult::Default if start.starts_with('$') => get_env_matches(start),
                 HookResult::Default => {
+                    if let Some(ret) = self.command_matches(start) {
+                        return ret;
+                    }
                     let mut ret = get_dir_matches(start);
                     find_lisp_symbols(&self.environment.borrow(), &mut ret, start);
                     ret
@@ -209,6 +293,7 @@ impl Completer for ShellCompleter {
 
     fn on_event(&mut self, event: Event<'_, '_>) {
         self.args.clear();
+        self.command = None;
         if let EventKind::BeforeComplete = event.kind {
             let (words, pos) = event.editor.get_words_and_cursor_position();
             for word_limits in &words {
@@ -221,6 +306,7 @@ impl Completer for ShellCompleter {
             if String::from(event.editor.current_buffer().clone()).ends_with(' ') {
                 self.args.push("".to_string());
             }
+            self.command = find_command(&self.args);
             self.comp_type = match pos {
                 _ if words.is_empty() => CompType::Nothing,
                 CursorPosition::InWord(0) => CompType::Command,
@@ -383,7 +469,209 @@ fn get_env_matches(start: &str) -> Vec<String> {
     ret
 }
 
-fn find_lisp_fns(environment: &Environment, comps: &mut Vec<String>, start: &str) {
+// Find the command for the last word in args, this is the first word of the
+// innermost open form (or the line if no form is open).
+fn find_command(args: &[String]) -> Option<String> {
+    if args.len() < 2 {
+        return None;
+    }
+    let mut stack: Vec<Option<String>> = vec![None];
+    let mut need_command = true;
+    for arg in &args[..args.len() - 1] {
+        match &arg[..] {
+            "(" => {
+                stack.push(None);
+                need_command = true;
+            }
+            ")" => {
+                stack.pop();
+                if stack.is_empty() {
+                    stack.push(None);
+                }
+                need_command = false;
+            }
+            _ => {
+                if need_command {
+                    stack.pop();
+                    stack.push(Some(arg.to_string()));
+                    need_command = false;
+                }
+            }
+        }
+    }
+    stack.pop().unwrap_or(None)
+}
+
+fn get_host_matches(start: &str) -> Vec<String> {
+    // Keep any user@ prefix and only complete the host part.
+    let (prefix, host_start) = match start.rfind('@') {
+        Some(idx) => (&start[..=idx], &start[idx + 1..]),
+        None => ("", start),
+    };
+    let home = match env::var("HOME") {
+        Ok(val) => val,
+        Err(_) => return Vec::new(),
+    };
+    let mut hosts: Vec<String> = Vec::new();
+    if let Ok(config) = fs::read_to_string(format!("{}/.ssh/config", home)) {
+        for line in config.lines() {
+            let mut words = line.split_whitespace();
+            if let Some(key) = words.next() {
+                if key.to_lowercase() == "host" {
+                    for host in words {
+                        if !host.contains('*') && !host.contains('?') && !host.starts_with('!') {
+                            hosts.push(host.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if let Ok(known) = fs::read_to_string(format!("{}/.ssh/known_hosts", home)) {
+        for line in known.lines() {
+            // Hashed entries (|1|...) can not be used for completion.
+            if line.starts_with('#') || line.starts_with('|') {
+                continue;
+            }
+            if let Some(names) = line.split_whitespace().next() {
+                for name in names.split(',') {
+                    // [host]:port form.
+                    let name = if name.starts_with('[') {
+                        match name.find(']') {
+                            Some(idx) => &name[1..idx],
+                            None => name,
+                        }
+                    } else {
+                        name
+                    };
+                    hosts.push(name.to_string());
+                }
+            }
+        }
+    }
+    hosts.sort();
+    hosts.dedup();
+    hosts
+        .drain(..)
+        .filter(|h| h.starts_with(host_start))
+        .map(|h| format!("{}{}", prefix, h))
+        .collect()
+}
+
+// A [user@]host:path argument (a / before the : means a local path).
+fn is_remote_path(start: &str) -> bool {
+    match start.find(':') {
+        Some(idx) => idx > 0 && !start[..idx].contains('/'),
+        None => false,
+    }
+}
+
+// Glob for the remote shell, any glob characters typed are kept so things like
+// src/**/*.rs work (with bash) and everything else is escaped.
+fn remote_pattern(path: &str) -> String {
+    let mut pattern = String::with_capacity(path.len() + 1);
+    for ch in path.chars() {
+        match ch {
+            '/' | '.' | '_' | '-' | '~' | '*' | '?' | '[' | ']' | '+' | ',' | '=' | '@' | '%'
+            | ':' => pattern.push(ch),
+            _ if ch.is_alphanumeric() => pattern.push(ch),
+            _ => {
+                pattern.push('\\');
+                pattern.push(ch);
+            }
+        }
+    }
+    pattern.push('*');
+    pattern
+}
+
+// Paths matching pattern on host, directories end in /.  Never prompts for a
+// password and gives up quickly if the host can not be reached.
+fn list_remote(host: &str, pattern: &str) -> Vec<String> {
+    let control = match control_args() {
+        Ok(control) => control,
+        Err(_) => return Vec::new(),
+    };
+    let remote = format!(
+        "shopt -s globstar 2>/dev/null; ls -1dp -- {} 2>/dev/null",
+        pattern
+    );
+    let output = Command::new("ssh")
+        .args(control)
+        .args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "ConnectTimeout=3",
+            host,
+            &remote,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Returns (pid, command name) for all the processes in /proc.
+fn get_processes() -> Vec<(String, String)> {
+    let mut procs = Vec::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries {
+            if let Ok(entry) = entry {
+                let pid = entry.file_name().to_string_lossy().to_string();
+                if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) {
+                    continue;
+                }
+                if let Ok(name) = fs::read_to_string(format!("/proc/{}/comm", pid)) {
+                    procs.push((pid, name.trim().to_string()));
+                }
+            }
+        }
+    }
+    procs
+}
+
+// Match start against either the pid or the process name but always produce pids.
+fn get_pid_matches(start: &str) -> Vec<String> {
+    let mut ret: Vec<String> = get_processes()
+        .drain(..)
+        .filter(|(pid, name)| pid.starts_with(start) || name.starts_with(start))
+        .map(|(pid, _)| pid)
+        .collect();
+    ret.sort_by_key(|pid| pid.parse::<u32>().unwrap_or(0));
+    ret
+}
+
+fn get_process_name_matches(start: &str) -> Vec<String> {
+    let mut ret: Vec<String> = get_processes()
+        .drain(..)
+        .filter(|(_, name)| name.starts_with(start))
+        .map(|(_, name)| name)
+        .collect();
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+fn get_job_matches(environment: &Environment, start: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    for i in 0..environment.jobs.borrow().len() {
+        let job = format!("{}", i);
+        if job.starts_with(start) {
+            ret.push(job);
+        }
+    }
+    ret
+}
+
+pub fn find_lisp_fns(environment: &Environment, comps: &mut Vec<String>, start: &str) {
     let data = &environment.root_scope.borrow().data;
     for key in data.keys() {
         if key.starts_with(start) {
@@ -399,7 +687,7 @@ fn find_lisp_fns(environment: &Environment, comps: &mut Vec<String>, start: &str
     }
 }
 
-fn find_lisp_symbols(environment: &Environment, comps: &mut Vec<String>, org_start: &str) {
+pub fn find_lisp_symbols(environment: &Environment, comps: &mut Vec<String>, org_start: &str) {
     let (start, need_quote) = if org_start.starts_with('\'') {
         (&org_start[1..], true)
     } else {
@@ -5,6 +5,11 @@ pub struct Config {
     pub command: Option<String>,
     pub script: Option<String>,
     pub args: Vec<String>,
+    pub lsp: bool,
+    pub check: bool,
+    pub fmt: bool,
+    pub profile_startup: bool,
+    pub login: bool,
 }
 
 pub const VERSION_STRING: &str = env!("VERSION_STRING");
@@ -18,12 +23,20 @@ USAGE:
 FLAGS:
     -v, --version  Print the version, platform and revision of server then exit.
     -h, --help     Print help (this) and exit.
+    -l, --login    Run as a login shell (*login* is t), also when started as -slsh.
+    --lsp          Run as a language server (LSP) on stdin/stdout for editor integration.
+    --check        Parse and lint the script(s) in args without running them.
+    --fmt          Format the script(s) in args in place (stdin to stdout if none).
+    --profile-startup
+                   Load the startup files, print how long each took and exit.
 
 OPTIONS:
     -c             Command to run instead of entering the REPL.
 
 ARGS:
-    <args>...      Script to run with arguments."#;
+    <args>...      Script to run with arguments, anything after the script (or
+                   after --) is passed to it.  In the script they are args or
+                   $1..$n and the script is *script-name* or $0."#;
 
 fn help(_name: &str) {
     println!("{}", HELP);
@@ -47,14 +60,42 @@ pub fn get_config() -> Result<Config, ()> {
     let mut command: Option<String> = None;
     let mut script: Option<String> = None;
     let mut command_args: Vec<String> = Vec::new();
+    let mut lsp = false;
+    let mut check = false;
+    let mut fmt = false;
+    let mut profile_startup = false;
+    let mut login = false;
+    let mut options_done = false;
 
     let mut args: Vec<OsString> = env::args_os().collect();
     args.reverse();
     let exe_name = get_arg("unknown", &mut args)?; // Pop off the executable name.
+                                                   // Login programs start a login shell with a - in front of its name.
+    if exe_name.starts_with('-') {
+        login = true;
+    }
     while !args.is_empty() {
         if let Some(argument) = args.pop() {
             if let Ok(arg) = argument.into_string() {
+                if script.is_some() || options_done {
+                    // Everything after the script (or --) is not an option.
+                    if command.is_none() && script.is_none() {
+                        script = Some(arg);
+                    } else {
+                        command_args.push(arg);
+                    }
+                    continue;
+                }
+                // A shebang line without env -S gives all its options as one
+                // arg (#!/usr/bin/sl-sh -l --), split them up.
+                if arg.starts_with('-') && arg.contains(char::is_whitespace) {
+                    for opt in arg.split_whitespace().rev() {
+                        args.push(opt.into());
+                    }
+                    continue;
+                }
                 match &arg[..] {
+                    "--" => options_done = true,
                     "-c" => {
                         if command.is_some() {
                             help(&exe_name);
@@ -70,6 +111,13 @@ pub fn get_config() -> Result<Config, ()> {
                         help(&exe_name);
                         return Err(());
                     }
+                    "-l" | "--login" if command.is_none() && script.is_none() => login = true,
+                    "--lsp" if command.is_none() && script.is_none() => lsp = true,
+                    "--check" if command.is_none() && script.is_none() => check = true,
+                    "--fmt" if command.is_none() && script.is_none() => fmt = true,
+                    "--profile-startup" if command.is_none() && script.is_none() => {
+                        profile_startup = true
+                    }
                     _ => {
                         if command.is_none() && script.is_none() {
                             script = Some(arg);
@@ -88,5 +136,10 @@ pub fn get_config() -> Result<Config, ()> {
         command,
         script,
         args: command_args,
+        lsp,
+        check,
+        fmt,
+        profile_startup,
+        login,
     })
 }
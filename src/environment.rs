@@ -1,23 +1,45 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
 use std::io;
-use std::process::Child;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-use crate::builtins::add_builtins;
+use crate::builtins::{add_builtins, add_shell_builtins, CaseTable};
+use crate::builtins_array::add_array_builtins;
+use crate::builtins_bus::{add_bus_builtins, BusSocket};
 use crate::builtins_file::add_file_builtins;
+use crate::builtins_follow::{add_follow_builtins, OutputFollower};
+use crate::builtins_generic::add_generic_builtins;
+use crate::builtins_git::{add_git_builtins, GitStatus};
 use crate::builtins_hashmap::add_hash_builtins;
 use crate::builtins_io::add_io_builtins;
+use crate::builtins_json::add_json_builtins;
+use crate::builtins_local_env::{add_local_env_builtins, LocalEnv};
 use crate::builtins_math::add_math_builtins;
+use crate::builtins_options::{add_options_builtins, default_options, ShellOption};
 use crate::builtins_pair::add_pair_builtins;
+use crate::builtins_parallel::add_parallel_builtins;
+use crate::builtins_persistent::add_persistent_builtins;
+use crate::builtins_pick::add_pick_builtins;
+use crate::builtins_pty::{add_pty_builtins, PtySession};
+use crate::builtins_seq::add_seq_builtins;
+use crate::builtins_sh_import::add_sh_import_builtins;
+use crate::builtins_ssh::add_ssh_builtins;
 use crate::builtins_str::add_str_builtins;
+use crate::builtins_struct::add_struct_builtins;
 use crate::builtins_types::add_type_builtins;
 use crate::builtins_vector::add_vec_builtins;
+use crate::eval::{main_stack_size, thread_stack_size, MacroExpansion, DEFAULT_MAX_EVAL_DEPTH};
+use crate::gc::{add_gc_builtins, GcState};
+use crate::history::{add_history_builtins, HistoryFile};
+use crate::paths::add_paths_builtins;
 use crate::process::*;
+use crate::startup::StartupProfile;
 use crate::types::*;
 
 #[derive(Clone, Debug)]
@@ -31,11 +53,29 @@ pub enum IOState {
 pub struct EnvState {
     pub recur_num_args: Option<usize>,
     pub gensym_count: u32,
+    // Stdin of commands when there is no pipe input.
+    pub stdin_status: Option<IOState>,
     pub stdout_status: Option<IOState>,
     pub stderr_status: Option<IOState>,
     pub eval_level: u32,
     pub is_spawn: bool,
     pub pipe_pgid: Option<u32>,
+    // Commands started in with-new-pgroup share a process group led by the
+    // first one started (0 until then).
+    pub new_pgroup: Option<u32>,
+    // Commands are started in a new session (setsid).
+    pub new_session: bool,
+    // Resource limits for spawned commands (from run-limited).
+    pub limits: Option<ProcLimits>,
+    // Environment variables for spawned commands only (with-env), None removes.
+    pub child_env: Vec<(String, Option<String>)>,
+    // Commands still running at this point are killed (run-with-timeout),
+    // while set commands get their own process group.
+    pub deadline: Option<Instant>,
+    // Set when the deadline stopped an eval or signalled a command.
+    pub deadline_hit: Cell<bool>,
+    // The file being loaded, recorded in the metadata of what it defines.
+    pub load_file: Option<String>,
 }
 
 impl Default for EnvState {
@@ -43,11 +83,19 @@ impl Default for EnvState {
         EnvState {
             recur_num_args: None,
             gensym_count: 0,
+            stdin_status: None,
             stdout_status: None,
             stderr_status: None,
             eval_level: 0,
             is_spawn: false,
             pipe_pgid: None,
+            new_pgroup: None,
+            new_session: false,
+            limits: None,
+            child_env: Vec::new(),
+            deadline: None,
+            deadline_hit: Cell::new(false),
+            load_file: None,
         }
     }
 }
@@ -59,26 +107,62 @@ pub enum FormType {
     ExternalOnly,
 }
 
+pub type AddBuiltins = fn(&mut HashMap<String, Rc<Expression>>);
+
+// The functions that put the builtins in the root scope, in order, and the
+// help category their builtins are listed under.
+pub const BUILTINS: &[(&str, AddBuiltins)] = &[
+    ("core", add_builtins),
+    ("shell", add_shell_builtins),
+    ("math", add_math_builtins),
+    ("str", add_str_builtins),
+    ("vector", add_vec_builtins),
+    ("array", add_array_builtins),
+    ("file", add_file_builtins),
+    ("io", add_io_builtins),
+    ("pair", add_pair_builtins),
+    ("hashmap", add_hash_builtins),
+    ("json", add_json_builtins),
+    ("persistent", add_persistent_builtins),
+    ("type", add_type_builtins),
+    ("shell", add_pick_builtins),
+    ("shell", add_bus_builtins),
+    ("shell", add_parallel_builtins),
+    ("shell", add_pty_builtins),
+    ("shell", add_ssh_builtins),
+    ("shell", add_sh_import_builtins),
+    ("shell", add_local_env_builtins),
+    ("struct", add_struct_builtins),
+    ("struct", add_generic_builtins),
+    ("shell", add_options_builtins),
+    ("shell", add_follow_builtins),
+    ("shell", add_git_builtins),
+    ("seq", add_seq_builtins),
+    ("shell", add_paths_builtins),
+    ("core", add_gc_builtins),
+    ("shell", add_history_builtins),
+];
+
 #[derive(Clone, Debug)]
 pub struct Scope {
     pub data: HashMap<String, Rc<Expression>>,
     pub outer: Option<Rc<RefCell<Scope>>>,
     // If this scope is a namespace it will have a name otherwise it will be None.
     pub name: Option<String>,
+    // Metadata (doc, author, file, etc) for symbols defined in this scope.
+    pub meta: HashMap<String, HashMap<String, Rc<Expression>>>,
+    // Parameters of a resolved lambda call by slot (Atom::Local uses the slot)
+    // and their names for lookups by name.
+    pub frame: Vec<Rc<Expression>>,
+    pub frame_names: Option<Rc<Vec<String>>>,
 }
 
 impl Default for Scope {
     fn default() -> Self {
         let mut data = HashMap::new();
-        add_builtins(&mut data);
-        add_math_builtins(&mut data);
-        add_str_builtins(&mut data);
-        add_vec_builtins(&mut data);
-        add_file_builtins(&mut data);
-        add_io_builtins(&mut data);
-        add_pair_builtins(&mut data);
-        add_hash_builtins(&mut data);
-        add_type_builtins(&mut data);
+        for (_, add) in BUILTINS {
+            add(&mut data);
+        }
         data.insert(
             "*stdin*".to_string(),
             Rc::new(Expression::File(FileState::Stdin)),
@@ -93,12 +177,15 @@ impl Default for Scope {
         );
         data.insert(
             "*ns*".to_string(),
-            Rc::new(Expression::Atom(Atom::String("root".to_string()))),
+            Rc::new(Expression::Atom(Atom::String("root".into()))),
         );
         Scope {
             data,
             outer: None,
             name: Some("root".to_string()),
+            meta: HashMap::new(),
+            frame: Vec::new(),
+            frame_names: None,
         }
     }
 }
@@ -125,8 +212,63 @@ impl Scope {
             data,
             outer,
             name: None,
+            meta: HashMap::new(),
+            frame: Vec::new(),
+            frame_names: None,
         }
     }
+
+    fn slot(&self, key: &str) -> Option<usize> {
+        match &self.frame_names {
+            Some(names) => names.iter().position(|name| name == key),
+            None => None,
+        }
+    }
+
+    // The binding for key in this scope (not the outer ones).
+    pub fn get(&self, key: &str) -> Option<&Rc<Expression>> {
+        if let Some(slot) = self.slot(key) {
+            if let Some(exp) = self.frame.get(slot) {
+                return Some(exp);
+            }
+        }
+        self.data.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Bind key in this scope, in the frame if it is a parameter.
+    pub fn insert(&mut self, key: String, expression: Rc<Expression>) {
+        match self.slot(&key) {
+            Some(slot) => {
+                while self.frame.len() <= slot {
+                    self.frame.push(Rc::new(Expression::Atom(Atom::Nil)));
+                }
+                self.frame[slot] = expression;
+            }
+            None => {
+                self.data.insert(key, expression);
+            }
+        }
+    }
+
+    // Unbind key in this scope.  A parameter's slot is given an empty name (not
+    // a symbol) so neither lookups by name nor by slot find it.
+    pub fn remove(&mut self, key: &str) {
+        if let Some(slot) = self.slot(key) {
+            if let Some(names) = &self.frame_names {
+                let mut names = (**names).clone();
+                names[slot] = String::new();
+                self.frame_names = Some(Rc::new(names));
+            }
+            if let Some(exp) = self.frame.get_mut(slot) {
+                *exp = Rc::new(Expression::Atom(Atom::Nil));
+            }
+        }
+        self.data.remove(key);
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -149,6 +291,7 @@ pub struct Job {
     pub pids: Vec<u32>,
     pub names: Vec<String>,
     pub status: JobStatus,
+    pub started: SystemTime,
 }
 
 #[derive(Clone, Debug)]
@@ -164,14 +307,41 @@ pub struct Environment {
     pub do_job_control: bool,
     pub loose_symbols: bool,
     pub str_ignore_expand: bool,
-    pub procs: Rc<RefCell<HashMap<u32, Child>>>,
+    pub procs: Rc<RefCell<HashMap<u32, ProcessHandle>>>,
+    // Exit status of processes in procs that were already waited for.
+    pub reaped: Rc<RefCell<HashMap<u32, ExitInfo>>>,
     pub data_in: Option<Expression>,
     pub form_type: FormType,
     pub save_exit_status: bool,
     pub stack_on_error: bool,
     pub error_expression: Option<Expression>,
+    // Names of the functions and macros being called, innermost last.
+    pub call_stack: Vec<Rc<str>>,
+    // The calls (with where they are defined) when error_expression was set,
+    // only the innermost ones unless error-stack is on.
+    pub error_chain: Option<Vec<String>>,
+    // Number of outer calls left out of error_chain.
+    pub error_chain_elided: usize,
+    // Data given to err with the error being unwound.
+    pub error_data: Option<Expression>,
+    // Deepest eval allowed (the max-eval-depth option).
+    pub max_eval_depth: u32,
+    // Address near the start of the stack (set by the first eval) and the
+    // stack size, evals stop before the stack overflows.
+    pub stack_base: usize,
+    pub stack_size: usize,
     // If this is Some then need to unwind and exit with then provided code (exit was called).
     pub exit_code: Option<i32>,
+    // Exit even if there are jobs (exit :force).
+    pub force_exit: bool,
+    // Value of the throw being unwound to it's catch.
+    pub thrown: Option<Expression>,
+    // Tags of the catch forms being evaluated, innermost last.
+    pub catch_tags: Vec<String>,
+    // Forms from defer for each lambda body or loaded file being evaluated.
+    pub defers: Vec<Vec<Expression>>,
+    // Functions to call when the shell or script ends (on-exit).
+    pub exit_hooks: Vec<Expression>,
     // This is the dynamic bindings.  These take precidence over the other
     // bindings.
     pub dynamic_scope: HashMap<String, Rc<Expression>>,
@@ -184,10 +354,44 @@ pub struct Environment {
     pub current_scope: Vec<Rc<RefCell<Scope>>>,
     // Map of all the created namespaces.
     pub namespaces: HashMap<String, Rc<RefCell<Scope>>>,
+    // Cache of help text for external commands (used by doc).
+    pub doc_cache: HashMap<String, String>,
+    // Socket for the message bus (only when subscribed to something).
+    pub bus_socket: Option<Rc<BusSocket>>,
+    // Handlers for bus messages by topic.
+    pub bus_handlers: HashMap<String, Vec<Expression>>,
+    // Terminals of the processes started with pty-spawn by pid.
+    pub ptys: HashMap<u32, Rc<RefCell<PtySession>>>,
+    // Types made with defstruct by name.
+    pub struct_types: HashMap<String, Rc<StructType>>,
+    // Methods of the generic functions by name then type name.
+    pub generics: HashMap<String, HashMap<String, Expression>>,
+    // Shell options (option and set-option!) by name.
+    pub options: HashMap<String, ShellOption>,
+    // Background process output passed to on-output handlers by pid.
+    pub output_followers: HashMap<u32, Rc<RefCell<OutputFollower>>>,
+    // Last git status (git-info) by .git directory.
+    pub git_status: HashMap<PathBuf, GitStatus>,
+    // Local env files loaded for the current directory, outermost first, and
+    // the directory they were found for (see builtins_local_env.rs).
+    pub local_envs: Vec<LocalEnv>,
+    pub local_env_dir: Option<PathBuf>,
+    // The history file, the interactive shell's or one from history-load.
+    pub history: Option<HistoryFile>,
+    // Jump tables for case forms by the address of their first clause.
+    pub case_tables: HashMap<usize, Rc<CaseTable>>,
+    // Expanded macro calls by the address of the call (see recompile).
+    pub macro_expansions: HashMap<usize, MacroExpansion>,
+    // Containers that may be in a reference cycle (see gc.rs).
+    pub gc: GcState,
+    // Empty scopes kept for reuse by calls (see pooled_scope).
+    pub scope_pool: Vec<Rc<RefCell<Scope>>>,
+    // Load times when started with --profile-startup.
+    pub startup_profile: Option<StartupProfile>,
 }
 
 pub fn build_default_environment(sig_int: Arc<AtomicBool>) -> Environment {
-    let procs: Rc<RefCell<HashMap<u32, Child>>> = Rc::new(RefCell::new(HashMap::new()));
+    let procs: Rc<RefCell<HashMap<u32, ProcessHandle>>> = Rc::new(RefCell::new(HashMap::new()));
     let root_scope = Rc::new(RefCell::new(Scope::default()));
     let mut current_scope = Vec::new();
     current_scope.push(root_scope.clone());
@@ -205,16 +409,46 @@ pub fn build_default_environment(sig_int: Arc<AtomicBool>) -> Environment {
         loose_symbols: false,
         str_ignore_expand: false,
         procs,
+        reaped: Rc::new(RefCell::new(HashMap::new())),
         data_in: None,
         form_type: FormType::Any,
         save_exit_status: true,
         stack_on_error: false,
         error_expression: None,
+        call_stack: Vec::new(),
+        error_chain: None,
+        error_chain_elided: 0,
+        error_data: None,
+        max_eval_depth: DEFAULT_MAX_EVAL_DEPTH,
+        stack_base: 0,
+        stack_size: main_stack_size(),
         exit_code: None,
+        force_exit: false,
+        thrown: None,
+        catch_tags: Vec::new(),
+        defers: Vec::new(),
+        exit_hooks: Vec::new(),
         dynamic_scope: HashMap::new(),
         root_scope,
         current_scope,
         namespaces,
+        doc_cache: HashMap::new(),
+        bus_socket: None,
+        bus_handlers: HashMap::new(),
+        ptys: HashMap::new(),
+        struct_types: HashMap::new(),
+        generics: HashMap::new(),
+        options: default_options(),
+        output_followers: HashMap::new(),
+        git_status: HashMap::new(),
+        local_envs: Vec::new(),
+        local_env_dir: None,
+        history: None,
+        case_tables: HashMap::new(),
+        macro_expansions: HashMap::new(),
+        gc: GcState::default(),
+        scope_pool: Vec::new(),
+        startup_profile: None,
     }
 }
 
@@ -222,12 +456,12 @@ pub fn build_new_spawn_scope<S: ::std::hash::BuildHasher>(
     mut data_in: HashMap<String, Expression, S>,
     sig_int: Arc<AtomicBool>,
 ) -> Environment {
-    let procs: Rc<RefCell<HashMap<u32, Child>>> = Rc::new(RefCell::new(HashMap::new()));
+    let procs: Rc<RefCell<HashMap<u32, ProcessHandle>>> = Rc::new(RefCell::new(HashMap::new()));
     let mut state = EnvState::default();
     let mut data: HashMap<String, Rc<Expression>> = HashMap::with_capacity(data_in.len());
     data.insert(
         "*ns*".to_string(),
-        Rc::new(Expression::Atom(Atom::String("root".to_string()))),
+        Rc::new(Expression::Atom(Atom::String("root".into()))),
     );
     for (k, v) in data_in.drain() {
         data.insert(k, Rc::new(v));
@@ -250,16 +484,46 @@ pub fn build_new_spawn_scope<S: ::std::hash::BuildHasher>(
         loose_symbols: false,
         str_ignore_expand: false,
         procs,
+        reaped: Rc::new(RefCell::new(HashMap::new())),
         data_in: None,
         form_type: FormType::Any,
         save_exit_status: true,
         stack_on_error: false,
         error_expression: None,
+        call_stack: Vec::new(),
+        error_chain: None,
+        error_chain_elided: 0,
+        error_data: None,
+        max_eval_depth: DEFAULT_MAX_EVAL_DEPTH,
+        stack_base: 0,
+        stack_size: thread_stack_size(),
         exit_code: None,
+        force_exit: false,
+        thrown: None,
+        catch_tags: Vec::new(),
+        defers: Vec::new(),
+        exit_hooks: Vec::new(),
         dynamic_scope: HashMap::new(),
         root_scope,
         current_scope,
         namespaces,
+        doc_cache: HashMap::new(),
+        bus_socket: None,
+        bus_handlers: HashMap::new(),
+        ptys: HashMap::new(),
+        struct_types: HashMap::new(),
+        generics: HashMap::new(),
+        options: default_options(),
+        output_followers: HashMap::new(),
+        git_status: HashMap::new(),
+        local_envs: Vec::new(),
+        local_env_dir: None,
+        history: None,
+        case_tables: HashMap::new(),
+        macro_expansions: HashMap::new(),
+        gc: GcState::default(),
+        scope_pool: Vec::new(),
+        startup_profile: None,
     }
 }
 
@@ -269,9 +533,51 @@ pub fn build_new_scope(outer: Option<Rc<RefCell<Scope>>>) -> Rc<RefCell<Scope>>
         data,
         outer,
         name: None,
+        meta: HashMap::new(),
+        frame: Vec::new(),
+        frame_names: None,
     }))
 }
 
+// Most scopes kept for reuse and the most bindings a reused scope may have
+// had (a bigger hashmap is freed so a big let does not hold memory).
+const MAX_POOLED_SCOPES: usize = 64;
+const MAX_POOLED_BINDINGS: usize = 32;
+
+// A scope for a call or macro expansion, from the pool if there is one so
+// the hashmap (and Rc) allocation is reused.  Give it back with release_scope.
+pub fn pooled_scope(
+    environment: &mut Environment,
+    outer: Option<Rc<RefCell<Scope>>>,
+) -> Rc<RefCell<Scope>> {
+    match environment.scope_pool.pop() {
+        Some(scope) => {
+            scope.borrow_mut().outer = outer;
+            scope
+        }
+        None => build_new_scope(outer),
+    }
+}
+
+// Done with a scope from pooled_scope, it is only reused if nothing else (a
+// closure) still has it.
+pub fn release_scope(environment: &mut Environment, scope: Rc<RefCell<Scope>>) {
+    if Rc::strong_count(&scope) != 1 || environment.scope_pool.len() >= MAX_POOLED_SCOPES {
+        return;
+    }
+    {
+        let mut s = scope.borrow_mut();
+        if s.data.capacity() > MAX_POOLED_BINDINGS || !s.meta.is_empty() || s.name.is_some() {
+            return;
+        }
+        s.data.clear();
+        s.frame.clear();
+        s.frame_names = None;
+        s.outer = None;
+    }
+    environment.scope_pool.push(scope);
+}
+
 pub fn build_new_namespace(
     environment: &mut Environment,
     name: &str,
@@ -283,12 +589,15 @@ pub fn build_new_namespace(
         let mut data: HashMap<String, Rc<Expression>> = HashMap::new();
         data.insert(
             "*ns*".to_string(),
-            Rc::new(Expression::Atom(Atom::String(name.to_string()))),
+            Rc::new(Expression::Atom(Atom::String(name.into()))),
         );
         let scope = Scope {
             data,
             outer: Some(environment.root_scope.clone()),
             name: Some(name.to_string()),
+            meta: HashMap::new(),
+            frame: Vec::new(),
+            frame_names: None,
         };
         let scope = Rc::new(RefCell::new(scope));
         environment
@@ -306,6 +615,11 @@ pub fn clone_symbols<S: ::std::hash::BuildHasher>(
         let v = &**v;
         data_in.insert(k.clone(), v.clone());
     }
+    if let Some(names) = &scope.frame_names {
+        for (k, v) in names.iter().zip(scope.frame.iter()) {
+            data_in.insert(k.clone(), (**v).clone());
+        }
+    }
     if let Some(outer) = &scope.outer {
         clone_symbols(&outer.borrow(), data_in);
     }
@@ -330,7 +644,7 @@ pub fn get_expression(environment: &Environment, key: &str) -> Option<Rc<Express
     } else {
         let mut loop_scope = Some(environment.current_scope.last().unwrap().clone());
         while let Some(scope) = loop_scope {
-            if let Some(exp) = scope.borrow().data.get(key) {
+            if let Some(exp) = scope.borrow().get(key) {
                 return Some(exp.clone());
             }
             loop_scope = scope.borrow().outer.clone();
@@ -339,6 +653,40 @@ pub fn get_expression(environment: &Environment, key: &str) -> Option<Rc<Express
     }
 }
 
+// The value of a resolved lambda parameter, None if the scope depth out from
+// the current one is not the frame it was resolved for (a scope was pushed
+// in between or it is dynamically bound), then look it up by name.
+pub fn get_local(
+    environment: &Environment,
+    key: &str,
+    depth: usize,
+    slot: usize,
+) -> Option<Rc<Expression>> {
+    if !environment.dynamic_scope.is_empty() && environment.dynamic_scope.contains_key(key) {
+        return None;
+    }
+    let current = environment.current_scope.last()?;
+    if depth == 0 {
+        return frame_value(&current.borrow(), key, slot);
+    }
+    let mut scope = current.clone();
+    for _ in 0..depth {
+        let outer = scope.borrow().outer.clone()?;
+        scope = outer;
+    }
+    let scope = scope.borrow();
+    frame_value(&scope, key, slot)
+}
+
+fn frame_value(scope: &Scope, key: &str, slot: usize) -> Option<Rc<Expression>> {
+    match &scope.frame_names {
+        Some(names) if names.get(slot).map_or(false, |name| name == key) => {
+            scope.frame.get(slot).cloned()
+        }
+        _ => None,
+    }
+}
+
 pub fn overwrite_expression(environment: &mut Environment, key: &str, expression: Rc<Expression>) {
     if environment.dynamic_scope.contains_key(key) {
         environment
@@ -359,8 +707,8 @@ pub fn overwrite_expression(environment: &mut Environment, key: &str, expression
     } else {
         let mut loop_scope = Some(environment.current_scope.last().unwrap().clone());
         while let Some(scope) = loop_scope {
-            if scope.borrow().data.contains_key(key) {
-                scope.borrow_mut().data.insert(key.to_string(), expression);
+            if scope.borrow().contains_key(key) {
+                scope.borrow_mut().insert(key.to_string(), expression);
                 return;
             }
             loop_scope = scope.borrow().outer.clone();
@@ -378,18 +726,17 @@ pub fn set_expression_current(
         .last()
         .unwrap() // Always has at least root scope unless horribly broken.
         .borrow_mut()
-        .data
         .insert(key, expression);
 }
 
 pub fn remove_expression_current(environment: &mut Environment, key: &str) {
-    environment
+    let mut scope = environment
         .current_scope
         .last()
         .unwrap() // Always has at least root scope unless horribly broken.
-        .borrow_mut()
-        .data
-        .remove(key);
+        .borrow_mut();
+    scope.remove(key);
+    scope.meta.remove(key);
 }
 
 pub fn is_expression(environment: &Environment, key: &str) -> bool {
@@ -407,7 +754,7 @@ pub fn get_symbols_scope(environment: &Environment, key: &str) -> Option<Rc<RefC
         let mut loop_scope = Some(environment.current_scope.last().unwrap().clone());
         while loop_scope.is_some() {
             let scope = loop_scope.unwrap();
-            if let Some(_exp) = scope.borrow().data.get(key) {
+            if scope.borrow().contains_key(key) {
                 return Some(scope.clone());
             }
             loop_scope = scope.borrow().outer.clone();
@@ -461,25 +808,28 @@ pub fn remove_job(environment: &Environment, pid: u32) {
     }
 }
 
-pub fn add_process(environment: &Environment, process: Child) -> u32 {
-    let pid = process.id();
+pub fn add_process(environment: &Environment, process: ProcessHandle) -> u32 {
+    let pid = process.pid();
     environment.procs.borrow_mut().insert(pid, process);
     pid
 }
 
+// Reap what changed (see reap_changed) and forget processes that are done,
+// any output of theirs that was not read stays with their handle.
 pub fn reap_procs(environment: &Environment) -> io::Result<()> {
-    let mut procs = environment.procs.borrow_mut();
-    let keys: Vec<u32> = procs.keys().copied().collect();
-    let mut pids: Vec<u32> = Vec::with_capacity(keys.len());
-    for key in keys {
-        if let Some(child) = procs.get_mut(&key) {
-            pids.push(child.id());
-        }
+    if sigchld_handler_installed() {
+        reap_changed(environment);
+    } else {
+        reap_children(environment);
     }
-    drop(procs);
-    for pid in pids {
-        try_wait_pid(environment, pid);
+    let done: Vec<u32> = environment
+        .reaped
+        .borrow_mut()
+        .drain()
+        .map(|(pid, _)| pid)
+        .collect();
+    for pid in done {
+        environment.procs.borrow_mut().remove(&pid);
     }
-    // XXX remove them or better replace pid with exit status
     Ok(())
 }
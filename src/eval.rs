@@ -1,108 +1,191 @@
-use std::cell::RefCell;
 use std::env;
 use std::io;
 use std::rc::Rc;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
+use crate::builtins::{run_cleanup, same_form, symbol_file};
 use crate::builtins_util::*;
 use crate::environment::*;
 use crate::process::*;
+use crate::resolve::*;
+use crate::suggest::*;
 use crate::types::*;
 
 fn box_slice_it<'a>(v: &'a [Expression]) -> Box<dyn Iterator<Item = &Expression> + 'a> {
     Box::new(v.iter())
 }
 
+// Run the deferred forms of the innermost body, last one first.  They all run
+// even if some fail, the first error is returned unless result is already one.
+pub fn run_defers(
+    environment: &mut Environment,
+    result: io::Result<Expression>,
+) -> io::Result<Expression> {
+    let defers = match environment.defers.last_mut() {
+        Some(defers) => std::mem::replace(defers, Vec::new()),
+        None => return result,
+    };
+    let mut result = result;
+    for form in defers.iter().rev() {
+        if let Err(err) = run_cleanup(environment, |environment| eval(environment, form)) {
+            if result.is_ok() {
+                result = Err(err);
+            } else {
+                eprintln!("ERROR in deferred form {}, {}", form, err);
+            }
+        }
+    }
+    result
+}
+
+fn lambda_body(environment: &mut Environment, lambda: &Lambda) -> io::Result<Expression> {
+    loop {
+        let last_eval = eval(environment, &lambda.body)?;
+        if environment.state.recur_num_args.is_none() || environment.exit_code.is_some() {
+            return Ok(last_eval);
+        }
+        let recur_args = environment.state.recur_num_args.unwrap();
+        environment.state.recur_num_args = None;
+        // Each pass through the body is done with it's defers.
+        let last_eval = run_defers(environment, Ok(last_eval))?;
+        if let Expression::Vector(new_args) = &last_eval {
+            if recur_args != new_args.borrow().len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Called recur in a non-tail position.",
+                ));
+            }
+            let new_args1 = new_args.borrow();
+            let ib = box_slice_it(&new_args1);
+            setup_args(environment, None, &lambda.params, ib, false)?;
+        }
+    }
+}
+
 fn call_lambda<'a>(
     environment: &mut Environment,
     lambda: &Lambda,
     args: Box<dyn Iterator<Item = &Expression> + 'a>,
 ) -> io::Result<Expression> {
-    // DO NOT use ? in here, need to make sure the new_scope is popped off the
-    // current_scope list before ending.
-    let mut looping = true;
-    let mut last_eval = Expression::Atom(Atom::Nil);
-    let new_scope = build_new_scope(Some(lambda.capture.clone()));
-    if let Err(err) = setup_args(
+    let new_scope = pooled_scope(environment, Some(lambda.capture.clone()));
+    new_scope.borrow_mut().frame_names = lambda.slots.clone();
+    let res = setup_args(
         environment,
         Some(&mut new_scope.borrow_mut()),
         &lambda.params,
         args,
         true,
-    ) {
+    );
+    if let Err(err) = res {
+        release_scope(environment, new_scope);
         return Err(err);
     }
+    // The new_scope and defers must be popped off before returning so no ? in
+    // lambda_body's caller.
     environment.current_scope.push(new_scope);
+    environment.defers.push(Vec::new());
     let old_loose = environment.loose_symbols;
     environment.loose_symbols = false;
-    while looping {
-        last_eval = match eval(environment, &lambda.body) {
-            Ok(e) => e,
-            Err(err) => {
-                environment.current_scope.pop();
-                return Err(err);
-            }
-        };
-        looping = environment.state.recur_num_args.is_some() && environment.exit_code.is_none();
-        if looping {
-            let recur_args = environment.state.recur_num_args.unwrap();
-            environment.state.recur_num_args = None;
-            if let Expression::Vector(new_args) = &last_eval {
-                if recur_args != new_args.borrow().len() {
-                    environment.current_scope.pop();
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Called recur in a non-tail position.",
-                    ));
-                }
-                let new_args1 = new_args.borrow();
-                let ib = box_slice_it(&new_args1);
-                if let Err(err) = setup_args(environment, None, &lambda.params, ib, false) {
-                    environment.current_scope.pop();
-                    return Err(err);
-                }
-            }
-        }
-    }
+    let result = lambda_body(environment, lambda);
+    let result = run_defers(environment, result);
     environment.loose_symbols = old_loose;
-    environment.current_scope.pop();
-    Ok(last_eval)
+    environment.defers.pop();
+    if let Some(scope) = environment.current_scope.pop() {
+        release_scope(environment, scope);
+    }
+    result
 }
 
-fn expand_macro<'a>(
+// The form a macro call expands to.
+fn macro_expansion<'a>(
     environment: &mut Environment,
     sh_macro: &Macro,
     args: Box<dyn Iterator<Item = &Expression> + 'a>,
 ) -> io::Result<Expression> {
     // DO NOT use ? in here, need to make sure the new_scope is popped off the
     // current_scope list before ending.
-    let mut new_scope = Scope::default();
-    match setup_args(
+    let outer = environment.current_scope.last().cloned();
+    let new_scope = pooled_scope(environment, outer);
+    let args = unresolve_all(args.cloned().collect());
+    let res = setup_args(
         environment,
-        Some(&mut new_scope),
+        Some(&mut new_scope.borrow_mut()),
         &sh_macro.params,
-        args,
+        Box::new(args.iter()),
         false,
-    ) {
-        Ok(_) => {}
-        Err(err) => {
-            return Err(err);
-        }
+    );
+    if let Err(err) = res {
+        release_scope(environment, new_scope);
+        return Err(err);
+    }
+    environment.current_scope.push(new_scope);
+    let expansion = eval(environment, &sh_macro.body);
+    if let Some(scope) = environment.current_scope.pop() {
+        release_scope(environment, scope);
+    }
+    expansion
+}
+
+fn expand_macro<'a>(
+    environment: &mut Environment,
+    sh_macro: &Macro,
+    args: Box<dyn Iterator<Item = &Expression> + 'a>,
+) -> io::Result<Expression> {
+    let expansion = macro_expansion(environment, sh_macro, args)?;
+    eval(environment, &expansion)
+}
+
+// Expansion of a macro call kept for the next time the call is evaluated.
+#[derive(Clone, Debug)]
+pub struct MacroExpansion {
+    // The call, to make sure a cached expansion is for this form.
+    form: Expression,
+    // The macro it was expanded with, a redefined macro expands again.
+    binding: Rc<Expression>,
+    expansion: Rc<Expression>,
+}
+
+// Expansions kept before the cache is cleared.
+const MAX_MACRO_EXPANSIONS: usize = 1000;
+
+// Expand a macro call once and eval the cached expansion after that (until
+// the macro is redefined or recompile is called).
+fn expand_macro_cached<'a>(
+    environment: &mut Environment,
+    form: &Expression,
+    binding: &Rc<Expression>,
+    sh_macro: &Macro,
+    args: Box<dyn Iterator<Item = &Expression> + 'a>,
+) -> io::Result<Expression> {
+    // Calls are found by the address of their list, checked since a freed
+    // form's address can be reused.
+    let id = match form {
+        Expression::Pair(car, _) => Rc::as_ptr(car) as usize,
+        Expression::Vector(list) => Rc::as_ptr(list) as usize,
+        _ => return expand_macro(environment, sh_macro, args),
     };
-    new_scope.outer = Some(environment.current_scope.last().unwrap().clone());
-    environment
-        .current_scope
-        .push(Rc::new(RefCell::new(new_scope)));
-    match eval(environment, &sh_macro.body) {
-        Ok(expansion) => {
-            environment.current_scope.pop();
-            eval(environment, &expansion)
+    let expansion = match environment.macro_expansions.get(&id) {
+        Some(cached) if same_form(&cached.form, form) && Rc::ptr_eq(&cached.binding, binding) => {
+            cached.expansion.clone()
         }
-        Err(err) => {
-            environment.current_scope.pop();
-            Err(err)
+        _ => {
+            let expansion = Rc::new(macro_expansion(environment, sh_macro, args)?);
+            if environment.macro_expansions.len() >= MAX_MACRO_EXPANSIONS {
+                environment.macro_expansions.clear();
+            }
+            environment.macro_expansions.insert(
+                id,
+                MacroExpansion {
+                    form: form.clone(),
+                    binding: binding.clone(),
+                    expansion: expansion.clone(),
+                },
+            );
+            expansion
         }
-    }
+    };
+    eval(environment, &expansion)
 }
 
 pub fn fn_call<'a>(
@@ -156,8 +239,11 @@ pub fn fn_call<'a>(
     }
 }
 
+// call is the whole form (None if there is not one), macro expansions are
+// cached by it.
 fn fn_eval<'a>(
     environment: &mut Environment,
+    call: Option<&Expression>,
     command: &Expression,
     mut parts: Box<dyn Iterator<Item = &Expression> + 'a>,
 ) -> io::Result<Expression> {
@@ -180,8 +266,21 @@ fn fn_eval<'a>(
                         f(environment, &parts)
                     }
                     Expression::Function(c) => (c.func)(environment, &mut *parts),
-                    Expression::Atom(Atom::Lambda(f)) => call_lambda(environment, &f, parts),
-                    Expression::Atom(Atom::Macro(m)) => expand_macro(environment, &m, parts),
+                    Expression::Atom(Atom::Lambda(f)) => {
+                        environment.call_stack.push(command.clone());
+                        let res = call_lambda(environment, &f, parts);
+                        environment.call_stack.pop();
+                        res
+                    }
+                    Expression::Atom(Atom::Macro(m)) => {
+                        environment.call_stack.push(command.clone());
+                        let res = match call {
+                            Some(call) => expand_macro_cached(environment, call, &exp, &m, parts),
+                            None => expand_macro(environment, &m, parts),
+                        };
+                        environment.call_stack.pop();
+                        res
+                    }
                     _ => {
                         let exp = exp.clone();
                         eval(environment, &exp)
@@ -190,7 +289,9 @@ fn fn_eval<'a>(
             } else if environment.form_type == FormType::ExternalOnly
                 || environment.form_type == FormType::Any
             {
-                if command.starts_with('$') {
+                if env_assignment(command).is_some() {
+                    env_prefix(environment, command, parts)
+                } else if command.starts_with('$') {
                     if let Ok(Expression::Atom(Atom::String(command))) =
                         str_process(environment, command)
                     {
@@ -199,11 +300,19 @@ fn fn_eval<'a>(
                         let msg = format!("Not a valid form {}, not found.", command.to_string());
                         Err(io::Error::new(io::ErrorKind::Other, msg))
                     }
+                } else if let Some(correction) = auto_correct(environment, command) {
+                    let correction = Expression::Atom(Atom::Symbol(correction.into()));
+                    fn_eval(environment, None, &correction, parts)
                 } else {
                     do_command(environment, command, parts)
                 }
             } else {
-                let msg = format!("Not a valid form {}, not found.", command.to_string());
+                let suggestions = closest(command, lisp_names(environment));
+                let msg = format!(
+                    "Not a valid form {}, not found.{}",
+                    command.to_string(),
+                    did_you_mean(&suggestions)
+                );
                 Err(io::Error::new(io::ErrorKind::Other, msg))
             }
         }
@@ -253,17 +362,81 @@ fn fn_eval<'a>(
     }
 }
 
+// An environment variable, $? is the last status (LAST_STATUS) like other
+// shells, $0 the script name and $1..$n its args.
+fn env_var(environment: &Environment, name: &str) -> Result<String, env::VarError> {
+    if name == "?" {
+        env::var("LAST_STATUS")
+    } else if !name.is_empty() && name.chars().all(|ch| ch.is_ascii_digit()) {
+        script_arg(environment, name).ok_or(env::VarError::NotPresent)
+    } else {
+        env::var(name)
+    }
+}
+
+// From the root scope so a local named args does not change $1.
+fn script_arg(environment: &Environment, name: &str) -> Option<String> {
+    let root = environment.root_scope.borrow();
+    let idx: usize = name.parse().ok()?;
+    if idx == 0 {
+        return root.data.get("*script-name*")?.as_string(environment).ok();
+    }
+    match root.data.get("args").map(|a| &**a) {
+        Some(Expression::Vector(args)) => args.borrow().get(idx - 1)?.as_string(environment).ok(),
+        _ => None,
+    }
+}
+
+// (NAME=value ... (command) ...) or (NAME=value ... command arg ...), the vars
+// are only set for the commands started (values get $ expansion).
+fn env_prefix<'a>(
+    environment: &mut Environment,
+    first: &str,
+    parts: Box<dyn Iterator<Item = &Expression> + 'a>,
+) -> io::Result<Expression> {
+    let parts: Vec<&Expression> = parts.collect();
+    let mut words = vec![first];
+    for part in &parts {
+        match part {
+            Expression::Atom(Atom::Symbol(s)) if env_assignment(s).is_some() => words.push(s),
+            _ => break,
+        }
+    }
+    let mut vars = Vec::with_capacity(words.len());
+    for word in &words {
+        if let Some((name, value)) = env_assignment(word) {
+            let value = str_process(environment, value)?.as_string(environment)?;
+            vars.push((name.to_string(), Some(value)));
+        }
+    }
+    let rest = &parts[words.len() - 1..];
+    match rest.first() {
+        None => {
+            let msg = format!(
+                "{}: no command to set it for (use export to set it in the shell)",
+                first
+            );
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+        Some(Expression::Atom(Atom::Symbol(_))) => {
+            let form = Expression::with_list(rest.iter().map(|e| (*e).clone()).collect());
+            with_child_env(environment, vars, &mut std::iter::once(&form))
+        }
+        Some(_) => with_child_env(environment, vars, &mut rest.iter().copied()),
+    }
+}
+
 fn str_process(environment: &mut Environment, string: &str) -> io::Result<Expression> {
     if !environment.str_ignore_expand && string.contains('$') {
         let mut new_string = String::new();
         let mut last_ch = '\0';
         let mut in_var = false;
         let mut var_start = 0;
-        for (i, ch) in string.chars().enumerate() {
+        for (i, ch) in string.char_indices() {
             if in_var {
                 if ch == ' ' || (ch == '$' && last_ch != '\\') {
                     in_var = false;
-                    match env::var(&string[var_start + 1..i]) {
+                    match env_var(environment, &string[var_start + 1..i]) {
                         Ok(val) => new_string.push_str(&val),
                         Err(_) => new_string.push_str(""),
                     }
@@ -274,7 +447,12 @@ fn str_process(environment: &mut Environment, string: &str) -> io::Result<Expres
             } else if ch == '$' && last_ch != '\\' {
                 in_var = true;
                 var_start = i;
-            } else if ch != '\\' {
+            } else if ch == '\\' {
+                // Held until the next char since it only escapes a $.
+                if last_ch == '\\' {
+                    new_string.push('\\');
+                }
+            } else {
                 if last_ch == '\\' && ch != '$' {
                     new_string.push('\\');
                 }
@@ -283,14 +461,29 @@ fn str_process(environment: &mut Environment, string: &str) -> io::Result<Expres
             last_ch = ch;
         }
         if in_var {
-            match env::var(&string[var_start + 1..]) {
+            match env_var(environment, &string[var_start + 1..]) {
                 Ok(val) => new_string.push_str(&val),
                 Err(_) => new_string.push_str(""),
             }
+        } else if last_ch == '\\' {
+            new_string.push('\\');
         }
-        Ok(Expression::Atom(Atom::String(new_string)))
+        Ok(Expression::Atom(Atom::String(new_string.into())))
+    } else {
+        Ok(Expression::Atom(Atom::String(string.into())))
+    }
+}
+
+// An :interrupted error if a SIGINT (ctrl-c) arrived, builtins that loop
+// without calling eval use this to stop.
+pub fn check_interrupted(environment: &Environment) -> io::Result<()> {
+    if environment.sig_int.load(Ordering::Relaxed) {
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Interrupted by SIGINT.",
+        ))
     } else {
-        Ok(Expression::Atom(Atom::String(string.to_string())))
+        Ok(())
     }
 }
 
@@ -298,15 +491,20 @@ fn internal_eval<'a>(
     environment: &mut Environment,
     expression: &'a Expression,
 ) -> io::Result<Expression> {
-    if environment.sig_int.load(Ordering::Relaxed) {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Script interupted by SIGINT.",
-        ));
+    check_interrupted(environment)?;
+    reap_changed(environment);
+    if let Some(deadline) = environment.state.deadline {
+        if Instant::now() >= deadline {
+            environment.state.deadline_hit.set(true);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Evaluation timed out (run-with-timeout).",
+            ));
+        }
     }
-    // exit was called so just return nil to unwind.
-    if environment.exit_code.is_some() {
-        return Ok(Expression::Atom(Atom::Nil));
+    // exit was called, keep unwinding even if something handled the error.
+    if let Some(code) = environment.exit_code {
+        return Err(exit_error(code));
     }
     let in_recur = environment.state.recur_num_args.is_some();
     if in_recur {
@@ -327,15 +525,24 @@ fn internal_eval<'a>(
                 }
             };
             let ib = box_slice_it(&parts);
-            fn_eval(environment, command, ib)
+            fn_eval(environment, Some(expression), command, ib)
         }
-        Expression::Pair(command, rest) => {
-            fn_eval(environment, &command.borrow(), rest.borrow().iter())
+        Expression::Pair(command, rest) => fn_eval(
+            environment,
+            Some(expression),
+            &command.borrow(),
+            rest.borrow().iter(),
+        ),
+        Expression::Atom(Atom::Local(s, depth, slot)) => {
+            match get_local(environment, s, *depth, *slot) {
+                Some(exp) => Ok((*exp).clone()),
+                None => internal_eval(environment, &Expression::Atom(Atom::Symbol(s.clone()))),
+            }
         }
         Expression::Atom(Atom::Symbol(s)) => {
             if s.starts_with('$') {
-                match env::var(&s[1..]) {
-                    Ok(val) => Ok(Expression::Atom(Atom::String(val))),
+                match env_var(environment, &s[1..]) {
+                    Ok(val) => Ok(Expression::Atom(Atom::String(val.into()))),
                     Err(_) => Ok(Expression::Atom(Atom::Nil)),
                 }
             } else if s.starts_with(':') {
@@ -357,35 +564,179 @@ fn internal_eval<'a>(
             }
         }
         Expression::HashMap(map) => Ok(Expression::HashMap(map.clone())),
+        Expression::Struct(stype, vals) => Ok(Expression::Struct(stype.clone(), vals.clone())),
+        Expression::PVec(_) | Expression::PMap(_) => Ok(expression.clone()),
+        Expression::Array(arr) => Ok(Expression::Array(arr.clone())),
+        Expression::Atom(Atom::String(string)) if string.literal => Ok(expression.clone()),
         Expression::Atom(Atom::String(string)) => str_process(environment, &string),
         Expression::Atom(atom) => Ok(Expression::Atom(atom.clone())),
         Expression::Func(_) => Ok(Expression::Atom(Atom::Nil)),
         Expression::Function(_) => Ok(Expression::Atom(Atom::Nil)),
-        Expression::Process(state) => Ok(Expression::Process(*state)),
+        Expression::Process(proc) => Ok(Expression::Process(proc.clone())),
         Expression::File(_) => Ok(Expression::Atom(Atom::Nil)),
     }
 }
 
+// Default for the max-eval-depth option.
+pub const DEFAULT_MAX_EVAL_DEPTH: u32 = 10000;
+// Stack left for the builtins and printing between evals.
+const STACK_RESERVE: usize = 512 * 1024;
+// Calls listed in a stack depth error.
+const DEPTH_ERROR_CALLS: usize = 5;
+
+// Size of the main thread's stack (the stack rlimit, 8M if unlimited).
+pub fn main_stack_size() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let res = unsafe { libc::getrlimit(libc::RLIMIT_STACK, &mut limit) };
+    if res != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        8 * 1024 * 1024
+    } else {
+        limit.rlim_cur as usize
+    }
+}
+
+// Size of the stack of a thread started with std::thread::spawn.
+pub fn thread_stack_size() -> usize {
+    match env::var("RUST_MIN_STACK") {
+        Ok(size) => size.parse().unwrap_or(2 * 1024 * 1024),
+        Err(_) => 2 * 1024 * 1024,
+    }
+}
+
+// Error if evals are nested too deep or the stack is almost used up, so
+// runaway recursion is an error that can be handled instead of a crash.
+fn check_depth(environment: &mut Environment) -> io::Result<()> {
+    let marker = 0_u8;
+    let here = &marker as *const u8 as usize;
+    // The stack grows down, keep the highest address seen at the top level.
+    if environment.state.eval_level == 1 && here > environment.stack_base {
+        environment.stack_base = here;
+    }
+    let level = environment.state.eval_level;
+    let used = environment.stack_base.saturating_sub(here);
+    let reserve = STACK_RESERVE.min(environment.stack_size / 2);
+    let why = if level > environment.max_eval_depth {
+        format!("max-eval-depth is {}", environment.max_eval_depth)
+    } else if used > environment.stack_size - reserve {
+        format!("out of stack at eval depth {}", level)
+    } else {
+        return Ok(());
+    };
+    let calls: Vec<&str> = environment
+        .call_stack
+        .iter()
+        .rev()
+        .take(DEPTH_ERROR_CALLS)
+        .map(|c| &**c)
+        .collect();
+    let mut msg = format!("stack depth exceeded ({})", why);
+    if !calls.is_empty() {
+        msg.push_str(&format!(", innermost calls: {}", calls.join(", ")));
+        if environment.call_stack.len() > calls.len() {
+            msg.push_str(", ...");
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        TypedError {
+            kind: ":stack-depth".to_string(),
+            msg,
+        },
+    ))
+}
+
 pub fn eval<'a>(
     environment: &mut Environment,
     expression: &'a Expression,
 ) -> io::Result<Expression> {
     environment.state.eval_level += 1;
-    let result = internal_eval(environment, expression);
-    if let Err(_err) = &result {
-        if environment.error_expression.is_none() {
-            environment.error_expression = Some(expression.clone());
-        }
-        if environment.stack_on_error {
-            eprintln!("{}: Error evaluting:", environment.state.eval_level);
-            let stderr = io::stderr();
-            let mut handle = stderr.lock();
-            if let Err(err) = expression.pretty_printf(environment, &mut handle) {
-                eprintln!("\nGOT SECONDARY ERROR PRINTING EXPRESSION: {}", err);
+    let result = match check_depth(environment) {
+        Ok(()) => internal_eval(environment, expression),
+        Err(err) => Err(err),
+    };
+    match &result {
+        // A throw or exit is not an error, just unwinding.
+        Err(err) if throw_tag(err).is_some() || is_exit(err) => {}
+        Err(_err) => {
+            if environment.error_expression.is_none() {
+                environment.error_expression = Some(expression.clone());
+                let keep = if environment.stack_on_error {
+                    environment.call_stack.len()
+                } else {
+                    environment.call_stack.len().min(MAX_BACKTRACE)
+                };
+                let chain = environment
+                    .call_stack
+                    .iter()
+                    .rev()
+                    .take(keep)
+                    .map(|name| match symbol_file(environment, name) {
+                        Some(file) => format!("{} ({})", name, file),
+                        None => name.to_string(),
+                    })
+                    .collect();
+                environment.error_chain = Some(chain);
+                environment.error_chain_elided = environment.call_stack.len() - keep;
+            }
+            if environment.stack_on_error {
+                eprintln!("{}: Error evaluting:", environment.state.eval_level);
+                let stderr = io::stderr();
+                let mut handle = stderr.lock();
+                if let Err(err) = expression.pretty_printf(environment, &mut handle) {
+                    eprintln!("\nGOT SECONDARY ERROR PRINTING EXPRESSION: {}", err);
+                }
+                eprintln!("\n=============================================================");
             }
-            eprintln!("\n=============================================================");
         }
+        Ok(_) => {}
     }
     environment.state.eval_level -= 1;
     result
 }
+
+// Longest form and most calls shown for an error unless error-stack is on.
+const MAX_ERROR_FORM: usize = 200;
+const MAX_ERROR_CHAIN: usize = 8;
+// Most calls kept for an error object's :backtrace unless error-stack is on
+// (deep recursion would make it huge).
+const MAX_BACKTRACE: usize = 64;
+
+// Forget the form and calls of an error that was handled.
+pub fn clear_error(environment: &mut Environment) {
+    environment.error_expression = None;
+    environment.error_chain = None;
+    environment.error_chain_elided = 0;
+    environment.error_data = None;
+}
+
+// The message of err followed by the form that failed and the calls it was in
+// (innermost first), then clears them for the next error.
+pub fn error_report(environment: &mut Environment, err: &io::Error) -> String {
+    let mut report = err.to_string();
+    if let Some(exp) = environment.error_expression.take() {
+        let mut form = exp.to_string();
+        if !environment.stack_on_error && form.chars().count() > MAX_ERROR_FORM {
+            form = form.chars().take(MAX_ERROR_FORM).collect::<String>() + " ...";
+        }
+        report.push_str(&format!("\n    evaluating: {}", form));
+    }
+    if let Some(chain) = environment.error_chain.take() {
+        let shown = if environment.stack_on_error {
+            chain.len()
+        } else {
+            chain.len().min(MAX_ERROR_CHAIN)
+        };
+        for call in &chain[..shown] {
+            report.push_str(&format!("\n    in: {}", call));
+        }
+        let more = chain.len() - shown + environment.error_chain_elided;
+        if more > 0 {
+            report.push_str(&format!("\n    ... {} more", more));
+        }
+    }
+    environment.error_chain_elided = 0;
+    report
+}
@@ -0,0 +1,314 @@
+use std::fs;
+use std::io::{self, Read, Write};
+
+use crate::reader::*;
+use crate::types::*;
+
+// Try to keep lines shorter then this.
+const WIDTH: usize = 80;
+// Spaces to indent the body of a broken form.
+const INDENT: usize = 4;
+
+// Number of forms after the head that stay on the head's line when a form is
+// broken over lines (the default is one).
+const INLINE_ARGS: &[(&str, usize)] = &[
+    ("progn", 0),
+    ("cond", 0),
+    ("defn", 2),
+    ("defmacro", 2),
+    ("setfn", 2),
+    ("setmacro", 2),
+    ("loop", 2),
+    ("for", 2),
+    ("dotimesi", 2),
+    ("fori", 3),
+];
+
+enum Node {
+    List(String, Vec<Node>),
+    Atom(String),
+    Comment(String, bool),
+    Blank,
+}
+
+fn build(tokens: Vec<SourceToken>) -> Result<Vec<Node>, ParseError> {
+    let mut stack: Vec<(String, Vec<Node>)> = vec![(String::new(), Vec::new())];
+    let mut prefix = String::new();
+    for token in tokens {
+        match token {
+            SourceToken::Open(open) => {
+                stack.push((format!("{}{}", prefix, open), Vec::new()));
+                prefix.clear();
+            }
+            SourceToken::Close => {
+                if stack.len() < 2 {
+                    return Err(ParseError {
+                        reason: "Unexpected `)`".to_string(),
+                    });
+                }
+                let (open, items) = stack.pop().unwrap();
+                stack.last_mut().unwrap().1.push(Node::List(open, items));
+            }
+            SourceToken::Prefix(p) => prefix.push_str(&p),
+            SourceToken::Atom(atom) => {
+                let atom = format!("{}{}", prefix, atom);
+                prefix.clear();
+                stack.last_mut().unwrap().1.push(Node::Atom(atom));
+            }
+            SourceToken::Comment(comment, trailing) => stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Comment(comment, trailing)),
+            SourceToken::Blank => stack.last_mut().unwrap().1.push(Node::Blank),
+        }
+    }
+    if stack.len() != 1 {
+        return Err(ParseError {
+            reason: "Unclosed list(s)".to_string(),
+        });
+    }
+    Ok(stack.pop().unwrap().1)
+}
+
+// The single line version of node, None if it can not be on one line.
+fn flat(node: &Node) -> Option<String> {
+    match node {
+        Node::Atom(atom) if !atom.contains('\n') => Some(atom.clone()),
+        Node::List(open, items) => {
+            let mut res = open.clone();
+            let mut first = true;
+            for item in items {
+                if let Node::Blank = item {
+                    continue;
+                }
+                if !first {
+                    res.push(' ');
+                }
+                res.push_str(&flat(item)?);
+                first = false;
+            }
+            res.push(')');
+            Some(res)
+        }
+        _ => None,
+    }
+}
+
+fn column(out: &str) -> usize {
+    match out.rfind('\n') {
+        Some(i) => out[i + 1..].chars().count(),
+        None => out.chars().count(),
+    }
+}
+
+fn newline(out: &mut String, col: usize) {
+    // No trailing whitespace.
+    while out.ends_with(' ') {
+        out.pop();
+    }
+    out.push('\n');
+    for _ in 0..col {
+        out.push(' ');
+    }
+}
+
+fn inline_args(head: &Node) -> Option<usize> {
+    if let Node::Atom(name) = head {
+        if name.starts_with('"') || name.parse::<f64>().is_ok() {
+            return None;
+        }
+        let name = name.rsplit("::").next().unwrap_or(name);
+        Some(
+            INLINE_ARGS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map_or(1, |(_, args)| *args),
+        )
+    } else {
+        None
+    }
+}
+
+fn pp(node: &Node, out: &mut String) {
+    let col = column(out);
+    if let Some(flat) = flat(node) {
+        if col + flat.chars().count() <= WIDTH {
+            out.push_str(&flat);
+            return;
+        }
+    }
+    match node {
+        Node::Atom(atom) => out.push_str(atom),
+        Node::Comment(comment, _) => out.push_str(comment),
+        Node::Blank => {}
+        Node::List(open, items) => {
+            out.push_str(open);
+            let items: Vec<&Node> = {
+                // Drop blank lines at the start and end of the list.
+                let start = items.iter().position(|i| !matches_blank(i));
+                let end = items.iter().rposition(|i| !matches_blank(i));
+                match (start, end) {
+                    (Some(start), Some(end)) => items[start..=end].iter().collect(),
+                    _ => Vec::new(),
+                }
+            };
+            // Code is indented, data (no symbol at the head) is aligned.
+            let (mut inline, indent) = match items.first().and_then(|h| inline_args(h)) {
+                Some(args) => (args + 1, col + INDENT),
+                None => (1, col + open.chars().count()),
+            };
+            let mut last_blank = false;
+            let mut last_line_comment = false;
+            for (i, item) in items.iter().enumerate() {
+                last_line_comment = false;
+                match item {
+                    Node::Blank => {
+                        if !last_blank {
+                            newline(out, 0);
+                        }
+                        last_blank = true;
+                        inline = 0;
+                        continue;
+                    }
+                    Node::Comment(comment, trailing) => {
+                        if *trailing && i > 0 {
+                            out.push(' ');
+                        } else if i > 0 {
+                            newline(out, indent);
+                        }
+                        out.push_str(comment);
+                        last_line_comment = comment.starts_with(';');
+                        inline = 0;
+                    }
+                    _ => {
+                        // A form that has to be broken starting far to the
+                        // right goes on the next line instead.
+                        let fits = i == 0
+                            || column(out) < indent + 2
+                            || flat(item)
+                                .map_or(false, |f| column(out) + 1 + f.chars().count() <= WIDTH);
+                        if i < inline && fits {
+                            if i > 0 {
+                                out.push(' ');
+                            }
+                        } else {
+                            inline = 0;
+                            newline(out, indent);
+                        }
+                        pp(item, out);
+                    }
+                }
+                last_blank = false;
+            }
+            if last_line_comment {
+                newline(out, indent);
+            }
+            out.push(')');
+        }
+    }
+}
+
+fn matches_blank(node: &Node) -> bool {
+    match node {
+        Node::Blank => true,
+        _ => false,
+    }
+}
+
+// Re-print lisp source with canonical indentation and spacing, comments and
+// (single) blank lines are kept.
+pub fn format_source(text: &str) -> Result<String, ParseError> {
+    let tokens = source_tokens(text);
+    let has_code = tokens.iter().any(|t| match t {
+        SourceToken::Comment(_, _) | SourceToken::Blank => false,
+        _ => true,
+    });
+    let nodes = build(tokens)?;
+    let mut out = String::new();
+    let mut last_blank = true;
+    for (i, node) in nodes.iter().enumerate() {
+        match node {
+            Node::Blank => {
+                if !last_blank {
+                    out.push('\n');
+                }
+                last_blank = true;
+                continue;
+            }
+            Node::Comment(comment, true) if i > 0 => {
+                out.pop();
+                out.push(' ');
+                out.push_str(comment);
+            }
+            _ => pp(node, &mut out),
+        }
+        out.push('\n');
+        last_blank = false;
+    }
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    if has_code {
+        // Make sure nothing but layout changed.
+        let before = read(text, false)?;
+        let after = read(&out, false)?;
+        if before.to_string() != after.to_string() {
+            return Err(ParseError {
+                reason: "Formatting would change the meaning of the source, not formatting"
+                    .to_string(),
+            });
+        }
+    }
+    Ok(out)
+}
+
+// Format each file in place, with no files format stdin to stdout.
+pub fn run_fmt(files: &[String]) -> i32 {
+    if files.is_empty() {
+        let mut text = String::new();
+        if let Err(err) = io::stdin().read_to_string(&mut text) {
+            eprintln!("Error reading stdin: {}", err);
+            return 1;
+        }
+        return match format_source(&text) {
+            Ok(formatted) => {
+                if let Err(err) = io::stdout().write_all(formatted.as_bytes()) {
+                    eprintln!("Error writing stdout: {}", err);
+                    return 1;
+                }
+                0
+            }
+            Err(err) => {
+                eprintln!("stdin: {}", err.reason);
+                1
+            }
+        };
+    }
+    let mut code = 0;
+    for file in files {
+        let text = match fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("{}: {}", file, err);
+                code = 1;
+                continue;
+            }
+        };
+        match format_source(&text) {
+            Ok(formatted) => {
+                if formatted != text {
+                    if let Err(err) = fs::write(file, formatted) {
+                        eprintln!("{}: {}", file, err);
+                        code = 1;
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("{}: {}", file, err.reason);
+                code = 1;
+            }
+        }
+    }
+    code
+}
@@ -0,0 +1,393 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::{Rc, Weak};
+
+use crate::environment::*;
+use crate::types::*;
+
+// Cycle collection for the Rc data.  Reference counting frees everything but
+// cycles (a vector pushed into itself, a lambda kept in the scope it
+// captured, ...).  A container is remembered (weakly) as a candidate when a
+// destructive builtin stores a container in it or a lambda captures it's
+// scope, nothing else can close a cycle.  A collection walks everything
+// reachable from the candidates and counts the references that come from
+// inside that graph.  A node with more strong references than that is held
+// from outside (the environment or a running builtin) and keeps everything it
+// reaches alive, what is left is only referenced by cycles and is cleared to
+// break them.  A node that is borrowed is treated as held from outside so a
+// collection is safe at any point, it may just miss some garbage.
+
+// Collect when there are at least this many candidates.
+const GC_MIN_THRESHOLD: usize = 10_000;
+
+// A container that may be part of a cycle.
+#[derive(Clone, Debug)]
+pub enum GcCandidate {
+    Vector(Weak<RefCell<Vec<Expression>>>),
+    Cell(Weak<RefCell<Expression>>),
+    HashMap(Weak<RefCell<HashMap<String, Rc<Expression>>>>),
+    Scope(Weak<RefCell<Scope>>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GcState {
+    pub candidates: Vec<GcCandidate>,
+    // Candidates that survived the last collection, the next one waits for
+    // twice as many.
+    pub survivors: usize,
+    pub collections: usize,
+    pub freed: usize,
+}
+
+enum Node {
+    Vector(Rc<RefCell<Vec<Expression>>>),
+    Cell(Rc<RefCell<Expression>>),
+    HashMap(Rc<RefCell<HashMap<String, Rc<Expression>>>>),
+    Value(Rc<Expression>),
+    Scope(Rc<RefCell<Scope>>),
+}
+
+impl Node {
+    fn id(&self) -> usize {
+        match self {
+            Node::Vector(rc) => Rc::as_ptr(rc) as *const u8 as usize,
+            Node::Cell(rc) => Rc::as_ptr(rc) as *const u8 as usize,
+            Node::HashMap(rc) => Rc::as_ptr(rc) as *const u8 as usize,
+            Node::Value(rc) => Rc::as_ptr(rc) as *const u8 as usize,
+            Node::Scope(rc) => Rc::as_ptr(rc) as *const u8 as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Vector(rc) => Rc::strong_count(rc),
+            Node::Cell(rc) => Rc::strong_count(rc),
+            Node::HashMap(rc) => Rc::strong_count(rc),
+            Node::Value(rc) => Rc::strong_count(rc),
+            Node::Scope(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // Push the nodes this one references, false if it is borrowed.
+    fn children(&self, out: &mut Vec<Node>) -> bool {
+        match self {
+            Node::Vector(list) => match list.try_borrow() {
+                Ok(list) => {
+                    for item in list.iter() {
+                        expression_children(item, out);
+                    }
+                }
+                Err(_) => return false,
+            },
+            Node::Cell(cell) => match cell.try_borrow() {
+                Ok(exp) => expression_children(&exp, out),
+                Err(_) => return false,
+            },
+            Node::HashMap(map) => match map.try_borrow() {
+                Ok(map) => {
+                    for val in map.values() {
+                        out.push(Node::Value(val.clone()));
+                    }
+                }
+                Err(_) => return false,
+            },
+            Node::Value(exp) => expression_children(exp, out),
+            Node::Scope(scope) => match scope.try_borrow() {
+                Ok(scope) => {
+                    for val in scope.data.values().chain(scope.frame.iter()) {
+                        out.push(Node::Value(val.clone()));
+                    }
+                    for meta in scope.meta.values() {
+                        for val in meta.values() {
+                            out.push(Node::Value(val.clone()));
+                        }
+                    }
+                    if let Some(outer) = &scope.outer {
+                        scope_child(outer, out);
+                    }
+                }
+                Err(_) => return false,
+            },
+        }
+        true
+    }
+
+    // Drop what this garbage node references (into dropped, they are freed
+    // after every node is cleared).
+    fn clear(&self, dropped: &mut Vec<Expression>) {
+        match self {
+            Node::Vector(list) => dropped.append(&mut list.borrow_mut()),
+            Node::Cell(cell) => dropped.push(cell.replace(Expression::Atom(Atom::Nil))),
+            Node::HashMap(map) => {
+                dropped.extend(map.borrow_mut().drain().map(|(_, v)| (*v).clone()));
+            }
+            // Freed with the containers that hold it.
+            Node::Value(_) => {}
+            Node::Scope(scope) => {
+                let mut scope = scope.borrow_mut();
+                scope.data.clear();
+                scope.frame.clear();
+                scope.meta.clear();
+                scope.outer = None;
+            }
+        }
+    }
+}
+
+// The containers an expression references directly.  Lambda bodies are code
+// and not followed (anything in them looks held from outside).
+fn expression_children(exp: &Expression, out: &mut Vec<Node>) {
+    match exp {
+        Expression::Vector(list) => out.push(Node::Vector(list.clone())),
+        Expression::Pair(car, cdr) => {
+            out.push(Node::Cell(car.clone()));
+            out.push(Node::Cell(cdr.clone()));
+        }
+        Expression::HashMap(map) => out.push(Node::HashMap(map.clone())),
+        Expression::Struct(_, vals) => out.push(Node::Vector(vals.clone())),
+        Expression::Atom(Atom::Lambda(lambda)) => scope_child(&lambda.capture, out),
+        _ => {}
+    }
+}
+
+// Namespaces are held by the environment and not walked (they have most of
+// the data).
+fn scope_child(scope: &Rc<RefCell<Scope>>, out: &mut Vec<Node>) {
+    if let Ok(s) = scope.try_borrow() {
+        if s.name.is_some() {
+            return;
+        }
+    }
+    out.push(Node::Scope(scope.clone()));
+}
+
+// Can exp close a cycle if it is stored in a container?
+pub fn gc_may_cycle(exp: &Expression) -> bool {
+    matches!(
+        exp,
+        Expression::Vector(_)
+            | Expression::Pair(_, _)
+            | Expression::HashMap(_)
+            | Expression::Struct(_, _)
+            | Expression::Atom(Atom::Lambda(_))
+    )
+}
+
+// Remember a container that was given val, collects when there are enough
+// candidates.
+pub fn gc_track(environment: &mut Environment, candidate: GcCandidate) {
+    environment.gc.candidates.push(candidate);
+    if environment.gc.candidates.len() >= GC_MIN_THRESHOLD.max(environment.gc.survivors * 2) {
+        gc_collect(environment);
+    }
+}
+
+// Track the scope a new lambda captured, namespaces are never garbage.
+pub fn gc_track_capture(environment: &mut Environment, capture: &Rc<RefCell<Scope>>) {
+    if capture.borrow().name.is_none() {
+        gc_track(environment, GcCandidate::Scope(Rc::downgrade(capture)));
+    }
+}
+
+// Walk the graph from the given nodes, the nodes (each held once) with the
+// references they get from inside the graph and if they could be read.
+fn discover(start: Vec<Node>) -> (Vec<Node>, Vec<usize>, Vec<bool>, HashMap<usize, usize>) {
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut internal: Vec<usize> = Vec::new();
+    let mut readable: Vec<bool> = Vec::new();
+    let mut index: HashMap<usize, usize> = HashMap::new();
+    for node in start {
+        if let Entry::Vacant(entry) = index.entry(node.id()) {
+            entry.insert(nodes.len());
+            nodes.push(node);
+            internal.push(0);
+            readable.push(true);
+        }
+    }
+    let mut i = 0;
+    let mut children = Vec::new();
+    while i < nodes.len() {
+        readable[i] = nodes[i].children(&mut children);
+        for child in children.drain(..) {
+            match index.get(&child.id()) {
+                Some(idx) => internal[*idx] += 1,
+                None => {
+                    index.insert(child.id(), nodes.len());
+                    nodes.push(child);
+                    internal.push(1);
+                    readable.push(true);
+                }
+            }
+        }
+        i += 1;
+    }
+    (nodes, internal, readable, index)
+}
+
+// Break the cycles nothing outside references, returns the number of
+// containers cleared.
+pub fn gc_collect(environment: &mut Environment) -> usize {
+    let candidates = std::mem::take(&mut environment.gc.candidates);
+    let start: Vec<Node> = candidates
+        .iter()
+        .filter_map(|candidate| match candidate {
+            GcCandidate::Vector(weak) => weak.upgrade().map(Node::Vector),
+            GcCandidate::Cell(weak) => weak.upgrade().map(Node::Cell),
+            GcCandidate::HashMap(weak) => weak.upgrade().map(Node::HashMap),
+            GcCandidate::Scope(weak) => weak.upgrade().map(Node::Scope),
+        })
+        .collect();
+    let (nodes, internal, readable, index) = discover(start);
+    // Everything reachable from a node held from outside is alive.
+    let mut alive = vec![false; nodes.len()];
+    let mut work: Vec<usize> = (0..nodes.len())
+        .filter(|i| !readable[*i] || nodes[*i].strong_count() - 1 > internal[*i])
+        .collect();
+    for i in &work {
+        alive[*i] = true;
+    }
+    let mut children = Vec::new();
+    while let Some(i) = work.pop() {
+        nodes[i].children(&mut children);
+        for child in children.drain(..) {
+            if let Some(idx) = index.get(&child.id()) {
+                if !alive[*idx] {
+                    alive[*idx] = true;
+                    work.push(*idx);
+                }
+            }
+        }
+    }
+    let mut dropped = Vec::new();
+    let mut freed = 0;
+    for (node, alive) in nodes.iter().zip(alive.iter()) {
+        if !alive {
+            if let Node::Value(_) = node {
+            } else {
+                freed += 1;
+            }
+            node.clear(&mut dropped);
+        }
+    }
+    drop(dropped);
+    // Keep the candidates that are still alive (once each).
+    let mut seen = HashSet::new();
+    let survivors: Vec<GcCandidate> = candidates
+        .into_iter()
+        .filter(|candidate| {
+            let id = match candidate {
+                GcCandidate::Vector(weak) => weak.as_ptr() as *const u8 as usize,
+                GcCandidate::Cell(weak) => weak.as_ptr() as *const u8 as usize,
+                GcCandidate::HashMap(weak) => weak.as_ptr() as *const u8 as usize,
+                GcCandidate::Scope(weak) => weak.as_ptr() as *const u8 as usize,
+            };
+            match index.get(&id) {
+                Some(idx) => alive[*idx] && seen.insert(id),
+                None => false,
+            }
+        })
+        .collect();
+    environment.gc.survivors = survivors.len();
+    environment.gc.candidates = survivors;
+    environment.gc.collections += 1;
+    environment.gc.freed += freed;
+    freed
+}
+
+fn builtin_gc(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "gc takes no arguments",
+        ));
+    }
+    Ok(Expression::Atom(Atom::Int(gc_collect(environment) as i64)))
+}
+
+fn builtin_gc_stats(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "gc-stats takes no arguments",
+        ));
+    }
+    // Count what is reachable from the namespaces, the scopes in use and the
+    // dynamic bindings.
+    let mut start: Vec<Node> = Vec::new();
+    start.extend(environment.namespaces.values().cloned().map(Node::Scope));
+    start.extend(environment.current_scope.iter().cloned().map(Node::Scope));
+    start.push(Node::Scope(environment.root_scope.clone()));
+    start.extend(environment.dynamic_scope.values().cloned().map(Node::Value));
+    let (nodes, _, _, _) = discover(start);
+    let is_lambda = |exp: &Expression| matches!(exp, Expression::Atom(Atom::Lambda(_)));
+    let (mut scopes, mut vectors, mut pairs, mut hashmaps, mut lambdas) = (0, 0, 0, 0, 0);
+    for node in &nodes {
+        match node {
+            Node::Scope(_) => scopes += 1,
+            Node::Vector(list) => {
+                vectors += 1;
+                if let Ok(list) = list.try_borrow() {
+                    lambdas += list.iter().filter(|item| is_lambda(item)).count();
+                }
+            }
+            Node::HashMap(_) => hashmaps += 1,
+            // Every pair is two cells.
+            Node::Cell(cell) => {
+                pairs += 1;
+                if let Ok(exp) = cell.try_borrow() {
+                    if is_lambda(&exp) {
+                        lambdas += 1;
+                    }
+                }
+            }
+            Node::Value(exp) => {
+                if is_lambda(exp) {
+                    lambdas += 1;
+                }
+            }
+        }
+    }
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    for (key, val) in &[
+        (":scopes", scopes),
+        (":vectors", vectors),
+        (":pairs", pairs / 2),
+        (":hashmaps", hashmaps),
+        (":lambdas", lambdas),
+        (":candidates", environment.gc.candidates.len()),
+        (":collections", environment.gc.collections),
+        (":freed", environment.gc.freed),
+    ] {
+        map.insert(
+            key.to_string(),
+            Rc::new(Expression::Atom(Atom::Int(*val as i64))),
+        );
+    }
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+pub fn add_gc_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "gc".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_gc,
+            "Free the data that is only referenced by reference cycles now (this also happens as destructive forms create candidates), returns how many containers were cleared.",
+        )),
+    );
+    data.insert(
+        "gc-stats".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_gc_stats,
+            "Return a hashmap of live data counts (:scopes :vectors :pairs :hashmaps :lambdas reachable from the namespaces and current scopes) and the cycle collector's :candidates, :collections and :freed containers.",
+        )),
+    );
+}
@@ -0,0 +1,496 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::fcntl::{flock, FlockArg};
+
+use crate::builtins_options::get_option;
+use crate::builtins_util::expand_tilde;
+use crate::environment::*;
+use crate::eval::*;
+use crate::types::*;
+
+// First line of the history file.  A file without it is the old format (one
+// command per line) and is rewritten in this one when loaded.
+const HEADER: &str = "#sl-sh-history 2";
+
+// One command, the old format had no times or status so those entries have
+// all zeros.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    // Seconds since the epoch.
+    pub start: u64,
+    // Milliseconds.
+    pub duration: u64,
+    pub status: i32,
+    pub command: String,
+}
+
+impl HistoryEntry {
+    pub fn new(command: &str, started: SystemTime, duration: Duration, status: i32) -> Self {
+        HistoryEntry {
+            start: started
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            duration: duration.as_millis() as u64,
+            status,
+            command: command.to_string(),
+        }
+    }
+
+    // start, duration, status and the (escaped) command, tab separated.
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.start,
+            self.duration,
+            self.status,
+            escape(&self.command)
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, '\t');
+        let start = fields.next()?.parse().ok()?;
+        let duration = fields.next()?.parse().ok()?;
+        let status = fields.next()?.parse().ok()?;
+        let command = unescape(fields.next()?);
+        Some(HistoryEntry {
+            start,
+            duration,
+            status,
+            command,
+        })
+    }
+}
+
+// Keep each entry on one line (multi-line commands).
+fn escape(command: &str) -> String {
+    let mut res = String::with_capacity(command.len());
+    for ch in command.chars() {
+        match ch {
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            _ => res.push(ch),
+        }
+    }
+    res
+}
+
+fn unescape(field: &str) -> String {
+    let mut res = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            res.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('t') => res.push('\t'),
+            Some(ch) => res.push(ch),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+// An exclusive flock on the lock file next to the history, released when
+// dropped.  The history itself is replaced when compacted so it can not hold
+// the lock.
+struct HistoryLock {
+    _file: File,
+}
+
+impl HistoryLock {
+    fn new(path: &Path) -> io::Result<Self> {
+        let mut lock_name = path.to_path_buf().into_os_string();
+        lock_name.push(".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(PathBuf::from(lock_name))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        Ok(HistoryLock { _file: file })
+    }
+}
+
+// The history file shared by every session and the last max entries this
+// session knows about.  Entries are appended as they are run (under the lock)
+// and the entries other sessions appended since the last look are read in
+// by push and merge.
+#[derive(Clone, Debug)]
+pub struct HistoryFile {
+    path: PathBuf,
+    entries: Vec<HistoryEntry>,
+    // Read by push but not handed out by merge yet.
+    merged: Vec<HistoryEntry>,
+    // How much of the file has been read and which file it was, another
+    // session compacting it replaces it.
+    read_to: u64,
+    inode: u64,
+    // The last line read, to find the new entries in a replaced file.
+    last_line: Option<String>,
+    max: usize,
+}
+
+impl HistoryFile {
+    // Load path (a missing file is an empty history), an old format file is
+    // converted.  With a max of 0 the file is not read or converted.
+    pub fn load(path: &Path, max: usize) -> io::Result<Self> {
+        let mut history = HistoryFile {
+            path: path.to_path_buf(),
+            entries: Vec::new(),
+            merged: Vec::new(),
+            read_to: 0,
+            inode: 0,
+            last_line: None,
+            max,
+        };
+        if max == 0 {
+            return Ok(history);
+        }
+        let _lock = HistoryLock::new(path)?;
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(err) => return Err(err),
+        };
+        let mut lines = BufReader::new(&file).lines();
+        match lines.next() {
+            Some(line) => {
+                let line = line?;
+                if line != HEADER {
+                    history.push_old(line);
+                    for line in lines {
+                        history.push_old(line?);
+                    }
+                    history.trim();
+                    history.rewrite(history.entries.clone())?;
+                    return Ok(history);
+                }
+            }
+            None => return Ok(history),
+        }
+        drop(lines);
+        history.entries = history.read_new()?;
+        history.trim();
+        Ok(history)
+    }
+
+    fn push_old(&mut self, line: String) {
+        if !line.trim().is_empty() {
+            self.entries.push(HistoryEntry {
+                start: 0,
+                duration: 0,
+                status: 0,
+                command: line,
+            });
+        }
+    }
+
+    fn trim(&mut self) {
+        if self.entries.len() > self.max {
+            self.entries.drain(..self.entries.len() - self.max);
+        }
+        if self.merged.len() > self.max {
+            self.merged.drain(..self.merged.len() - self.max);
+        }
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+        self.trim();
+    }
+
+    // The entries appended after read_to, or after last_line if the file was
+    // replaced.  Only call with the lock held.
+    fn read_new(&mut self) -> io::Result<Vec<HistoryEntry>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.read_to = 0;
+                self.inode = 0;
+                return Ok(Vec::new());
+            }
+            Err(err) => return Err(err),
+        };
+        let meta = file.metadata()?;
+        let replaced = meta.ino() != self.inode || meta.len() < self.read_to;
+        let start = if replaced { 0 } else { self.read_to };
+        file.seek(SeekFrom::Start(start))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        // Writers hold the lock but a partial line (a crash) waits for its end.
+        let complete = match text.rfind('\n') {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        let mut lines: Vec<&str> = text[..complete].lines().collect();
+        if replaced && start == 0 {
+            if let Some(last) = &self.last_line {
+                lines = match lines.iter().rposition(|l| l == last) {
+                    Some(i) => lines.split_off(i + 1),
+                    // Nothing in common (a new file), all of it is new.
+                    None => lines,
+                };
+            }
+        }
+        if let Some(last) = lines.last() {
+            self.last_line = Some(last.to_string());
+        }
+        let entries = lines
+            .iter()
+            .filter_map(|l| HistoryEntry::from_line(l))
+            .collect();
+        self.read_to = start + complete as u64;
+        self.inode = meta.ino();
+        Ok(entries)
+    }
+
+    // Add entry and append it to the file, picking up what other sessions
+    // appended first so the file order is kept.  A max of 0 saves nothing.
+    pub fn push(&mut self, entry: HistoryEntry) -> io::Result<()> {
+        if self.max == 0 {
+            return Ok(());
+        }
+        let _lock = HistoryLock::new(&self.path)?;
+        let others = self.read_new()?;
+        self.entries.extend(others.iter().cloned());
+        self.merged.extend(others);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let line = entry.to_line();
+        let mut text = String::new();
+        if file.metadata()?.len() == 0 {
+            text.push_str(HEADER);
+            text.push('\n');
+        }
+        text.push_str(&line);
+        text.push('\n');
+        file.write_all(text.as_bytes())?;
+        let meta = file.metadata()?;
+        self.read_to = meta.len();
+        self.inode = meta.ino();
+        self.last_line = Some(line);
+        self.entries.push(entry);
+        self.trim();
+        Ok(())
+    }
+
+    // Entries other sessions added since the last merge, oldest first.
+    pub fn merge(&mut self) -> io::Result<Vec<HistoryEntry>> {
+        let unchanged = match fs::metadata(&self.path) {
+            Ok(meta) => meta.ino() == self.inode && meta.len() == self.read_to,
+            Err(_) => self.inode == 0,
+        };
+        if !unchanged {
+            let _lock = HistoryLock::new(&self.path)?;
+            let others = self.read_new()?;
+            self.entries.extend(others.iter().cloned());
+            self.merged.extend(others);
+            self.trim();
+        }
+        Ok(std::mem::take(&mut self.merged))
+    }
+
+    // Trim the file to the last max entries if every session together has
+    // added more than that (a max of 0 leaves the file alone).
+    pub fn compact(&mut self) -> io::Result<()> {
+        if self.max == 0 {
+            return Ok(());
+        }
+        let _lock = HistoryLock::new(&self.path)?;
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines().skip(1) {
+            if let Some(entry) = HistoryEntry::from_line(&line?) {
+                entries.push(entry);
+            }
+        }
+        if entries.len() > self.max {
+            entries.drain(..entries.len() - self.max);
+            self.rewrite(entries)?;
+        }
+        Ok(())
+    }
+
+    // Write to a temp file and rename it so the history is never half written.
+    // Only call with the lock held.
+    fn rewrite(&mut self, entries: Vec<HistoryEntry>) -> io::Result<()> {
+        let mut tmp_name = self.path.clone().into_os_string();
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        {
+            let mut file = io::BufWriter::new(File::create(&tmp)?);
+            writeln!(file, "{}", HEADER)?;
+            for entry in &entries {
+                writeln!(file, "{}", entry.to_line())?;
+            }
+            file.flush()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        let meta = fs::metadata(&self.path)?;
+        self.read_to = meta.len();
+        self.inode = meta.ino();
+        self.last_line = entries.last().map(HistoryEntry::to_line);
+        Ok(())
+    }
+}
+
+// The max-history option.
+pub fn max_history(environment: &Environment) -> usize {
+    match get_option(environment, "max-history") {
+        Expression::Atom(Atom::Int(max)) if max > 0 => max as usize,
+        _ => 0,
+    }
+}
+
+fn entry_to_expression(entry: &HistoryEntry) -> Expression {
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    for (key, val) in &[
+        (":start", entry.start as i64),
+        (":duration", entry.duration as i64),
+        (":status", i64::from(entry.status)),
+    ] {
+        map.insert(key.to_string(), Rc::new(Expression::Atom(Atom::Int(*val))));
+    }
+    map.insert(
+        ":command".to_string(),
+        Rc::new(Expression::Atom(Atom::String(entry.command.clone().into()))),
+    );
+    Expression::HashMap(Rc::new(RefCell::new(map)))
+}
+
+fn builtin_history(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "history takes no arguments",
+        ));
+    }
+    let entries = match &environment.history {
+        Some(history) => history.entries().iter().map(entry_to_expression).collect(),
+        None => Vec::new(),
+    };
+    Ok(Expression::with_list(entries))
+}
+
+fn builtin_history_load(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if let Some(arg) = args.next() {
+        if args.next().is_none() {
+            let path = eval(environment, arg)?.as_string(environment)?;
+            let path = expand_tilde(&path).unwrap_or(path);
+            let history = HistoryFile::load(Path::new(&path), max_history(environment))?;
+            let len = history.entries().len();
+            environment.history = Some(history);
+            return Ok(Expression::Atom(Atom::Int(len as i64)));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "history-load takes one form (a file)",
+    ))
+}
+
+fn builtin_history_add(
+    environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let command = match args.next() {
+        Some(arg) => eval(environment, arg)?.as_string(environment)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "history-add takes a command and an optional status",
+            ))
+        }
+    };
+    let status = match args.next() {
+        Some(arg) => match eval(environment, arg)? {
+            Expression::Atom(Atom::Int(status)) => status as i32,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "history-add: the status must be an integer",
+                ))
+            }
+        },
+        None => 0,
+    };
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "history-add takes a command and an optional status",
+        ));
+    }
+    match &mut environment.history {
+        Some(history) => {
+            let entry = HistoryEntry::new(
+                &command,
+                SystemTime::now(),
+                Duration::from_millis(0),
+                status,
+            );
+            history.push(entry)?;
+            Ok(Expression::Atom(Atom::Nil))
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "history-add: no history file (see history-load)",
+        )),
+    }
+}
+
+pub fn add_history_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "history".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_history,
+            "Vector of the history entries, oldest first, as hashmaps with :start (seconds since the epoch), :duration (milliseconds), :status and :command.  Entries from a file in the old format have zero times and status.",
+        )),
+    );
+    data.insert(
+        "history-load".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_history_load,
+            "Load a history file (converting one in the old format) as the history that history and history-add use, returns the number of entries kept (up to max-history).",
+        )),
+    );
+    data.insert(
+        "history-add".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_history_add,
+            "Append a command (and exit status, default 0) to the history file like the interactive shell does, after the entries other sessions appended since the last look.",
+        )),
+    );
+}
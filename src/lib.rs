@@ -15,6 +15,9 @@ pub use crate::shell::*;
 pub mod eval;
 pub use crate::eval::*;
 
+pub mod resolve;
+pub use crate::resolve::*;
+
 pub mod config;
 pub use crate::config::*;
 
@@ -33,6 +36,9 @@ pub use crate::builtins_str::*;
 pub mod builtins_vector;
 pub use crate::builtins_vector::*;
 
+pub mod builtins_array;
+pub use crate::builtins_array::*;
+
 pub mod builtins;
 pub use crate::builtins::*;
 
@@ -56,3 +62,78 @@ pub use crate::builtins_types::*;
 
 pub mod process;
 pub use crate::process::*;
+
+pub mod builtins_pick;
+pub use crate::builtins_pick::*;
+
+pub mod builtins_bus;
+pub use crate::builtins_bus::*;
+
+pub mod builtins_parallel;
+pub use crate::builtins_parallel::*;
+
+pub mod builtins_pty;
+pub use crate::builtins_pty::*;
+
+pub mod builtins_ssh;
+pub use crate::builtins_ssh::*;
+
+pub mod builtins_sh_import;
+pub use crate::builtins_sh_import::*;
+
+pub mod builtins_local_env;
+pub use crate::builtins_local_env::*;
+
+pub mod builtins_struct;
+pub use crate::builtins_struct::*;
+
+pub mod builtins_generic;
+pub use crate::builtins_generic::*;
+
+pub mod builtins_follow;
+pub use crate::builtins_follow::*;
+
+pub mod builtins_options;
+pub use crate::builtins_options::*;
+
+pub mod builtins_git;
+pub use crate::builtins_git::*;
+
+pub mod builtins_seq;
+pub use crate::builtins_seq::*;
+
+pub mod builtins_json;
+pub use crate::builtins_json::*;
+
+pub mod suggest;
+pub use crate::suggest::*;
+
+pub mod paths;
+pub use crate::paths::*;
+
+pub mod history;
+pub use crate::history::*;
+
+pub mod gc;
+pub use crate::gc::*;
+
+pub mod persistent;
+pub use crate::persistent::*;
+
+pub mod builtins_persistent;
+pub use crate::builtins_persistent::*;
+
+pub mod startup;
+pub use crate::startup::*;
+
+pub mod pretty;
+pub use crate::pretty::*;
+
+pub mod lsp;
+pub use crate::lsp::*;
+
+pub mod lint;
+pub use crate::lint::*;
+
+pub mod fmt;
+pub use crate::fmt::*;
@@ -0,0 +1,561 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::builtins::load;
+use crate::builtins_struct::struct_functions;
+use crate::environment::*;
+use crate::process::env_assignment;
+use crate::reader::*;
+use crate::types::*;
+
+// Arity (min, max) of builtins that take a fixed number of forms.
+const BUILTIN_ARITY: &[(&str, usize, usize)] = &[
+    ("car", 1, 1),
+    ("cdr", 1, 1),
+    ("err", 1, 1),
+    ("export", 2, 3),
+    ("export-path", 1, 2),
+    ("fn", 2, 2),
+    ("if", 2, 3),
+    ("length", 1, 1),
+    ("not", 1, 1),
+    ("null", 1, 1),
+    ("quote", 1, 1),
+    ("sh-import", 1, 1),
+    ("str-trim", 1, 1),
+    ("throw", 1, 2),
+    ("trust-dir", 0, 1),
+    ("undef", 1, 1),
+    ("untrust-dir", 0, 1),
+    ("vec-nth", 2, 2),
+    ("xar!", 2, 2),
+    ("xdr!", 2, 2),
+];
+
+// Macros that evaluate all their arguments as normal forms.
+const EVAL_MACROS: &[&str] = &[
+    "|",
+    "dotimes",
+    "out>",
+    "out>>",
+    "err>",
+    "err>>",
+    "out-err>",
+    "out-err>>",
+    "out>null",
+    "err>null",
+    "out-err>null",
+    "out-null",
+    "err-null",
+    "io-null",
+    "quiet",
+    "stdin-from",
+];
+
+// Symbols that exist when a script runs but are not in the environment here.
+const SCRIPT_SYMBOLS: &[&str] = &[
+    "args",
+    "*stdout*",
+    "*stderr*",
+    "*repl-settings*",
+    "*last-status*",
+    "*last-status-info*",
+    "*interactive*",
+    "*login*",
+    "*script*",
+    "*script-name*",
+];
+
+// Minimum number of params and if there is a &rest param.
+fn params_arity(params: &Expression) -> (usize, bool) {
+    let mut min = 0;
+    for p in params.iter() {
+        if let Expression::Atom(Atom::Symbol(s)) = p {
+            if &**s == "&rest" {
+                return (min, true);
+            }
+        }
+        min += 1;
+    }
+    (min, false)
+}
+
+fn list_items(exp: &Expression) -> Vec<Expression> {
+    exp.iter().cloned().collect()
+}
+
+fn symbol_name(exp: &Expression) -> Option<&str> {
+    if let Expression::Atom(Atom::Symbol(s)) = exp {
+        Some(s)
+    } else {
+        None
+    }
+}
+
+// Strip any namespace from a symbol (core::let -> let).
+fn base_name(name: &str) -> &str {
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn contains_symbol(exp: &Expression, name: &str) -> bool {
+    match exp {
+        Expression::Atom(Atom::Symbol(s)) => &**s == name,
+        Expression::Pair(_, _) => exp.iter().any(|e| contains_symbol(e, name)),
+        Expression::Vector(list) => list.borrow().iter().any(|e| contains_symbol(e, name)),
+        _ => false,
+    }
+}
+
+fn is_command(name: &str) -> bool {
+    if name.contains('/') {
+        return Path::new(name).exists();
+    }
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            if dir.join(name).is_file() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+struct Linter<'a> {
+    environment: &'a Environment,
+    // Symbols def'ed anywhere in the file.
+    globals: HashSet<String>,
+    // Arity of lambdas and macros defined in the file.
+    file_fns: HashMap<String, (usize, bool)>,
+    file_macros: HashSet<String>,
+    // Files already scanned for definitions.
+    loaded: HashSet<String>,
+    locals: Vec<String>,
+    messages: Vec<String>,
+    line: usize,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, msg: String) {
+        self.messages.push(format!("{}: {}", self.line, msg));
+    }
+
+    fn lookup(&self, name: &str) -> Option<Rc<Expression>> {
+        if let Some(exp) = get_expression(self.environment, name) {
+            return Some(exp);
+        }
+        for scope in self.environment.namespaces.values() {
+            if let Some(exp) = scope.borrow().data.get(name) {
+                return Some(exp.clone());
+            }
+        }
+        None
+    }
+
+    fn is_bound(&self, name: &str) -> bool {
+        if name.starts_with('$') || name.starts_with(':') || name == "&rest" {
+            return true;
+        }
+        if name.contains("::") {
+            // Only check namespaces that exist, the script may create others.
+            let ns = name.splitn(2, "::").next().unwrap_or("");
+            if !self.environment.namespaces.contains_key(ns) {
+                return true;
+            }
+        }
+        self.locals.iter().any(|l| l == name)
+            || self.globals.contains(name)
+            || SCRIPT_SYMBOLS.contains(&name)
+            || self.lookup(name).is_some()
+    }
+
+    // Find all the defined symbols first so use before def in the file is fine.
+    fn collect_defs(&mut self, exp: &Expression) {
+        if let Expression::Pair(_, _) = exp {
+            let items = list_items(exp);
+            if let Some(head) = items.get(0).and_then(symbol_name) {
+                let name = match (base_name(head), items.get(1)) {
+                    ("defn", Some(name)) | ("defmacro", Some(name)) | ("defq", Some(name)) => {
+                        symbol_name(name).map(|n| n.to_string())
+                    }
+                    ("defgeneric", Some(name)) | ("defmethod", Some(name)) => {
+                        symbol_name(name).map(|n| n.to_string())
+                    }
+                    ("def", Some(name)) => match list_items(name).get(1) {
+                        Some(Expression::Atom(Atom::Symbol(n))) => Some(n.to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if base_name(head) == "defstruct" {
+                    self.collect_struct(&items[1..]);
+                }
+                if let ("load", Some(Expression::Atom(Atom::String(file)))) =
+                    (base_name(head), items.get(1))
+                {
+                    self.collect_file_defs(file);
+                }
+                if let Some(name) = name {
+                    match base_name(head) {
+                        "defn" | "defmacro" => {
+                            if let Some(params) = items.get(2) {
+                                self.file_fns.insert(name.clone(), params_arity(params));
+                            }
+                            if base_name(head) == "defmacro" {
+                                self.file_macros.insert(name.clone());
+                            }
+                        }
+                        _ => {}
+                    }
+                    self.globals.insert(name);
+                }
+            }
+            for item in items.iter() {
+                self.collect_defs(item);
+            }
+        }
+    }
+
+    // The functions a defstruct defines.
+    fn collect_struct(&mut self, args: &[Expression]) {
+        let mut names = Vec::new();
+        for arg in args {
+            match symbol_name(arg) {
+                Some(name) => names.push(name.to_string()),
+                None => return,
+            }
+        }
+        if names.is_empty() {
+            return;
+        }
+        let name = names.remove(0);
+        for (fn_name, params, _) in struct_functions(&name, &names) {
+            self.file_fns.insert(fn_name.clone(), (params.len(), false));
+            self.globals.insert(fn_name);
+        }
+    }
+
+    // Pick up the definitions from a file the script loads (without running it).
+    fn collect_file_defs(&mut self, file_name: &str) {
+        if !self.loaded.insert(file_name.to_string()) {
+            return;
+        }
+        if let Ok(text) = fs::read_to_string(file_name) {
+            if let Ok(ast) = read(&text, false) {
+                for form in top_level_forms(&ast) {
+                    self.collect_defs(&form);
+                }
+            }
+        }
+    }
+
+    fn lint_all(&mut self, items: &[Expression]) {
+        for item in items {
+            self.lint(item);
+        }
+    }
+
+    fn lint_with(&mut self, names: Vec<String>, items: &[Expression]) {
+        let len = self.locals.len();
+        self.locals.extend(names);
+        self.lint_all(items);
+        self.locals.truncate(len);
+    }
+
+    fn lint(&mut self, exp: &Expression) {
+        match exp {
+            Expression::Atom(Atom::Symbol(name)) => {
+                if !self.is_bound(name) {
+                    self.report(format!("unbound symbol {}", name));
+                }
+            }
+            Expression::Pair(_, _) => {
+                let items = list_items(exp);
+                self.lint_call(&items);
+            }
+            _ => {}
+        }
+    }
+
+    fn param_names(params: Option<&Expression>) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Some(params) = params {
+            for p in params.iter() {
+                if let Some(name) = symbol_name(p) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names
+    }
+
+    fn check_arity(&mut self, name: &str, nargs: usize) {
+        let arity = if let Some((min, rest)) = self.file_fns.get(name) {
+            Some((*min, if *rest { None } else { Some(*min) }))
+        } else {
+            match self.lookup(name).as_ref().map(|e| &**e) {
+                Some(Expression::Atom(Atom::Lambda(l))) => {
+                    let (min, rest) = params_arity(&l.params);
+                    Some((min, if rest { None } else { Some(min) }))
+                }
+                Some(Expression::Atom(Atom::Macro(m))) => {
+                    let (min, rest) = params_arity(&m.params);
+                    Some((min, if rest { None } else { Some(min) }))
+                }
+                Some(_) => BUILTIN_ARITY
+                    .iter()
+                    .find(|(n, _, _)| *n == name)
+                    .map(|(_, min, max)| (*min, Some(*max))),
+                None => None,
+            }
+        };
+        if let Some((min, max)) = arity {
+            let bad = nargs < min || max.map_or(false, |max| nargs > max);
+            if bad {
+                let expected = match max {
+                    Some(max) if max == min => format!("{}", min),
+                    Some(max) => format!("{} to {}", min, max),
+                    None => format!("at least {}", min),
+                };
+                self.report(format!(
+                    "{} called with {} argument(s), expected {}",
+                    name, nargs, expected
+                ));
+            }
+        }
+    }
+
+    fn lint_call(&mut self, items: &[Expression]) {
+        let head = match items.get(0) {
+            Some(Expression::Atom(Atom::Symbol(head))) => head.to_string(),
+            Some(_) => {
+                self.lint_all(items);
+                return;
+            }
+            None => return,
+        };
+        let args = &items[1..];
+        match base_name(&head) {
+            "quote" | "bquote" | "loose-symbols" | "alias" | "undef" | "defstruct" => {}
+            "fn" | "macro" => {
+                self.check_arity("fn", args.len());
+                let names = Linter::param_names(args.get(0));
+                self.lint_with(names, &args[1.min(args.len())..]);
+            }
+            "defn" | "defmacro" | "setfn" | "setmacro" => {
+                let names = Linter::param_names(args.get(1));
+                self.lint_with(names, &args[2.min(args.len())..]);
+            }
+            "defgeneric" => {}
+            "defmethod" => {
+                let names = Linter::param_names(args.get(2));
+                self.lint_with(names, &args[3.min(args.len())..]);
+            }
+            "defq" | "setq" | "def" | "set" => {
+                if let Some(Expression::Pair(_, _)) = args.get(0) {
+                    self.lint_all(&args[..1]);
+                }
+                self.lint_all(&args[1.min(args.len())..]);
+            }
+            "dyn" => self.lint_all(&args[1.min(args.len())..]),
+            "let" | "let-env" => {
+                let mut names = Vec::new();
+                if let Some(bindings) = args.get(0) {
+                    for binding in bindings.iter() {
+                        let binding = list_items(binding);
+                        if let Some(name) = binding.get(0).and_then(symbol_name) {
+                            names.push(name.to_string());
+                        }
+                        self.lint_all(&binding[1.min(binding.len())..]);
+                    }
+                }
+                let body = &args[1.min(args.len())..];
+                if base_name(&head) == "let" {
+                    for name in &names {
+                        if !name.starts_with('_') && !body.iter().any(|e| contains_symbol(e, name))
+                        {
+                            self.report(format!("unused let binding {}", name));
+                        }
+                    }
+                    self.lint_with(names, body);
+                } else {
+                    self.lint_all(body);
+                }
+            }
+            "loop" => {
+                let names = Linter::param_names(args.get(0));
+                if let Some(bindings) = args.get(1) {
+                    self.lint_all(&list_items(bindings));
+                }
+                self.lint_with(names, &args[2.min(args.len())..]);
+            }
+            "for" | "dotimesi" => {
+                let names: Vec<String> = args
+                    .iter()
+                    .take(1)
+                    .filter_map(symbol_name)
+                    .map(|n| n.to_string())
+                    .collect();
+                self.lint_all(&args[1.min(args.len())..2.min(args.len())]);
+                self.lint_with(names, &args[2.min(args.len())..]);
+            }
+            "fori" => {
+                let names: Vec<String> = args
+                    .iter()
+                    .take(2)
+                    .filter_map(symbol_name)
+                    .map(|n| n.to_string())
+                    .collect();
+                self.lint_all(&args[2.min(args.len())..3.min(args.len())]);
+                self.lint_with(names, &args[3.min(args.len())..]);
+            }
+            "match" => {
+                self.lint_all(&args[..1.min(args.len())]);
+                for branch in args.iter().skip(1) {
+                    self.lint_all(&list_items(branch));
+                }
+            }
+            "cond" => {
+                for clause in args {
+                    let items = list_items(clause);
+                    let skip = items.first().and_then(symbol_name) == Some("else");
+                    self.lint_all(&items[skip as usize..]);
+                }
+            }
+            "->" | "->>" => {
+                // Steps are missing an argument so only lint what is there.
+                self.lint_all(&args[..1.min(args.len())]);
+                for step in args.iter().skip(1) {
+                    match step {
+                        Expression::Pair(_, _) => self.lint_all(&list_items(step)),
+                        _ => self.lint(step),
+                    }
+                }
+            }
+            "as->" => {
+                let names: Vec<String> = args
+                    .iter()
+                    .skip(1)
+                    .take(1)
+                    .filter_map(symbol_name)
+                    .map(|n| n.to_string())
+                    .collect();
+                self.lint_all(&args[..1.min(args.len())]);
+                self.lint_with(names, &args[2.min(args.len())..]);
+            }
+            "case" | "match-equal" => {
+                self.lint_all(&args[..1.min(args.len())]);
+                for clause in args.iter().skip(1) {
+                    let items = list_items(clause);
+                    self.lint_all(&items[1.min(items.len())..]);
+                }
+            }
+            "sh" if args.len() != 1 || symbol_name(&args[0]).is_some() => {
+                // The sh command, only the forms in it are evaluated.
+                let forms: Vec<Expression> = args
+                    .iter()
+                    .filter(|a| matches!(a, Expression::Vector(_) | Expression::Pair(_, _)))
+                    .cloned()
+                    .collect();
+                self.lint_all(&forms);
+            }
+            _ => {
+                if self.locals.iter().any(|l| *l == head) {
+                    self.lint_all(args);
+                    return;
+                }
+                let is_macro = self.file_macros.contains(&head)
+                    || match self.lookup(&head).as_ref().map(|e| &**e) {
+                        Some(Expression::Atom(Atom::Macro(_))) => true,
+                        _ => false,
+                    };
+                if self.file_fns.contains_key(&head) || self.lookup(&head).is_some() {
+                    self.check_arity(&head, args.len());
+                    if !is_macro || EVAL_MACROS.contains(&base_name(&head)) {
+                        self.lint_all(args);
+                    }
+                } else if env_assignment(&head).is_some() {
+                    // NAME=value prefix, only the forms in it are evaluated.
+                    let forms: Vec<Expression> = args
+                        .iter()
+                        .filter(|a| matches!(a, Expression::Vector(_) | Expression::Pair(_, _)))
+                        .cloned()
+                        .collect();
+                    self.lint_all(&forms);
+                } else if !self.globals.contains(&head) && !is_command(&head) {
+                    self.report(format!("unknown function or command {}", head));
+                }
+                // External command arguments are not evaluated as symbols.
+            }
+        }
+    }
+}
+
+// Check (parse and lint) a script file without running it, print any
+// problems and return true if there were none.
+fn check_file(environment: &Environment, file_name: &str) -> bool {
+    let text = match fs::read_to_string(file_name) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("{}: {}", file_name, err);
+            return false;
+        }
+    };
+    let ast = match read(&text, false) {
+        Ok(ast) => ast,
+        Err(err) => {
+            println!("{}: parse error: {}", file_name, err.reason);
+            return false;
+        }
+    };
+    let forms = top_level_forms(&ast);
+    let lines = top_level_lines(&text);
+    let mut linter = Linter {
+        environment,
+        globals: HashSet::new(),
+        file_fns: HashMap::new(),
+        file_macros: HashSet::new(),
+        loaded: HashSet::new(),
+        locals: Vec::new(),
+        messages: Vec::new(),
+        line: 1,
+    };
+    for form in &forms {
+        linter.collect_defs(form);
+    }
+    for (i, form) in forms.iter().enumerate() {
+        linter.line = *lines.get(i).unwrap_or(&1);
+        linter.lint(form);
+    }
+    for msg in &linter.messages {
+        println!("{}:{}", file_name, msg);
+    }
+    linter.messages.is_empty()
+}
+
+pub fn run_check(files: &[String]) -> i32 {
+    let mut environment = build_default_environment(Arc::new(AtomicBool::new(false)));
+    environment.do_job_control = false;
+    environment.is_tty = false;
+    if let Err(err) = load(&mut environment, "slsh-std.lisp") {
+        eprintln!("Failed to load slsh-std.lisp: {}", err);
+        return 2;
+    }
+    if files.is_empty() {
+        eprintln!("--check requires at least one script");
+        return 2;
+    }
+    let mut ok = true;
+    for file in files {
+        ok = check_file(&environment, file) && ok;
+    }
+    if ok {
+        0
+    } else {
+        1
+    }
+}
@@ -0,0 +1,669 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::builtins::{load, symbol_doc};
+use crate::completions::{find_lisp_fns, find_lisp_symbols};
+use crate::environment::*;
+use crate::reader::*;
+
+// Just enough JSON to speak the language server protocol.
+#[derive(Clone, Debug)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    // A number without a fraction or exponent that fits.
+    Int(i64),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> &Json {
+        if let Json::Object(fields) = self {
+            for (k, v) in fields {
+                if k == key {
+                    return v;
+                }
+            }
+        }
+        &Json::Null
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        if let Json::Str(s) = self {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    fn as_usize(&self) -> usize {
+        match self {
+            Json::Int(i) => *i as usize,
+            Json::Number(n) => *n as usize,
+            _ => 0,
+        }
+    }
+
+    fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+            f.write_str("\"")?;
+            for ch in s.chars() {
+                match ch {
+                    '"' => f.write_str("\\\"")?,
+                    '\\' => f.write_str("\\\\")?,
+                    '\n' => f.write_str("\\n")?,
+                    '\r' => f.write_str("\\r")?,
+                    '\t' => f.write_str("\\t")?,
+                    ch if (ch as u32) < 0x20 => write!(f, "\\u{:04x}", ch as u32)?,
+                    ch => write!(f, "{}", ch)?,
+                }
+            }
+            f.write_str("\"")
+        }
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Number(n) => {
+                if n.fract() == 0.0 && n.abs() < 1e15 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{}", n)
+                }
+            }
+            Json::Str(s) => write_str(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", val)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonParser {
+    fn skip_ws(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, ch: char) -> Result<(), String> {
+        self.skip_ws();
+        if self.pos < self.chars.len() && self.chars[self.pos] == ch {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected {} at {}", ch, self.pos))
+        }
+    }
+
+    fn literal(&mut self, word: &str, val: Json) -> Result<Json, String> {
+        for ch in word.chars() {
+            if self.pos >= self.chars.len() || self.chars[self.pos] != ch {
+                return Err(format!("invalid literal at {}", self.pos));
+            }
+            self.pos += 1;
+        }
+        Ok(val)
+    }
+
+    // The four hex digits of a \u escape.
+    fn hex4(&mut self) -> Result<u32, String> {
+        let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+        self.pos += 4;
+        u32::from_str_radix(&hex, 16).map_err(|_| "invalid unicode escape".to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut res = String::new();
+        while self.pos < self.chars.len() {
+            let ch = self.chars[self.pos];
+            self.pos += 1;
+            match ch {
+                '"' => return Ok(res),
+                '\\' => {
+                    if self.pos >= self.chars.len() {
+                        break;
+                    }
+                    let esc = self.chars[self.pos];
+                    self.pos += 1;
+                    match esc {
+                        'n' => res.push('\n'),
+                        'r' => res.push('\r'),
+                        't' => res.push('\t'),
+                        'b' => res.push('\u{8}'),
+                        'f' => res.push('\u{c}'),
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            // A pair of escapes for a char outside the BMP.
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.chars.get(self.pos) == Some(&'\\')
+                                && self.chars.get(self.pos + 1) == Some(&'u')
+                            {
+                                let save = self.pos;
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if (0xdc00..0xe000).contains(&low) {
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                } else {
+                                    self.pos = save;
+                                }
+                            }
+                            res.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        ch => res.push(ch),
+                    }
+                }
+                ch => res.push(ch),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_ws();
+        if self.pos >= self.chars.len() {
+            return Err("unexpected end of input".to_string());
+        }
+        match self.chars[self.pos] {
+            '{' => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_ws();
+                if self.pos < self.chars.len() && self.chars[self.pos] == '}' {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_ws();
+                    if self.pos < self.chars.len() && self.chars[self.pos] == ',' {
+                        self.pos += 1;
+                    } else {
+                        self.expect('}')?;
+                        return Ok(Json::Object(fields));
+                    }
+                }
+            }
+            '[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_ws();
+                if self.pos < self.chars.len() && self.chars[self.pos] == ']' {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_ws();
+                    if self.pos < self.chars.len() && self.chars[self.pos] == ',' {
+                        self.pos += 1;
+                    } else {
+                        self.expect(']')?;
+                        return Ok(Json::Array(items));
+                    }
+                }
+            }
+            '"' => Ok(Json::Str(self.string()?)),
+            't' => self.literal("true", Json::Bool(true)),
+            'f' => self.literal("false", Json::Bool(false)),
+            'n' => self.literal("null", Json::Null),
+            _ => {
+                let start = self.pos;
+                while self.pos < self.chars.len()
+                    && (self.chars[self.pos].is_ascii_digit()
+                        || "+-.eE".contains(self.chars[self.pos]))
+                {
+                    self.pos += 1;
+                }
+                let num: String = self.chars[start..self.pos].iter().collect();
+                if !num.contains(&['.', 'e', 'E'][..]) {
+                    if let Ok(i) = num.parse::<i64>() {
+                        return Ok(Json::Int(i));
+                    }
+                }
+                match num.parse::<f64>() {
+                    Ok(n) => Ok(Json::Number(n)),
+                    Err(_) => Err(format!("invalid number at {}", start)),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn parse_json(text: &str) -> Result<Json, String> {
+    let mut parser = JsonParser {
+        chars: text.chars().collect(),
+        pos: 0,
+    };
+    parser.value()
+}
+
+fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if lower.starts_with("content-length:") {
+            length = line["content-length:".len()..].trim().parse::<usize>().ok();
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "lsp: message without a Content-Length header",
+            ))
+        }
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    match parse_json(&String::from_utf8_lossy(&body)) {
+        Ok(msg) => Ok(Some(msg)),
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+    }
+}
+
+fn send_message(msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn send_result(id: &Json, result: Json) -> io::Result<()> {
+    send_message(&Json::object(vec![
+        ("jsonrpc", Json::Str("2.0".to_string())),
+        ("id", id.clone()),
+        ("result", result),
+    ]))
+}
+
+fn send_notification(method: &str, params: Json) -> io::Result<()> {
+    send_message(&Json::object(vec![
+        ("jsonrpc", Json::Str("2.0".to_string())),
+        ("method", Json::Str(method.to_string())),
+        ("params", params),
+    ]))
+}
+
+fn is_symbol_char(ch: char) -> bool {
+    !(ch.is_whitespace() || "()#'`,\"".contains(ch))
+}
+
+// LSP columns count UTF-16 code units, the column of the char at index in a
+// line.
+fn utf16_col(chars: &[char], index: usize) -> usize {
+    chars[..index.min(chars.len())]
+        .iter()
+        .map(|ch| ch.len_utf16())
+        .sum()
+}
+
+// The index of the char at an LSP column in a line.
+fn char_index(chars: &[char], col: usize) -> usize {
+    let mut units = 0;
+    for (i, ch) in chars.iter().enumerate() {
+        if units >= col {
+            return i;
+        }
+        units += ch.len_utf16();
+    }
+    chars.len()
+}
+
+// The symbol under (or ending at) the position and the part before the position.
+fn word_at(text: &str, line: usize, character: usize) -> (String, String) {
+    let chars: Vec<char> = match text.lines().nth(line) {
+        Some(l) => l.chars().collect(),
+        None => return (String::new(), String::new()),
+    };
+    let pos = char_index(&chars, character);
+    let mut start = pos;
+    while start > 0 && is_symbol_char(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = pos;
+    while end < chars.len() && is_symbol_char(chars[end]) {
+        end += 1;
+    }
+    (
+        chars[start..end].iter().collect(),
+        chars[start..pos].iter().collect(),
+    )
+}
+
+// Find the symbols a document defines (defn, defmacro, defq and def) with
+// their line and (LSP) column.
+fn find_definitions(text: &str) -> Vec<(String, usize, usize)> {
+    let mut defs = Vec::new();
+    for (line_num, line) in text.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] != '(' {
+                i += 1;
+                continue;
+            }
+            i += 1;
+            let form_start = i;
+            while i < chars.len() && is_symbol_char(chars[i]) {
+                i += 1;
+            }
+            let form: String = chars[form_start..i].iter().collect();
+            if form != "defn" && form != "defmacro" && form != "defq" && form != "def" {
+                continue;
+            }
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            if i < chars.len() && chars[i] == '\'' {
+                i += 1;
+            }
+            let name_start = i;
+            while i < chars.len() && is_symbol_char(chars[i]) {
+                i += 1;
+            }
+            if i > name_start {
+                defs.push((
+                    chars[name_start..i].iter().collect(),
+                    line_num,
+                    utf16_col(&chars, name_start),
+                ));
+            }
+        }
+    }
+    defs
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![
+        ("line", Json::Number(line as f64)),
+        ("character", Json::Number(character as f64)),
+    ])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    Json::object(vec![
+        ("start", position(line, start)),
+        ("end", position(line, end)),
+    ])
+}
+
+// The range of a parse error from the "line N, col: M" the reader puts at the
+// end of it's errors (one based, M counts chars), the start of the document if
+// it has no location.
+fn error_range(text: &str, reason: &str) -> Json {
+    let location = reason.rfind("line ").and_then(|i| {
+        let mut parts = reason[i + 5..].splitn(2, ", col: ");
+        let line = parts.next()?.trim().parse::<usize>().ok()?;
+        let col = parts.next()?.trim().parse::<usize>().ok()?;
+        Some((line.max(1) - 1, col.max(1) - 1))
+    });
+    match location {
+        Some((line, col)) => {
+            let chars: Vec<char> = text.lines().nth(line).unwrap_or("").chars().collect();
+            let start = utf16_col(&chars, col);
+            let end = utf16_col(&chars, col + 1);
+            range(line, start, end)
+        }
+        None => range(0, 0, 0),
+    }
+}
+
+fn publish_diagnostics(uri: &str, text: &str) -> io::Result<()> {
+    let mut diagnostics = Vec::new();
+    if !text.trim().is_empty() {
+        if let Err(err) = read(text, false) {
+            diagnostics.push(Json::object(vec![
+                ("range", error_range(text, &err.reason)),
+                ("severity", Json::Number(1.0)),
+                ("source", Json::Str("sl-sh".to_string())),
+                ("message", Json::Str(err.reason)),
+            ]));
+        }
+    }
+    send_notification(
+        "textDocument/publishDiagnostics",
+        Json::object(vec![
+            ("uri", Json::Str(uri.to_string())),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]),
+    )
+}
+
+fn completion(environment: &Environment, docs: &HashMap<String, String>, params: &Json) -> Json {
+    let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+    let pos = params.get("position");
+    let text = docs.get(uri).map(|t| &t[..]).unwrap_or("");
+    let (_, prefix) = word_at(
+        text,
+        pos.get("line").as_usize(),
+        pos.get("character").as_usize(),
+    );
+    let mut fns = Vec::new();
+    find_lisp_fns(environment, &mut fns, &prefix);
+    let mut symbols = Vec::new();
+    find_lisp_symbols(environment, &mut symbols, &prefix);
+    let mut items = Vec::new();
+    let mut add_item = |label: &str, kind: f64, doc: Option<String>| {
+        let mut fields = vec![
+            ("label", Json::Str(label.to_string())),
+            ("kind", Json::Number(kind)),
+        ];
+        if let Some(doc) = doc {
+            fields.push(("documentation", Json::Str(doc)));
+        }
+        items.push(Json::object(fields));
+    };
+    // Function and Variable completion item kinds.
+    for name in &fns {
+        add_item(name, 3.0, symbol_doc(environment, name));
+    }
+    for name in symbols {
+        if !fns.contains(&name) {
+            add_item(&name, 6.0, None);
+        }
+    }
+    for (name, _, _) in find_definitions(text) {
+        if name.starts_with(&prefix) && !is_expression(environment, &name) {
+            add_item(&name, 3.0, None);
+        }
+    }
+    Json::Array(items)
+}
+
+fn hover(environment: &Environment, docs: &HashMap<String, String>, params: &Json) -> Json {
+    let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+    let pos = params.get("position");
+    let text = docs.get(uri).map(|t| &t[..]).unwrap_or("");
+    let (word, _) = word_at(
+        text,
+        pos.get("line").as_usize(),
+        pos.get("character").as_usize(),
+    );
+    match symbol_doc(environment, &word) {
+        Some(doc) if !doc.is_empty() => Json::object(vec![(
+            "contents",
+            Json::object(vec![
+                ("kind", Json::Str("plaintext".to_string())),
+                ("value", Json::Str(format!("{}\n\n{}", word, doc))),
+            ]),
+        )]),
+        _ => Json::Null,
+    }
+}
+
+fn definition(docs: &HashMap<String, String>, params: &Json) -> Json {
+    let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+    let pos = params.get("position");
+    let text = docs.get(uri).map(|t| &t[..]).unwrap_or("");
+    let (word, _) = word_at(
+        text,
+        pos.get("line").as_usize(),
+        pos.get("character").as_usize(),
+    );
+    let mut locations = Vec::new();
+    for (doc_uri, doc_text) in docs {
+        for (name, line, col) in find_definitions(doc_text) {
+            if name == word {
+                locations.push(Json::object(vec![
+                    ("uri", Json::Str(doc_uri.to_string())),
+                    ("range", range(line, col, col + name.encode_utf16().count())),
+                ]));
+            }
+        }
+    }
+    Json::Array(locations)
+}
+
+// Run a minimal language server on stdin/stdout, returns the exit code.
+pub fn run_lsp() -> i32 {
+    let mut environment = build_default_environment(Arc::new(AtomicBool::new(false)));
+    environment.do_job_control = false;
+    environment.is_tty = false;
+    if let Err(err) = load(&mut environment, "slsh-std.lisp") {
+        eprintln!("lsp: failed to load slsh-std.lisp: {}", err);
+    }
+    let mut docs: HashMap<String, String> = HashMap::new();
+    let mut shutdown = false;
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    loop {
+        let msg = match read_message(&mut input) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return 1,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let id = msg.get("id");
+        let params = msg.get("params");
+        let res = match msg.get("method").as_str().unwrap_or("") {
+            "initialize" => send_result(
+                id,
+                Json::object(vec![(
+                    "capabilities",
+                    Json::object(vec![
+                        ("textDocumentSync", Json::Number(1.0)),
+                        ("completionProvider", Json::object(vec![])),
+                        ("hoverProvider", Json::Bool(true)),
+                        ("definitionProvider", Json::Bool(true)),
+                    ]),
+                )]),
+            ),
+            "textDocument/didOpen" => {
+                let doc = params.get("textDocument");
+                let uri = doc.get("uri").as_str().unwrap_or("").to_string();
+                let text = doc.get("text").as_str().unwrap_or("").to_string();
+                let res = publish_diagnostics(&uri, &text);
+                docs.insert(uri, text);
+                res
+            }
+            "textDocument/didChange" => {
+                let uri = params
+                    .get("textDocument")
+                    .get("uri")
+                    .as_str()
+                    .unwrap_or("")
+                    .to_string();
+                // Full sync, the last change is the whole document.
+                if let Json::Array(changes) = params.get("contentChanges") {
+                    if let Some(text) = changes.last().and_then(|c| c.get("text").as_str()) {
+                        docs.insert(uri.clone(), text.to_string());
+                    }
+                }
+                let text = docs.get(&uri).cloned().unwrap_or_default();
+                publish_diagnostics(&uri, &text)
+            }
+            "textDocument/didClose" => {
+                let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+                docs.remove(uri);
+                publish_diagnostics(uri, "")
+            }
+            "textDocument/completion" => send_result(id, completion(&environment, &docs, params)),
+            "textDocument/hover" => send_result(id, hover(&environment, &docs, params)),
+            "textDocument/definition" => send_result(id, definition(&docs, params)),
+            "shutdown" => {
+                shutdown = true;
+                send_result(id, Json::Null)
+            }
+            "exit" => return if shutdown { 0 } else { 1 },
+            _ => {
+                if let Json::Null = id {
+                    // Unhandled notification.
+                    Ok(())
+                } else {
+                    send_message(&Json::object(vec![
+                        ("jsonrpc", Json::Str("2.0".to_string())),
+                        ("id", id.clone()),
+                        (
+                            "error",
+                            Json::object(vec![
+                                ("code", Json::Number(-32601.0)),
+                                ("message", Json::Str("method not found".to_string())),
+                            ]),
+                        ),
+                    ]))
+                }
+            }
+        };
+        if let Err(err) = res {
+            eprintln!("lsp: error writing response: {}", err);
+            return 1;
+        }
+    }
+}
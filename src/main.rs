@@ -14,11 +14,45 @@ use nix::{
 };
 
 use ::sl_sh::config::*;
+use ::sl_sh::fmt::*;
+use ::sl_sh::lint::*;
+use ::sl_sh::lsp::*;
+use ::sl_sh::process::*;
 use ::sl_sh::shell::*;
 
+// Set when the terminal hangs up so the shell can exit cleanly.
+static SIG_HUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_sig: libc::c_int) {
+    SIG_HUP.store(true, Ordering::Relaxed);
+    // Interrupt whatever is running (the SIGINT thread sets the flag).
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGINT);
+    }
+}
+
 fn main() -> io::Result<()> {
     let config = get_config();
     if let Ok(config) = config {
+        if config.lsp {
+            std::process::exit(run_lsp());
+        }
+        if config.check {
+            let mut files: Vec<String> = config.script.into_iter().collect();
+            files.extend(config.args);
+            std::process::exit(run_check(&files));
+        }
+        if config.profile_startup {
+            std::process::exit(run_profile_startup());
+        }
+        if config.fmt {
+            let mut files: Vec<String> = config.script.into_iter().collect();
+            files.extend(config.args);
+            std::process::exit(run_fmt(&files));
+        }
+        if let Err(err) = install_sigchld_handler() {
+            eprintln!("Error installing the SIGCHLD handler: {}", err);
+        }
         if config.command.is_none() && config.script.is_none() {
             /* See if we are running interactively.  */
             let shell_terminal = nix::libc::STDIN_FILENO;
@@ -41,6 +75,7 @@ fn main() -> io::Result<()> {
                     signal::signal(Signal::SIGTSTP, SigHandler::SigIgn).unwrap();
                     signal::signal(Signal::SIGTTIN, SigHandler::SigIgn).unwrap();
                     signal::signal(Signal::SIGTTOU, SigHandler::SigIgn).unwrap();
+                    signal::signal(Signal::SIGHUP, SigHandler::Handler(handle_sighup)).unwrap();
                     // Ignoring sigchild will mess up waitpid and cause Command::spawn to panic under some conditions.
                     //signal::signal(Signal::SIGCHLD, SigHandler::SigIgn).unwrap();
                 }
@@ -115,7 +150,7 @@ fn main() -> io::Result<()> {
                     }
                 });
 
-                let code = start_interactive(sig_int);
+                let code = start_interactive(sig_int, &SIG_HUP, config.login);
                 sig_int_stop.store(true, Ordering::Relaxed);
                 if let Err(err) = signal::kill(shell_pgid, Signal::SIGINT) {
                     eprintln!(
@@ -129,7 +164,7 @@ fn main() -> io::Result<()> {
                 std::process::exit(code);
             } else {
                 // No tty, just read stdin and do something with it..
-                let code = read_stdin();
+                let code = read_stdin(config.login);
                 std::process::exit(code);
             }
         } else if config.command.is_some() {
@@ -140,7 +175,7 @@ fn main() -> io::Result<()> {
             }
         } else if config.script.is_some() {
             let script = config.script.unwrap();
-            let code = run_one_script(&script, &config.args);
+            let code = run_one_script(&script, &config.args, config.login);
             std::process::exit(code);
         }
     }
@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::types::*;
+
+// Where the shell keeps its files.  $SLSH_HOME puts everything under one
+// directory, otherwise the XDG base directories are used (with the XDG
+// defaults when not set).
+
+// The home directory without a trailing /, . if HOME is not set.
+pub fn home_dir() -> String {
+    let mut home = match env::var("HOME") {
+        Ok(val) => val,
+        Err(_) => ".".to_string(),
+    };
+    if home.ends_with('/') {
+        home = home[..home.len() - 1].to_string();
+    }
+    home
+}
+
+fn slsh_home() -> Option<PathBuf> {
+    match env::var("SLSH_HOME") {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => None,
+    }
+}
+
+// An XDG directory variable, relative paths are invalid and ignored as the
+// spec says.
+fn xdg_dir(var: &str, default: &str) -> PathBuf {
+    match env::var(var) {
+        Ok(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => Path::new(&home_dir()).join(default),
+    }
+}
+
+// Config files (slshrc and anything it loads), the first entry in *load-path*.
+pub fn config_dir() -> PathBuf {
+    match slsh_home() {
+        Some(dir) => dir,
+        None => xdg_dir("XDG_CONFIG_HOME", ".config").join("sl-sh"),
+    }
+}
+
+// Data the shell keeps between sessions.
+pub fn data_dir() -> PathBuf {
+    match slsh_home() {
+        Some(dir) => dir.join("data"),
+        None => xdg_dir("XDG_DATA_HOME", ".local/share").join("sl-sh"),
+    }
+}
+
+// State that is fine to lose (history).
+pub fn state_dir() -> PathBuf {
+    match slsh_home() {
+        Some(dir) => dir.join("state"),
+        None => xdg_dir("XDG_STATE_HOME", ".local/state").join("sl-sh"),
+    }
+}
+
+// Files that can be rebuilt (the parsed core library).
+pub fn cache_dir() -> PathBuf {
+    match slsh_home() {
+        Some(dir) => dir.join("cache"),
+        None => xdg_dir("XDG_CACHE_HOME", ".cache").join("slsh"),
+    }
+}
+
+// Where everything was before the XDG variables and SLSH_HOME were used.
+fn legacy_config_dir() -> PathBuf {
+    Path::new(&home_dir()).join(".config/sl-sh")
+}
+
+fn legacy_history_file() -> PathBuf {
+    Path::new(&home_dir()).join(".local/share/sl-sh/history")
+}
+
+// The history file, moved from the old location (the data dir) if it is
+// only there.  Creates the directory.
+pub fn history_file() -> io::Result<PathBuf> {
+    let dir = state_dir();
+    fs::create_dir_all(&dir)?;
+    let history = dir.join("history");
+    let legacy = legacy_history_file();
+    if !history.exists() && legacy.is_file() {
+        if fs::rename(&legacy, &history).is_err() {
+            // Different file systems.
+            fs::copy(&legacy, &history)?;
+            fs::remove_file(&legacy)?;
+        }
+        eprintln!(
+            "NOTE: Moved history from {} to {}.",
+            legacy.display(),
+            history.display()
+        );
+    }
+    Ok(history)
+}
+
+// True if the slshrc is only in the old config dir.
+fn legacy_config_in_use() -> bool {
+    let config = config_dir();
+    let legacy = legacy_config_dir();
+    legacy != config && !config.join("slshrc").exists() && legacy.join("slshrc").exists()
+}
+
+// Directories searched by load, the config dir then the old location if it
+// still has the slshrc.
+pub fn load_path() -> Vec<PathBuf> {
+    let mut load_path = vec![config_dir()];
+    if legacy_config_in_use() {
+        load_path.push(legacy_config_dir());
+    }
+    load_path
+}
+
+// Tell the user to move their config if it is only found in the old place.
+pub fn config_migration_note() -> Option<String> {
+    if legacy_config_in_use() {
+        let legacy = legacy_config_dir();
+        Some(format!(
+            "NOTE: Using {}/slshrc, move the files in {} to {} (SLSH_HOME or XDG_CONFIG_HOME is set).",
+            legacy.display(),
+            legacy.display(),
+            config_dir().display()
+        ))
+    } else {
+        None
+    }
+}
+
+fn builtin_slsh_dirs(
+    _environment: &mut Environment,
+    args: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    if args.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "slsh-dirs takes no arguments",
+        ));
+    }
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    for (key, dir) in &[
+        (":config", config_dir()),
+        (":data", data_dir()),
+        (":state", state_dir()),
+        (":cache", cache_dir()),
+    ] {
+        map.insert(
+            key.to_string(),
+            Rc::new(Expression::Atom(Atom::String(
+                dir.to_string_lossy().to_string().into(),
+            ))),
+        );
+    }
+    Ok(Expression::HashMap(Rc::new(RefCell::new(map))))
+}
+
+pub fn add_paths_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "slsh-dirs".to_string(),
+        Rc::new(Expression::make_function(
+            builtin_slsh_dirs,
+            "Return a hashmap of the directories the shell uses (:config :data :state :cache), from SLSH_HOME or the XDG variables.",
+        )),
+    );
+}
@@ -0,0 +1,487 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::types::Expression;
+
+// Persistent (immutable) vectors and maps.  An update returns a new value that
+// shares everything but the path to the changed item with the old one, so
+// functional code does not copy the whole thing for each change.
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone, Debug)]
+enum VecNode {
+    Branch(Vec<Rc<VecNode>>),
+    Leaf(Vec<Expression>),
+}
+
+// A trie of 32 wide nodes with the last (up to 32) items kept in a tail so
+// pushes and pops usually only copy the tail.
+#[derive(Clone, Debug)]
+pub struct PVec {
+    len: usize,
+    shift: usize,
+    root: Rc<VecNode>,
+    tail: Rc<Vec<Expression>>,
+}
+
+impl Default for PVec {
+    fn default() -> Self {
+        PVec {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(VecNode::Branch(Vec::new())),
+            tail: Rc::new(Vec::new()),
+        }
+    }
+}
+
+impl PVec {
+    pub fn from_vec(items: Vec<Expression>) -> PVec {
+        let mut pvec = PVec::default();
+        for item in items {
+            pvec = pvec.push(item);
+        }
+        pvec
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Index of the first item in the tail.
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH {
+            0
+        } else {
+            ((self.len - 1) >> BITS) << BITS
+        }
+    }
+
+    // The leaf in the trie that holds idx.
+    fn leaf(&self, idx: usize) -> &Vec<Expression> {
+        let mut node = &self.root;
+        let mut level = self.shift;
+        loop {
+            match &**node {
+                VecNode::Branch(children) => {
+                    node = &children[(idx >> level) & MASK];
+                    level -= BITS;
+                }
+                VecNode::Leaf(items) => return items,
+            }
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Expression> {
+        if idx >= self.len {
+            None
+        } else if idx >= self.tail_offset() {
+            self.tail.get(idx - self.tail_offset())
+        } else {
+            self.leaf(idx).get(idx & MASK)
+        }
+    }
+
+    pub fn push(&self, item: Expression) -> PVec {
+        if self.len - self.tail_offset() < WIDTH {
+            let mut tail = (*self.tail).clone();
+            tail.push(item);
+            return PVec {
+                len: self.len + 1,
+                shift: self.shift,
+                root: self.root.clone(),
+                tail: Rc::new(tail),
+            };
+        }
+        // The tail is full, it goes into the trie.
+        let tail_node = Rc::new(VecNode::Leaf((*self.tail).clone()));
+        let (root, shift) = if (self.len >> BITS) > (1 << self.shift) {
+            let path = new_path(self.shift, tail_node);
+            (
+                Rc::new(VecNode::Branch(vec![self.root.clone(), path])),
+                self.shift + BITS,
+            )
+        } else {
+            (
+                self.push_tail(self.shift, &self.root, tail_node),
+                self.shift,
+            )
+        };
+        PVec {
+            len: self.len + 1,
+            shift,
+            root,
+            tail: Rc::new(vec![item]),
+        }
+    }
+
+    fn push_tail(&self, level: usize, parent: &VecNode, tail_node: Rc<VecNode>) -> Rc<VecNode> {
+        let mut children = match parent {
+            VecNode::Branch(children) => children.clone(),
+            VecNode::Leaf(_) => Vec::new(),
+        };
+        let sub = ((self.len - 1) >> level) & MASK;
+        let child = if level == BITS {
+            tail_node
+        } else if sub < children.len() {
+            self.push_tail(level - BITS, &children[sub], tail_node)
+        } else {
+            new_path(level - BITS, tail_node)
+        };
+        if sub < children.len() {
+            children[sub] = child;
+        } else {
+            children.push(child);
+        }
+        Rc::new(VecNode::Branch(children))
+    }
+
+    // A copy with item at idx, None if idx is out of range.
+    pub fn set(&self, idx: usize, item: Expression) -> Option<PVec> {
+        if idx >= self.len {
+            return None;
+        }
+        let mut pvec = self.clone();
+        if idx >= self.tail_offset() {
+            let mut tail = (*self.tail).clone();
+            tail[idx - self.tail_offset()] = item;
+            pvec.tail = Rc::new(tail);
+        } else {
+            pvec.root = set_in(self.shift, &self.root, idx, item);
+        }
+        Some(pvec)
+    }
+
+    // A copy without the last item, None if empty.
+    pub fn pop(&self) -> Option<PVec> {
+        if self.len == 0 {
+            return None;
+        }
+        if self.len == 1 {
+            return Some(PVec::default());
+        }
+        if self.len - self.tail_offset() > 1 {
+            let mut tail = (*self.tail).clone();
+            tail.pop();
+            return Some(PVec {
+                len: self.len - 1,
+                shift: self.shift,
+                root: self.root.clone(),
+                tail: Rc::new(tail),
+            });
+        }
+        // The tail is emptied, the last leaf of the trie becomes the tail.
+        let tail = self.leaf(self.len - 2).clone();
+        let mut root = match self.pop_tail(self.shift, &self.root) {
+            Some(root) => root,
+            None => Rc::new(VecNode::Branch(Vec::new())),
+        };
+        let mut shift = self.shift;
+        if shift > BITS {
+            if let VecNode::Branch(children) = &*root.clone() {
+                if children.len() == 1 {
+                    root = children[0].clone();
+                    shift -= BITS;
+                }
+            }
+        }
+        Some(PVec {
+            len: self.len - 1,
+            shift,
+            root,
+            tail: Rc::new(tail),
+        })
+    }
+
+    fn pop_tail(&self, level: usize, node: &VecNode) -> Option<Rc<VecNode>> {
+        let children = match node {
+            VecNode::Branch(children) => children,
+            VecNode::Leaf(_) => return None,
+        };
+        let sub = ((self.len - 2) >> level) & MASK;
+        if level > BITS {
+            let child = self.pop_tail(level - BITS, &children[sub]);
+            if child.is_none() && sub == 0 {
+                return None;
+            }
+            let mut children = children.clone();
+            match child {
+                Some(child) => children[sub] = child,
+                None => children.truncate(sub),
+            }
+            Some(Rc::new(VecNode::Branch(children)))
+        } else if sub == 0 {
+            None
+        } else {
+            let mut children = children.clone();
+            children.truncate(sub);
+            Some(Rc::new(VecNode::Branch(children)))
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<Expression> {
+        let mut items = Vec::with_capacity(self.len);
+        leaves(&self.root, &mut items);
+        items.extend(self.tail.iter().cloned());
+        items
+    }
+}
+
+fn new_path(level: usize, node: Rc<VecNode>) -> Rc<VecNode> {
+    if level == 0 {
+        node
+    } else {
+        Rc::new(VecNode::Branch(vec![new_path(level - BITS, node)]))
+    }
+}
+
+fn set_in(level: usize, node: &VecNode, idx: usize, item: Expression) -> Rc<VecNode> {
+    match node {
+        VecNode::Leaf(items) => {
+            let mut items = items.clone();
+            items[idx & MASK] = item;
+            Rc::new(VecNode::Leaf(items))
+        }
+        VecNode::Branch(children) => {
+            let mut children = children.clone();
+            let sub = (idx >> level) & MASK;
+            children[sub] = set_in(level - BITS, &children[sub], idx, item);
+            Rc::new(VecNode::Branch(children))
+        }
+    }
+}
+
+fn leaves(node: &VecNode, items: &mut Vec<Expression>) {
+    match node {
+        VecNode::Branch(children) => {
+            for child in children {
+                leaves(child, items);
+            }
+        }
+        VecNode::Leaf(leaf) => items.extend(leaf.iter().cloned()),
+    }
+}
+
+#[derive(Clone, Debug)]
+enum MapEntry {
+    Item(u64, String, Expression),
+    // Keys with the same hash.
+    Collision(u64, Vec<(String, Expression)>),
+    Node(Rc<MapNode>),
+}
+
+// Node of a hash array mapped trie, 5 bits of the key's hash pick the entry
+// at each level and the bitmap says which entries are there.
+#[derive(Clone, Debug, Default)]
+struct MapNode {
+    bitmap: u32,
+    entries: Vec<MapEntry>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PMap {
+    len: usize,
+    root: Rc<MapNode>,
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn fragment(hash: u64, shift: usize) -> u32 {
+    1 << ((hash >> shift) as usize & MASK)
+}
+
+impl MapNode {
+    fn index(&self, bit: u32) -> usize {
+        (self.bitmap & (bit - 1)).count_ones() as usize
+    }
+
+    fn get(&self, shift: usize, hash: u64, key: &str) -> Option<&Expression> {
+        let bit = fragment(hash, shift);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        match &self.entries[self.index(bit)] {
+            MapEntry::Item(_, k, val) if k == key => Some(val),
+            MapEntry::Item(_, _, _) => None,
+            MapEntry::Collision(_, items) => items.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            MapEntry::Node(node) => node.get(shift + BITS, hash, key),
+        }
+    }
+
+    // A copy with key set and true if the key is new.
+    fn insert(&self, shift: usize, hash: u64, key: String, val: Expression) -> (MapNode, bool) {
+        let bit = fragment(hash, shift);
+        let idx = self.index(bit);
+        let mut node = self.clone();
+        if self.bitmap & bit == 0 {
+            node.bitmap |= bit;
+            node.entries.insert(idx, MapEntry::Item(hash, key, val));
+            return (node, true);
+        }
+        let (entry, added) = match &self.entries[idx] {
+            MapEntry::Item(_, k, _) if *k == key => (MapEntry::Item(hash, key, val), false),
+            MapEntry::Item(h, k, v) if *h == hash => (
+                MapEntry::Collision(hash, vec![(k.clone(), v.clone()), (key, val)]),
+                true,
+            ),
+            MapEntry::Collision(h, items) if *h == hash => {
+                let mut items = items.clone();
+                let added = match items.iter_mut().find(|(k, _)| *k == key) {
+                    Some(item) => {
+                        item.1 = val;
+                        false
+                    }
+                    None => {
+                        items.push((key, val));
+                        true
+                    }
+                };
+                (MapEntry::Collision(hash, items), added)
+            }
+            MapEntry::Node(child) => {
+                let (child, added) = child.insert(shift + BITS, hash, key, val);
+                (MapEntry::Node(Rc::new(child)), added)
+            }
+            // A different hash in the same place, both go down a level.
+            other => {
+                let other_hash = match other {
+                    MapEntry::Item(h, _, _) | MapEntry::Collision(h, _) => *h,
+                    MapEntry::Node(_) => unreachable!(),
+                };
+                let sub = merge(
+                    shift + BITS,
+                    other.clone(),
+                    other_hash,
+                    MapEntry::Item(hash, key, val),
+                    hash,
+                );
+                (MapEntry::Node(Rc::new(sub)), true)
+            }
+        };
+        node.entries[idx] = entry;
+        (node, added)
+    }
+
+    // A copy without key, None if the key is not in the node.
+    fn remove(&self, shift: usize, hash: u64, key: &str) -> Option<MapNode> {
+        let bit = fragment(hash, shift);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        let idx = self.index(bit);
+        let entry = match &self.entries[idx] {
+            MapEntry::Item(_, k, _) if k == key => None,
+            MapEntry::Item(_, _, _) => return None,
+            MapEntry::Collision(h, items) => {
+                let pos = items.iter().position(|(k, _)| k == key)?;
+                let mut items = items.clone();
+                items.remove(pos);
+                if items.len() == 1 {
+                    let (k, v) = items.remove(0);
+                    Some(MapEntry::Item(*h, k, v))
+                } else {
+                    Some(MapEntry::Collision(*h, items))
+                }
+            }
+            MapEntry::Node(child) => {
+                let child = child.remove(shift + BITS, hash, key)?;
+                if child.entries.is_empty() {
+                    None
+                } else if child.entries.len() == 1 && !matches!(child.entries[0], MapEntry::Node(_))
+                {
+                    // A lone item does not need it's own node.
+                    Some(child.entries[0].clone())
+                } else {
+                    Some(MapEntry::Node(Rc::new(child)))
+                }
+            }
+        };
+        let mut node = self.clone();
+        match entry {
+            Some(entry) => node.entries[idx] = entry,
+            None => {
+                node.bitmap &= !bit;
+                node.entries.remove(idx);
+            }
+        }
+        Some(node)
+    }
+
+    fn items<'a>(&'a self, items: &mut Vec<(&'a String, &'a Expression)>) {
+        for entry in &self.entries {
+            match entry {
+                MapEntry::Item(_, k, v) => items.push((k, v)),
+                MapEntry::Collision(_, list) => items.extend(list.iter().map(|(k, v)| (k, v))),
+                MapEntry::Node(node) => node.items(items),
+            }
+        }
+    }
+}
+
+// A node with two entries that had the same fragment at the level above.
+fn merge(shift: usize, e1: MapEntry, h1: u64, e2: MapEntry, h2: u64) -> MapNode {
+    let (b1, b2) = (fragment(h1, shift), fragment(h2, shift));
+    if b1 == b2 {
+        MapNode {
+            bitmap: b1,
+            entries: vec![MapEntry::Node(Rc::new(merge(shift + BITS, e1, h1, e2, h2)))],
+        }
+    } else {
+        MapNode {
+            bitmap: b1 | b2,
+            entries: if b1 < b2 { vec![e1, e2] } else { vec![e2, e1] },
+        }
+    }
+}
+
+impl PMap {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Expression> {
+        self.root.get(0, hash_key(key), key)
+    }
+
+    pub fn insert(&self, key: String, val: Expression) -> PMap {
+        let (root, added) = self.root.insert(0, hash_key(&key), key, val);
+        PMap {
+            len: if added { self.len + 1 } else { self.len },
+            root: Rc::new(root),
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> PMap {
+        match self.root.remove(0, hash_key(key), key) {
+            Some(root) => PMap {
+                len: self.len - 1,
+                root: Rc::new(root),
+            },
+            None => self.clone(),
+        }
+    }
+
+    // The keys and values sorted by key.
+    pub fn items(&self) -> Vec<(&String, &Expression)> {
+        let mut items = Vec::with_capacity(self.len);
+        self.root.items(&mut items);
+        items.sort_by(|a, b| a.0.cmp(b.0));
+        items
+    }
+}
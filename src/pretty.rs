@@ -0,0 +1,363 @@
+use std::cell::Cell;
+use std::io::{self, Write};
+
+use crate::builtins_options::get_option;
+use crate::builtins_util::is_proper_list;
+use crate::environment::*;
+use crate::types::*;
+
+pub struct PrettySettings {
+    // Try to keep lines shorter then this.
+    pub width: usize,
+    // Number of spaces to indent each level of a broken form.
+    pub indent: usize,
+    // Forms nested deeper then this are printed as "...".
+    pub max_depth: Option<usize>,
+    // Sequences longer then this are truncated with "...".
+    pub max_length: Option<usize>,
+    // String results with more lines then this are truncated.
+    pub max_lines: Option<usize>,
+    pub color: bool,
+    // Set when something was left out.
+    pub truncated: Cell<bool>,
+}
+
+fn term_width() -> usize {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) };
+    if res == 0 && ws.ws_col > 0 {
+        ws.ws_col as usize
+    } else {
+        80
+    }
+}
+
+// An int option as a limit, None if it is 0 (or negative) for no limit.
+fn option_limit(environment: &Environment, name: &str) -> Option<usize> {
+    match get_option(environment, name) {
+        Expression::Atom(Atom::Int(i)) if i > 0 => Some(i as usize),
+        _ => None,
+    }
+}
+
+// Settings are read from the print-width, print-indent, print-length,
+// print-level, print-lines and print-color options.  Truncation and color are
+// only used when interactive is true so printing data is never altered.
+pub fn pretty_settings(environment: &Environment, interactive: bool) -> PrettySettings {
+    let mut settings = PrettySettings {
+        width: option_limit(environment, "print-width").unwrap_or_else(term_width),
+        indent: match get_option(environment, "print-indent") {
+            Expression::Atom(Atom::Int(i)) if i >= 0 => i as usize,
+            _ => 4,
+        },
+        max_depth: None,
+        max_length: None,
+        max_lines: None,
+        color: false,
+        truncated: Cell::new(false),
+    };
+    if interactive {
+        settings.max_depth = option_limit(environment, "print-level");
+        settings.max_length = option_limit(environment, "print-length");
+        settings.max_lines = option_limit(environment, "print-lines");
+        settings.color = !matches!(
+            get_option(environment, "print-color"),
+            Expression::Atom(Atom::Nil)
+        );
+    }
+    settings
+}
+
+fn ellipsis(color: bool) -> String {
+    if color {
+        "\x1b[90m...\x1b[39m".to_string()
+    } else {
+        "...".to_string()
+    }
+}
+
+fn atom_string(exp: &Expression, color: bool) -> String {
+    let s = exp.to_string();
+    if !color {
+        return s;
+    }
+    let code = match exp {
+        Expression::Atom(Atom::String(_)) => "32",
+        Expression::Atom(Atom::StringBuf(_)) => "32",
+        Expression::Atom(Atom::Char(_)) => "32",
+        Expression::Atom(Atom::Int(_)) => "36",
+        Expression::Atom(Atom::Float(_)) => "36",
+        Expression::Atom(Atom::Symbol(sym)) if sym.starts_with(':') => "35",
+        Expression::Atom(Atom::Nil) => "33",
+        Expression::Atom(Atom::True) => "33",
+        _ => return s,
+    };
+    format!("\x1b[{}m{}\x1b[39m", code, s)
+}
+
+fn is_comma(exp: &Expression) -> bool {
+    if let Expression::Atom(Atom::Symbol(sym)) = exp {
+        &**sym == "," || &**sym == ",@"
+    } else {
+        false
+    }
+}
+
+// If exp is (quote x) or (bquote x) return the reader prefix and x.
+fn quote_prefix(exp: &Expression) -> Option<(&'static str, Expression)> {
+    if let Expression::Pair(e1, e2) = exp {
+        let prefix = match &*e1.borrow() {
+            Expression::Atom(Atom::Symbol(sym)) if &**sym == "quote" => "'",
+            Expression::Atom(Atom::Symbol(sym)) if &**sym == "bquote" => "`",
+            _ => return None,
+        };
+        if let Expression::Pair(a2, _is_nil) = &*e2.borrow() {
+            return Some((prefix, a2.borrow().clone()));
+        }
+    }
+    None
+}
+
+impl PrettySettings {
+    fn too_deep(&self, depth: usize) -> bool {
+        let too_deep = match self.max_depth {
+            Some(max) => depth >= max,
+            None => false,
+        };
+        if too_deep {
+            self.truncated.set(true);
+        }
+        too_deep
+    }
+
+    fn too_long(&self, idx: usize) -> bool {
+        let too_long = match self.max_length {
+            Some(max) => idx >= max,
+            None => false,
+        };
+        if too_long {
+            self.truncated.set(true);
+        }
+        too_long
+    }
+
+    // Write a string result, only the first max_lines lines.
+    pub fn write_lines(&self, text: &str, writer: &mut dyn Write) -> io::Result<()> {
+        if let Some(max) = self.max_lines {
+            if let Some((end, _)) = text.match_indices('\n').nth(max) {
+                self.truncated.set(true);
+                writer.write_all(text[..end].as_bytes())?;
+                return writer.flush();
+            }
+        }
+        writer.write_all(text.as_bytes())?;
+        writer.flush()
+    }
+
+    fn flat_items(&self, items: &[&Expression], depth: usize, color: bool) -> String {
+        let mut res = String::new();
+        let mut last_comma = false;
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 && !last_comma {
+                res.push(' ');
+            }
+            if self.too_long(i) {
+                res.push_str(&ellipsis(color));
+                break;
+            }
+            res.push_str(&self.flat(item, depth, color));
+            last_comma = is_comma(item);
+        }
+        res
+    }
+
+    // The single line representation of exp (with truncation applied).
+    fn flat(&self, exp: &Expression, depth: usize, color: bool) -> String {
+        match exp {
+            Expression::Vector(list) => {
+                if self.too_deep(depth) {
+                    return ellipsis(color);
+                }
+                let list = list.borrow();
+                let items: Vec<&Expression> = list.iter().collect();
+                format!("#({})", self.flat_items(&items, depth + 1, color))
+            }
+            Expression::Pair(e1, e2) => {
+                if let Some((prefix, quoted)) = quote_prefix(exp) {
+                    return format!("{}{}", prefix, self.flat(&quoted, depth, color));
+                }
+                if self.too_deep(depth) {
+                    return ellipsis(color);
+                }
+                if is_proper_list(exp) {
+                    let items: Vec<&Expression> = exp.iter().collect();
+                    format!("({})", self.flat_items(&items, depth + 1, color))
+                } else {
+                    format!(
+                        "({} . {})",
+                        self.flat(&e1.borrow(), depth + 1, color),
+                        self.flat(&e2.borrow(), depth + 1, color)
+                    )
+                }
+            }
+            Expression::HashMap(map) => {
+                if self.too_deep(depth) {
+                    return ellipsis(color);
+                }
+                let mut res = String::new();
+                res.push_str("(make-hash (");
+                for (i, (key, val)) in map.borrow().iter().enumerate() {
+                    if self.too_long(i) {
+                        res.push_str(&ellipsis(color));
+                        break;
+                    }
+                    res.push_str(&format!("({} . {})", key, self.flat(val, depth + 1, color)));
+                }
+                res.push_str("))");
+                res
+            }
+            Expression::Atom(Atom::StringBuf(_)) => {
+                format!("(str-buf {})", atom_string(exp, color))
+            }
+            Expression::Atom(Atom::Lambda(l)) => format!(
+                "(fn {} {})",
+                l.params.to_string(),
+                self.flat(&l.body, depth + 1, color)
+            ),
+            Expression::Atom(Atom::Macro(m)) => format!(
+                "(macro {} {})",
+                m.params.to_string(),
+                self.flat(&m.body, depth + 1, color)
+            ),
+            _ => atom_string(exp, color),
+        }
+    }
+
+    fn newline(&self, col: usize, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(b"\n")?;
+        for _ in 0..col {
+            writer.write_all(b" ")?;
+        }
+        Ok(())
+    }
+
+    // Print open, the first item (and second if the first is a symbol, ie
+    // "(if test") then each following item on it's own line.
+    fn pp_items(
+        &self,
+        items: &[&Expression],
+        open: &str,
+        col: usize,
+        depth: usize,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        writer.write_all(open.as_bytes())?;
+        let inner = col + self.indent;
+        let mut line_col = col + open.len();
+        let inline = match items.first() {
+            Some(Expression::Atom(Atom::Symbol(_))) => 2,
+            _ => 1,
+        };
+        let mut last_comma = false;
+        for (i, item) in items.iter().enumerate() {
+            if self.too_long(i) {
+                self.newline(inner, writer)?;
+                writer.write_all(ellipsis(self.color).as_bytes())?;
+                break;
+            }
+            if i < inline {
+                if i > 0 {
+                    writer.write_all(b" ")?;
+                    line_col += 1;
+                }
+                self.pp(item, line_col, depth + 1, writer)?;
+                line_col += self.flat(item, depth + 1, false).chars().count();
+            } else if last_comma {
+                self.pp(item, inner + 1, depth + 1, writer)?;
+            } else {
+                self.newline(inner, writer)?;
+                self.pp(item, inner, depth + 1, writer)?;
+            }
+            last_comma = is_comma(item);
+        }
+        writer.write_all(b")")
+    }
+
+    fn pp(
+        &self,
+        exp: &Expression,
+        col: usize,
+        depth: usize,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let plain = self.flat(exp, depth, false);
+        if col + plain.chars().count() <= self.width || self.too_deep(depth) {
+            if self.color {
+                return writer.write_all(self.flat(exp, depth, true).as_bytes());
+            } else {
+                return writer.write_all(plain.as_bytes());
+            }
+        }
+        match exp {
+            Expression::Vector(list) => {
+                let list = list.borrow();
+                let items: Vec<&Expression> = list.iter().collect();
+                self.pp_items(&items, "#(", col, depth, writer)
+            }
+            Expression::Pair(_, _) if is_proper_list(exp) => {
+                if let Some((prefix, quoted)) = quote_prefix(exp) {
+                    writer.write_all(prefix.as_bytes())?;
+                    return self.pp(&quoted, col + 1, depth, writer);
+                }
+                let items: Vec<&Expression> = exp.iter().collect();
+                self.pp_items(&items, "(", col, depth, writer)
+            }
+            Expression::HashMap(map) => {
+                writer.write_all(b"(make-hash (")?;
+                let inner = col + self.indent;
+                for (i, (key, val)) in map.borrow().iter().enumerate() {
+                    self.newline(inner, writer)?;
+                    if self.too_long(i) {
+                        writer.write_all(ellipsis(self.color).as_bytes())?;
+                        break;
+                    }
+                    write!(writer, "({} . ", key)?;
+                    self.pp(val, inner + key.len() + 4, depth + 1, writer)?;
+                    writer.write_all(b")")?;
+                }
+                writer.write_all(b"))")
+            }
+            Expression::Atom(Atom::Lambda(l)) => {
+                write!(writer, "(fn {}", l.params.to_string())?;
+                self.newline(col + self.indent, writer)?;
+                self.pp(&l.body, col + self.indent, depth + 1, writer)?;
+                writer.write_all(b")")
+            }
+            Expression::Atom(Atom::Macro(m)) => {
+                write!(writer, "(macro {}", m.params.to_string())?;
+                self.newline(col + self.indent, writer)?;
+                self.pp(&m.body, col + self.indent, depth + 1, writer)?;
+                writer.write_all(b")")
+            }
+            _ => writer.write_all(self.flat(exp, depth, self.color).as_bytes()),
+        }
+    }
+}
+
+pub fn pretty_write(
+    exp: &Expression,
+    environment: &Environment,
+    settings: &PrettySettings,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    match exp {
+        // These produce their contents (process output for instance).
+        Expression::Process(_)
+        | Expression::File(_)
+        | Expression::Func(_)
+        | Expression::Function(_) => exp.writef(environment, writer),
+        // The labeled (#n=) form from to_string is the only sane output for a cycle.
+        _ if has_cycle(exp) => write!(writer, "{}", exp),
+        _ => settings.pp(exp, 0, 0, writer),
+    }
+}
@@ -1,36 +1,695 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
-use std::io::{self, Write};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{ChildStdin, ChildStdout, Command, Stdio};
 use std::rc::Rc;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use glob::glob;
 use nix::{
+    errno::Errno,
     sys::{
-        signal::{self, kill, SigHandler, Signal},
+        signal::{self, kill, SaFlags, SigAction, SigHandler, SigSet, Signal},
         termios,
-        wait::{self, WaitPidFlag, WaitStatus},
+        wait::{WaitPidFlag, WaitStatus},
     },
     unistd::{self, Pid},
 };
 
+use crate::builtins_options::get_option;
 use crate::builtins_util::*;
 use crate::environment::*;
 use crate::eval::*;
+use crate::resolve::*;
+use crate::suggest::*;
 use crate::types::*;
 
-pub fn try_wait_pid(environment: &Environment, pid: u32) -> (bool, Option<i32>) {
+// Resource limits for spawned commands (see run-limited).
+#[derive(Clone, Debug, Default)]
+pub struct ProcLimits {
+    // Added to the child's nice value.
+    pub nice: Option<i32>,
+    // Address space limit in bytes (and memory.max of the cgroup if one).
+    pub mem: Option<u64>,
+    // CPU time limit in seconds.
+    pub cpu: Option<u64>,
+    // Max open files.
+    pub nofile: Option<u64>,
+    // The cgroup (directory) children are moved into.
+    pub cgroup: Option<PathBuf>,
+}
+
+// Parse a size like 1024, "512K", "100M" or "2G".
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (num, mult) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1024),
+        Some('M') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('G') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        Some('T') => (&size[..size.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    // None (an invalid size) if it does not fit.
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+}
+
+// Relative cgroup names are created under the shell's own (v2) cgroup.
+fn cgroup_path(name: &str) -> io::Result<PathBuf> {
+    if name.starts_with('/') {
+        return Ok(PathBuf::from(name));
+    }
+    let own = fs::read_to_string("/proc/self/cgroup")?;
+    let own = own
+        .lines()
+        .find(|l| l.starts_with("0::"))
+        .map(|l| &l[3..])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "cgroup v2 is not available"))?;
+    Ok(Path::new("/sys/fs/cgroup")
+        .join(own.trim_start_matches('/'))
+        .join(name))
+}
+
+fn limit_value(
+    environment: &Environment,
+    key: &str,
+    val: &Expression,
+    limits: &mut ProcLimits,
+) -> io::Result<()> {
+    let bad_value = || {
+        let msg = format!("run-limited: invalid value for {}: {}", key, val);
+        io::Error::new(io::ErrorKind::Other, msg)
+    };
+    let number = |val: &Expression| match val {
+        Expression::Atom(Atom::Int(i)) if *i >= 0 => Some(*i as u64),
+        Expression::Atom(Atom::Int(_)) => None,
+        _ => val.as_string(environment).ok().and_then(|s| parse_size(&s)),
+    };
+    match key {
+        ":nice" => match val {
+            // Nice values are -20 to 19 so a bigger change means nothing.
+            Expression::Atom(Atom::Int(i)) if (-39..=39).contains(i) => {
+                limits.nice = Some(*i as i32)
+            }
+            _ => return Err(bad_value()),
+        },
+        ":mem" => limits.mem = Some(number(val).ok_or_else(bad_value)?),
+        ":cpu" => limits.cpu = Some(number(val).ok_or_else(bad_value)?),
+        ":nofile" => limits.nofile = Some(number(val).ok_or_else(bad_value)?),
+        ":cgroup" => limits.cgroup = Some(cgroup_path(&val.as_string(environment)?)?),
+        _ => {
+            let msg = format!(
+                "run-limited: unknown option {} (use :nice, :mem, :cpu, :nofile or :cgroup)",
+                key
+            );
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    Ok(())
+}
+
+// Build limits from a hashmap or a list/vector of keyword value pairs and
+// setup the cgroup if one was requested.
+pub fn proc_limits(environment: &Environment, options: &Expression) -> io::Result<ProcLimits> {
+    let mut limits = ProcLimits::default();
+    match options {
+        Expression::HashMap(map) => {
+            for (key, val) in map.borrow().iter() {
+                limit_value(environment, key, val, &mut limits)?;
+            }
+        }
+        Expression::Vector(_) | Expression::Pair(_, _) => {
+            let items: Vec<Expression> = match options {
+                Expression::Vector(list) => list.borrow().clone(),
+                _ => options.iter().cloned().collect(),
+            };
+            if items.len() % 2 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "run-limited: options must be keyword value pairs",
+                ));
+            }
+            for pair in items.chunks(2) {
+                let key = pair[0].as_string(environment)?;
+                limit_value(environment, &key, &pair[1], &mut limits)?;
+            }
+        }
+        Expression::Atom(Atom::Nil) => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "run-limited: options must be a hashmap, list or vector",
+            ))
+        }
+    }
+    if let Some(cgroup) = &limits.cgroup {
+        // Only make cgroups inside of an existing cgroup (v2) hierarchy.
+        let parent = cgroup.parent().unwrap_or(cgroup);
+        if !cgroup.join("cgroup.procs").exists() && !parent.join("cgroup.procs").exists() {
+            let msg = format!(
+                "run-limited: {} is not in a cgroup v2 hierarchy",
+                cgroup.display()
+            );
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        fs::create_dir_all(cgroup)?;
+        if let Some(mem) = limits.mem {
+            fs::write(cgroup.join("memory.max"), mem.to_string())?;
+        }
+    }
+    Ok(limits)
+}
+
+// NAME=value in front of a command, None if word does not start with a valid
+// environment variable name and =.
+pub fn env_assignment(word: &str) -> Option<(&str, &str)> {
+    let idx = word.find('=')?;
+    let name = &word[..idx];
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(ch) if ch == '_' || ch.is_ascii_alphabetic() => {}
+        _ => return None,
+    }
+    if chars.all(|ch| ch == '_' || ch.is_ascii_alphanumeric()) {
+        Some((name, &word[idx + 1..]))
+    } else {
+        None
+    }
+}
+
+// Vars for with-env from a hashmap or key value list, nil removes a var.
+pub fn child_env_vars(
+    environment: &Environment,
+    vars: &Expression,
+) -> io::Result<Vec<(String, Option<String>)>> {
+    let value = |val: &Expression| -> io::Result<Option<String>> {
+        match val {
+            Expression::Atom(Atom::Nil) => Ok(None),
+            _ => Ok(Some(val.as_string(environment)?)),
+        }
+    };
+    let mut child_env = Vec::new();
+    match vars {
+        Expression::HashMap(map) => {
+            for (key, val) in map.borrow().iter() {
+                child_env.push((key.trim_start_matches(':').to_string(), value(val)?));
+            }
+        }
+        Expression::Vector(_) | Expression::Pair(_, _) => {
+            let items: Vec<Expression> = match vars {
+                Expression::Vector(list) => list.borrow().clone(),
+                _ => vars.iter().cloned().collect(),
+            };
+            if items.len() % 2 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "with-env: vars must be name value pairs",
+                ));
+            }
+            for pair in items.chunks(2) {
+                let key = pair[0].as_string(environment)?;
+                child_env.push((key.trim_start_matches(':').to_string(), value(&pair[1])?));
+            }
+        }
+        Expression::Atom(Atom::Nil) => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "with-env: vars must be a hashmap, list or vector",
+            ))
+        }
+    }
+    for (name, _) in &child_env {
+        if name.is_empty() || name.contains('=') || name.contains('\0') {
+            let msg = format!("with-env: invalid variable name {:?}", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    Ok(child_env)
+}
+
+// Eval forms with vars set (or removed when None) in the environment of the
+// commands they start, the shell's own environment is not changed.
+pub fn with_child_env(
+    environment: &mut Environment,
+    vars: Vec<(String, Option<String>)>,
+    forms: &mut dyn Iterator<Item = &Expression>,
+) -> io::Result<Expression> {
+    let old_len = environment.state.child_env.len();
+    environment.state.child_env.extend(vars);
+    let mut last_eval = Ok(Expression::Atom(Atom::Nil));
+    for form in forms {
+        last_eval = eval(environment, form);
+        if last_eval.is_err() {
+            break;
+        }
+    }
+    environment.state.child_env.truncate(old_len);
+    last_eval
+}
+
+fn set_rlimit(resource: u32, value: u64) -> io::Result<()> {
+    let mut lim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Can not raise the hard limit so stay under it.
+    unsafe {
+        if libc::getrlimit(resource as _, &mut lim) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let value = (value as libc::rlim_t).min(lim.rlim_max);
+    lim.rlim_cur = value;
+    lim.rlim_max = value;
+    if unsafe { libc::setrlimit(resource as _, &lim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Called in the child (pre_exec) so only async signal safe calls here.
+fn apply_limits(limits: &ProcLimits, cgroup_procs: &Option<CString>) -> io::Result<()> {
+    if let Some(procs) = cgroup_procs {
+        unsafe {
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // "0" is the writing process.
+            let res = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+            libc::close(fd);
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    if let Some(nice) = limits.nice {
+        unsafe {
+            // -1 is also a valid priority, only errno tells them apart.
+            Errno::clear();
+            let current = libc::getpriority(libc::PRIO_PROCESS as _, 0);
+            if current == -1 && Errno::last() != Errno::UnknownErrno {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setpriority(libc::PRIO_PROCESS as _, 0, current + nice) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    if let Some(mem) = limits.mem {
+        set_rlimit(libc::RLIMIT_AS as u32, mem)?;
+    }
+    if let Some(cpu) = limits.cpu {
+        set_rlimit(libc::RLIMIT_CPU as u32, cpu)?;
+    }
+    if let Some(nofile) = limits.nofile {
+        set_rlimit(libc::RLIMIT_NOFILE as u32, nofile)?;
+    }
+    Ok(())
+}
+
+// An unlinked temp file to capture output in, a real file so processes run
+// by with-out-str etc write to it as well.
+pub fn capture_file() -> io::Result<fs::File> {
+    let template = env::temp_dir().join("slsh-capture-XXXXXX");
+    let (fd, path) = match unistd::mkstemp(&template) {
+        Ok(res) => res,
+        Err(err) => {
+            let msg = format!("unable to create a capture file: {}", err);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let file = unsafe { fs::File::from_raw_fd(fd) };
+    fs::remove_file(path)?;
+    Ok(file)
+}
+
+// Output of a captured foreground process, it is read while waiting for the
+// process so a big output can not fill the pipe and block it.  Kept in memory
+// up to the capture-spill-bytes option then moved to a capture file.
+#[derive(Debug)]
+pub enum Captured {
+    Memory(io::Cursor<Vec<u8>>),
+    Spilled(BufReader<fs::File>),
+}
+
+impl Read for Captured {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Captured::Memory(out) => out.read(buf),
+            Captured::Spilled(out) => out.read(buf),
+        }
+    }
+}
+
+impl BufRead for Captured {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Captured::Memory(out) => out.fill_buf(),
+            Captured::Spilled(out) => out.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Captured::Memory(out) => out.consume(amt),
+            Captured::Spilled(out) => out.consume(amt),
+        }
+    }
+}
+
+// Read all of out, spilling to a capture file once it is over spill bytes
+// (never if spill is 0).
+fn capture_output(mut out: ChildStdout, spill: usize) -> io::Result<Captured> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        match out.read(&mut chunk) {
+            Ok(0) => return Ok(Captured::Memory(io::Cursor::new(buffer))),
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+        if spill > 0 && buffer.len() > spill {
+            break;
+        }
+    }
+    let mut file = capture_file()?;
+    file.write_all(&buffer)?;
+    drop(buffer);
+    io::copy(&mut out, &mut file)?;
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(Captured::Spilled(BufReader::new(file)))
+}
+
+// Smallest and largest buffer for copy_stream.
+const COPY_BUF_MIN: usize = 8 * 1024;
+const COPY_BUF_MAX: usize = 1024 * 1024;
+
+// Copy all of reader to writer, the buffer doubles while reads fill it so a
+// big output takes few reads but a trickle does not need a big buffer.
+pub fn copy_stream(reader: &mut dyn Read, writer: &mut dyn Write) -> io::Result<u64> {
+    let mut buf = vec![0; COPY_BUF_MIN];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+        if n == buf.len() && buf.len() < COPY_BUF_MAX {
+            buf.resize(buf.len() * 2, 0);
+        }
+    }
+}
+
+// Move everything from the pipe from to the file to in the kernel, Ok(false)
+// if splice can not be used for them (nothing moved yet).
+#[cfg(target_os = "linux")]
+fn splice_all(from: RawFd, to: RawFd) -> io::Result<bool> {
+    let mut moved = false;
+    loop {
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                COPY_BUF_MAX,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if n == 0 {
+            return Ok(true);
+        }
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // An append only file for instance.
+                Some(libc::EINVAL) if !moved => return Ok(false),
+                _ => return Err(err),
+            }
+        }
+        moved = true;
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn splice_all(_from: RawFd, _to: RawFd) -> io::Result<bool> {
+    Ok(false)
+}
+
+// Write the rest of proc's output to file without copying it through the
+// shell, Ok(false) if that is not possible and the caller should copy it.
+pub fn splice_process_to_file(
+    environment: &Environment,
+    proc: &ProcessHandle,
+    file: &mut io::BufWriter<fs::File>,
+) -> io::Result<bool> {
+    let from = match proc.stdout_fd() {
+        Some(out) => out,
+        None => return Ok(false),
+    };
+    // Anything already written goes first.
+    file.flush()?;
+    if !splice_all(from, file.get_ref().as_raw_fd())? {
+        return Ok(false);
+    }
+    if proc.status().is_none() {
+        wait_pid(environment, proc.pid(), None);
+    }
+    Ok(true)
+}
+
+// Record how a process ended in it's handle (if it is still in procs).
+fn set_exit(environment: &Environment, exit: ExitInfo) {
+    if let Some(proc) = environment.procs.borrow().get(&exit.pid) {
+        proc.set_exit(exit);
+    }
+}
+
+// How a process ended, from wait4 so it includes the resources it used.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitInfo {
+    pub pid: u32,
+    pub code: Option<i32>,
+    pub signal: Option<Signal>,
+    pub core_dumped: bool,
+    pub user_time: f64,
+    pub sys_time: f64,
+    // In KB (as getrusage reports it on Linux).
+    pub max_rss: i64,
+}
+
+impl ExitInfo {
+    // The status the shell reports, 128 + the signal if it was killed.
+    pub fn status(&self) -> i32 {
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(sig)) => 128 + sig as i32,
+            (None, None) => 0,
+        }
+    }
+}
+
+fn timeval_secs(tv: &libc::timeval) -> f64 {
+    tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0
+}
+
+// A waitpid that also returns how the process ended if it did.
+fn wait_rusage(pid: u32, opts: WaitPidFlag) -> nix::Result<(WaitStatus, Option<ExitInfo>)> {
+    let mut status: libc::c_int = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::wait4(pid as libc::pid_t, &mut status, opts.bits(), &mut usage) };
+    if res < 0 {
+        return Err(nix::Error::Sys(nix::errno::Errno::last()));
+    }
+    if res == 0 {
+        return Ok((WaitStatus::StillAlive, None));
+    }
+    let wait_status = WaitStatus::from_raw(Pid::from_raw(res), status)?;
+    let (code, signal, core_dumped) = match wait_status {
+        WaitStatus::Exited(_, code) => (Some(code), None, false),
+        WaitStatus::Signaled(_, sig, core_dumped) => (None, Some(sig), core_dumped),
+        _ => return Ok((wait_status, None)),
+    };
+    let exit = ExitInfo {
+        pid,
+        code,
+        signal,
+        core_dumped,
+        user_time: timeval_secs(&usage.ru_utime),
+        sys_time: timeval_secs(&usage.ru_stime),
+        max_rss: usage.ru_maxrss as i64,
+    };
+    Ok((wait_status, Some(exit)))
+}
+
+// Hashmap describing how a process ended, a pipeline also has the status of
+// each of it's commands under :pipeline.
+pub fn status_object(exit: &ExitInfo, pipeline: Option<Vec<Expression>>) -> Expression {
+    let mut map: HashMap<String, Rc<Expression>> = HashMap::new();
+    let int = |i: i64| Rc::new(Expression::Atom(Atom::Int(i)));
+    let nil = || Rc::new(Expression::Atom(Atom::Nil));
+    map.insert(":pid".to_string(), int(i64::from(exit.pid)));
+    map.insert(":status".to_string(), int(i64::from(exit.status())));
+    map.insert(
+        ":code".to_string(),
+        exit.code.map_or_else(nil, |code| int(i64::from(code))),
+    );
+    map.insert(
+        ":signal".to_string(),
+        exit.signal.map_or_else(nil, |sig| int(sig as i64)),
+    );
+    map.insert(
+        ":signal-name".to_string(),
+        exit.signal.map_or_else(nil, |sig| {
+            Rc::new(Expression::Atom(Atom::String(
+                sig.as_ref().to_string().into(),
+            )))
+        }),
+    );
+    map.insert(
+        ":core-dumped".to_string(),
+        Rc::new(if exit.core_dumped {
+            Expression::Atom(Atom::True)
+        } else {
+            Expression::Atom(Atom::Nil)
+        }),
+    );
+    let mut rusage: HashMap<String, Rc<Expression>> = HashMap::new();
+    rusage.insert(
+        ":user-time".to_string(),
+        Rc::new(Expression::Atom(Atom::Float(exit.user_time))),
+    );
+    rusage.insert(
+        ":sys-time".to_string(),
+        Rc::new(Expression::Atom(Atom::Float(exit.sys_time))),
+    );
+    rusage.insert(":max-rss".to_string(), int(exit.max_rss * 1024));
+    map.insert(
+        ":rusage".to_string(),
+        Rc::new(Expression::HashMap(Rc::new(RefCell::new(rusage)))),
+    );
+    if let Some(pipeline) = pipeline {
+        map.insert(
+            ":pipeline".to_string(),
+            Rc::new(Expression::with_list(pipeline)),
+        );
+    }
+    Expression::HashMap(Rc::new(RefCell::new(map)))
+}
+
+// Set $?/LAST_STATUS and *last-status* to status and *last-status-info* to
+// the status object (or nil).
+pub fn set_last_status(environment: &Environment, status: i32, info: Expression) {
+    env::set_var("LAST_STATUS".to_string(), format!("{}", status));
+    let mut root = environment.root_scope.borrow_mut();
+    root.data.insert(
+        "*last-status*".to_string(),
+        Rc::new(Expression::Atom(Atom::Int(i64::from(status)))),
+    );
+    root.data
+        .insert("*last-status-info*".to_string(), Rc::new(info));
+}
+
+// Set by the SIGCHLD handler, some child changed state since reap_changed
+// last looked.
+static SIG_CHLD: AtomicBool = AtomicBool::new(false);
+// Without the handler reap_procs has to look every time.
+static SIG_CHLD_HANDLER: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigchld(_sig: libc::c_int) {
+    SIG_CHLD.store(true, Ordering::Relaxed);
+}
+
+// Install the SIGCHLD handler, it only sets a flag (signal safe) and the
+// reaping is done by reap_changed.
+pub fn install_sigchld_handler() -> io::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigchld),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { signal::sigaction(Signal::SIGCHLD, &action) }
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    SIG_CHLD_HANDLER.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn sigchld_handler_installed() -> bool {
+    SIG_CHLD_HANDLER.load(Ordering::Relaxed)
+}
+
+// Reap the children that changed state if there was a SIGCHLD, cheap enough
+// to call from eval.
+pub fn reap_changed(environment: &Environment) {
+    if SIG_CHLD.load(Ordering::Relaxed) && SIG_CHLD.swap(false, Ordering::Relaxed) {
+        reap_children(environment);
+    }
+}
+
+// Wait (without blocking) on the processes the shell started to reap the ones
+// that exited and update jobs for stops and continues.  Only our pids are
+// waited on, a waitpid(-1) would take children that std::process or the
+// parallel workers are waiting for.  An exited process stays in procs (it may
+// still have output to read) with it's status in reaped for a later wait_pid.
+pub fn reap_children(environment: &Environment) {
+    let pids: Vec<u32> = environment.procs.borrow().keys().copied().collect();
     let mut opts = WaitPidFlag::WUNTRACED;
     opts.insert(WaitPidFlag::WCONTINUED);
     opts.insert(WaitPidFlag::WNOHANG);
-    match wait::waitpid(Pid::from_raw(pid as i32), Some(opts)) {
+    for pid in pids {
+        if environment.reaped.borrow().contains_key(&pid) {
+            continue;
+        }
+        match wait_rusage(pid, opts) {
+            Ok((_, Some(exit))) => {
+                environment.reaped.borrow_mut().insert(pid, exit);
+                set_exit(environment, exit);
+                remove_job(environment, pid);
+            }
+            Ok((WaitStatus::Stopped(..), _)) => {
+                let mut stopped = environment.stopped_procs.borrow_mut();
+                if !stopped.contains(&pid) {
+                    stopped.push(pid);
+                }
+                drop(stopped);
+                mark_job_stopped(environment, pid);
+            }
+            Ok((WaitStatus::Continued(_), _)) => mark_job_running(environment, pid),
+            // Still running or already waited for.
+            _ => {}
+        }
+    }
+}
+
+pub fn try_wait_pid(environment: &Environment, pid: u32) -> (bool, Option<ExitInfo>) {
+    let mut opts = WaitPidFlag::WUNTRACED;
+    opts.insert(WaitPidFlag::WCONTINUED);
+    opts.insert(WaitPidFlag::WNOHANG);
+    match wait_rusage(pid, opts) {
         Err(nix::Error::Sys(nix::errno::Errno::ECHILD)) => {
-            // Does not exist.
+            // Does not exist (or reap_changed got it).
+            let exit = environment.reaped.borrow_mut().remove(&pid);
             environment.procs.borrow_mut().remove(&pid);
             remove_job(environment, pid);
-            (true, None)
+            (true, exit)
         }
         Err(err) => {
             eprintln!("Error waiting for pid {}, {}", pid, err);
@@ -38,21 +697,36 @@ pub fn try_wait_pid(environment: &Environment, pid: u32) -> (bool, Option<i32>)
             remove_job(environment, pid);
             (true, None)
         }
-        Ok(WaitStatus::Exited(_, status)) => {
+        Ok((_, Some(exit))) => {
+            set_exit(environment, exit);
             environment.procs.borrow_mut().remove(&pid);
             remove_job(environment, pid);
-            (true, Some(status))
+            (true, Some(exit))
         }
-        Ok(WaitStatus::Stopped(..)) => {
+        Ok((WaitStatus::Stopped(..), _)) => {
             environment.stopped_procs.borrow_mut().push(pid);
             mark_job_stopped(environment, pid);
             (true, None)
         }
-        Ok(WaitStatus::Continued(_)) => (false, None),
+        Ok((WaitStatus::Continued(_), _)) => (false, None),
         Ok(_) => (false, None),
     }
 }
 
+// Time between SIGTERM and SIGKILL for a command past it's deadline.
+pub const KILL_GRACE: Duration = Duration::from_secs(2);
+
+// Signal pid's process group (just pid if it is in the shell's group).
+pub fn kill_pgroup(pid: u32, sig: Signal) -> nix::Result<()> {
+    let pid = Pid::from_raw(pid as i32);
+    let pgid = unistd::getpgid(Some(pid))?;
+    if pgid == unistd::getpgrp() {
+        kill(pid, sig)
+    } else {
+        signal::killpg(pgid, sig)
+    }
+}
+
 pub fn wait_pid(
     environment: &Environment,
     pid: u32,
@@ -60,7 +734,22 @@ pub fn wait_pid(
 ) -> Option<i32> {
     let result: Option<i32>;
     let mut int_cnt = 0;
+    let mut term_sent: Option<Instant> = None;
     loop {
+        if let Some(deadline) = environment.state.deadline {
+            let now = Instant::now();
+            match term_sent {
+                None if now >= deadline => {
+                    let _ = kill_pgroup(pid, Signal::SIGTERM);
+                    environment.state.deadline_hit.set(true);
+                    term_sent = Some(now);
+                }
+                Some(sent) if now >= sent + KILL_GRACE => {
+                    let _ = kill_pgroup(pid, Signal::SIGKILL);
+                }
+                _ => {}
+            }
+        }
         if environment.sig_int.load(Ordering::Relaxed) {
             if int_cnt == 0 {
                 if let Err(err) = kill(Pid::from_raw(pid as i32), Signal::SIGINT) {
@@ -76,16 +765,14 @@ pub fn wait_pid(
             int_cnt += 1;
             environment.sig_int.store(false, Ordering::Relaxed);
         }
-        let (stop, status) = try_wait_pid(environment, pid);
+        // Keep up with background jobs while waiting on this one.
+        reap_changed(environment);
+        let (stop, exit) = try_wait_pid(environment, pid);
         if stop {
-            result = status;
-            if let Some(status) = status {
+            result = exit.map(|exit| exit.status());
+            if let Some(exit) = exit {
                 if environment.save_exit_status {
-                    env::set_var("LAST_STATUS".to_string(), format!("{}", status));
-                    environment.root_scope.borrow_mut().data.insert(
-                        "*last-status*".to_string(),
-                        Rc::new(Expression::Atom(Atom::Int(i64::from(status)))),
-                    );
+                    set_last_status(environment, exit.status(), status_object(&exit, None));
                 }
             }
             break;
@@ -132,12 +819,49 @@ fn run_command(
         .stdin(stdin)
         .stdout(stdout)
         .stderr(stderr);
-    let pgid = environment.state.pipe_pgid;
+    for (name, value) in &environment.state.child_env {
+        match value {
+            Some(value) => com_obj.env(name, value),
+            None => com_obj.env_remove(name),
+        };
+    }
+    // The group is gone once all of its processes have exited, start a new one.
+    if let Some(leader) = environment.state.new_pgroup {
+        if leader != 0 && signal::killpg(Pid::from_raw(leader as i32), None).is_err() {
+            environment.state.new_pgroup = Some(0);
+        }
+    }
+    let pgid = match (environment.state.pipe_pgid, environment.state.new_pgroup) {
+        // A pipe in with-new-pgroup joins the new group too.
+        (_, Some(leader)) if leader != 0 => Some(leader),
+        (pipe_pgid, _) => pipe_pgid,
+    };
+    let new_session = environment.state.new_session;
     let do_job_control = environment.do_job_control;
+    let limits = environment.state.limits.clone();
+    let cgroup_procs = match limits.as_ref().and_then(|l| l.cgroup.as_ref()) {
+        Some(cgroup) => Some(
+            CString::new(cgroup.join("cgroup.procs").as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+        ),
+        None => None,
+    };
+
+    // A deadline kills the process group so make sure it is not ours.
+    let own_pgroup = !new_session
+        && (do_job_control
+            || environment.state.deadline.is_some()
+            || environment.state.new_pgroup.is_some());
 
     unsafe {
         com_obj.pre_exec(move || -> io::Result<()> {
-            if do_job_control {
+            if let Some(limits) = &limits {
+                apply_limits(limits, &cgroup_procs)?;
+            }
+            if new_session {
+                unistd::setsid().map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            }
+            if own_pgroup {
                 let pid = unistd::getpid();
                 let pgid = match pgid {
                     Some(pgid) => Pid::from_raw(pgid as i32),
@@ -147,7 +871,7 @@ fn run_command(
                     // Ignore, do in parent and child.
                     //let msg = format!("Error setting pgid for {}: {}", pid, err);
                 }
-                if foreground {
+                if do_job_control && foreground {
                     if let Err(_err) = unistd::tcsetpgrp(nix::libc::STDIN_FILENO, pgid) {
                         // Ignore, do in parent and child.
                         //let msg = format!("Error making {} foreground: {}", pid, err);
@@ -176,6 +900,7 @@ fn run_command(
         None
     };
     let proc = com_obj.spawn();
+    let started = Instant::now();
 
     match proc {
         Ok(mut proc) => {
@@ -183,27 +908,39 @@ fn run_command(
                 Some(pgid) => Pid::from_raw(pgid as i32),
                 None => Pid::from_raw(proc.id() as i32),
             };
+            if let Some(0) = environment.state.new_pgroup {
+                environment.state.new_pgroup = Some(proc.id());
+            }
             if environment.do_job_control {
                 let pid = Pid::from_raw(proc.id() as i32);
-                if pgid.is_none() {
+                // Part of the job of the group it joined if that is still around.
+                let mut jobs = environment.jobs.borrow_mut();
+                let job = match pgid {
+                    Some(pgid) => jobs.iter_mut().find(|j| j.pids.contains(&pgid)),
+                    None => None,
+                };
+                if let Some(job) = job {
+                    job.pids.push(proc.id());
+                    job.names.push(command.to_string());
+                } else {
                     let mut job = Job {
                         pids: Vec::new(),
                         names: Vec::new(),
                         status: JobStatus::Running,
+                        started: SystemTime::now(),
                     };
                     job.pids.push(proc.id());
                     job.names.push(command.to_string());
-                    environment.jobs.borrow_mut().push(job);
-                } else {
-                    let job = environment.jobs.borrow_mut().pop();
-                    if let Some(mut job) = job {
-                        job.pids.push(proc.id());
-                        job.names.push(command.to_string());
-                        environment.jobs.borrow_mut().push(job);
-                    } else {
-                        eprintln!("WARNING: Soemthing in pipe is amiss, probably a command not part of pipe or a bug!");
+                    jobs.push(job);
+                }
+                drop(jobs);
+                if own_pgroup {
+                    if let Err(_err) = unistd::setpgid(pid, pgid_raw) {
+                        // Ignore, do in parent and child.
                     }
                 }
+            } else if own_pgroup {
+                let pid = Pid::from_raw(proc.id() as i32);
                 if let Err(_err) = unistd::setpgid(pid, pgid_raw) {
                     // Ignore, do in parent and child.
                 }
@@ -213,29 +950,70 @@ fn run_command(
                     let mut input: Option<ChildStdin> = None;
                     std::mem::swap(&mut proc.stdin, &mut input);
                     let mut input = input.unwrap();
-                    input.write_all(data_in.to_string().as_bytes())?;
+                    // A string (like each-arg's output) is piped as is, not printed.
+                    let data = match data_in {
+                        Atom::String(s) => s.to_string(),
+                        atom => atom.to_string(),
+                    };
+                    input.write_all(data.as_bytes())?;
                 }
             }
             let pid = proc.id();
+            // The group the process ended up in, a new session leads it's own.
+            let pgid = if new_session {
+                pid
+            } else if own_pgroup {
+                pgid_raw.as_raw() as u32
+            } else {
+                unistd::getpgrp().as_raw() as u32
+            };
             let result = if foreground && !environment.in_pipe {
+                // Drain captured output while waiting or the process blocks
+                // once the pipe is full.
+                let spill = match get_option(environment, "capture-spill-bytes") {
+                    Expression::Atom(Atom::Int(i)) if i > 0 => i as usize,
+                    _ => 0,
+                };
+                let drain = proc
+                    .stdout
+                    .take()
+                    .map(|out| std::thread::spawn(move || capture_output(out, spill)));
                 if environment.do_job_control {
                     if let Err(_err) = unistd::tcsetpgrp(shell_terminal, pgid_raw) {
                         // Ignore, do in parent and child.
                     }
                 }
+                // In procs while waiting so it's exit is recorded in it.
+                let handle = ProcessHandle::new(proc, pgid, started);
+                add_process(environment, handle.clone());
                 let status = if let Some(term_settings) = term_settings {
-                    wait_pid(environment, proc.id(), Some(&term_settings))
+                    wait_pid(environment, pid, Some(&term_settings))
                 } else {
-                    wait_pid(environment, proc.id(), None)
+                    wait_pid(environment, pid, None)
                 };
+                // A stopped process keeps it's drain thread, the output is
+                // lost but the shell does not block on it.
+                if let (Some(drain), Some(_)) = (drain, status) {
+                    match drain.join() {
+                        Ok(Ok(captured)) => handle.set_captured(captured),
+                        Ok(Err(err)) => return Err(err),
+                        Err(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                "failed to capture process output",
+                            ))
+                        }
+                    }
+                }
                 match status {
-                    Some(code) => Expression::Process(ProcessState::Over(pid, code as i32)),
+                    Some(_) => Expression::Process(handle),
                     None => Expression::Atom(Atom::Nil),
                 }
             } else {
-                Expression::Process(ProcessState::Running(pid))
+                let handle = ProcessHandle::new(proc, pgid, started);
+                add_process(environment, handle.clone());
+                Expression::Process(handle)
             };
-            add_process(environment, proc);
             Ok(result)
         }
         Err(e) => {
@@ -245,6 +1023,13 @@ fn run_command(
                 err_msg.push_str(&format!(" {}", n));
             }
             err_msg.push_str(&format!("]: {}", e));
+            if e.kind() == io::ErrorKind::NotFound && !command.contains('/') {
+                let hint = did_you_mean(&command_suggestions(environment, command));
+                if !hint.is_empty() {
+                    err_msg.push('.');
+                    err_msg.push_str(&hint);
+                }
+            }
             // Recover from the failed spawn...
             // If we were saved terminal settings restore them.
             if let Some(settings) = term_settings {
@@ -290,7 +1075,9 @@ fn get_std_io(environment: &Environment, is_out: bool) -> io::Result<Stdio> {
                         }
                     }
                     FileState::Write(f) => {
-                        let f = f.borrow();
+                        // Anything printed already goes before the process output.
+                        let mut f = f.borrow_mut();
+                        f.flush()?;
                         Ok(Stdio::from(f.get_ref().try_clone()?))
                     }
                     _ => Err(io::Error::new(
@@ -309,6 +1096,30 @@ fn get_std_io(environment: &Environment, is_out: bool) -> io::Result<Stdio> {
     }
 }
 
+// Stdin for a command from *stdin* (see stdin-from).
+fn get_std_in(environment: &Environment, foreground: bool) -> io::Result<Stdio> {
+    match get_expression(environment, "*stdin*").as_deref() {
+        Some(Expression::File(FileState::Read(f))) => {
+            let mut f = f.borrow_mut();
+            // Give back what was buffered so the command starts where lisp
+            // reads left off (not possible for a pipe).
+            let _ = f.seek(io::SeekFrom::Current(0));
+            Ok(Stdio::from(f.get_ref().try_clone()?))
+        }
+        Some(Expression::File(FileState::Stdin)) | None => {
+            if foreground {
+                Ok(Stdio::inherit())
+            } else {
+                Ok(Stdio::null())
+            }
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can not read from a non-readable file.",
+        )),
+    }
+}
+
 fn get_output(
     environment: &Environment,
     out_status: &Option<IOState>,
@@ -329,6 +1140,46 @@ fn get_output(
     Ok((out_res, err_res))
 }
 
+// Start argv in the background with the shell's stdin, stdout and stderr
+// (redirects like out> and err> or a pipe), the words are used as is (no
+// globbing or $ expansion).
+pub fn spawn_argv(environment: &mut Environment, argv: &[String]) -> io::Result<ProcessHandle> {
+    let stdin = match environment.state.stdin_status {
+        Some(IOState::Inherit) => Stdio::inherit(),
+        // Nothing would write to a piped stdin.
+        Some(IOState::Pipe) | Some(IOState::Null) => Stdio::null(),
+        None => get_std_in(environment, false)?,
+    };
+    let (stdout, stderr) = get_output(
+        environment,
+        &environment.state.stdout_status,
+        &environment.state.stderr_status,
+    )?;
+    let mut args: Vec<Expression> = argv[1..]
+        .iter()
+        .map(|arg| Expression::Atom(Atom::String(arg.as_str().into())))
+        .collect();
+    let old_background = environment.run_background;
+    environment.run_background = true;
+    let res = run_command(
+        environment,
+        &argv[0],
+        &mut args,
+        stdin,
+        stdout,
+        stderr,
+        None,
+    );
+    environment.run_background = old_background;
+    match res? {
+        Expression::Process(proc) => Ok(proc),
+        _ => {
+            let msg = format!("{} did not start", argv[0]);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
 pub fn prep_string_arg(s: &str, nargs: &mut Vec<Expression>) -> io::Result<()> {
     let s = match expand_tilde(&s) {
         Some(p) => p,
@@ -343,7 +1194,7 @@ pub fn prep_string_arg(s: &str, nargs: &mut Vec<Expression>) -> io::Result<()> {
                         Ok(p) => {
                             i += 1;
                             if let Some(p) = p.to_str() {
-                                nargs.push(Expression::Atom(Atom::String(p.to_string())));
+                                nargs.push(Expression::Atom(Atom::String(p.into())));
                             }
                         }
                         Err(err) => {
@@ -353,15 +1204,15 @@ pub fn prep_string_arg(s: &str, nargs: &mut Vec<Expression>) -> io::Result<()> {
                     }
                 }
                 if i == 0 {
-                    nargs.push(Expression::Atom(Atom::String(s)));
+                    nargs.push(Expression::Atom(Atom::String(s.into())));
                 }
             }
             Err(_err) => {
-                nargs.push(Expression::Atom(Atom::String(s)));
+                nargs.push(Expression::Atom(Atom::String(s.into())));
             }
         }
     } else {
-        nargs.push(Expression::Atom(Atom::String(s)));
+        nargs.push(Expression::Atom(Atom::String(s.into())));
     }
     Ok(())
 }
@@ -380,31 +1231,19 @@ pub fn do_command<'a>(
             data = Some(atom.clone());
             Stdio::piped()
         }
-        Some(Expression::Process(ProcessState::Running(pid))) => {
-            let procs = environment.procs.clone();
-            let mut procs = procs.borrow_mut();
-            if let Some(proc) = procs.get_mut(&pid) {
-                if proc.stdout.is_some() {
-                    let mut out: Option<ChildStdout> = None;
-                    std::mem::swap(&mut proc.stdout, &mut out);
-                    Stdio::from(out.unwrap())
-                } else if foreground {
-                    Stdio::inherit()
-                } else {
-                    Stdio::null()
-                }
-            } else if foreground {
-                Stdio::inherit()
-            } else {
-                Stdio::null()
+        // An earlier stage of a pipe may have been reaped already, it's
+        // output is still there to read.
+        Some(Expression::Process(proc)) => match (proc.take_stdout(), proc.state()) {
+            (Some(out), _) => Stdio::from(out),
+            (None, ProcessState::Over(_pid, _exit_status)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Invalid expression state before command (process is already done).",
+                ))
             }
-        }
-        Some(Expression::Process(ProcessState::Over(_pid, _exit_status))) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Invalid expression state before command (process is already done).",
-            ))
-        }
+            (None, ProcessState::Running(_pid)) if foreground => Stdio::inherit(),
+            (None, ProcessState::Running(_pid)) => Stdio::null(),
+        },
         Some(Expression::Func(_)) => {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -435,6 +1274,24 @@ pub fn do_command<'a>(
                 "Invalid expression state before command (hashmap).",
             ))
         }
+        Some(Expression::Struct(_, _)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Invalid expression state before command (struct).",
+            ))
+        }
+        Some(Expression::PVec(_)) | Some(Expression::PMap(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Invalid expression state before command (persistent).",
+            ))
+        }
+        Some(Expression::Array(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Invalid expression state before command (array).",
+            ))
+        }
         Some(Expression::File(FileState::Stdin)) => Stdio::inherit(),
         Some(Expression::File(FileState::Read(file))) => {
             // If there is ever a Windows version then use raw_handle instead of raw_fd.
@@ -446,13 +1303,12 @@ pub fn do_command<'a>(
                 "Invalid expression state before command (not a readable file).",
             ))
         }
-        None => {
-            if foreground {
-                Stdio::inherit()
-            } else {
-                Stdio::null()
-            }
-        }
+        None => match environment.state.stdin_status {
+            Some(IOState::Pipe) => Stdio::piped(),
+            Some(IOState::Inherit) => Stdio::inherit(),
+            Some(IOState::Null) => Stdio::null(),
+            None => get_std_in(environment, foreground)?,
+        },
     };
     let (stdout, stderr) = get_output(
         environment,
@@ -463,12 +1319,28 @@ pub fn do_command<'a>(
     environment.loose_symbols = true;
     let mut args = Vec::new();
     for a in parts {
+        // Parameters are treated like any other symbol here.
+        let a = &match unresolve(a) {
+            Some(a) => a,
+            None => a.clone(),
+        };
         if let Expression::Atom(Atom::String(_)) = a {
             let new_a = eval(environment, &a)?;
             args.push(new_a);
         } else {
-            let glob_expand = if let Expression::Atom(Atom::Symbol(_)) = a {
-                true
+            // Only glob literal words, the value of a variable or $VAR is
+            // always delivered as a single argument.  Words that name a
+            // callable (like *) are used as the word so they glob too.
+            let glob_expand = if let Expression::Atom(Atom::Symbol(s)) = a {
+                !s.starts_with('$')
+                    && match get_expression(environment, s).as_deref() {
+                        None
+                        | Some(Expression::Func(_))
+                        | Some(Expression::Function(_))
+                        | Some(Expression::Atom(Atom::Lambda(_)))
+                        | Some(Expression::Atom(Atom::Macro(_))) => true,
+                        Some(_) => false,
+                    }
             } else {
                 false
             };
@@ -477,18 +1349,22 @@ pub fn do_command<'a>(
             let new_a = match a {
                 Expression::Atom(Atom::Symbol(s)) => match get_expression(environment, s) {
                     Some(exp) => match &*exp {
-                        Expression::Func(_) => {
-                            eval(environment, &Expression::Atom(Atom::String(s.to_string())))?
-                        }
-                        Expression::Function(_) => {
-                            eval(environment, &Expression::Atom(Atom::String(s.to_string())))?
-                        }
-                        Expression::Atom(Atom::Lambda(_)) => {
-                            eval(environment, &Expression::Atom(Atom::String(s.to_string())))?
-                        }
-                        Expression::Atom(Atom::Macro(_)) => {
-                            eval(environment, &Expression::Atom(Atom::String(s.to_string())))?
-                        }
+                        Expression::Func(_) => eval(
+                            environment,
+                            &Expression::Atom(Atom::String(s.clone().into())),
+                        )?,
+                        Expression::Function(_) => eval(
+                            environment,
+                            &Expression::Atom(Atom::String(s.clone().into())),
+                        )?,
+                        Expression::Atom(Atom::Lambda(_)) => eval(
+                            environment,
+                            &Expression::Atom(Atom::String(s.clone().into())),
+                        )?,
+                        Expression::Atom(Atom::Macro(_)) => eval(
+                            environment,
+                            &Expression::Atom(Atom::String(s.clone().into())),
+                        )?,
                         _ => eval(environment, &a)?,
                     },
                     _ => eval(environment, &a)?,
@@ -1,5 +1,4 @@
 use std::cell::RefCell;
-use std::cmp::Ordering;
 use std::num::{ParseFloatError, ParseIntError};
 use std::rc::Rc;
 
@@ -42,32 +41,33 @@ macro_rules! save_token {
     }};
 }
 
-fn char_to_hex_num(ch: char) -> u8 {
-    if ch > '0' && ch < '9' {
-        ch as u8 - b'0'
-    } else {
-        match ch {
-            'a' => 10,
-            'b' => 11,
-            'c' => 12,
-            'd' => 13,
-            'e' => 14,
-            'f' => 15,
-            _ => 0,
+// The char for a \x or \u{} escape (escape_code is what followed the \), None
+// if it is not a valid escape.
+fn escape_to_char(escape_code: &[char]) -> Option<char> {
+    let digits: String = match escape_code.first() {
+        Some('x') => escape_code[1..].iter().collect(),
+        Some('u') if escape_code.len() > 3 => {
+            escape_code[2..escape_code.len() - 1].iter().collect()
         }
-    }
+        _ => return None,
+    };
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(std::char::from_u32)
 }
 
-fn escape_to_char(escape_code: &[char]) -> char {
-    let mut ch_n: u8 = 0;
-    match escape_code.len().cmp(&1) {
-        Ordering::Greater => {
-            ch_n = (char_to_hex_num(escape_code[0]) * 16) + (char_to_hex_num(escape_code[1]))
-        }
-        Ordering::Equal => ch_n = char_to_hex_num(escape_code[0]),
-        Ordering::Less => {}
+// True when escape_code holds a complete \x or \u{} escape (or one that can
+// not be valid).
+fn escape_code_done(escape_code: &[char]) -> bool {
+    if escape_code[0] == 'x' {
+        escape_code.len() == 3 || !escape_code[escape_code.len() - 1].is_ascii_hexdigit()
+    } else {
+        let last = escape_code[escape_code.len() - 1];
+        (escape_code.len() == 2 && last != '{')
+            || last == '}'
+            || escape_code.len() > 9
+            || (escape_code.len() > 2 && !last.is_ascii_hexdigit())
     }
-    ch_n as char
 }
 
 fn do_in_string(
@@ -78,23 +78,37 @@ fn do_in_string(
     escape_code: &mut Vec<char>,
 ) -> String {
     let mut set_last_char = false;
+    if *in_escape_code {
+        escape_code.push(ch);
+        if escape_code_done(escape_code) {
+            match escape_to_char(escape_code) {
+                Some(c) => token.push(c),
+                None => {
+                    // Not valid so keep it as written.
+                    token.push('\\');
+                    token.extend(escape_code.iter());
+                }
+            }
+            escape_code.clear();
+            *in_escape_code = false;
+        }
+        // The escape is done with, do not let a \ in it start another.
+        *last_ch = ' ';
+        return token;
+    }
     if !(ch == '\\' && *last_ch != '\\') {
         // skip a standalone \ for now
-        if *in_escape_code {
-            escape_code.push(ch);
-            if escape_code.len() == 2 {
-                token.push(escape_to_char(escape_code));
-                escape_code.clear();
-                *in_escape_code = false;
-            }
-        } else if *last_ch == '\\' {
+        if *last_ch == '\\' {
             match ch {
                 'n' => token.push('\n'),
                 'r' => token.push('\r'),
                 't' => token.push('\t'),
+                '0' => token.push('\0'),
+                'e' => token.push('\x1b'),
                 '"' => token.push('"'),
-                'x' => {
+                'x' | 'u' => {
                     *in_escape_code = true;
+                    escape_code.push(ch);
                 }
                 '\\' => {
                     // These \ are consumed so do not use again.
@@ -213,6 +227,88 @@ fn handle_char(
     token
 }
 
+fn starts_with(chars: &[char], prefix: &str) -> bool {
+    prefix
+        .chars()
+        .enumerate()
+        .all(|(i, c)| chars.get(i) == Some(&c))
+}
+
+// The text from start up to close and the index after close (the rest of the
+// text if it is not closed).
+fn delimited(chars: &[char], start: usize, close: &str) -> (String, usize) {
+    let mut end = start;
+    while end < chars.len() {
+        if starts_with(&chars[end..], close) {
+            let text = chars[start..end].iter().collect();
+            return (text, end + close.chars().count());
+        }
+        end += 1;
+    }
+    (chars[start..].iter().collect(), chars.len())
+}
+
+// Process the escapes in a string as the reader does for "" strings, \$ is
+// just $ since it is not expanded anyway.
+fn unescape(text: &str) -> String {
+    let mut token = String::new();
+    let mut last_ch = ' ';
+    let mut in_escape_code = false;
+    let mut escape_code = Vec::new();
+    for ch in text.chars() {
+        if ch == '$' && last_ch == '\\' && !in_escape_code {
+            token.push(ch);
+            last_ch = ch;
+            continue;
+        }
+        token = do_in_string(
+            token,
+            ch,
+            &mut last_ch,
+            &mut in_escape_code,
+            &mut escape_code,
+        );
+    }
+    if in_escape_code {
+        token.push('\\');
+        token.extend(escape_code.iter());
+    }
+    token
+}
+
+// Drop the line break after an opening """, the indent of the closing """ and
+// the indent all the lines share.
+fn dedent(text: &str) -> String {
+    let text = if text.starts_with('\n') {
+        &text[1..]
+    } else {
+        text
+    };
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if lines.len() > 1 && lines[lines.len() - 1].trim().is_empty() {
+        lines.pop();
+        lines.push("");
+    }
+    let indent_len = |l: &str| l.len() - l.trim_start_matches(|c| c == ' ' || c == '\t').len();
+    let indent = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| indent_len(l))
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|l| {
+            if l.trim().is_empty() {
+                ""
+            } else {
+                &l[indent..]
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
 fn tokenize(text: &str, add_parens: bool) -> Vec<Token> {
     let mut tokens: Vec<Token> = Vec::new();
     let mut in_string = false;
@@ -237,7 +333,11 @@ fn tokenize(text: &str, add_parens: bool) -> Vec<Token> {
         // Work with shebanged scripts.
         in_comment = true;
     }
-    for ch in text.chars() {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        i += 1;
         if ch == '\n' {
             line += 1;
             column = 0;
@@ -283,9 +383,44 @@ fn tokenize(text: &str, add_parens: bool) -> Vec<Token> {
             last_ch = ch;
             continue;
         }
+        if ch == '\"'
+            && !in_string
+            && last_ch != '\\'
+            && (last_ch == '#' || starts_with(&chars[i..], "\"\""))
+        {
+            // A raw #"..."# or a """ multi-line string.
+            save_token!(tokens, token, line, column);
+            let (string, end) = if last_ch == '#' {
+                delimited(&chars, i, "\"#")
+            } else {
+                let (raw, end) = delimited(&chars, i + 2, "\"\"\"");
+                (unescape(&dedent(&raw)), end)
+            };
+            for ch in &chars[i..end] {
+                if *ch == '\n' {
+                    line += 1;
+                    column = 0;
+                } else {
+                    column += 1;
+                }
+            }
+            i = end;
+            // The # marks it literal for parse_atom (not expanded by eval).
+            tokens.push(Token {
+                token: format!("#\"{}\"", string),
+                line,
+                column,
+            });
+            last_ch = ' ';
+            continue;
+        }
         if ch == '\"' && last_ch != '\\' {
             if !in_string {
                 save_token!(tokens, token, line, column);
+            } else if in_escape_code {
+                // Unfinished escape, keep it as written.
+                token.push('\\');
+                token.extend(escape_code.iter());
             }
             in_string = !in_string;
             token.push(ch);
@@ -374,23 +509,76 @@ fn parse_atom(token: &str) -> Expression {
     }
     if token.len() > 1 && token.starts_with('\"') && token.ends_with('\"') {
         let string = token[1..token.len() - 1].to_string();
-        return Expression::Atom(Atom::String(string));
+        return Expression::Atom(Atom::String(string.into()));
+    }
+    if token.len() > 2 && token.starts_with("#\"") && token.ends_with('\"') {
+        return Expression::Atom(Atom::String(Str::literal(&token[2..token.len() - 1])));
     }
 
     if token == "t" {
         Expression::Atom(Atom::True)
     } else if token == "nil" {
         Expression::Atom(Atom::Nil)
+    } else if let Some(number) = parse_number(token) {
+        number
     } else {
-        let potential_int: Result<i64, ParseIntError> = token.parse();
-        match potential_int {
-            Ok(v) => Expression::Atom(Atom::Int(v)),
-            Err(_) => {
-                let potential_float: Result<f64, ParseFloatError> = token.parse();
-                match potential_float {
-                    Ok(v) => Expression::Atom(Atom::Float(v)),
-                    Err(_) => Expression::Atom(Atom::Symbol(token.to_string())),
-                }
+        Expression::Atom(Atom::Symbol(token.into()))
+    }
+}
+
+// An int (decimal or with a 0x, 0o or 0b prefix) or float, _ can separate
+// digits (1_000_000).
+fn parse_number(token: &str) -> Option<Expression> {
+    let chars: Vec<char> = token.chars().collect();
+    for (i, ch) in chars.iter().enumerate() {
+        if *ch == '_'
+            && (i == 0
+                || !chars[i - 1].is_ascii_alphanumeric()
+                || !chars
+                    .get(i + 1)
+                    .map_or(false, |c| c.is_ascii_alphanumeric()))
+        {
+            return None;
+        }
+    }
+    let text: String = chars.iter().filter(|c| **c != '_').collect();
+    if text.len() < token.len()
+        && !token
+            .trim_start_matches(|c| c == '-' || c == '+')
+            .starts_with(|c: char| c.is_ascii_digit())
+    {
+        return None;
+    }
+    let (sign, digits) = if text.starts_with('-') || text.starts_with('+') {
+        text.split_at(1)
+    } else {
+        ("", &text[..])
+    };
+    let radix = match digits.get(..2) {
+        Some("0x") | Some("0X") => 16,
+        Some("0o") | Some("0O") => 8,
+        Some("0b") | Some("0B") => 2,
+        _ => 10,
+    };
+    if radix != 10 {
+        let digits = &digits[2..];
+        // from_str_radix would take a second sign.
+        if digits.starts_with('-') || digits.starts_with('+') {
+            return None;
+        }
+        let sign = if sign == "-" { "-" } else { "" };
+        return i64::from_str_radix(&format!("{}{}", sign, digits), radix)
+            .ok()
+            .map(|i| Expression::Atom(Atom::Int(i)));
+    }
+    let potential_int: Result<i64, ParseIntError> = text.parse();
+    match potential_int {
+        Ok(v) => Some(Expression::Atom(Atom::Int(v))),
+        Err(_) => {
+            let potential_float: Result<f64, ParseFloatError> = text.parse();
+            match potential_float {
+                Ok(v) => Some(Expression::Atom(Atom::Float(v))),
+                Err(_) => None,
             }
         }
     }
@@ -457,6 +645,8 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
     let mut qexits: Vec<i32> = Vec::new();
     let mut backtick_level = 0;
     let mut is_char = false;
+    // Locations of the currently open lists, for error reporting.
+    let mut opens: Vec<(usize, usize)> = Vec::new();
     for token_full in tokens {
         let token = &token_full.token;
         match &token[..] {
@@ -464,7 +654,7 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
                 level += 1;
                 qexits.push(level);
                 let mut quoted = Vec::<Expression>::new();
-                quoted.push(Expression::Atom(Atom::Symbol("quote".to_string())));
+                quoted.push(Expression::Atom(Atom::Symbol("quote".into())));
                 stack.push(List {
                     list_type: ListType::List,
                     vec: quoted,
@@ -475,9 +665,9 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
                 qexits.push(level);
                 let mut quoted = Vec::<Expression>::new();
                 if backtick_level > 0 {
-                    quoted.push(Expression::Atom(Atom::Symbol("quote".to_string())));
+                    quoted.push(Expression::Atom(Atom::Symbol("quote".into())));
                 } else {
-                    quoted.push(Expression::Atom(Atom::Symbol("bquote".to_string())));
+                    quoted.push(Expression::Atom(Atom::Symbol("bquote".into())));
                     backtick_level = level;
                 }
                 stack.push(List {
@@ -487,6 +677,7 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
             }
             "#(" => {
                 level += 1;
+                opens.push((token_full.line, token_full.column));
                 stack.push(List {
                     list_type: ListType::Vector,
                     vec: Vec::<Expression>::new(),
@@ -494,12 +685,20 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
             }
             "(" if !is_char => {
                 level += 1;
+                opens.push((token_full.line, token_full.column));
                 stack.push(List {
                     list_type: ListType::List,
                     vec: Vec::<Expression>::new(),
                 });
             }
             ")" if !is_char => {
+                if opens.pop().is_none() {
+                    let reason = format!(
+                        "Unexpected `)`: line {}, col: {}",
+                        token_full.line, token_full.column
+                    );
+                    return Err(ParseError { reason });
+                }
                 level -= 1;
                 close_list(level, &mut stack)?;
                 while let Some(quote_exit_level) = qexits.pop() {
@@ -575,9 +774,11 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
         }
     }
     if level != 0 {
-        return Err(ParseError {
-            reason: "Unclosed list(s)".to_string(),
-        });
+        let reason = match opens.pop() {
+            Some((line, column)) => format!("Unclosed list(s): line {}, col: {}", line, column),
+            None => "Unclosed list(s)".to_string(),
+        };
+        return Err(ParseError { reason });
     }
     if stack.len() > 1 {
         let mut v: Vec<Expression> = Vec::new();
@@ -606,7 +807,260 @@ fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
     }
 }
 
+// The line each top level form starts on (forms do not carry their location).
+pub fn top_level_lines(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    for token in tokenize(text, false) {
+        match &token.token[..] {
+            "(" | "#(" => {
+                if depth == 0 && !quoted {
+                    lines.push(token.line);
+                }
+                quoted = false;
+                depth += 1;
+            }
+            ")" => depth -= 1,
+            "'" | "`" => {
+                if depth == 0 && !quoted {
+                    lines.push(token.line);
+                    quoted = true;
+                }
+            }
+            _ => {
+                if depth == 0 && !quoted {
+                    lines.push(token.line);
+                }
+                quoted = false;
+            }
+        }
+    }
+    lines
+}
+
 pub fn read(text: &str, add_parens: bool) -> Result<Expression, ParseError> {
     let tokens = tokenize(text, add_parens);
     parse(&tokens)
 }
+
+// Raw source tokens (text exactly as written) including comments, used to
+// re-print source (formatting) where the parsed forms would lose comments.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceToken {
+    // "(" or "#(".
+    Open(String),
+    Close,
+    // ', `, , or ,@
+    Prefix(String),
+    Atom(String),
+    // A ; or #| |# comment, trailing is true if code came first on it's line.
+    Comment(String, bool),
+    // One or more empty lines.
+    Blank,
+}
+
+fn is_delimiter(ch: char) -> bool {
+    ch == '(' || ch == ')' || ch == '"' || ch == ';' || ch.is_whitespace()
+}
+
+pub fn source_tokens(text: &str) -> Vec<SourceToken> {
+    source_token_spans(text).0
+}
+
+// Source tokens, the char index each ends at and if the text ended inside a
+// string or block comment.
+fn source_token_spans(text: &str) -> (Vec<SourceToken>, Vec<usize>, bool) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut ends = Vec::new();
+    let mut open = false;
+    let mut i = 0;
+    // Newlines since the last token.
+    let mut newlines = 1;
+    while i < chars.len() {
+        while ends.len() < tokens.len() {
+            ends.push(i);
+        }
+        let ch = chars[i];
+        if ch == '\n' {
+            newlines += 1;
+            if newlines == 2 && !tokens.is_empty() {
+                tokens.push(SourceToken::Blank);
+            }
+            i += 1;
+            continue;
+        }
+        if ch.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let trailing = newlines == 0;
+        newlines = 0;
+        let start = i;
+        let next = chars.get(i + 1).copied().unwrap_or(' ');
+        if ch == ';' || (i == 0 && ch == '#' && next == '!') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            let comment: String = chars[start..i].iter().collect();
+            tokens.push(SourceToken::Comment(
+                comment.trim_end().to_string(),
+                trailing,
+            ));
+        } else if ch == '#' && next == '|' {
+            let mut depth = 0;
+            while i < chars.len() {
+                if chars[i] == '#' && chars.get(i + 1) == Some(&'|') {
+                    depth += 1;
+                    i += 2;
+                } else if chars[i] == '|' && chars.get(i + 1) == Some(&'#') {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+            open = depth > 0;
+            tokens.push(SourceToken::Comment(
+                chars[start..i].iter().collect(),
+                trailing,
+            ));
+        } else if ch == '(' {
+            tokens.push(SourceToken::Open("(".to_string()));
+            i += 1;
+        } else if ch == '#' && next == '(' {
+            tokens.push(SourceToken::Open("#(".to_string()));
+            i += 2;
+        } else if ch == ')' {
+            tokens.push(SourceToken::Close);
+            i += 1;
+        } else if ch == '\'' || ch == '`' {
+            tokens.push(SourceToken::Prefix(ch.to_string()));
+            i += 1;
+        } else if ch == ',' {
+            if next == '@' {
+                tokens.push(SourceToken::Prefix(",@".to_string()));
+                i += 2;
+            } else {
+                tokens.push(SourceToken::Prefix(",".to_string()));
+                i += 1;
+            }
+        } else if ch == '#' && next == '"' {
+            i = delimited(&chars, i + 2, "\"#").1;
+            open = i == chars.len() && (i < start + 4 || !text.ends_with("\"#"));
+            tokens.push(SourceToken::Atom(chars[start..i].iter().collect()));
+        } else if starts_with(&chars[i..], "\"\"\"") {
+            i = delimited(&chars, i + 3, "\"\"\"").1;
+            open = i == chars.len() && (i < start + 6 || !text.ends_with("\"\"\""));
+            tokens.push(SourceToken::Atom(chars[start..i].iter().collect()));
+        } else if ch == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            open = i > chars.len();
+            let end = i.min(chars.len());
+            tokens.push(SourceToken::Atom(chars[start..end].iter().collect()));
+        } else {
+            if ch == '#' && next == '\\' {
+                // A char, the char itself may be a delimiter (#\().
+                i += 3;
+            }
+            while i < chars.len() && !is_delimiter(chars[i]) {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            let end = i.min(chars.len());
+            tokens.push(SourceToken::Atom(chars[start..end].iter().collect()));
+        }
+    }
+    while ends.len() < tokens.len() {
+        ends.push(i.min(chars.len()));
+    }
+    (tokens, ends, open)
+}
+
+// Same as load, multiple top level forms come back in a vector.
+pub fn top_level_forms(ast: &Expression) -> Vec<Expression> {
+    match ast {
+        Expression::Vector(list) => match list.borrow().get(0) {
+            Some(Expression::Vector(_)) | Some(Expression::Pair(_, _)) => list.borrow().clone(),
+            _ => vec![ast.clone()],
+        },
+        _ => vec![ast.clone()],
+    }
+}
+
+// True if text ends inside a list, string or block comment, more lines are
+// needed before it can be read.
+pub fn is_incomplete(text: &str) -> bool {
+    let (tokens, _, open) = source_token_spans(text);
+    let mut depth = 0;
+    for token in &tokens {
+        match token {
+            SourceToken::Open(_) => depth += 1,
+            // An extra ) is an error, reading more will not fix it.
+            SourceToken::Close if depth == 0 => return false,
+            SourceToken::Close => depth -= 1,
+            _ => {}
+        }
+    }
+    open || depth > 0
+}
+
+// Read the first form in text, returns it and the byte offset of the
+// remaining text (None if text has no forms).
+pub fn read_form(text: &str) -> Result<Option<(Expression, usize)>, ParseError> {
+    let (tokens, ends, _) = source_token_spans(text);
+    let mut depth = 0;
+    let mut end = None;
+    for (token, token_end) in tokens.iter().zip(ends) {
+        match token {
+            SourceToken::Open(_) => depth += 1,
+            SourceToken::Close => {
+                if depth == 0 {
+                    return Err(ParseError {
+                        reason: "Unexpected `)`".to_string(),
+                    });
+                }
+                depth -= 1;
+            }
+            SourceToken::Atom(_) => {}
+            _ => continue,
+        }
+        if depth == 0 {
+            end = Some(token_end);
+            break;
+        }
+    }
+    let end = match end {
+        Some(end) => text.char_indices().nth(end).map_or(text.len(), |(i, _)| i),
+        None => {
+            if depth > 0 {
+                return Err(ParseError {
+                    reason: "Unclosed list(s)".to_string(),
+                });
+            }
+            return Ok(None);
+        }
+    };
+    match read(&text[..end], true)? {
+        Expression::Pair(form, _) => {
+            let form = form.borrow().clone();
+            Ok(Some((form, end)))
+        }
+        _ => Err(ParseError {
+            reason: "Empty results".to_string(),
+        }),
+    }
+}
@@ -0,0 +1,208 @@
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::types::*;
+
+// Lexical addressing for lambda parameters.  When a lambda is created the
+// references to it's parameters in the body are replaced with Atom::Local
+// (depth and slot) so a call finds them in the frame of it's scope without
+// hashing or walking the scope chain.  Only forms that eval their arguments
+// in the current scope are resolved (calls, if, progn etc and nested fn
+// bodies), anything else (quote, macros, let, loop and other special forms)
+// is left alone and looks the names up like before.  A Local that does not
+// match the scope it finds (see get_local) is looked up by name as well.
+
+// Special forms that eval their arguments in the current scope.
+const RESOLVED_SPECIALS: &[&str] = &["if", "progn", "and", "or", "when", "unless", "cond"];
+
+// Parameter names of a lambda (without &rest), None if params is not valid
+// (setup_args will report it).
+pub fn param_names(params: &Expression) -> Option<Vec<String>> {
+    let items: Vec<Expression> = match params {
+        Expression::Vector(list) => list.borrow().clone(),
+        Expression::Pair(_, _) => list_items(params)?,
+        Expression::Atom(Atom::Nil) => Vec::new(),
+        _ => return None,
+    };
+    let mut names = Vec::with_capacity(items.len());
+    for item in items {
+        match item {
+            Expression::Atom(Atom::Symbol(s)) => {
+                if &*s != "&rest" {
+                    names.push(s.to_string());
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(names)
+}
+
+// Items of a proper list, None for a dotted one.
+fn list_items(exp: &Expression) -> Option<Vec<Expression>> {
+    let mut items = Vec::new();
+    let mut tail = exp.clone();
+    while let Expression::Pair(car, cdr) = tail {
+        items.push(car.borrow().clone());
+        let next = cdr.borrow().clone();
+        tail = next;
+    }
+    match tail {
+        Expression::Atom(Atom::Nil) => Some(items),
+        _ => None,
+    }
+}
+
+struct Resolver<'a> {
+    environment: &'a Environment,
+    // Parameter names of the lambdas being resolved, innermost last.
+    frames: Vec<Rc<Vec<String>>>,
+}
+
+impl<'a> Resolver<'a> {
+    fn local(&self, name: &str) -> Option<Expression> {
+        for (depth, names) in self.frames.iter().rev().enumerate() {
+            if let Some(slot) = names.iter().position(|n| n == name) {
+                return Some(Expression::Atom(Atom::Local(name.into(), depth, slot)));
+            }
+        }
+        None
+    }
+
+    // The global value of a form's head, None if a parameter shadows it.
+    fn global(&self, head: &str) -> Option<Rc<Expression>> {
+        if self.local(head).is_some() {
+            None
+        } else {
+            get_expression(self.environment, head)
+        }
+    }
+
+    // Are the arguments of a call to head evaluated in the current scope?
+    fn resolve_args(&self, head: &str) -> bool {
+        match self.global(head) {
+            Some(exp) => match &*exp {
+                Expression::Function(c) => !c.is_special_form || RESOLVED_SPECIALS.contains(&head),
+                Expression::Func(_) | Expression::Atom(Atom::Macro(_)) => false,
+                _ => true,
+            },
+            // A parameter, a command or a function defined later.
+            None => true,
+        }
+    }
+
+    fn is_builtin_fn(&self, head: &str) -> bool {
+        match self.global(head) {
+            Some(exp) => matches!(&*exp, Expression::Func(_)),
+            None => false,
+        }
+    }
+
+    // The resolved form or None if nothing in it changed.
+    fn resolve(&mut self, exp: &Expression) -> Option<Expression> {
+        match exp {
+            Expression::Atom(Atom::Symbol(s)) => self.local(s),
+            Expression::Pair(_, _) => {
+                let items = list_items(exp)?;
+                let first = match &items[0] {
+                    Expression::Atom(Atom::Symbol(head)) => {
+                        if &**head == "fn" && self.is_builtin_fn(head) {
+                            return self.resolve_fn(&items);
+                        }
+                        if !self.resolve_args(head) {
+                            return None;
+                        }
+                        // The head is looked up by name, it may be a command.
+                        1
+                    }
+                    Expression::Pair(_, _) => 0,
+                    _ => return None,
+                };
+                let mut changed = false;
+                let mut new_items = items.clone();
+                for (item, new_item) in items.iter().zip(new_items.iter_mut()).skip(first) {
+                    if let Some(resolved) = self.resolve(item) {
+                        changed = true;
+                        *new_item = resolved;
+                    }
+                }
+                if changed {
+                    Some(Expression::cons_from_vec(&mut new_items))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // A nested fn, it's body is one scope further in than the outer params.
+    fn resolve_fn(&mut self, items: &[Expression]) -> Option<Expression> {
+        if items.len() != 3 {
+            return None;
+        }
+        let names = param_names(&items[1])?;
+        self.frames.push(Rc::new(names));
+        let body = self.resolve(&items[2]);
+        self.frames.pop();
+        Some(Expression::cons_from_vec(&mut vec![
+            items[0].clone(),
+            items[1].clone(),
+            body?,
+        ]))
+    }
+}
+
+// The body of a lambda with the references to names (it's parameters)
+// resolved.
+pub fn resolve_body(
+    environment: &Environment,
+    names: &Rc<Vec<String>>,
+    body: &Expression,
+) -> Expression {
+    let mut resolver = Resolver {
+        environment,
+        frames: vec![names.clone()],
+    };
+    match resolver.resolve(body) {
+        Some(body) => body,
+        None => body.clone(),
+    }
+}
+
+// Back to symbols, for forms given to a macro (it expects the source).  None
+// if there is nothing resolved in exp.
+pub fn unresolve(exp: &Expression) -> Option<Expression> {
+    match exp {
+        Expression::Atom(Atom::Local(name, _, _)) => {
+            Some(Expression::Atom(Atom::Symbol(name.clone())))
+        }
+        Expression::Pair(_, _) => {
+            let mut items = list_items(exp)?;
+            let mut changed = false;
+            for item in items.iter_mut() {
+                if let Some(new_item) = unresolve(item) {
+                    changed = true;
+                    *item = new_item;
+                }
+            }
+            if changed {
+                Some(Expression::cons_from_vec(&mut items))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// The forms with any resolved parameters back to symbols.
+pub fn unresolve_all(forms: Vec<Expression>) -> Vec<Expression> {
+    forms
+        .into_iter()
+        .map(|form| match unresolve(&form) {
+            Some(form) => form,
+            None => form,
+        })
+        .collect()
+}
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::CStr;
-use std::fs::create_dir_all;
+use std::fs;
 use std::io::{self, ErrorKind};
 use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
@@ -10,17 +10,27 @@ use std::process::{Command, Stdio};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
 use liner::{keymap, Buffer, ColorClosure, Context, Prompt};
 
 use nix::sys::signal::{self, SigHandler, Signal};
-use nix::unistd::gethostname;
+use nix::sys::termios::{self, SetArg};
+use nix::unistd::{gethostname, Pid};
 
-use crate::builtins::load;
+use crate::builtins::{load, run_exit_hooks};
+use crate::builtins_bus::bus_poll;
+use crate::builtins_follow::output_poll;
+use crate::builtins_local_env::update_local_envs;
+use crate::builtins_options::{get_option, get_option_keyword};
 use crate::completions::*;
 use crate::environment::*;
 use crate::eval::*;
+use crate::history::*;
+use crate::paths::*;
+use crate::process::set_last_status;
 use crate::reader::*;
+use crate::startup::*;
 use crate::types::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,16 +50,36 @@ struct ReplSettings {
     vi_insert_prompt_suffix: Option<String>,
 }
 
-fn load_user_env(environment: &mut Environment, home: &str) {
-    let mut load_path = Vec::new();
-    load_path.push(Expression::Atom(Atom::String(format!(
-        "{}/.config/sl-sh",
-        home
-    ))));
+// How the shell is running, sets *interactive*, *login* and *script* and
+// picks the rc files to load.
+pub struct ShellMode {
+    pub interactive: bool,
+    pub login: bool,
+    pub script: bool,
+}
+
+fn load_user_env(environment: &mut Environment, mode: &ShellMode) {
+    let dirs = load_path();
+    let load_path: Vec<Expression> = dirs
+        .iter()
+        .map(|dir| Expression::Atom(Atom::String(dir.to_string_lossy().to_string().into())))
+        .collect();
     environment.root_scope.borrow_mut().data.insert(
         "*load-path*".to_string(),
         Rc::new(Expression::with_list(load_path)),
     );
+    for (name, val) in &[
+        ("*interactive*", mode.interactive),
+        ("*login*", mode.login),
+        ("*script*", mode.script),
+    ] {
+        let val = if *val { Atom::True } else { Atom::Nil };
+        environment
+            .root_scope
+            .borrow_mut()
+            .data
+            .insert(name.to_string(), Rc::new(Expression::Atom(val)));
+    }
     if let Err(err) = load(environment, "slsh-std.lisp") {
         eprintln!(
             "WARNING: Failed to load standard macros script slsh-std.lisp: {}",
@@ -59,11 +89,8 @@ fn load_user_env(environment: &mut Environment, home: &str) {
     let dname = build_new_namespace(environment, "user");
     match dname {
         Ok(scope) => {
+            // Only so old configs that set it still load, see repl_settings_note.
             let settings = Rc::new(RefCell::new(HashMap::new()));
-            settings.borrow_mut().insert(
-                "keybindings".to_string(),
-                Rc::new(Expression::Atom(Atom::Symbol("emacs".to_string()))),
-            );
             scope.borrow_mut().data.insert(
                 "*repl-settings*".to_string(),
                 Rc::new(Expression::HashMap(settings)),
@@ -75,8 +102,32 @@ fn load_user_env(environment: &mut Environment, home: &str) {
             msg
         ),
     }
-    if let Err(err) = load(environment, "slshrc") {
-        eprintln!("WARNING: Failed to load init script slshrc: {}", err);
+    // Scripts do not load slshrc so give them what it normally imports.
+    for import in &["(core::ns-import 'core)", "(ns-import 'shell)"] {
+        let res = match read(import, false) {
+            Ok(exp) => eval(environment, &exp),
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+        };
+        if let Err(err) = res {
+            eprintln!("WARNING: Failed to import into namespace \"user\": {}", err);
+        }
+    }
+    // slsh_env is optional and loaded in every mode, slshrc only interactively.
+    if dirs.iter().any(|dir| dir.join("slsh_env").is_file()) {
+        if let Err(err) = load(environment, "slsh_env") {
+            eprintln!(
+                "WARNING: Failed to load init script slsh_env: {}",
+                error_report(environment, &err)
+            );
+        }
+    }
+    if mode.interactive {
+        if let Err(err) = load(environment, "slshrc") {
+            eprintln!(
+                "WARNING: Failed to load init script slshrc: {}",
+                error_report(environment, &err)
+            );
+        }
     }
 }
 
@@ -85,7 +136,7 @@ fn get_prompt(environment: &mut Environment) -> Prompt {
         let exp = match *exp {
             Expression::Atom(Atom::Lambda(_)) => {
                 let mut v = Vec::with_capacity(1);
-                v.push(Expression::Atom(Atom::Symbol("__prompt".to_string())));
+                v.push(Expression::Atom(Atom::Symbol("__prompt".into())));
                 Rc::new(Expression::with_list(v))
             }
             _ => exp,
@@ -94,42 +145,51 @@ fn get_prompt(environment: &mut Environment) -> Prompt {
         let res = eval(environment, &exp);
         environment.save_exit_status = true;
         let ptext = res
-            .unwrap_or_else(|e| Expression::Atom(Atom::String(format!("ERROR: {}", e))))
+            .unwrap_or_else(|e| Expression::Atom(Atom::String(format!("ERROR: {}", e).into())))
             .as_string(environment)
             .unwrap_or_else(|_| "ERROR".to_string());
         Prompt::from(ptext)
     } else {
-        // Nothing set, use a default.
-        let hostname = match env::var("HOST") {
-            Ok(val) => val,
-            Err(_) => "UNKNOWN".to_string(),
-        };
-        let pwd = match env::current_dir() {
-            Ok(val) => val,
-            Err(_) => {
-                let mut p = PathBuf::new();
-                p.push("/");
-                p
-            }
-        };
-        let namespace = if let Some(exp) = get_expression(environment, "*ns*") {
-            match &*exp {
-                Expression::Atom(Atom::String(s)) => s.to_string(),
-                _ => "NO_NAME".to_string(),
+        match get_option(environment, "prompt") {
+            Expression::Atom(Atom::String(prompt)) if !prompt.is_empty() => {
+                Prompt::from(prompt.to_string())
             }
-        } else {
-            "NO_NAME".to_string()
-        };
-        let ptext = format!(
-            "\x1b[32m{}:\x1b[34m{}\x1b[37m(sl-sh::{})\x1b[32m>\x1b[39m ",
-            hostname,
-            pwd.display(),
-            namespace,
-        );
-        Prompt::from(ptext)
+            _ => default_prompt(environment),
+        }
     }
 }
 
+// host:dir(sl-sh::namespace)> in color.
+fn default_prompt(environment: &Environment) -> Prompt {
+    let hostname = match env::var("HOST") {
+        Ok(val) => val,
+        Err(_) => "UNKNOWN".to_string(),
+    };
+    let pwd = match env::current_dir() {
+        Ok(val) => val,
+        Err(_) => {
+            let mut p = PathBuf::new();
+            p.push("/");
+            p
+        }
+    };
+    let namespace = if let Some(exp) = get_expression(environment, "*ns*") {
+        match &*exp {
+            Expression::Atom(Atom::String(s)) => s.to_string(),
+            _ => "NO_NAME".to_string(),
+        }
+    } else {
+        "NO_NAME".to_string()
+    };
+    let ptext = format!(
+        "\x1b[32m{}:\x1b[34m{}\x1b[37m(sl-sh::{})\x1b[32m>\x1b[39m ",
+        hostname,
+        pwd.display(),
+        namespace,
+    );
+    Prompt::from(ptext)
+}
+
 fn get_color_closure(environment: Rc<RefCell<Environment>>) -> Option<ColorClosure> {
     let mut has_handle = false;
     let mut exp = Rc::new(Expression::Atom(Atom::Nil));
@@ -142,8 +202,8 @@ fn get_color_closure(environment: Rc<RefCell<Environment>>) -> Option<ColorClosu
             let exp = match *exp {
                 Expression::Atom(Atom::Lambda(_)) => {
                     let mut v = Vec::with_capacity(1);
-                    v.push(Expression::Atom(Atom::Symbol("__line_handler".to_string())));
-                    v.push(Expression::Atom(Atom::String(input.to_string())));
+                    v.push(Expression::Atom(Atom::Symbol("__line_handler".into())));
+                    v.push(Expression::Atom(Atom::String(input.into())));
                     Rc::new(Expression::with_list(v))
                 }
                 _ => return input.to_string(),
@@ -153,7 +213,7 @@ fn get_color_closure(environment: Rc<RefCell<Environment>>) -> Option<ColorClosu
             let res = eval(&mut environment.borrow_mut(), &exp);
             environment.borrow_mut().str_ignore_expand = false;
             environment.borrow_mut().save_exit_status = true;
-            res.unwrap_or_else(|e| Expression::Atom(Atom::String(format!("ERROR: {}", e))))
+            res.unwrap_or_else(|e| Expression::Atom(Atom::String(format!("ERROR: {}", e).into())))
                 .as_string(&environment.borrow())
                 .unwrap_or_else(|_| "ERROR".to_string())
         };
@@ -163,6 +223,17 @@ fn get_color_closure(environment: Rc<RefCell<Environment>>) -> Option<ColorClosu
     }
 }
 
+// Shift the REPL result history (*1 is the most recent result, *3 the oldest).
+fn push_result_history(environment: &mut Environment, exp: &Expression) {
+    let mut root = environment.root_scope.borrow_mut();
+    let nil = Rc::new(Expression::Atom(Atom::Nil));
+    let one = root.data.get("*1").unwrap_or(&nil).clone();
+    let two = root.data.get("*2").unwrap_or(&nil).clone();
+    root.data.insert("*3".to_string(), two);
+    root.data.insert("*2".to_string(), one);
+    root.data.insert("*1".to_string(), Rc::new(exp.clone()));
+}
+
 fn handle_result(
     environment: &mut Environment,
     res: io::Result<Expression>,
@@ -180,173 +251,206 @@ fn handle_result(
                 }
                 environment.root_scope.borrow_mut().data.insert(
                     "*last-command*".to_string(),
-                    Rc::new(Expression::Atom(Atom::String(input.to_string()))),
+                    Rc::new(Expression::Atom(Atom::String(input.into()))),
                 );
             }
+            push_result_history(environment, &exp);
             match exp {
                 Expression::Atom(Atom::Nil) => { /* don't print nil */ }
                 Expression::File(_) => { /* don't print file contents */ }
                 Expression::Process(_) => { /* should have used stdout */ }
-                Expression::Atom(Atom::String(_)) => {
-                    if let Err(err) = exp.write(environment) {
-                        eprintln!("Error writing result: {}", err);
-                    }
-                }
                 _ => {
-                    if let Err(err) = exp.pretty_print(environment) {
+                    if let Err(err) = exp.pretty_print(environment, false) {
                         eprintln!("Error writing result: {}", err);
                     }
                 }
             }
         }
+        // exit unwinding, not an error.
+        Err(ref err) if is_exit(err) => {
+            if save_history && !input.is_empty() {
+                if let Err(err) = con.history.push(input.into()) {
+                    eprintln!("Error saving history: {}", err);
+                }
+            }
+        }
         Err(err) => {
             if save_history && !input.is_empty() {
                 if let Err(err) = con.history.push_throwaway(input.into()) {
                     eprintln!("Error saving temp history: {}", err);
                 }
             }
-            if !environment.stack_on_error {
-                if let Some(exp) = &environment.error_expression {
-                    let exp = exp.clone();
-                    eprintln!("Error evaluting:");
-                    let stderr = io::stderr();
-                    let mut handle = stderr.lock();
-                    if let Err(err) = exp.pretty_printf(environment, &mut handle) {
-                        eprintln!("\nGOT SECONDARY ERROR PRINTING EXPRESSION: {}", err);
-                    }
-                    eprintln!("");
-                }
-                eprintln!("{}", err);
-            } else {
-                eprintln!("{}", err);
-            }
+            environment.root_scope.borrow_mut().data.insert(
+                "*e".to_string(),
+                Rc::new(Expression::Atom(Atom::String(err.to_string().into()))),
+            );
+            eprintln!("{}", error_report(environment, &err));
         }
     }
 }
 
-fn apply_repl_settings(repl_settings: Rc<Expression>) -> ReplSettings {
-    let mut ret = ReplSettings {
-        key_bindings: Keys::Emacs,
-        max_history: 1000,
-        vi_esc_sequence: None,
-        vi_normal_prompt_prefix: None,
-        vi_normal_prompt_suffix: None,
-        vi_insert_prompt_prefix: None,
-        vi_insert_prompt_suffix: None,
+// The line editor settings from the options.
+fn repl_settings(environment: &Environment) -> ReplSettings {
+    let string = |name: &str| match get_option(environment, name) {
+        Expression::Atom(Atom::String(s)) if !s.is_empty() => Some(s.to_string()),
+        _ => None,
     };
-    if let Expression::HashMap(repl_settings) = &*repl_settings {
-        if let Some(keybindings) = repl_settings.borrow().get(":keybindings") {
-            let keybindings = keybindings.clone();
-            if let Expression::Atom(Atom::Symbol(keybindings)) = &*keybindings {
-                match &keybindings[..] {
-                    ":vi" => ret.key_bindings = Keys::Vi,
-                    ":emacs" => ret.key_bindings = Keys::Emacs,
-                    _ => eprintln!("Invalid keybinding setting: {}", keybindings),
-                }
-            }
-        }
-        if let Some(max) = repl_settings.borrow().get(":max-history") {
-            let max = max.clone();
-            if let Expression::Atom(Atom::Int(max)) = &*max {
-                if *max >= 0 {
-                    ret.max_history = *max as usize;
-                } else {
-                    eprintln!("Max history must be positive: {}", max);
-                }
-            } else {
-                eprintln!("Max history must be a positive integer: {}", max);
+    let vi_esc_sequence = string("vi-esc-sequence").and_then(|keys| {
+        let mut chars = keys.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some(ch1), Some(ch2), None) => {
+                let timeout = match get_option(environment, "vi-esc-timeout") {
+                    Expression::Atom(Atom::Int(ms)) if ms > 0 => ms as u32,
+                    _ => 0,
+                };
+                Some((ch1, ch2, timeout))
             }
-        }
-        if let Some(vi_esc) = repl_settings.borrow().get(":vi_esc_sequence") {
-            let vi_esc = vi_esc.clone();
-            let vl_i;
-            let mut i = match &*vi_esc {
-                Expression::Vector(vl) => {
-                    vl_i = vl.borrow();
-                    Box::new(vl_i.iter())
-                }
-                _ => vi_esc.iter(),
-            };
-            if let Some(Expression::Atom(Atom::String(keys))) = i.next() {
-                if let Some(Expression::Atom(Atom::Int(ms))) = i.next() {
-                    if keys.len() == 2 {
-                        let mut chars = keys.chars();
-                        ret.vi_esc_sequence =
-                            Some((chars.next().unwrap(), chars.next().unwrap(), *ms as u32));
-                    } else {
-                        eprintln!(":vi_esc_sequence first value should be a string of two characters (two key sequence for escape)");
-                    }
-                } else {
-                    eprintln!(":vi_esc_sequence second value should be number (ms delay)");
-                }
-            } else {
+            _ => {
                 eprintln!(
-                    ":vi_esc_sequence first value should be a string (two key sequence for escape)"
+                    "vi-esc-sequence should be two characters (the keys for escape), not {}",
+                    keys
                 );
+                None
             }
         }
-        if let Some(prefix) = repl_settings.borrow().get(":vi-normal-prompt-prefix") {
-            let prefix = prefix.clone();
-            if let Expression::Atom(Atom::String(prefix)) = &*prefix {
-                ret.vi_normal_prompt_prefix = Some(prefix.to_string());
-            }
-        }
-        if let Some(suffix) = repl_settings.borrow().get(":vi-normal-prompt-suffix") {
-            let suffix = suffix.clone();
-            if let Expression::Atom(Atom::String(suffix)) = &*suffix {
-                ret.vi_normal_prompt_suffix = Some(suffix.to_string());
+    });
+    ReplSettings {
+        key_bindings: if get_option_keyword(environment, "keybindings") == ":vi" {
+            Keys::Vi
+        } else {
+            Keys::Emacs
+        },
+        max_history: max_history(environment),
+        vi_esc_sequence,
+        vi_normal_prompt_prefix: string("vi-normal-prompt-prefix"),
+        vi_normal_prompt_suffix: string("vi-normal-prompt-suffix"),
+        vi_insert_prompt_prefix: string("vi-insert-prompt-prefix"),
+        vi_insert_prompt_suffix: string("vi-insert-prompt-suffix"),
+    }
+}
+
+// The line editor and pretty print settings that were in *repl-settings* are
+// options now, point out the ones an old config still sets.
+fn repl_settings_note(environment: &Environment) {
+    if let Some(settings) = get_expression(environment, "*repl-settings*") {
+        if let Expression::HashMap(map) = &*settings {
+            for (key, option) in &[
+                (":keybindings", "keybindings"),
+                (":max-history", "max-history"),
+                (":pp-color", "print-color"),
+                (":pp-indent", "print-indent"),
+                (":pp-max-depth", "print-level"),
+                (":pp-max-length", "print-length"),
+                (":pp-max-lines", "print-lines"),
+                (":pp-width", "print-width"),
+                (":vi_esc_sequence", "vi-esc-sequence"),
+                (":vi-insert-prompt-prefix", "vi-insert-prompt-prefix"),
+                (":vi-insert-prompt-suffix", "vi-insert-prompt-suffix"),
+                (":vi-normal-prompt-prefix", "vi-normal-prompt-prefix"),
+                (":vi-normal-prompt-suffix", "vi-normal-prompt-suffix"),
+            ] {
+                if map.borrow().contains_key(*key) {
+                    eprintln!(
+                        "NOTE: *repl-settings* {} is ignored, use (set-option! '{} ...) instead.",
+                        key, option
+                    );
+                }
             }
         }
-        if let Some(prefix) = repl_settings.borrow().get(":vi-insert-prompt-prefix") {
-            let prefix = prefix.clone();
-            if let Expression::Atom(Atom::String(prefix)) = &*prefix {
-                ret.vi_insert_prompt_prefix = Some(prefix.to_string());
+    }
+}
+
+// The loose-symbols option for forms from the user.
+fn loose_symbols(environment: &Environment) -> bool {
+    !matches!(
+        get_option(environment, "loose-symbols"),
+        Expression::Atom(Atom::Nil)
+    )
+}
+
+// The default for __read-hook, wrap the line in parens unless it is already
+// a form (starts with ( ' ` or #), !cmd is a line for /bin/sh.
+pub fn default_read_hook(input: &str) -> String {
+    if let Some(line) = input.strip_prefix('!') {
+        format!("(sh (quote {}))", escape_str(line.trim_start()))
+    } else if input.starts_with('(')
+        || input.starts_with('\'')
+        || input.starts_with('`')
+        || input.starts_with('#')
+    {
+        input.to_string()
+    } else {
+        format!("({})", input)
+    }
+}
+
+// Read a line of input, __read-hook (if defined) gets the raw line and
+// returns the string to parse.
+fn read_line_form(environment: &mut Environment, input: &str) -> Result<Expression, ParseError> {
+    let text = match get_expression(&environment, "__read-hook") {
+        Some(hook) => match &*hook {
+            Expression::Atom(Atom::Lambda(_)) => {
+                let exp = Expression::with_list(vec![
+                    Expression::Atom(Atom::Symbol("__read-hook".into())),
+                    Expression::Atom(Atom::String(input.into())),
+                ]);
+                match eval(environment, &exp) {
+                    Ok(Expression::Atom(Atom::String(s))) => s.to_string(),
+                    Ok(Expression::Atom(Atom::StringBuf(s))) => s.borrow().clone(),
+                    Ok(Expression::Atom(Atom::Nil)) => default_read_hook(input),
+                    Ok(res) => {
+                        eprintln!(
+                            "WARNING: __read-hook returned {} not a string, ignoring.",
+                            res.display_type()
+                        );
+                        default_read_hook(input)
+                    }
+                    Err(err) => {
+                        eprintln!("ERROR calling __read-hook: {}", err);
+                        default_read_hook(input)
+                    }
+                }
             }
-        }
-        if let Some(suffix) = repl_settings.borrow().get(":vi-insert-prompt-suffix") {
-            let suffix = suffix.clone();
-            if let Expression::Atom(Atom::String(suffix)) = &*suffix {
-                ret.vi_insert_prompt_suffix = Some(suffix.to_string());
+            _ => {
+                eprintln!("WARNING: __read-hook not a lambda, ignoring.");
+                default_read_hook(input)
             }
-        }
-    }
-    ret
+        },
+        None => default_read_hook(input),
+    };
+    read(&text, false)
 }
 
 fn exec_hook(environment: &mut Environment, input: &str) -> Result<Expression, ParseError> {
-    fn read_add_parens(input: &str) -> Result<Expression, ParseError> {
-        let add_parens = !(input.starts_with('(')
-            || input.starts_with('\'')
-            || input.starts_with('`')
-            || input.starts_with('#'));
-        read(input, add_parens)
-    }
     if let Some(exec_exp) = get_expression(&environment, "__exec_hook") {
         let exp = match *exec_exp {
             Expression::Atom(Atom::Lambda(_)) => {
                 let mut v = Vec::with_capacity(2);
-                v.push(Expression::Atom(Atom::Symbol("__exec_hook".to_string())));
-                v.push(Expression::Atom(Atom::String(input.to_string())));
+                v.push(Expression::Atom(Atom::Symbol("__exec_hook".into())));
+                v.push(Expression::Atom(Atom::String(input.into())));
                 Rc::new(Expression::with_list(v))
             }
             _ => {
                 eprintln!("WARNING: __exec_hook not a lambda, ignoring.");
-                return read_add_parens(input);
+                return read_line_form(environment, input);
             }
         };
         match eval(environment, &exp) {
             Ok(res) => match res {
-                Expression::Atom(Atom::String(s)) => read_add_parens(&s),
-                Expression::Atom(Atom::StringBuf(s)) => read_add_parens(&s.borrow()),
+                Expression::Atom(Atom::String(s)) => read_line_form(environment, &s),
+                Expression::Atom(Atom::StringBuf(s)) => {
+                    let s = s.borrow().clone();
+                    read_line_form(environment, &s)
+                }
                 _ => Ok(res),
             },
             Err(err) => {
                 eprintln!("ERROR calling __exec_hook: {}", err);
-                read_add_parens(input)
+                read_line_form(environment, input)
             }
         }
     } else {
-        read_add_parens(input)
+        read_line_form(environment, input)
     }
 }
 
@@ -387,7 +491,198 @@ fn get_liner_words(buf: &Buffer) -> Vec<(usize, usize)> {
     res
 }
 
-pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
+// Read and eval the argument to a meta command.
+fn meta_eval(environment: &mut Environment, arg: &str) -> io::Result<Expression> {
+    let is_list = arg.starts_with('(') || arg.starts_with('\'') || arg.starts_with('`');
+    // A single token is evaluated as is, otherwise treat it like a command line.
+    let ast = if !is_list && !arg.contains(char::is_whitespace) {
+        read(&format!("(progn {})", arg), false)
+    } else {
+        read(arg, !is_list)
+    };
+    match ast {
+        Ok(ast) => {
+            clear_error(environment);
+            environment.loose_symbols = loose_symbols(environment);
+            let res = eval(environment, &ast);
+            environment.loose_symbols = false;
+            res
+        }
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+    }
+}
+
+// Handle a REPL meta command (a line starting with :quit, :env, :ns,
+// :reload, :type or :time), returns false if input is not a meta command.
+fn meta_command(environment: &mut Environment, con: &mut Context, input: &str) -> bool {
+    let mut parts = input.splitn(2, char::is_whitespace);
+    let command = parts.next().unwrap_or("");
+    let arg = parts.next().unwrap_or("").trim();
+    match command {
+        ":quit" => environment.exit_code = Some(0),
+        ":env" => {
+            let mut vars: Vec<(String, String)> = env::vars().collect();
+            vars.sort();
+            for (key, val) in vars {
+                println!("{}={}", key, val);
+            }
+        }
+        ":ns" => {
+            if let Some(ns) = get_expression(environment, "*ns*") {
+                if let Ok(ns) = ns.as_string(environment) {
+                    println!("current: {}", ns);
+                }
+            }
+            let mut namespaces: Vec<&String> = environment.namespaces.keys().collect();
+            namespaces.sort();
+            let namespaces: Vec<&str> = namespaces.iter().map(|ns| &ns[..]).collect();
+            println!("namespaces: {}", namespaces.join(" "));
+        }
+        ":reload" => {
+            let file = if arg.is_empty() { "slshrc" } else { arg };
+            if let Err(err) = load(environment, file) {
+                eprintln!("Error loading {}: {}", file, err);
+            }
+        }
+        ":type" => match meta_eval(environment, arg) {
+            Ok(exp) => println!("{}", exp.display_type()),
+            Err(err) => eprintln!("{}", error_report(environment, &err)),
+        },
+        ":time" => {
+            let start = Instant::now();
+            let res = meta_eval(environment, arg);
+            let elapsed = start.elapsed();
+            let printed = match &res {
+                Ok(Expression::Atom(Atom::Nil)) | Err(_) => false,
+                Ok(_) => true,
+            };
+            handle_result(environment, res, con, arg, false);
+            if printed {
+                println!();
+            }
+            println!(
+                "Elapsed time: {}.{:03}s",
+                elapsed.as_secs(),
+                elapsed.subsec_millis()
+            );
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Signal the remaining jobs as the exit-jobs option says (:hup, :term or
+// :disown), by default (:on-hangup) they are only hung up when the terminal was.
+fn exit_jobs(environment: &Environment, hangup: bool) {
+    let sig = match &get_option_keyword(environment, "exit-jobs")[..] {
+        ":hup" => Signal::SIGHUP,
+        ":term" => Signal::SIGTERM,
+        ":on-hangup" if hangup => Signal::SIGHUP,
+        _ => return,
+    };
+    for job in environment.jobs.borrow().iter() {
+        for pid in &job.pids {
+            let pid = Pid::from_raw(*pid as i32);
+            if let Err(err) = signal::kill(pid, sig) {
+                eprintln!("Error signaling job process {}: {}", pid, err);
+            }
+            // A stopped job has to continue to see the signal.
+            let _ = signal::kill(pid, Signal::SIGCONT);
+        }
+    }
+}
+
+// Returns false and warns if the shell should not exit yet because there are
+// jobs, exiting again (warned) or with :force does exit.  The exit-with-jobs
+// option is :warn (the default) or :ok to always exit.
+fn exit_ok(environment: &mut Environment, warned: &mut bool) -> bool {
+    if *warned || environment.force_exit {
+        return true;
+    }
+    if let Err(err) = reap_procs(environment) {
+        eprintln!("Error reaping processes: {}", err);
+    }
+    if get_option_keyword(environment, "exit-with-jobs") == ":ok" {
+        return true;
+    }
+    let jobs = environment.jobs.borrow();
+    if jobs.is_empty() {
+        return true;
+    }
+    let stopped = jobs
+        .iter()
+        .filter(|j| {
+            if let JobStatus::Stopped = j.status {
+                true
+            } else {
+                false
+            }
+        })
+        .count();
+    eprintln!(
+        "There are jobs ({} running, {} stopped), exit again (or exit :force) to quit.",
+        jobs.len() - stopped,
+        stopped
+    );
+    drop(jobs);
+    environment.exit_code = None;
+    *warned = true;
+    false
+}
+
+// The exit status of an interactive command for the history.
+fn command_status(environment: &Environment, res: &io::Result<Expression>) -> i32 {
+    match res {
+        Ok(_) => match get_expression(environment, "*last-status*").as_deref() {
+            Some(Expression::Atom(Atom::Int(status))) => *status as i32,
+            _ => 0,
+        },
+        Err(err) => exit_status(err).unwrap_or(1),
+    }
+}
+
+// Append a command to the history file (the line editor keeps its own copy).
+fn save_history(
+    history: &mut Option<HistoryFile>,
+    input: &str,
+    started: SystemTime,
+    timer: Instant,
+    status: i32,
+) {
+    if let Some(history) = history {
+        let entry = HistoryEntry::new(input, started, timer.elapsed(), status);
+        if let Err(err) = history.push(entry) {
+            eprintln!("Error saving history: {}", err);
+        }
+    }
+}
+
+// Add the commands other sessions saved to the line editor's history (with the
+// share-history option).
+fn merge_history(environment: &mut Environment, con: &mut Context) {
+    let share = !matches!(
+        get_option(environment, "share-history"),
+        Expression::Atom(Atom::Nil)
+    );
+    if let Some(history) = &mut environment.history {
+        match history.merge() {
+            Ok(entries) => {
+                if !share {
+                    return;
+                }
+                for entry in entries {
+                    if let Err(err) = con.history.push(entry.command.into()) {
+                        eprintln!("Error merging history: {}", err);
+                        break;
+                    }
+                }
+            }
+            Err(err) => eprintln!("Error reading history: {}", err),
+        }
+    }
+}
+
+pub fn start_interactive(sig_int: Arc<AtomicBool>, sig_hup: &AtomicBool, login: bool) -> i32 {
     let mut con = Context::new();
     con.set_word_divider(Box::new(get_liner_words));
     // Initialize the HOST variable
@@ -402,38 +697,49 @@ pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
     if let Ok(dir) = env::current_dir() {
         env::set_var("PWD", dir);
     }
-    let mut home = match env::var("HOME") {
-        Ok(val) => val,
-        Err(_) => ".".to_string(),
-    };
-    if home.ends_with('/') {
-        home = home[..home.len() - 1].to_string();
-    }
-    let share_dir = format!("{}/.local/share/sl-sh", home);
-    if let Err(err) = create_dir_all(&share_dir) {
-        eprintln!(
-            "WARNING: Unable to create share directory: {}- {}",
-            share_dir, err
-        );
-    }
-    if let Err(err) = con
-        .history
-        .set_file_name_and_load_history(format!("{}/history", share_dir))
-    {
-        eprintln!("WARNING: Unable to load history: {}", err);
+    if let Some(note) = config_migration_note() {
+        eprintln!("{}", note);
     }
+    // Restored on exit in case something left the terminal in a bad state.
+    let saved_termios = termios::tcgetattr(0).ok();
     let environment = Rc::new(RefCell::new(build_default_environment(sig_int)));
-    load_user_env(&mut environment.borrow_mut(), &home);
-    let repl_settings = get_expression(&environment.borrow(), "*repl-settings*").unwrap();
-    environment
-        .borrow_mut()
-        .root_scope
-        .borrow_mut()
-        .data
-        .insert(
-            "*last-status*".to_string(),
-            Rc::new(Expression::Atom(Atom::Int(0))),
-        );
+    let mode = ShellMode {
+        interactive: true,
+        login,
+        script: false,
+    };
+    load_user_env(&mut environment.borrow_mut(), &mode);
+    repl_settings_note(&environment.borrow());
+    // Loaded after the config so it's max-history applies.
+    let max = max_history(&environment.borrow());
+    con.history.set_max_history_size(max);
+    let history = match history_file() {
+        Ok(path) => match HistoryFile::load(&path, max) {
+            Ok(history) => {
+                for entry in history.entries() {
+                    if let Err(err) = con.history.push(entry.command.as_str().into()) {
+                        eprintln!("WARNING: Unable to load history: {}", err);
+                        break;
+                    }
+                }
+                Some(history)
+            }
+            Err(err) => {
+                eprintln!("WARNING: Unable to load history: {}", err);
+                None
+            }
+        },
+        Err(err) => {
+            eprintln!(
+                "WARNING: Unable to create state directory: {}- {}",
+                state_dir().display(),
+                err
+            );
+            None
+        }
+    };
+    environment.borrow_mut().history = history;
+    set_last_status(&environment.borrow(), 0, Expression::Atom(Atom::Nil));
     environment
         .borrow_mut()
         .root_scope
@@ -441,21 +747,28 @@ pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
         .data
         .insert(
             "*last-command*".to_string(),
-            Rc::new(Expression::Atom(Atom::String("".to_string()))),
+            Rc::new(Expression::Atom(Atom::String("".into()))),
         );
-    let mut current_repl_settings = ReplSettings {
-        key_bindings: Keys::Emacs,
-        max_history: 1000,
-        vi_esc_sequence: None,
-        vi_normal_prompt_prefix: None,
-        vi_normal_prompt_suffix: None,
-        vi_insert_prompt_prefix: None,
-        vi_insert_prompt_suffix: None,
-    };
+    for result_var in &["*1", "*2", "*3", "*e"] {
+        environment
+            .borrow_mut()
+            .root_scope
+            .borrow_mut()
+            .data
+            .insert(
+                (*result_var).to_string(),
+                Rc::new(Expression::Atom(Atom::Nil)),
+            );
+    }
+    let mut current_repl_settings: Option<ReplSettings> = None;
     con.set_completer(Box::new(ShellCompleter::new(environment.clone())));
+    let mut exit_warned = false;
     loop {
-        let new_repl_settings = apply_repl_settings(repl_settings.clone());
-        if current_repl_settings != new_repl_settings {
+        if sig_hup.load(Ordering::Relaxed) {
+            break;
+        }
+        let new_repl_settings = repl_settings(&environment.borrow());
+        if current_repl_settings.as_ref() != Some(&new_repl_settings) {
             let keymap: Box<dyn keymap::KeyMap> = match new_repl_settings.key_bindings {
                 Keys::Vi => {
                     let mut vi = keymap::Vi::new();
@@ -473,8 +786,11 @@ pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
             con.set_keymap(keymap);
             con.history
                 .set_max_history_size(new_repl_settings.max_history);
+            if let Some(history) = &mut environment.borrow_mut().history {
+                history.set_max(new_repl_settings.max_history);
+            }
         };
-        current_repl_settings = new_repl_settings.clone();
+        current_repl_settings = Some(new_repl_settings);
         environment.borrow_mut().state.stdout_status = None;
         environment.borrow_mut().state.stderr_status = None;
         // Clear the SIGINT if one occured.
@@ -482,10 +798,28 @@ pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
             .borrow()
             .sig_int
             .compare_and_swap(true, false, Ordering::Relaxed);
+        if let Err(err) = bus_poll(&mut environment.borrow_mut()) {
+            if !is_exit(&err) {
+                eprintln!("Error reading the message bus: {}", err);
+            }
+        }
+        if let Err(err) = output_poll(&mut environment.borrow_mut(), None) {
+            if !is_exit(&err) {
+                eprintln!("Error reading process output: {}", err);
+            }
+        }
+        // A handler called exit.
+        if environment.borrow().exit_code.is_some()
+            && exit_ok(&mut environment.borrow_mut(), &mut exit_warned)
+        {
+            break;
+        }
+        update_local_envs(&mut environment.borrow_mut());
         let prompt = get_prompt(&mut environment.borrow_mut());
         if let Err(err) = reap_procs(&environment.borrow()) {
             eprintln!("Error reaping processes: {}", err);
         }
+        merge_history(&mut environment.borrow_mut(), &mut con);
         con.history
             .set_search_context(if let Ok(cur_dir) = env::current_dir() {
                 Some(cur_dir.to_string_lossy().to_string())
@@ -499,27 +833,49 @@ pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
                 if input.is_empty() {
                     continue;
                 }
-                // Clear the last status once something new is entered.
-                env::set_var("LAST_STATUS".to_string(), format!("{}", 0));
-                environment
-                    .borrow_mut()
-                    .root_scope
-                    .borrow_mut()
-                    .data
-                    .insert(
-                        "*last-status*".to_string(),
-                        Rc::new(Expression::Atom(Atom::Int(i64::from(0)))),
+                let started = SystemTime::now();
+                let timer = Instant::now();
+                if input.starts_with(':')
+                    && meta_command(&mut environment.borrow_mut(), &mut con, input)
+                {
+                    if let Err(err) = con.history.push(input.into()) {
+                        eprintln!("Error saving history: {}", err);
+                    }
+                    save_history(
+                        &mut environment.borrow_mut().history,
+                        input,
+                        started,
+                        timer,
+                        0,
                     );
+                    if environment.borrow().exit_code.is_some()
+                        && exit_ok(&mut environment.borrow_mut(), &mut exit_warned)
+                    {
+                        break;
+                    }
+                    continue;
+                }
+                // Clear the last status once something new is entered.
+                set_last_status(&environment.borrow(), 0, Expression::Atom(Atom::Nil));
                 let ast = exec_hook(&mut environment.borrow_mut(), &input);
                 match ast {
                     Ok(ast) => {
                         if let Err(err) = con.history.push(input.into()) {
                             eprintln!("Error saving history: {}", err);
                         }
-                        environment.borrow_mut().loose_symbols = true;
-                        environment.borrow_mut().error_expression = None;
+                        let loose = loose_symbols(&environment.borrow());
+                        environment.borrow_mut().loose_symbols = loose;
+                        clear_error(&mut environment.borrow_mut());
                         let res = eval(&mut environment.borrow_mut(), &ast);
+                        let status = command_status(&environment.borrow(), &res);
                         handle_result(&mut environment.borrow_mut(), res, &mut con, &input, false);
+                        save_history(
+                            &mut environment.borrow_mut().history,
+                            input,
+                            started,
+                            timer,
+                            status,
+                        );
                         environment.borrow_mut().loose_symbols = false;
                     }
                     Err(err) => {
@@ -533,89 +889,116 @@ pub fn start_interactive(sig_int: Arc<AtomicBool>) -> i32 {
                 }
             }
             Err(err) => match err.kind() {
-                ErrorKind::UnexpectedEof => return 0,
+                ErrorKind::UnexpectedEof => {
+                    if exit_ok(&mut environment.borrow_mut(), &mut exit_warned) {
+                        break;
+                    }
+                    continue;
+                }
                 ErrorKind::Interrupted => {}
+                // The terminal hung up (the SIGHUP may not be handled yet).
+                _ if err.raw_os_error() == Some(nix::libc::EIO) => {
+                    sig_hup.store(true, Ordering::Relaxed);
+                    break;
+                }
                 _ => println!("Error on input: {}", err),
             },
         }
-        if environment.borrow().exit_code.is_some() {
+        if environment.borrow().exit_code.is_none() {
+            exit_warned = false;
+        } else if exit_ok(&mut environment.borrow_mut(), &mut exit_warned) {
             break;
         }
     }
-    if environment.borrow().exit_code.is_some() {
-        environment.borrow().exit_code.unwrap()
-    } else {
-        0
+    let mut environment = environment.borrow_mut();
+    run_exit_hooks(&mut environment);
+    if let Some(history) = &mut environment.history {
+        if let Err(err) = history.compact() {
+            eprintln!("Error saving history: {}", err);
+        }
+    }
+    let hangup = sig_hup.load(Ordering::Relaxed);
+    exit_jobs(&environment, hangup);
+    if let (Some(saved_termios), false) = (&saved_termios, hangup) {
+        if let Err(err) = termios::tcsetattr(0, SetArg::TCSANOW, saved_termios) {
+            eprintln!("Error restoring the terminal: {}", err);
+        }
     }
+    environment.exit_code.unwrap_or(0)
 }
 
-pub fn read_stdin() -> i32 {
-    let mut home = match env::var("HOME") {
-        Ok(val) => val,
-        Err(_) => ".".to_string(),
-    };
-    if home.ends_with('/') {
-        home = home[..home.len() - 1].to_string();
-    }
-    let share_dir = format!("{}/.local/share/sl-sh", home);
-    if let Err(err) = create_dir_all(&share_dir) {
-        eprintln!(
-            "WARNING: Unable to create share directory: {}- {}",
-            share_dir, err
-        );
+fn eval_stdin_form(environment: &mut Environment, ast: &Expression) {
+    clear_error(environment);
+    environment.loose_symbols = loose_symbols(environment);
+    match eval(environment, ast) {
+        Ok(exp) => {
+            match exp {
+                Expression::Atom(Atom::Nil) => { /* don't print nil */ }
+                Expression::Process(_) => { /* should have used stdout */ }
+                _ => {
+                    if let Err(err) = exp.write(environment) {
+                        eprintln!("Error writing result: {}", err);
+                    }
+                }
+            }
+        }
+        Err(ref err) if is_exit(err) => {}
+        Err(err) => eprintln!("{}", error_report(environment, &err)),
     }
+    environment.loose_symbols = false;
+}
+
+pub fn read_stdin(login: bool) -> i32 {
     let mut environment = build_default_environment(Arc::new(AtomicBool::new(false)));
     environment.do_job_control = false;
     environment.is_tty = false;
-    load_user_env(&mut environment, &home);
+    let mode = ShellMode {
+        interactive: false,
+        login,
+        script: false,
+    };
+    load_user_env(&mut environment, &mode);
 
     let mut input = String::new();
     loop {
-        match io::stdin().read_line(&mut input) {
-            Ok(0) => return 0,
-            Ok(_n) => {
-                let input = input.trim();
-                environment.state.stdout_status = None;
-                let add_parens =
-                    !(input.starts_with('(') || input.starts_with('\'') || input.starts_with('`'));
-                let ast = read(input, add_parens);
-                match ast {
-                    Ok(ast) => {
-                        environment.loose_symbols = true;
-                        match eval(&mut environment, &ast) {
-                            Ok(exp) => {
-                                match exp {
-                                    Expression::Atom(Atom::Nil) => { /* don't print nil */ }
-                                    Expression::Process(_) => { /* should have used stdout */ }
-                                    _ => {
-                                        if let Err(err) = exp.write(&environment) {
-                                            eprintln!("Error writing result: {}", err);
-                                        }
-                                    }
-                                }
-                            }
-                            Err(err) => eprintln!("{}", err),
-                        }
-                        environment.loose_symbols = false;
-                    }
-                    Err(err) => eprintln!("{:?}", err),
-                }
-                environment.state.stderr_status = None;
-            }
+        let eof = match io::stdin().read_line(&mut input) {
+            Ok(n) => n == 0,
             Err(error) => {
                 eprintln!("ERROR reading stdin: {}", error);
+                run_exit_hooks(&mut environment);
                 return 66;
             }
+        };
+        // Keep reading until the forms are complete (a multi-line defn).
+        if !eof && is_incomplete(&input) {
+            continue;
+        }
+        let text = input.trim().to_string();
+        input.clear();
+        let only_comments = source_tokens(&text)
+            .iter()
+            .all(|t| matches!(t, SourceToken::Comment(_, _) | SourceToken::Blank));
+        if !only_comments {
+            environment.state.stdout_status = None;
+            match read_line_form(&mut environment, &text) {
+                Ok(ast) => {
+                    for form in top_level_forms(&ast) {
+                        eval_stdin_form(&mut environment, &form);
+                        if environment.exit_code.is_some() {
+                            break;
+                        }
+                    }
+                }
+                Err(err) => eprintln!("{:?}", err),
+            }
+            environment.state.stderr_status = None;
         }
-        if environment.exit_code.is_some() {
+        if eof || environment.exit_code.is_some() {
             break;
         }
     }
-    if environment.exit_code.is_some() {
-        environment.exit_code.unwrap()
-    } else {
-        0
-    }
+    run_exit_hooks(&mut environment);
+    environment.exit_code.unwrap_or(0)
 }
 
 fn parse_one_run_command_line(input: &str, nargs: &mut Vec<String>) -> io::Result<()> {
@@ -695,33 +1078,87 @@ pub fn run_one_command(command: &str, args: &[String]) -> io::Result<()> {
     Ok(())
 }
 
-pub fn run_one_script(command: &str, args: &[String]) -> i32 {
+// Load the startup files like the interactive shell would and report the
+// time for each.
+pub fn run_profile_startup() -> i32 {
+    let start = Instant::now();
     let mut environment = build_default_environment(Arc::new(AtomicBool::new(false)));
     environment.do_job_control = false;
+    let env_time = start.elapsed();
+    environment.startup_profile = Some(StartupProfile::default());
 
-    let mut home = match env::var("HOME") {
-        Ok(val) => val,
-        Err(_) => ".".to_string(),
+    let mode = ShellMode {
+        interactive: true,
+        login: false,
+        script: false,
     };
-    if home.ends_with('/') {
-        home = home[..home.len() - 1].to_string();
+    load_user_env(&mut environment, &mode);
+    if let Some(profile) = &environment.startup_profile {
+        print_startup_profile(profile, env_time, start);
     }
-    load_user_env(&mut environment, &home);
+    0
+}
+
+pub fn run_one_script(command: &str, args: &[String], login: bool) -> i32 {
+    let mut environment = build_default_environment(Arc::new(AtomicBool::new(false)));
+    environment.do_job_control = false;
+
+    let mode = ShellMode {
+        interactive: false,
+        login,
+        script: true,
+    };
+    load_user_env(&mut environment, &mode);
 
     let mut exp_args: Vec<Expression> = Vec::with_capacity(args.len());
     for a in args {
-        exp_args.push(Expression::Atom(Atom::String(a.clone())));
+        exp_args.push(Expression::Atom(Atom::String(a.clone().into())));
     }
     environment
         .root_scope
         .borrow_mut()
         .data
         .insert("args".to_string(), Rc::new(Expression::with_list(exp_args)));
-    if let Err(err) = load(&mut environment, command) {
-        eprintln!("Error running {}: {}", command, err);
-        if environment.exit_code.is_none() {
+    environment.root_scope.borrow_mut().data.insert(
+        "*script-name*".to_string(),
+        Rc::new(Expression::Atom(Atom::String(command.into()))),
+    );
+    // Files next to the script can be loaded by name.
+    if let Some(dir) = fs::canonicalize(command)
+        .ok()
+        .and_then(|p| p.parent().map(|p| p.to_string_lossy().to_string()))
+    {
+        if let Some(load_path) = get_expression(&environment, "*load-path*") {
+            if let Expression::Vector(list) = &*load_path {
+                list.borrow_mut()
+                    .insert(0, Expression::Atom(Atom::String(dir.into())));
+            }
+        }
+    }
+    let res = load(&mut environment, command);
+    run_exit_hooks(&mut environment);
+    match res {
+        Err(ref err) if is_exit(err) => {
+            if environment.exit_code.is_none() {
+                return exit_status(err).unwrap_or(0);
+            }
+        }
+        // A parse error, the message has the file and line.
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            eprintln!("{}", err);
             return 1;
         }
+        Err(err) => {
+            eprintln!(
+                "Error running {}: {}",
+                command,
+                error_report(&mut environment, &err)
+            );
+            if environment.exit_code.is_none() {
+                return 1;
+            }
+        }
+        Ok(_) => {}
     }
     if environment.exit_code.is_some() {
         environment.exit_code.unwrap()
@@ -0,0 +1,277 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::config::VERSION_STRING;
+use crate::paths::cache_dir;
+use crate::reader::*;
+use crate::types::*;
+
+// First line of a cache file, then the version that wrote it and a source hash.
+const CACHE_MAGIC: &str = "slsh-ast 1";
+
+// Tags for the encoded expressions.
+const TAG_NIL: u8 = 0;
+const TAG_TRUE: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_SYMBOL: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_CHAR: u8 = 6;
+const TAG_VECTOR: u8 = 7;
+const TAG_LIST: u8 = 8;
+const TAG_PAIR: u8 = 9;
+
+// Time spent on one load, nested loads follow with depth + 1.
+#[derive(Clone, Debug)]
+pub struct LoadProfile {
+    pub name: String,
+    pub depth: usize,
+    pub cached: bool,
+    pub read: Duration,
+    pub total: Duration,
+}
+
+// Load times collected for --profile-startup.
+#[derive(Clone, Debug, Default)]
+pub struct StartupProfile {
+    pub loads: Vec<LoadProfile>,
+    pub depth: usize,
+}
+
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn encode_str(s: &str, out: &mut Vec<u8>) {
+    encode_len(s.len(), out);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Encode what the reader produces, false for anything else.
+fn encode(exp: &Expression, out: &mut Vec<u8>) -> bool {
+    match exp {
+        Expression::Atom(Atom::Nil) => out.push(TAG_NIL),
+        Expression::Atom(Atom::True) => out.push(TAG_TRUE),
+        Expression::Atom(Atom::Float(f)) => {
+            out.push(TAG_FLOAT);
+            out.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        Expression::Atom(Atom::Int(i)) => {
+            out.push(TAG_INT);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Expression::Atom(Atom::Symbol(s)) => {
+            out.push(TAG_SYMBOL);
+            encode_str(s, out);
+        }
+        Expression::Atom(Atom::String(s)) => {
+            out.push(TAG_STRING);
+            encode_str(s, out);
+        }
+        Expression::Atom(Atom::Char(c)) => {
+            out.push(TAG_CHAR);
+            out.extend_from_slice(&(*c as u32).to_le_bytes());
+        }
+        Expression::Vector(list) => {
+            let list = list.borrow();
+            out.push(TAG_VECTOR);
+            encode_len(list.len(), out);
+            for item in list.iter() {
+                if !encode(item, out) {
+                    return false;
+                }
+            }
+        }
+        Expression::Pair(_, _) => {
+            // Proper lists are flattened so long ones do not recurse.
+            let mut items = Vec::new();
+            let mut tail = exp.clone();
+            while let Expression::Pair(car, cdr) = tail {
+                items.push(car.borrow().clone());
+                tail = cdr.borrow().clone();
+            }
+            if let Expression::Atom(Atom::Nil) = tail {
+                out.push(TAG_LIST);
+                encode_len(items.len(), out);
+                for item in &items {
+                    if !encode(item, out) {
+                        return false;
+                    }
+                }
+            } else if let Expression::Pair(car, cdr) = exp {
+                out.push(TAG_PAIR);
+                if !encode(&car.borrow(), out) || !encode(&cdr.borrow(), out) {
+                    return false;
+                }
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.bytes.len() {
+            return None;
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Some(slice)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut buf = [0_u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut buf = [0_u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(buf))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn items(&mut self) -> Option<Vec<Expression>> {
+        let len = self.u32()? as usize;
+        // Every item is at least a byte, do not trust a bad length.
+        let mut items = Vec::with_capacity(len.min(self.bytes.len() - self.pos));
+        for _ in 0..len {
+            items.push(self.expression()?);
+        }
+        Some(items)
+    }
+
+    fn expression(&mut self) -> Option<Expression> {
+        let tag = self.take(1)?[0];
+        Some(match tag {
+            TAG_NIL => Expression::Atom(Atom::Nil),
+            TAG_TRUE => Expression::Atom(Atom::True),
+            TAG_FLOAT => Expression::Atom(Atom::Float(f64::from_bits(self.u64()?))),
+            TAG_INT => Expression::Atom(Atom::Int(self.u64()? as i64)),
+            TAG_SYMBOL => Expression::Atom(Atom::Symbol(self.string()?.into())),
+            TAG_STRING => Expression::Atom(Atom::String(self.string()?.into())),
+            TAG_CHAR => Expression::Atom(Atom::Char(std::char::from_u32(self.u32()?)?)),
+            TAG_VECTOR => Expression::with_list(self.items()?),
+            TAG_LIST => Expression::cons_from_vec(&mut self.items()?),
+            TAG_PAIR => {
+                let car = self.expression()?;
+                let cdr = self.expression()?;
+                Expression::Pair(Rc::new(RefCell::new(car)), Rc::new(RefCell::new(cdr)))
+            }
+            _ => return None,
+        })
+    }
+}
+
+// Header of a cache file, the version and a hash of the source so a changed
+// lisp file is read again even if the version is the same.
+fn cache_header(source: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    format!(
+        "{}\n{}\n{:x}\n",
+        CACHE_MAGIC,
+        VERSION_STRING,
+        hasher.finish()
+    )
+}
+
+// Parsed expression from a cache file if it was written by this version from
+// the same source.
+fn read_cache(path: &PathBuf, header: &str) -> Option<Expression> {
+    let bytes = fs::read(path).ok()?;
+    if !bytes.starts_with(header.as_bytes()) {
+        return None;
+    }
+    let mut decoder = Decoder {
+        bytes: &bytes,
+        pos: header.len(),
+    };
+    let exp = decoder.expression()?;
+    if decoder.pos == bytes.len() {
+        Some(exp)
+    } else {
+        None
+    }
+}
+
+// Write to a temp file and rename so other shells never see part of one.
+fn write_cache(path: &PathBuf, header: &str, exp: &Expression) -> io::Result<()> {
+    let mut out = header.as_bytes().to_vec();
+    if !encode(exp, &mut out) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "can not cache expression",
+        ));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&out)?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+// Read one of the embedded lisp files, from the cache if possible (second
+// value is true if it was).  The cache is replaced when the version or the
+// source changes.
+pub fn read_embedded(name: &str, source: &[u8]) -> (Result<Expression, ParseError>, bool) {
+    let path = cache_dir().join(format!("{}.ast", name));
+    let header = cache_header(source);
+    if let Some(exp) = read_cache(&path, &header) {
+        return (Ok(exp), true);
+    }
+    let res = read(&String::from_utf8_lossy(source), false);
+    if let Ok(exp) = &res {
+        // Just slower next time if this fails.
+        let _ = write_cache(&path, &header, exp);
+    }
+    (res, false)
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+// Print the load times for --profile-startup.
+pub fn print_startup_profile(profile: &StartupProfile, env_time: Duration, start: Instant) {
+    eprintln!(
+        "{:<40} {:>10}",
+        "environment",
+        format!("{:.2}ms", millis(env_time))
+    );
+    for load in &profile.loads {
+        let name = format!("{}{}", "  ".repeat(load.depth), load.name);
+        eprintln!(
+            "{:<40} {:>10}  (read {:.2}ms{})",
+            name,
+            format!("{:.2}ms", millis(load.total)),
+            millis(load.read),
+            if load.cached { ", cached" } else { "" }
+        );
+    }
+    eprintln!(
+        "{:<40} {:>10}",
+        "total",
+        format!("{:.2}ms", millis(start.elapsed()))
+    );
+}
@@ -0,0 +1,213 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::builtins_options::*;
+use crate::environment::*;
+use crate::types::*;
+
+// Most suggestions given for a misspelling.
+const MAX_SUGGESTIONS: usize = 3;
+
+// Edits (insert, delete, substitute or swap two adjacent chars) to get from a to b.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut prev2: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut cur = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[j] = cur[j].min(prev2[j - 2] + 1);
+            }
+        }
+        prev2 = prev;
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+// How far off a word can be and still be a likely typo.
+fn max_distance(word: &str) -> usize {
+    if word.chars().count() <= 4 {
+        1
+    } else {
+        2
+    }
+}
+
+// The closest candidates to word, best first.
+pub fn closest<I: IntoIterator<Item = String>>(word: &str, candidates: I) -> Vec<String> {
+    let max = max_distance(word);
+    let mut found: Vec<(usize, String)> = candidates
+        .into_iter()
+        .filter(|c| c != word)
+        .map(|c| (edit_distance(word, &c), c))
+        .filter(|(d, _)| *d <= max)
+        .collect();
+    found.sort();
+    found.dedup_by(|a, b| a.1 == b.1);
+    found
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+// Names of the functions, macros (including aliases) and builtins in scope.
+pub fn lisp_names(environment: &Environment) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut loop_scope = environment.current_scope.last().cloned();
+    while let Some(scope) = loop_scope {
+        for (key, val) in &scope.borrow().data {
+            match **val {
+                Expression::Func(_)
+                | Expression::Function(_)
+                | Expression::Atom(Atom::Lambda(_))
+                | Expression::Atom(Atom::Macro(_)) => names.push(key.clone()),
+                _ => {}
+            }
+        }
+        loop_scope = scope.borrow().outer.clone();
+    }
+    names
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(md) => md.is_file() && md.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+// Names of the executables in PATH.
+pub fn path_executables() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    if is_executable(&entry.path()) {
+                        if let Some(name) = entry.file_name().to_str() {
+                            names.push(name.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    names
+}
+
+// True if command is a path or names an executable in PATH.
+pub fn command_exists(command: &str) -> bool {
+    if command.contains('/') {
+        return true;
+    }
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            if is_executable(&dir.join(command)) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Close matches to a command that was not found.
+pub fn command_suggestions(environment: &Environment, command: &str) -> Vec<String> {
+    let mut candidates = lisp_names(environment);
+    candidates.append(&mut path_executables());
+    closest(command, candidates)
+}
+
+// Replace the directories in path that do not exist with the closest sibling
+// directory, None if it has no misspelled directories or they have no match.
+pub fn correct_dir(path: &str) -> Option<String> {
+    let mut fixed = PathBuf::new();
+    let mut corrected = false;
+    for component in Path::new(path).components() {
+        if let Component::Normal(name) = component {
+            if !fixed.join(name).is_dir() {
+                let name = name.to_str()?;
+                let parent = if fixed.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    &fixed
+                };
+                let mut dirs = Vec::new();
+                for entry in fs::read_dir(parent).ok()?.flatten() {
+                    if let Some(dir) = entry.file_name().to_str() {
+                        if (!dir.starts_with('.') || name.starts_with('.')) && entry.path().is_dir()
+                        {
+                            dirs.push(dir.to_string());
+                        }
+                    }
+                }
+                let dir = closest(name, dirs).into_iter().next()?;
+                fixed.push(dir);
+                corrected = true;
+                continue;
+            }
+        }
+        fixed.push(component.as_os_str());
+    }
+    if corrected {
+        Some(fixed.to_string_lossy().to_string())
+    } else {
+        None
+    }
+}
+
+// Sentence to add to a not found error, empty if there are no suggestions.
+pub fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions.len() {
+        0 => String::new(),
+        1 => format!(" Did you mean {}?", suggestions[0]),
+        n => format!(
+            " Did you mean {} or {}?",
+            suggestions[..n - 1].join(", "),
+            suggestions[n - 1]
+        ),
+    }
+}
+
+// Ask on the terminal whether to use correction, false if not answered yes.
+pub fn confirm_correction(word: &str, correction: &str) -> bool {
+    eprint!("{}: not found, use {} instead? [y/N] ", word, correction);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => {
+            let answer = answer.trim();
+            answer == "y" || answer == "Y" || answer == "yes"
+        }
+        Err(_) => false,
+    }
+}
+
+// If the auto-correct option is :ask and command is not found then offer the closest
+// match, returns it if accepted.
+pub fn auto_correct(environment: &Environment, command: &str) -> Option<String> {
+    if get_option_keyword(environment, "auto-correct") != ":ask"
+        || !environment.is_tty
+        || environment.in_pipe
+        || environment.run_background
+        || command_exists(command)
+    {
+        return None;
+    }
+    let best = command_suggestions(environment, command)
+        .into_iter()
+        .next()?;
+    if confirm_correction(command, &best) {
+        Some(best)
+    } else {
+        None
+    }
+}
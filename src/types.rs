@@ -1,34 +1,266 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::iter;
 use std::marker;
 use std::num::{ParseFloatError, ParseIntError};
-use std::process::Child;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Child, ChildStdin, ChildStdout};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Instant;
 
+use crate::builtins_follow::FollowFile;
 use crate::builtins_util::is_proper_list;
 use crate::environment::*;
+use crate::persistent::{PMap, PVec};
+use crate::pretty::*;
 use crate::process::*;
 
+// Digits after the point when printing floats, negative for the shortest form
+// that reads back as the same float (the print-float-precision option).
+static FLOAT_PRECISION: AtomicI64 = AtomicI64::new(-1);
+
+pub fn set_float_precision(precision: i64) {
+    FLOAT_PRECISION.store(precision, Ordering::Relaxed);
+}
+
+pub fn float_precision() -> Option<usize> {
+    let precision = FLOAT_PRECISION.load(Ordering::Relaxed);
+    if precision < 0 {
+        None
+    } else {
+        Some(precision as usize)
+    }
+}
+
+// A float with precision digits after the point, or the shortest form that
+// reads back as the same float (always with a . or an exponent so it is not
+// read as an int).
+pub fn float_str(f: f64, precision: Option<usize>) -> String {
+    if let Some(precision) = precision {
+        return format!("{:.*}", precision, f);
+    }
+    if !f.is_finite() {
+        return f.to_string();
+    }
+    let abs = f.abs();
+    let s = if abs != 0.0 && (abs >= 1e16 || abs < 1e-5) {
+        format!("{:e}", f)
+    } else {
+        f.to_string()
+    };
+    if s.contains('.') || s.contains('e') {
+        s
+    } else {
+        s + ".0"
+    }
+}
+
+// A string as a literal the reader will read back as the same string.
+pub fn escape_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            '\x1b' => out.push_str("\\e"),
+            ch if ch.is_control() => out.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// A char as the reader reads it (#\a, #\space).
+pub fn char_literal(c: char) -> String {
+    match c {
+        ' ' => "#\\space".to_string(),
+        '\t' => "#\\tab".to_string(),
+        '\n' => "#\\newline".to_string(),
+        '\r' => "#\\return".to_string(),
+        '\u{0008}' => "#\\backspace".to_string(),
+        c => format!("#\\{}", c),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ParseError {
     pub reason: String,
 }
 
+// Error that unwinds eval to the catch for tag, the value thrown is in the
+// environment (it can not go in an io::Error).
+#[derive(Clone, Debug)]
+pub struct ThrowError {
+    pub tag: String,
+}
+
+impl fmt::Display for ThrowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "throw: no catch for tag {}", self.tag)
+    }
+}
+
+impl Error for ThrowError {}
+
+// The tag if err is from throw.
+pub fn throw_tag(err: &io::Error) -> Option<&str> {
+    err.get_ref()
+        .and_then(|e| e.downcast_ref::<ThrowError>())
+        .map(|e| &e.tag[..])
+}
+
+// Error that unwinds eval for exit, the code is also in the environment so
+// cleanups can put it aside and restore it.
+#[derive(Clone, Debug)]
+pub struct ExitError {
+    pub code: i32,
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "exit {}", self.code)
+    }
+}
+
+impl Error for ExitError {}
+
+pub fn exit_error(code: i32) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, ExitError { code })
+}
+
+// True if err is exit unwinding eval.
+pub fn is_exit(err: &io::Error) -> bool {
+    err.get_ref()
+        .map_or(false, |e| e.downcast_ref::<ExitError>().is_some())
+}
+
+// The code given to exit if err is exit unwinding eval.
+pub fn exit_status(err: &io::Error) -> Option<i32> {
+    err.get_ref()?.downcast_ref::<ExitError>().map(|e| e.code)
+}
+
+// Error raised by err with a type, any data is in the environment (like a
+// thrown value).
+#[derive(Clone, Debug)]
+pub struct TypedError {
+    pub kind: String,
+    pub msg: String,
+}
+
+impl fmt::Display for TypedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for TypedError {}
+
+// The type keyword of an error, from err or the kind of an io error.
+pub fn error_type(err: &io::Error) -> String {
+    if let Some(typed) = err.get_ref().and_then(|e| e.downcast_ref::<TypedError>()) {
+        return typed.kind.clone();
+    }
+    match err.kind() {
+        io::ErrorKind::NotFound => ":not-found",
+        io::ErrorKind::PermissionDenied => ":permission-denied",
+        io::ErrorKind::Interrupted => ":interrupted",
+        io::ErrorKind::TimedOut => ":timeout",
+        io::ErrorKind::AlreadyExists => ":already-exists",
+        io::ErrorKind::BrokenPipe => ":broken-pipe",
+        _ => ":error",
+    }
+    .to_string()
+}
+
 #[derive(Clone, Debug)]
 pub struct Lambda {
-    pub params: Box<Expression>,
-    pub body: Box<Expression>,
+    pub params: Rc<Expression>,
+    pub body: Rc<Expression>,
     pub capture: Rc<RefCell<Scope>>,
+    // Parameter names by slot if the body was resolved (see resolve.rs), the
+    // parameters are then bound in the call scope's frame.
+    pub slots: Option<Rc<Vec<String>>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Macro {
-    pub params: Box<Expression>,
-    pub body: Box<Expression>,
+    pub params: Rc<Expression>,
+    pub body: Rc<Expression>,
+}
+
+// The text of a string atom.  Raw and multi-line strings are literal, eval
+// does not expand $ in them.
+#[derive(Clone)]
+pub struct Str {
+    text: Rc<str>,
+    pub literal: bool,
+}
+
+impl Str {
+    pub fn literal(text: &str) -> Str {
+        Str {
+            text: text.into(),
+            literal: true,
+        }
+    }
+}
+
+impl std::ops::Deref for Str {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl fmt::Debug for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.text)
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl From<Rc<str>> for Str {
+    fn from(text: Rc<str>) -> Str {
+        Str {
+            text,
+            literal: false,
+        }
+    }
+}
+
+impl From<String> for Str {
+    fn from(text: String) -> Str {
+        Rc::<str>::from(text).into()
+    }
+}
+
+impl From<&str> for Str {
+    fn from(text: &str) -> Str {
+        Rc::<str>::from(text).into()
+    }
+}
+
+impl From<Str> for Rc<str> {
+    fn from(s: Str) -> Rc<str> {
+        s.text
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -37,8 +269,11 @@ pub enum Atom {
     True,
     Float(f64),
     Int(i64),
-    Symbol(String),
-    String(String),
+    Symbol(Rc<str>),
+    // A lambda parameter resolved when the lambda was created, the name, the
+    // scopes out from the current one and the slot in that scope's frame.
+    Local(Rc<str>, usize, usize),
+    String(Str),
     StringBuf(Rc<RefCell<String>>),
     Char(char),
     Lambda(Lambda),
@@ -50,12 +285,13 @@ impl fmt::Display for Atom {
         match self {
             Atom::Nil => write!(f, "nil"),
             Atom::True => write!(f, "true"),
-            Atom::Float(n) => write!(f, "{}", n),
+            Atom::Float(n) => write!(f, "{}", float_str(*n, float_precision())),
             Atom::Int(i) => write!(f, "{}", i),
             Atom::Symbol(s) => write!(f, "{}", s),
-            Atom::String(s) => write!(f, "\"{}\"", s),
-            Atom::StringBuf(s) => write!(f, "\"{}\"", s.borrow()),
-            Atom::Char(c) => write!(f, "#\\{}", c),
+            Atom::Local(s, _, _) => write!(f, "{}", s),
+            Atom::String(s) => write!(f, "{}", escape_str(s)),
+            Atom::StringBuf(s) => write!(f, "{}", escape_str(&s.borrow())),
+            Atom::Char(c) => write!(f, "{}", char_literal(*c)),
             Atom::Lambda(l) => write!(f, "(fn {} {})", l.params.to_string(), l.body.to_string()),
             Atom::Macro(m) => write!(f, "(macro {} {})", m.params.to_string(), m.body.to_string()),
         }
@@ -82,7 +318,7 @@ impl Atom {
             Atom::True => "True".to_string(),
             Atom::Float(_) => "Float".to_string(),
             Atom::Int(_) => "Int".to_string(),
-            Atom::Symbol(_) => "Symbol".to_string(),
+            Atom::Symbol(_) | Atom::Local(_, _, _) => "Symbol".to_string(),
             Atom::String(_) => "String".to_string(),
             Atom::StringBuf(_) => "StringBuf".to_string(),
             Atom::Char(_) => "Char".to_string(),
@@ -98,6 +334,142 @@ pub enum ProcessState {
     Over(u32, i32), // pid and exit status
 }
 
+#[derive(Debug)]
+struct ProcessInfo {
+    pid: u32,
+    pgid: u32,
+    started: Instant,
+    child: Child,
+    // Set once the process is waited for (or reaped).
+    exit: Option<ExitInfo>,
+    // Output read while waiting on a foreground process (see run_command).
+    captured: Option<Captured>,
+}
+
+// A process the shell started, owns it's Child (and so it's pipes).  Clones
+// share it, environment.procs holds one for reaping and job control.
+#[derive(Clone, Debug)]
+pub struct ProcessHandle(Rc<RefCell<ProcessInfo>>);
+
+impl ProcessHandle {
+    pub fn new(child: Child, pgid: u32, started: Instant) -> ProcessHandle {
+        ProcessHandle(Rc::new(RefCell::new(ProcessInfo {
+            pid: child.id(),
+            pgid,
+            started,
+            child,
+            exit: None,
+            captured: None,
+        })))
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.0.borrow().pid
+    }
+
+    pub fn pgid(&self) -> u32 {
+        self.0.borrow().pgid
+    }
+
+    pub fn started(&self) -> Instant {
+        self.0.borrow().started
+    }
+
+    pub fn status(&self) -> Option<i32> {
+        self.0.borrow().exit.map(|exit| exit.status())
+    }
+
+    pub fn exit(&self) -> Option<ExitInfo> {
+        self.0.borrow().exit
+    }
+
+    pub fn set_exit(&self, exit: ExitInfo) {
+        self.0.borrow_mut().exit = Some(exit);
+    }
+
+    pub fn state(&self) -> ProcessState {
+        let info = self.0.borrow();
+        match info.exit {
+            Some(exit) => ProcessState::Over(info.pid, exit.status()),
+            None => ProcessState::Running(info.pid),
+        }
+    }
+
+    pub fn set_captured(&self, captured: Captured) {
+        self.0.borrow_mut().captured = Some(captured);
+    }
+
+    // Captured output is only read once (like the pipe it replaces).
+    pub fn take_captured(&self) -> Option<Captured> {
+        self.0.borrow_mut().captured.take()
+    }
+
+    pub fn take_stdout(&self) -> Option<ChildStdout> {
+        self.0.borrow_mut().child.stdout.take()
+    }
+
+    pub fn stdout_fd(&self) -> Option<RawFd> {
+        self.0
+            .borrow()
+            .child
+            .stdout
+            .as_ref()
+            .map(|out| out.as_raw_fd())
+    }
+
+    pub fn has_stdout(&self) -> bool {
+        let info = self.0.borrow();
+        info.captured.is_some() || info.child.stdout.is_some()
+    }
+
+    // Write to the process's stdin, false if it is not piped (or closed).
+    pub fn write_stdin(&self, data: &[u8]) -> io::Result<bool> {
+        match &mut self.0.borrow_mut().child.stdin {
+            Some(stdin) => {
+                stdin.write_all(data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Close stdin so the process sees EOF.
+    pub fn close_stdin(&self) {
+        self.0.borrow_mut().child.stdin.take();
+    }
+
+    pub fn take_stdin(&self) -> Option<ChildStdin> {
+        self.0.borrow_mut().child.stdin.take()
+    }
+
+    // The rest of the output, empty if there is none (or it was read).
+    pub fn read_output(&self) -> io::Result<String> {
+        let mut buffer = String::new();
+        let mut info = self.0.borrow_mut();
+        if let Some(mut captured) = info.captured.take() {
+            captured.read_to_string(&mut buffer)?;
+        } else if let Some(stdout) = &mut info.child.stdout {
+            stdout.read_to_string(&mut buffer)?;
+        }
+        Ok(buffer)
+    }
+
+    pub fn copy_output(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut info = self.0.borrow_mut();
+        if let Some(mut captured) = info.captured.take() {
+            io::copy(&mut captured, writer)?;
+        } else if let Some(stdout) = &mut info.child.stdout {
+            copy_stream(stdout, writer)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Failed to get process out to write to.",
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub enum FileState {
     Stdin,
@@ -105,6 +477,7 @@ pub enum FileState {
     Stderr,
     Read(Rc<RefCell<BufReader<File>>>),
     Write(Rc<RefCell<BufWriter<File>>>),
+    Follow(Rc<RefCell<FollowFile>>),
     Closed,
 }
 
@@ -176,6 +549,50 @@ impl Callable {
     }
 }
 
+// A record type made with defstruct.
+#[derive(Debug)]
+pub struct StructType {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+// Numbers stored unboxed so bulk math does not go through an Atom per item.
+#[derive(Clone, Debug)]
+pub enum NumArray {
+    Float(Vec<f64>),
+    Int(Vec<i64>),
+}
+
+impl NumArray {
+    pub fn len(&self) -> usize {
+        match self {
+            NumArray::Float(v) => v.len(),
+            NumArray::Int(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Expression> {
+        match self {
+            NumArray::Float(v) => v.get(idx).map(|f| Expression::Atom(Atom::Float(*f))),
+            NumArray::Int(v) => v.get(idx).map(|i| Expression::Atom(Atom::Int(*i))),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<Expression> {
+        match self {
+            NumArray::Float(v) => v
+                .iter()
+                .map(|f| Expression::Atom(Atom::Float(*f)))
+                .collect(),
+            NumArray::Int(v) => v.iter().map(|i| Expression::Atom(Atom::Int(*i))).collect(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Expression {
     Atom(Atom),
@@ -186,95 +603,228 @@ pub enum Expression {
     // Func is depricated use Function for new code.
     Func(fn(&mut Environment, &[Expression]) -> io::Result<Expression>),
     Function(Callable),
-    Process(ProcessState),
+    Process(ProcessHandle),
     File(FileState),
+    // Instance of a defstruct type, the values are in field order.
+    Struct(Rc<StructType>, Rc<RefCell<Vec<Expression>>>),
+    // Persistent vector and map (see persistent.rs), updates make new ones.
+    PVec(PVec),
+    PMap(PMap),
+    // Typed numeric array, RefCell to allow destructive forms.
+    Array(Rc<RefCell<NumArray>>),
 }
 
-impl fmt::Display for Expression {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fn list_out(res: &mut String, itr: &mut dyn Iterator<Item = &Expression>) {
+// Identity of a container expression, used to find cycles when printing.
+fn container_id(exp: &Expression) -> Option<usize> {
+    match exp {
+        Expression::Vector(list) => Some(&**list as *const RefCell<Vec<Expression>> as usize),
+        Expression::Struct(_, vals) => Some(&**vals as *const RefCell<Vec<Expression>> as usize),
+        Expression::Pair(e1, _e2) => Some(&**e1 as *const RefCell<Expression> as usize),
+        Expression::HashMap(map) => {
+            Some(&**map as *const RefCell<HashMap<String, Rc<Expression>>> as usize)
+        }
+        _ => None,
+    }
+}
+
+// Find the containers that contain themselves (are on the stack when seen
+// again).  Follows the cdr of pairs with a loop so long lists are fine.
+fn find_cycles(
+    exp: &Expression,
+    stack: &mut HashSet<usize>,
+    done: &mut HashSet<usize>,
+    cyclic: &mut HashSet<usize>,
+) {
+    let mut pushed = Vec::new();
+    let mut current = exp.clone();
+    while let Some(id) = container_id(&current) {
+        if stack.contains(&id) {
+            cyclic.insert(id);
+            break;
+        }
+        if !done.insert(id) {
+            break;
+        }
+        stack.insert(id);
+        pushed.push(id);
+        let next = match &current {
+            Expression::Vector(list) => {
+                for e in list.borrow().iter() {
+                    find_cycles(e, stack, done, cyclic);
+                }
+                None
+            }
+            Expression::HashMap(map) => {
+                for e in map.borrow().values() {
+                    find_cycles(e, stack, done, cyclic);
+                }
+                None
+            }
+            Expression::PVec(pvec) => {
+                for e in pvec.to_vec().iter() {
+                    find_cycles(e, stack, done, cyclic);
+                }
+                None
+            }
+            Expression::PMap(pmap) => {
+                for (_, e) in pmap.items() {
+                    find_cycles(e, stack, done, cyclic);
+                }
+                None
+            }
+            Expression::Pair(e1, e2) => {
+                find_cycles(&e1.borrow(), stack, done, cyclic);
+                Some(e2.borrow().clone())
+            }
+            _ => None,
+        };
+        match next {
+            Some(next) => current = next,
+            None => break,
+        }
+    }
+    for id in pushed {
+        stack.remove(&id);
+    }
+}
+
+pub fn has_cycle(exp: &Expression) -> bool {
+    let mut cyclic = HashSet::new();
+    find_cycles(exp, &mut HashSet::new(), &mut HashSet::new(), &mut cyclic);
+    !cyclic.is_empty()
+}
+
+// Tracks the #n= labels for containers that are part of a cycle.
+struct CycleLabels {
+    cyclic: HashSet<usize>,
+    labels: HashMap<usize, usize>,
+}
+
+impl Expression {
+    // Write a #n= label for the first time a cyclic container is seen, returns
+    // true if it was already labeled (and #n# was written instead).
+    fn write_label(
+        &self,
+        f: &mut fmt::Formatter,
+        cycles: &mut CycleLabels,
+    ) -> Result<bool, fmt::Error> {
+        if let Some(id) = container_id(self) {
+            if cycles.cyclic.contains(&id) {
+                if let Some(label) = cycles.labels.get(&id) {
+                    write!(f, "#{}#", label)?;
+                    return Ok(true);
+                }
+                let label = cycles.labels.len();
+                cycles.labels.insert(id, label);
+                write!(f, "#{}=", label)?;
+            }
+        }
+        Ok(false)
+    }
+
+    fn fmt_cycle(&self, f: &mut fmt::Formatter, cycles: &mut CycleLabels) -> fmt::Result {
+        fn list_out(
+            f: &mut fmt::Formatter,
+            itr: &mut dyn Iterator<Item = &Expression>,
+            cycles: &mut CycleLabels,
+        ) -> fmt::Result {
             let mut first = true;
             let mut last_exp = &Expression::Atom(Atom::Nil);
             for p in itr {
                 if !first {
                     if let Expression::Atom(Atom::Symbol(sym)) = last_exp {
-                        if sym != "," && sym != ",@" {
-                            res.push_str(" ");
+                        if &**sym != "," && &**sym != ",@" {
+                            f.write_str(" ")?;
                         }
                     } else {
-                        res.push_str(" ");
+                        f.write_str(" ")?;
                     }
                 } else {
                     first = false;
                 }
-                res.push_str(&p.to_string());
+                p.fmt_cycle(f, cycles)?;
                 last_exp = p;
             }
+            Ok(())
         }
 
+        if self.write_label(f, cycles)? {
+            return Ok(());
+        }
         match self {
             Expression::Atom(a) => write!(f, "{}", a),
-            Expression::Process(ProcessState::Running(pid)) => write!(f, "#<PID: {} Running>", pid),
-            Expression::Process(ProcessState::Over(pid, exit_status)) => write!(
-                f,
-                "#<PID: {}, EXIT STATUS: {},  Complete>",
-                pid, exit_status
-            ),
+            Expression::Process(proc) => match proc.state() {
+                ProcessState::Running(pid) => write!(f, "#<PID: {} Running>", pid),
+                ProcessState::Over(pid, exit_status) => write!(
+                    f,
+                    "#<PID: {}, EXIT STATUS: {},  Complete>",
+                    pid, exit_status
+                ),
+            },
             Expression::Func(_) => write!(f, "#<Function>"),
             Expression::Function(_) => write!(f, "#<Function>"),
             Expression::Vector(list) => {
-                let mut res = String::new();
-                res.push_str("#(");
-                list_out(&mut res, &mut list.borrow().iter());
-                res.push(')');
-                write!(f, "{}", res)
+                f.write_str("#(")?;
+                list_out(f, &mut list.borrow().iter(), cycles)?;
+                f.write_str(")")
             }
             Expression::Pair(e1, e2) => {
-                if is_proper_list(self) {
+                // A labeled pair in the tail has to be printed dotted to show the label.
+                let proper = is_proper_list(self);
+                let mut labeled_tail = false;
+                if proper && !cycles.cyclic.is_empty() {
+                    let mut tail = e2.borrow().clone();
+                    while let Expression::Pair(t1, t2) = tail {
+                        labeled_tail = labeled_tail
+                            || cycles
+                                .cyclic
+                                .contains(&(&*t1 as *const RefCell<Expression> as usize));
+                        let next = t2.borrow().clone();
+                        tail = next;
+                    }
+                }
+                if proper && !labeled_tail {
                     match &*e1.borrow() {
-                        Expression::Atom(Atom::Symbol(sym)) if sym == "quote" => {
+                        Expression::Atom(Atom::Symbol(sym)) if &**sym == "quote" => {
                             f.write_str("'")?;
                             // This will be a two element list or something is wrong...
                             if let Expression::Pair(a2, _is_nil) = &*e2.borrow() {
-                                f.write_str(&a2.borrow().to_string())
+                                a2.borrow().fmt_cycle(f, cycles)
                             } else {
-                                f.write_str(&e2.borrow().to_string())
+                                e2.borrow().fmt_cycle(f, cycles)
                             }
                         }
-                        Expression::Atom(Atom::Symbol(sym)) if sym == "bquote" => {
+                        Expression::Atom(Atom::Symbol(sym)) if &**sym == "bquote" => {
                             f.write_str("`")?;
                             // This will be a two element list or something is wrong...
                             if let Expression::Pair(a2, _is_nil) = &*e2.borrow() {
-                                f.write_str(&a2.borrow().to_string())
+                                a2.borrow().fmt_cycle(f, cycles)
                             } else {
-                                f.write_str(&e2.borrow().to_string())
+                                e2.borrow().fmt_cycle(f, cycles)
                             }
                         }
                         _ => {
-                            let mut res = String::new();
-                            res.push_str("(");
-                            list_out(&mut res, &mut self.iter());
-                            res.push(')');
-                            write!(f, "{}", res)
+                            f.write_str("(")?;
+                            list_out(f, &mut self.iter(), cycles)?;
+                            f.write_str(")")
                         }
                     }
                 } else {
-                    write!(
-                        f,
-                        "({} . {})",
-                        e1.borrow().to_string(),
-                        e2.borrow().to_string()
-                    )
+                    f.write_str("(")?;
+                    e1.borrow().fmt_cycle(f, cycles)?;
+                    f.write_str(" . ")?;
+                    e2.borrow().fmt_cycle(f, cycles)?;
+                    f.write_str(")")
                 }
             }
             Expression::HashMap(map) => {
-                let mut res = String::new();
-                res.push_str("(make-hash (");
+                f.write_str("(make-hash (")?;
                 for (key, val) in map.borrow().iter() {
-                    res.push_str(&format!("({} . {})", key, val));
+                    write!(f, "({} . ", key)?;
+                    val.fmt_cycle(f, cycles)?;
+                    f.write_str(")")?;
                 }
-                res.push_str("))");
-                write!(f, "{}", res)
+                f.write_str("))")
             }
             Expression::File(FileState::Stdout) => write!(f, "#<STDOUT>"),
             Expression::File(FileState::Stderr) => write!(f, "#<STDERR>"),
@@ -282,10 +832,69 @@ impl fmt::Display for Expression {
             Expression::File(FileState::Closed) => write!(f, "#<CLOSED FILE>"),
             Expression::File(FileState::Read(_file)) => write!(f, "#<READ FILE>"),
             Expression::File(FileState::Write(_file)) => write!(f, "#<WRITE FILE>"),
+            Expression::File(FileState::Follow(_file)) => write!(f, "#<FOLLOW FILE>"),
+            Expression::Struct(stype, vals) => {
+                write!(f, "#S({}", stype.name)?;
+                for (field, val) in stype.fields.iter().zip(vals.borrow().iter()) {
+                    write!(f, " :{} ", field)?;
+                    val.fmt_cycle(f, cycles)?;
+                }
+                f.write_str(")")
+            }
+            Expression::PVec(pvec) => {
+                f.write_str("(pvec")?;
+                for item in pvec.to_vec() {
+                    f.write_str(" ")?;
+                    item.fmt_cycle(f, cycles)?;
+                }
+                f.write_str(")")
+            }
+            Expression::PMap(pmap) => {
+                f.write_str("(phash")?;
+                for (key, val) in pmap.items() {
+                    // Keyword keys print as keywords so the form reads back.
+                    if key.starts_with(':') {
+                        write!(f, " {} ", key)?;
+                    } else {
+                        write!(f, " {} ", Atom::String(key.as_str().into()))?;
+                    }
+                    val.fmt_cycle(f, cycles)?;
+                }
+                f.write_str(")")
+            }
+            Expression::Array(arr) => {
+                let arr = arr.borrow();
+                match &*arr {
+                    NumArray::Float(_) => f.write_str("(farray")?,
+                    NumArray::Int(_) => f.write_str("(iarray")?,
+                }
+                for item in arr.to_vec() {
+                    write!(f, " {}", item)?;
+                }
+                f.write_str(")")
+            }
         }
     }
 }
 
+impl fmt::Display for Expression {
+    // Containers that are part of a cycle are printed with #n= labels and
+    // referenced with #n# so self referencing lists/vectors can be printed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut cycles = CycleLabels {
+            cyclic: HashSet::new(),
+            labels: HashMap::new(),
+        };
+        find_cycles(
+            self,
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+            &mut cycles.cyclic,
+        );
+        self.fmt_cycle(f, &mut cycles)
+    }
+}
+
 impl fmt::Debug for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -297,15 +906,23 @@ impl fmt::Debug for Expression {
             Expression::HashMap(map) => write!(f, "Expression::HashMap({:?})", map.borrow()),
             Expression::Func(_) => write!(f, "Expression::Func(_)"),
             Expression::Function(_) => write!(f, "Expression::Function(_)"),
-            Expression::Process(ProcessState::Running(pid)) => {
-                write!(f, "Expression::Process(ProcessStats::Running({}))", pid)
-            }
-            Expression::Process(ProcessState::Over(pid, exit_status)) => write!(
-                f,
-                "Expression::Process(ProcessState::Over({}, {}))",
-                pid, exit_status
-            ),
+            Expression::Process(proc) => match proc.state() {
+                ProcessState::Running(pid) => {
+                    write!(f, "Expression::Process(ProcessState::Running({}))", pid)
+                }
+                ProcessState::Over(pid, exit_status) => write!(
+                    f,
+                    "Expression::Process(ProcessState::Over({}, {}))",
+                    pid, exit_status
+                ),
+            },
             Expression::File(_) => write!(f, "Expression::File(_)"),
+            Expression::Struct(stype, vals) => {
+                write!(f, "Expression::Struct({} {:?})", stype.name, vals.borrow())
+            }
+            Expression::PVec(pvec) => write!(f, "Expression::PVec({:?})", pvec.to_vec()),
+            Expression::Array(arr) => write!(f, "Expression::Array({:?})", arr.borrow()),
+            Expression::PMap(pmap) => write!(f, "Expression::PMap({:?})", pmap.items()),
         }
     }
 }
@@ -363,163 +980,68 @@ impl Expression {
             Expression::Pair(_, _) => "Pair".to_string(),
             Expression::HashMap(_) => "HashMap".to_string(),
             Expression::File(_) => "File".to_string(),
+            Expression::Struct(stype, _) => stype.name.clone(),
+            Expression::PVec(_) => "PVec".to_string(),
+            Expression::PMap(_) => "PHash".to_string(),
+            Expression::Array(arr) => match &*arr.borrow() {
+                NumArray::Float(_) => "FloatArray".to_string(),
+                NumArray::Int(_) => "IntArray".to_string(),
+            },
         }
     }
 
-    fn pid_to_string(
-        &self,
-        procs: Rc<RefCell<HashMap<u32, Child>>>,
-        pid: u32,
-    ) -> io::Result<String> {
-        match procs.borrow_mut().get_mut(&pid) {
-            Some(child) => {
-                if child.stdout.is_some() {
-                    let mut buffer = String::new();
-                    child.stdout.as_mut().unwrap().read_to_string(&mut buffer)?;
-                    Ok(buffer)
-                } else {
-                    Ok("".to_string())
-                }
-            }
-            None => Ok("".to_string()),
-        }
-    }
-
-    fn pretty_print_int(
-        &self,
-        environment: &mut Environment,
-        indent: usize,
-        writer: &mut dyn Write,
-    ) -> io::Result<()> {
-        fn init_space(indent: usize, writer: &mut dyn Write) -> io::Result<()> {
-            let mut i = 0;
-            if indent > 0 {
-                writer.write_all("\n".as_bytes())?;
-            }
-            while i < indent {
-                writer.write_all(b"    ")?;
-                i += 1;
-            }
-            Ok(())
-        }
-        match self {
-            Expression::Vector(list) => {
-                init_space(indent, writer)?;
-                let a_str = self.to_string();
-                if a_str.len() < 40 || a_str.starts_with('\'') || a_str.starts_with('`') {
-                    writer.write_all(a_str.as_bytes())?;
-                } else {
-                    writer.write_all(b"#(")?;
-                    let mut first = true;
-                    for exp in list.borrow().iter() {
-                        if !first {
-                            writer.write_all(b" ")?;
-                        } else {
-                            first = false;
-                        }
-                        exp.pretty_print_int(environment, indent + 1, writer)?;
-                    }
-                    writer.write_all(b")")?;
-                }
-            }
-            Expression::Pair(e1, e2) => {
-                init_space(indent, writer)?;
-                let a_str = self.to_string();
-                if a_str.len() < 40 || a_str.starts_with('\'') || a_str.starts_with('`') {
-                    writer.write_all(a_str.as_bytes())?;
-                } else if is_proper_list(self) {
-                    writer.write_all(b"(")?;
-                    let mut first = true;
-                    let mut last_p = &Expression::Atom(Atom::Nil);
-                    for p in self.iter() {
-                        if !first {
-                            if let Expression::Atom(Atom::Symbol(sym)) = last_p {
-                                if sym != "," && sym != ",@" {
-                                    writer.write_all(b" ")?;
-                                }
-                            } else {
-                                writer.write_all(b" ")?;
-                            }
-                        } else {
-                            first = false;
-                        }
-                        p.pretty_print_int(environment, indent + 1, writer)?;
-                        last_p = p;
-                    }
-                    writer.write_all(b")")?;
-                } else {
-                    write!(
-                        writer,
-                        "({} . {})",
-                        e1.borrow().to_string(),
-                        e2.borrow().to_string()
-                    )?;
-                }
-            }
-            Expression::HashMap(map) => {
-                init_space(indent, writer)?;
-                let a_str = self.to_string();
-                if a_str.len() < 40 {
-                    writer.write_all(a_str.as_bytes())?;
-                } else {
-                    writer.write_all(b"(make-hash (")?;
-                    for (key, val) in map.borrow().iter() {
-                        init_space(indent + 1, writer)?;
-                        write!(writer, "({} . {})", key, val)?;
-                    }
-                    write!(writer, "))")?;
-                }
-            }
-            Expression::Atom(Atom::String(_s)) => {
-                write!(writer, "{}", self.to_string())?;
-            }
-            Expression::Atom(Atom::StringBuf(_s)) => {
-                write!(writer, "(str-buf {})", self.to_string())?;
-            }
-            Expression::Atom(Atom::Char(_c)) => {
-                write!(writer, "{}", self.to_string())?;
-            }
-            Expression::Atom(Atom::Lambda(l)) => {
-                write!(writer, "(fn {}", l.params.to_string())?;
-                l.body.pretty_print_int(environment, indent + 1, writer)?;
-                writer.write_all(b")")?;
-            }
-            Expression::Atom(Atom::Macro(m)) => {
-                write!(writer, "(macro {}", m.params.to_string())?;
-                m.body.pretty_print_int(environment, indent + 1, writer)?;
-                writer.write_all(b")")?;
-            }
-            _ => self.writef(environment, writer)?,
-        }
-        Ok(())
-    }
-
     pub fn pretty_printf(
         &self,
         environment: &mut Environment,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
-        self.pretty_print_int(environment, 0, writer)
+        let settings = pretty_settings(environment, false);
+        pretty_write(self, environment, &settings, writer)
     }
 
-    pub fn pretty_print(&self, environment: &mut Environment) -> io::Result<()> {
+    // Used for REPL results, this version will truncate (unless all is set) and
+    // colorize if configured.  Strings are written as is.
+    pub fn pretty_print(&self, environment: &mut Environment, all: bool) -> io::Result<()> {
+        let mut settings = pretty_settings(environment, true);
+        settings.color = settings.color && nix::unistd::isatty(1).unwrap_or(false);
+        if all {
+            settings.max_depth = None;
+            settings.max_length = None;
+            settings.max_lines = None;
+        }
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        self.pretty_print_int(environment, 0, &mut handle)
+        match self {
+            Expression::Atom(Atom::String(s)) => settings.write_lines(s, &mut handle)?,
+            _ => pretty_write(self, environment, &settings, &mut handle)?,
+        }
+        if settings.truncated.get() {
+            let note = "... (truncated, (print-all *1) prints all of it)";
+            if settings.color {
+                write!(handle, "\n\x1b[90m{}\x1b[39m", note)?;
+            } else {
+                write!(handle, "\n{}", note)?;
+            }
+        }
+        handle.flush()
     }
 
-    pub fn make_string(&self, environment: &Environment) -> io::Result<String> {
+    pub fn make_string(&self, _environment: &Environment) -> io::Result<String> {
         match self {
             Expression::Atom(a) => Ok(a.to_string()),
-            Expression::Process(ProcessState::Running(_pid)) => Ok(self.to_string()),
-            Expression::Process(ProcessState::Over(pid, _exit_status)) => {
-                self.pid_to_string(environment.procs.clone(), *pid)
-            }
+            Expression::Process(proc) => match proc.state() {
+                ProcessState::Running(_pid) => Ok(self.to_string()),
+                ProcessState::Over(_pid, _exit_status) => proc.read_output(),
+            },
             Expression::Func(_) => Ok(self.to_string()),
             Expression::Function(_) => Ok(self.to_string()),
             Expression::Vector(_list) => Ok(self.to_string()),
             Expression::Pair(_e1, _e2) => Ok(self.to_string()),
             Expression::HashMap(_map) => Ok(self.to_string()),
+            Expression::Struct(_, _) => Ok(self.to_string()),
+            Expression::PVec(_) => Ok(self.to_string()),
+            Expression::PMap(_) => Ok(self.to_string()),
+            Expression::Array(_) => Ok(self.to_string()),
             Expression::File(FileState::Stdin) => {
                 let f = io::stdin();
                 let mut f = f.lock();
@@ -546,133 +1068,107 @@ impl Expression {
         }
     }
 
-    pub fn make_float(&self, environment: &Environment) -> io::Result<f64> {
+    pub fn make_float(&self, _environment: &Environment) -> io::Result<f64> {
         match self {
             Expression::Atom(Atom::Float(f)) => Ok(*f),
             Expression::Atom(Atom::Int(i)) => Ok(*i as f64),
             Expression::Atom(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
-            Expression::Process(ProcessState::Running(_pid)) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Not a number (process still running!)",
-            )),
-            Expression::Process(ProcessState::Over(pid, _exit_status)) => {
-                let buffer = self.pid_to_string(environment.procs.clone(), *pid)?;
-                let potential_float: Result<f64, ParseFloatError> = buffer.parse();
-                match potential_float {
-                    Ok(v) => Ok(v),
-                    Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
+            Expression::Process(proc) => match proc.state() {
+                ProcessState::Running(_pid) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Not a number (process still running!)",
+                )),
+                ProcessState::Over(_pid, _exit_status) => {
+                    let buffer = proc.read_output()?;
+                    let potential_float: Result<f64, ParseFloatError> = buffer.parse();
+                    match potential_float {
+                        Ok(v) => Ok(v),
+                        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
+                    }
                 }
-            }
+            },
             Expression::Func(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
             Expression::Function(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
             Expression::Vector(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
             Expression::Pair(_, _) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
             Expression::HashMap(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
+            Expression::Struct(_, _) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
+            Expression::PVec(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
+            Expression::PMap(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
+            Expression::Array(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
             Expression::File(_) => Err(io::Error::new(io::ErrorKind::Other, "Not a number")),
         }
     }
 
-    pub fn make_int(&self, environment: &Environment) -> io::Result<i64> {
+    pub fn make_int(&self, _environment: &Environment) -> io::Result<i64> {
         match self {
             Expression::Atom(Atom::Int(i)) => Ok(*i),
             Expression::Atom(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
-            Expression::Process(ProcessState::Running(_pid)) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Not an integer (process still running!)",
-            )),
-            Expression::Process(ProcessState::Over(pid, _exit_status)) => {
-                let buffer = self.pid_to_string(environment.procs.clone(), *pid)?;
-                let potential_int: Result<i64, ParseIntError> = buffer.parse();
-                match potential_int {
-                    Ok(v) => Ok(v),
-                    Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
+            Expression::Process(proc) => match proc.state() {
+                ProcessState::Running(_pid) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Not an integer (process still running!)",
+                )),
+                ProcessState::Over(_pid, _exit_status) => {
+                    let buffer = proc.read_output()?;
+                    let potential_int: Result<i64, ParseIntError> = buffer.parse();
+                    match potential_int {
+                        Ok(v) => Ok(v),
+                        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
+                    }
                 }
-            }
+            },
             Expression::Func(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
             Expression::Function(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
             Expression::Vector(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
             Expression::Pair(_, _) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
             Expression::HashMap(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
+            Expression::Struct(_, _) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
+            Expression::PVec(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
+            Expression::PMap(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
+            Expression::Array(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
             Expression::File(_) => Err(io::Error::new(io::ErrorKind::Other, "Not an integer")),
         }
     }
 
+    // Write the expression (the output of a process or file), the caller
+    // flushes when it is done writing.
     pub fn writef(&self, environment: &Environment, writer: &mut dyn Write) -> io::Result<()> {
         match self {
+            Expression::Atom(Atom::String(s)) => writer.write_all(s.as_bytes())?,
             Expression::Atom(a) => write!(writer, "{}", a.as_string())?,
-            Expression::Process(ps) => {
-                let pid = match ps {
-                    ProcessState::Running(pid) => pid,
-                    ProcessState::Over(pid, _exit_status) => pid,
-                };
-                let procs = environment.procs.clone();
-                let mut procs = procs.borrow_mut();
-                match procs.get_mut(&pid) {
-                    Some(child) => {
-                        if child.stdout.is_some() {
-                            let out = child.stdout.as_mut().unwrap();
-                            let mut buf = [0; 1024];
-                            loop {
-                                match out.read(&mut buf) {
-                                    Ok(0) => break,
-                                    Ok(n) => writer.write_all(&buf[..n])?,
-                                    Err(err) => return Err(err),
-                                }
-                            }
-                        } else {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "Failed to get process out to write to.",
-                            ));
-                        }
-                    }
-                    None => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "Failed to get process to write to.",
-                        ));
-                    }
+            Expression::Process(proc) => {
+                proc.copy_output(writer)?;
+                if proc.status().is_none() {
+                    wait_pid(environment, proc.pid(), None);
                 }
-                drop(procs);
-                wait_pid(environment, *pid, None);
             }
             Expression::Func(_) => write!(writer, "{}", self.to_string())?,
             Expression::Function(_) => write!(writer, "{}", self.to_string())?,
             Expression::Vector(_list) => write!(writer, "{}", self.to_string())?,
             Expression::Pair(_e1, _e2) => write!(writer, "{}", self.to_string())?,
             Expression::HashMap(_map) => write!(writer, "{}", self.to_string())?,
+            Expression::Struct(_, _) => write!(writer, "{}", self.to_string())?,
+            Expression::PVec(_) => write!(writer, "{}", self.to_string())?,
+            Expression::PMap(_) => write!(writer, "{}", self.to_string())?,
+            Expression::Array(_) => write!(writer, "{}", self.to_string())?,
             Expression::File(FileState::Stdin) => {
                 let f = io::stdin();
                 let mut f = f.lock();
-                let mut buf = [0; 1024];
-                loop {
-                    match f.read(&mut buf) {
-                        Ok(0) => break,
-                        Ok(n) => writer.write_all(&buf[..n])?,
-                        Err(err) => return Err(err),
-                    }
-                }
+                copy_stream(&mut f, writer)?;
             }
             Expression::File(FileState::Read(file)) => {
-                let mut f = file.borrow_mut();
-                let mut buf = [0; 1024];
-                loop {
-                    match f.read(&mut buf) {
-                        Ok(0) => break,
-                        Ok(n) => writer.write_all(&buf[..n])?,
-                        Err(err) => return Err(err),
-                    }
-                }
+                copy_stream(&mut *file.borrow_mut(), writer)?;
             }
             Expression::File(_) => write!(writer, "{}", self.to_string())?,
         }
-        writer.flush()?;
         Ok(())
     }
 
     pub fn write(&self, environment: &Environment) -> io::Result<()> {
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        self.writef(environment, &mut handle)
+        self.writef(environment, &mut handle)?;
+        handle.flush()
     }
 }
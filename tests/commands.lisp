@@ -0,0 +1,61 @@
+(load "tests/test.lisp")
+
+; Literal words are globbed, even one that names a builtin (* is multiply),
+; the value of a variable is not.
+(def 'glob-dir "/tmp/slsh-test-glob")
+(rm -rf glob-dir)
+(mkdir -p glob-dir)
+(touch (str glob-dir "/a.txt") (str glob-dir "/b.txt"))
+(def 'old-dir $PWD)
+(cd glob-dir)
+(assert-equal "a.txt b.txt" (str-trim (str (echo *))))
+(assert-equal "a.txt\nb.txt" (str-trim (str (ls *))))
+(assert-equal "a.txt b.txt" (str-trim (str (echo *.txt))))
+(def 'star "*")
+(assert-equal "*" (str-trim (str (echo star))))
+(cd old-dir)
+(rm -rf glob-dir)
+
+; A string literal given to sh is expanded by sh, not sl-sh.
+(assert-equal "v=1" (str-trim (str (sh "FOO=1; echo v=$FOO"))))
+(assert-equal "/bin/sh" (str-trim (str (sh "echo $0"))))
+
+; each-arg runs with the shell's redirects and pipes.
+(def 'each-out "/tmp/slsh-test-each-arg")
+(dyn '*stdout* (open each-out :create :truncate) (each-arg '(echo x {}) '(a b c) :batch 2))
+(assert-equal "x a b\nx c" (str-trim (str (cat each-out))))
+(rm -f each-out)
+(assert-equal "aa\nab" (str-trim (str (pipe (each-arg '(echo {}) '(aa bb ab)) (grep a)))))
+(assert-equal "1\n2\n3" (str-trim (str (each-arg '(echo) '(1 2 3) :parallel 3))))
+(assert-false (each-arg '(false) '(1)))
+(assert-equal "a" (str-trim (str (each-arg '(sh -c "kill -STOP \$\$; echo {}") '(a)))))
+
+; run-limited sizes that do not fit are an error, not a wrapped limit.
+(assert-equal "run-limited: invalid value for :mem: \"99999999999T\""
+              (hash-get (get-error (run-limited '(:mem "99999999999T") (true))) :msg))
+(assert-equal "run-limited: invalid value for :nice: 4294967297"
+              (hash-get (get-error (run-limited '(:nice 4294967297) (true))) :msg))
+(assert-equal (+ 1 (str->int (str-trim (str (nice)))))
+              (str->int (str-trim (str (run-limited '(:mem "1G" :nice 1) (nice))))))
+
+; Commands left running at a run-with-timeout deadline get SIGTERM and then
+; SIGKILL if they ignore it.
+(def 'term-file "/tmp/slsh-test-timeout")
+(rm -f term-file)
+(assert-equal :timeout (run-with-timeout 0.2
+    (run-bg (sh (str "trap 'echo term > " term-file "; exit 0' TERM; sleep 5 & wait")))
+    (sleep 1)))
+(assert-equal "term" (str-trim (str (cat term-file))))
+(rm -f term-file)
+(assert-equal :timeout (run-with-timeout 0.2 (run-bg (sh "trap '' TERM; sleep 10")) (sleep 1)))
+
+; run-with-timeout only returns :timeout if the deadline stopped something, a
+; body that finishes (or fails) just after it keeps it's result.
+(assert-equal 5000000 (run-with-timeout 0.001 (length (make-vec 5000000 1))))
+(assert-equal "boom5000000" (hash-get (get-error (run-with-timeout 0.001 (err (str "boom" (length (make-vec 5000000 1)))))) :msg))
+(assert-equal :timeout (run-with-timeout 0.1 (sleep 1)))
+(assert-equal :timeout (run-with-timeout 0.1 ((fn () (recur)))))
+
+; A bus message bigger than a receiver reads whole is an error, not cut short.
+(assert-equal "bus-send: topic and message are 65537 bytes, the limit is 65535"
+              (hash-get (get-error (bus-send "big" (str-cat-list "" (make-vec 65534 "a")))) :msg))
@@ -0,0 +1,10 @@
+(load "tests/test.lisp")
+
+; An error object's backtrace has the innermost calls and how many were left out.
+(defn bt-deep (n) (if (= n 0) (err "bottom") (bt-deep (- n 1))))
+(def 'bt (hash-get (get-error (bt-deep 100)) :backtrace))
+(assert-equal 65 (length bt))
+(assert-equal "... 37 more" (vec-nth 64 bt))
+(assert-true (str-starts-with "bt-deep" (vec-nth 0 bt)))
+(def 'bt (hash-get (get-error (bt-deep 3)) :backtrace))
+(assert-equal 4 (length bt))
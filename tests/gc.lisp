@@ -0,0 +1,34 @@
+(load "tests/test.lisp")
+
+; The cycle collector frees data only referenced by reference cycles and
+; leaves reachable cycles alone.
+(gc)
+(defq gc-freed (hash-get (gc-stats) :freed))
+(let ((v (vec 1 2))) (vec-push! v v) nil)
+(assert-equal 1 (gc))
+(assert-equal (+ gc-freed 1) (hash-get (gc-stats) :freed))
+(assert-equal 0 (gc))
+
+(defq gc-keep (vec 1 2))
+(vec-push! gc-keep gc-keep)
+(assert-equal 0 (gc))
+(assert-equal 3 (length gc-keep))
+(assert-equal 1 (vec-nth 0 gc-keep))
+(assert-equal 3 (length (vec-nth 2 gc-keep)))
+
+(let ((h (make-hash))) (hash-set! h :self h) nil)
+(assert-equal 1 (gc))
+(defq gc-hash (make-hash))
+(hash-set! gc-hash :self gc-hash)
+(assert-equal 0 (gc))
+(assert-true (hash-haskey (hash-get gc-hash :self) :self))
+
+; A lambda kept in the scope it captured.
+(defn gc-make-loop () (let ((f nil)) (setq f (fn () f)) nil))
+(gc-make-loop)
+(assert-true (> (gc) 0))
+(defn gc-make-counter () (let ((n 0) (f nil)) (setq f (fn () (setq n (+ n 1)))) f))
+(defq gc-counter (gc-make-counter))
+(gc)
+(assert-equal 1 (gc-counter))
+(assert-equal 2 (gc-counter))
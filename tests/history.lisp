@@ -0,0 +1,61 @@
+(load "tests/test.lisp")
+
+(def 'hist-file "/tmp/slsh-test-history")
+(rm -f hist-file (str hist-file ".lock"))
+(defn hist-write (text)
+    (let ((file (open hist-file :create :truncate)))
+        (write-string file text)
+        (close file)))
+(defn hist-commands () (map (fn (entry) (hash-get entry :command)) (history)))
+(defn hist-header () (read-line (open hist-file :read)))
+
+; A file in the old format (one command per line) is converted when loaded.
+(hist-write "ls\necho hi\n\ncd /tmp\n")
+(assert-equal 3 (history-load hist-file))
+(assert-equal '#("ls" "echo hi" "cd /tmp") (hist-commands))
+(assert-equal 0 (hash-get (vec-nth 0 (history)) :start))
+(assert-equal "#sl-sh-history 2\n" (hist-header))
+(assert-true (fs-exists? (str hist-file ".lock")))
+
+; Entries keep their status and multi-line commands stay whole.
+(history-add "make" 2)
+(history-add "for x\ndo\tdone")
+(assert-equal 2 (hash-get (vec-nth 3 (history)) :status))
+(assert-true (> (hash-get (vec-nth 3 (history)) :start) 0))
+(assert-equal 5 (history-load hist-file))
+(assert-equal '#("ls" "echo hi" "cd /tmp" "make" "for x\ndo\tdone") (hist-commands))
+
+; What another session appended is merged in before the next entry.
+(let ((file (open hist-file :append)))
+    (write-string file "1\t5\t1\tother\n")
+    (close file))
+(history-add "mine")
+(assert-equal '#("make" "for x\ndo\tdone" "other" "mine")
+              (vec-slice (hist-commands) 3))
+(assert-equal 1 (hash-get (vec-nth 5 (history)) :status))
+
+; Another session compacting the file replaces it, only the entries after the
+; last one read are new.
+(def 'hist-tmp (str hist-file ".new"))
+(cp hist-file hist-tmp)
+(let ((file (open hist-tmp :append)))
+    (write-string file "2\t0\t0\tafter-compact\n")
+    (close file))
+(mv hist-tmp hist-file)
+(history-add "last")
+(assert-equal '#("other" "mine" "after-compact" "last")
+              (vec-slice (hist-commands) 5))
+(assert-equal 9 (history-load hist-file))
+
+; A max-history of 0 (or less) saves nothing and leaves the file alone.
+(hist-write "ls\necho hi\n")
+(set-option! 'max-history 0)
+(assert-equal 0 (history-load hist-file))
+(history-add "not-saved")
+(set-option! 'max-history -5)
+(assert-equal 0 (history-load hist-file))
+(history-add "not-saved")
+(set-option! 'max-history 1000)
+(assert-equal "ls\n" (hist-header))
+(assert-equal 2 (history-load hist-file))
+(rm -f hist-file (str hist-file ".lock"))
@@ -0,0 +1,15 @@
+(load "tests/test.lisp")
+
+; Numbers without a fraction or exponent are ints when they fit.
+(def 'nums (json-parse "[1, -2, 1.0, 1e3, 9223372036854775807, 9223372036854775808]"))
+(assert-true (int? (vec-nth 0 nums)))
+(assert-equal -2 (vec-nth 1 nums))
+(assert-true (float? (vec-nth 2 nums)))
+(assert-true (float? (vec-nth 3 nums)))
+(assert-equal 9223372036854775807 (vec-nth 4 nums))
+(assert-true (float? (vec-nth 5 nums)))
+
+(def 'obj (json-parse "{\"a\": {\"b\": [true, null, \"\\ud83d\\ude00\"]}}"))
+(assert-true (query obj ".a.b[0]"))
+(assert-false (query obj ".a.b[1]"))
+(assert-equal "😀" (query obj ".a.b[2]"))
@@ -0,0 +1,39 @@
+(load "tests/test.lisp")
+
+; Parameters are read from their frame slot.
+(defn add3 (a b c) (+ a b c))
+(assert-equal 6 (add3 1 2 3))
+
+; Closures see the parameters of the lambda they were made in.
+(defn adder (n) (fn (x) (+ x n)))
+(def 'add10 (adder 10))
+(assert-equal 15 (add10 5))
+(assert-equal 7 ((adder 2) 5))
+
+; A let inside the body shadows a parameter, the parameter is back after.
+(defn shadow (x) (progn (def 'inner (let ((x 20)) x)) (list inner x)))
+(assert-equal '(20 1) (shadow 1))
+
+; set changes the slot.
+(defn bump (x) (progn (set 'x (+ x 1)) x))
+(assert-equal 2 (bump 1))
+
+; A dynamic binding of the name wins over the slot.
+(defn read-it (it) (dyn 'it 5 it))
+(assert-equal 5 (read-it 1))
+
+; Recursion gets a fresh frame each call.
+(defn fact (n) (if (< n 2) 1 (* n (fact (- n 1)))))
+(assert-equal 120 (fact 5))
+
+; undef unbinds a parameter, it can then be defined again.
+(defn undef-param (x) (progn (undef 'x) (def? 'x)))
+(assert-false (undef-param 1))
+(defn undef-read (x) (progn (undef 'x) x))
+(assert-equal "Symbol x not found." (hash-get (get-error (undef-read 1)) :msg))
+(defn undef-redef (x) (progn (undef 'x) (def 'x 3) x))
+(assert-equal 3 (undef-redef 1))
+; The lambda's other calls still have the parameter.
+(defn undef-once (x undo) (progn (if undo (undef 'x) nil) (def? 'x)))
+(assert-false (undef-once 1 t))
+(assert-true (undef-once 1 nil))
@@ -48,3 +48,10 @@ Some stuff
     (setq l4 (car (cdr (cdr l3))))
     (assert-equal l4 '(a b c)))
 
+
+; for and dotimes are native, the core:: names still work.
+(let ((total 0))
+    (core::for x '(1 2 3) (setq total (+ total x)))
+    (assert-equal 6 total)
+    (core::dotimes 4 (setq total (+ total 1)))
+    (assert-equal 10 total))
@@ -0,0 +1,34 @@
+(load "tests/test.lisp")
+
+; A macro call is expanded the first time it is evaluated and the expansion is
+; reused after that.
+(defq mac-expansions 0)
+(defmacro counted (x) (progn (setq mac-expansions (+ mac-expansions 1)) x))
+(defn use-counted () (counted 5))
+(assert-equal 5 (use-counted))
+(assert-equal 5 (use-counted))
+(assert-equal 5 (use-counted))
+(assert-equal 1 mac-expansions)
+
+; Redefining the macro expands its calls again.
+(defmacro counted (x) (progn (setq mac-expansions (+ mac-expansions 1)) `(+ ,x 1)))
+(assert-equal 6 (use-counted))
+(assert-equal 6 (use-counted))
+(assert-equal 2 mac-expansions)
+
+; State read while expanding is only read the first time, recompile forgets
+; the cached expansions.
+(defq mac-mode 1)
+(defmacro mode-mac () mac-mode)
+(defn get-mode () (mode-mac))
+(assert-equal 1 (get-mode))
+(setq mac-mode 2)
+(assert-equal 1 (get-mode))
+(assert-equal nil (recompile))
+(assert-equal 2 (get-mode))
+(assert-equal 6 (use-counted))
+(assert-equal 3 mac-expansions)
+
+; expand-macro always expands.
+(expand-macro (counted 1))
+(assert-equal 4 mac-expansions)
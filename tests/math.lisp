@@ -0,0 +1,10 @@
+(load "tests/test.lisp")
+
+; num-format groups thousands with , unless another :sep is given.
+(assert-equal "1,234,567" (num-format 1234567))
+(assert-equal "-1,234.50" (num-format -1234.5 :places 2))
+(assert-equal "1.234.567" (num-format 1234567 :sep "."))
+(assert-equal "1234567" (num-format 1234567 :sep ""))
+(assert-equal "999" (num-format 999))
+(assert-equal "12.5%" (num-format 0.125 :percent))
+(assert-equal "ff" (num-format 255 :hex))
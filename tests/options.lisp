@@ -0,0 +1,42 @@
+(load "tests/test.lisp")
+
+; The line editor, prompt and history settings are typed options.
+(assert-equal :emacs (option 'keybindings))
+(assert-equal :vi (set-option! 'keybindings :vi))
+(assert-equal "set-option!: keybindings must be one of :emacs :vi, not :foo"
+              (hash-get (get-error (set-option! 'keybindings :foo)) :msg))
+(assert-equal 1000 (option 'max-history))
+(assert-equal "set-option!: max-history must be an integer, not 10.5"
+              (hash-get (get-error (set-option! 'max-history 10.5)) :msg))
+(assert-equal "" (option 'prompt))
+(assert-equal "" (option 'vi-esc-sequence))
+(assert-equal 200 (option 'vi-esc-timeout))
+(assert-true (option 'loose-symbols))
+
+; A finished process is only true if it exited 0, unless status-as-bool is nil.
+(assert-false (if (false) t nil))
+(assert-true (if (true) t nil))
+(set-option! 'status-as-bool nil)
+(assert-true (if (false) t nil))
+(set-option! 'status-as-bool t)
+(assert-false (if (false) t nil))
+
+; The pretty print width, indent and truncation are print-* options.
+(assert-equal 1000 (option 'print-length))
+(assert-equal 0 (option 'print-level))
+(assert-equal 1000 (option 'print-lines))
+(defn pprint-string (form)
+    (let ((file "/tmp/slsh-test-pprint"))
+        (dyn '*stdout* (open file :create :truncate) (pprint form))
+        (let ((text (read-line (open file :read))))
+            (rm file)
+            text)))
+(set-option! 'print-length 3)
+(assert-equal "(1 2 3 ...)\n" (pprint-string '(1 2 3 4 5)))
+(set-option! 'print-length 0)
+(assert-equal "(1 2 3 4 5)\n" (pprint-string '(1 2 3 4 5)))
+(set-option! 'print-level 2)
+(assert-equal "(1 (2 ...))\n" (pprint-string '(1 (2 (3)))))
+(set-option! 'print-level 0)
+(assert-equal "(1 (2 (3)))\n" (pprint-string '(1 (2 (3)))))
+(set-option! 'print-length 1000)
@@ -73,3 +73,24 @@
 (assert-equal count 11)
 (assert-equal(get_count2) 4)
 
+
+
+; Call scopes are reused from a pool, a closure keeps the scope it captured and
+; a reused scope starts empty.
+(defn make-adder (n) (fn (x) (+ x n)))
+(defq add1 (make-adder 1))
+(defq add10 (make-adder 10))
+(defn pool-churn (n) (if (> n 0) (pool-churn (- n 1)) n))
+(pool-churn 100)
+(assert-equal 2 (add1 1))
+(assert-equal 11 (add10 1))
+(defn pool-probe (set) (progn (if set (defq pool-local 1)) (def? 'pool-local)))
+(assert-true (pool-probe t))
+(assert-false (pool-probe nil))
+(assert-false (def? 'pool-local))
+(defmacro pool-mac (a) (let ((b a)) `(+ ,b 1)))
+(assert-equal 2 (pool-mac 1))
+(assert-equal 3 (pool-mac 2))
+(defn pool-sum (n) (if (= n 0) 0 (+ n (pool-sum (- n 1)))))
+(assert-equal 5050 (pool-sum 100))
+(assert-equal 2 (add1 1))
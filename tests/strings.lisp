@@ -0,0 +1,36 @@
+(load "tests/test.lisp")
+
+(export 'SLSH_TEST_VAR "val")
+(def 'lit-var (str "\$" "SLSH_TEST_VAR"))
+
+; Ordinary strings expand $, unless it is escaped.
+(assert-equal "a val b" "a $SLSH_TEST_VAR b")
+(assert-equal (str "a " lit-var) "a \$SLSH_TEST_VAR")
+
+; Escapes.
+(assert-equal #\Newline (str-nth 0 "\n"))
+(assert-equal #\Tab (str-nth 0 "\t"))
+(assert-equal "A" "\x41")
+(assert-equal "é" "\u{e9}")
+(assert-equal "a\\b" (str "a" "\\" "b"))
+
+; Raw strings keep everything as written, $ is not expanded.
+(assert-equal (str "a " lit-var) #"a $SLSH_TEST_VAR"#)
+(assert-equal 4 (length #"\d$x"#))
+(assert-equal (str "\\d+" "\$") #"\d+$"#)
+(assert-equal (str "a\\\\b " "\$x") #"a\\b $x"#)
+(assert-equal (str "\\" "\$x") #"\$x"#)
+(assert-equal "say \"hi\"" #"say "hi""#)
+; Kept exactly as read, quoted or not.
+(assert-equal 6 (length (first '(#"a$b\$c"#))))
+(assert-equal "one" (case #"a$b"# (#"a$b"# "one") (:else "other")))
+(assert-equal (str "a" "\$" "b") (str-ignore-expand #"a$b"#))
+
+; Multi-line strings process escapes, are dedented and do not expand $.
+(assert-equal (str "one\n  two\nthree " lit-var) """
+    one
+      two
+    three $SLSH_TEST_VAR""")
+(assert-equal (str "é\t" "\$x") """\u{e9}\t$x""")
+(assert-equal "\$x" """\$x""")
+(assert-equal (str "a" "\$" "b") (first '("""a$b""")))